@@ -0,0 +1,222 @@
+//! The parsed tree of a query as JSON, for tools that want groq-format's
+//! parsing without depending on the parser's own types.
+
+use std::ops::Range;
+
+use groq_parser::ast::{Expr, FunctionDefinition, Literal, Token};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::lexer::{self, tokenize};
+use crate::path::{Node, Step, children, node_range};
+use crate::{FormatError, parse_query, trim_for_addressing};
+
+#[derive(Serialize)]
+struct Tree {
+    functions: Vec<Function>,
+    expr: SyntaxNode,
+    comments: Vec<Comment>,
+}
+
+#[derive(Serialize)]
+struct Function {
+    name: String,
+    parameters: Vec<String>,
+    span: Range<usize>,
+    body: SyntaxNode,
+}
+
+#[derive(Serialize)]
+struct Comment {
+    text: String,
+    span: Range<usize>,
+}
+
+#[derive(Serialize)]
+struct SyntaxNode {
+    kind: &'static str,
+    /// The step from the parent, as written in an [`AstPath`](crate::AstPath).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    role: Option<String>,
+    span: Range<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    operator: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<Value>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    children: Vec<SyntaxNode>,
+}
+
+/// Parse `query` and write its syntax tree as pretty-printed JSON.
+///
+/// The output is an object with the query's `functions`, its main `expr`
+/// and its `comments`. Each node of the tree has a `kind`, such as
+/// `"filter"` or `"attribute"`, and a `span` with the `start` and `end`
+/// byte offsets in `query` of the text it was parsed from. Nodes below the
+/// top have a `role`, the [`Step`](crate::Step) from their parent, so the
+/// roles from the top make up the [`AstPath`](crate::AstPath) of a node.
+/// Depending on the kind, a node also has a `name` (attributes, parameters
+/// and function calls), an `operator` (operators and ranges), a `value`
+/// (literals) and `children`.
+///
+/// The layout of the JSON doesn't change when the parser's types do, so
+/// linters and code generators can rely on it.
+///
+/// # Example
+///
+/// ```
+/// use groq_format::parse_to_json;
+///
+/// let json = parse_to_json("*[_type == 'post']").unwrap();
+/// assert!(json.contains(r#""kind": "filter""#));
+/// assert!(json.contains(r#""role": "constraint""#));
+/// ```
+pub fn parse_to_json(query: &str) -> Result<String, FormatError> {
+    let (leading, source) = trim_for_addressing(query)?;
+    let result = parse_query(source)?;
+    let tokens = tokenize(source);
+    let span = |range: Range<usize>| range.start + leading..range.end + leading;
+
+    let tree = Tree {
+        functions: result
+            .functions
+            .iter()
+            .map(|func| function(func, source, &tokens, leading))
+            .collect(),
+        expr: syntax_node(Node::Expr(&result.expr), None, source, &tokens, leading),
+        comments: result
+            .comments
+            .iter()
+            .map(|comment| Comment {
+                text: comment.text.clone(),
+                span: span(comment.pos.start..comment.pos.end),
+            })
+            .collect(),
+    };
+    Ok(serde_json::to_string_pretty(&tree).expect("syntax trees serialize"))
+}
+
+fn function(
+    func: &FunctionDefinition,
+    source: &str,
+    tokens: &[lexer::Token],
+    leading: usize,
+) -> Function {
+    let body = syntax_node(Node::Expr(&func.body), None, source, tokens, leading);
+    Function {
+        name: format!("{}::{}", func.id.namespace, func.id.name),
+        parameters: func.parameters.iter().map(|p| p.name.clone()).collect(),
+        span: func.pos.start + leading..body.span.end.max(func.pos.end + leading),
+        body,
+    }
+}
+
+fn syntax_node(
+    node: Node<'_>,
+    role: Option<Step>,
+    source: &str,
+    tokens: &[lexer::Token],
+    leading: usize,
+) -> SyntaxNode {
+    let range = node_range(node, source, tokens);
+    let mut syntax = SyntaxNode {
+        kind: kind(node),
+        role: role.map(|step| step.to_string()),
+        span: range.start + leading..range.end + leading,
+        name: None,
+        operator: None,
+        value: None,
+        children: Vec::new(),
+    };
+    match node {
+        Node::Call(call) => syntax.name = Some(function_name(&call.namespace, &call.name)),
+        Node::Object(_) => {}
+        Node::Expr(expr) | Node::Field(expr) => match expr {
+            Expr::Attribute(attr) => syntax.name = Some(attr.name.clone()),
+            Expr::Param(param) => syntax.name = Some(param.name.clone()),
+            Expr::FunctionCall(call) => {
+                syntax.name = Some(function_name(&call.namespace, &call.name))
+            }
+            Expr::Binary(bin) if bin.operator != Token::Colon => {
+                syntax.operator = Some(bin.operator.literal())
+            }
+            Expr::Prefix(prefix) => syntax.operator = Some(prefix.operator.literal()),
+            Expr::Postfix(postfix) => syntax.operator = Some(postfix.operator.literal()),
+            Expr::Range(range) => {
+                syntax.operator = Some(if range.inclusive { ".." } else { "..." })
+            }
+            Expr::Literal(literal) => {
+                syntax.value = Some(match literal {
+                    Literal::String(s) => Value::from(s.value.clone()),
+                    Literal::Integer(i) => Value::from(i.value),
+                    Literal::Float(f) => Value::from(f.value),
+                    Literal::Boolean(b) => Value::from(b.value),
+                    Literal::Null(_) => Value::Null,
+                })
+            }
+            _ => {}
+        },
+    }
+    syntax.children = children(node)
+        .into_iter()
+        // The arguments of a pipe stage belong to its function call.
+        .filter(|(step, _)| {
+            !matches!(
+                (node, step),
+                (Node::Expr(Expr::FunctionPipe(_)), Step::Arg(_))
+            )
+        })
+        .map(|(step, child)| syntax_node(child, Some(step), source, tokens, leading))
+        .collect();
+    syntax
+}
+
+fn function_name(namespace: &str, name: &str) -> String {
+    match namespace {
+        "" => name.to_string(),
+        namespace => format!("{}::{}", namespace, name),
+    }
+}
+
+fn kind(node: Node<'_>) -> &'static str {
+    let expr = match node {
+        Node::Object(_) => return "object",
+        Node::Call(_) => return "function_call",
+        Node::Field(Expr::Binary(bin)) if bin.operator == Token::Colon => return "pair",
+        Node::Expr(expr) | Node::Field(expr) => expr,
+    };
+    match expr {
+        Expr::Everything(_) => "everything",
+        Expr::This(_) => "this",
+        Expr::Parent(_) => "parent",
+        Expr::Literal(Literal::String(_)) => "string",
+        Expr::Literal(Literal::Integer(_)) => "integer",
+        Expr::Literal(Literal::Float(_)) => "float",
+        Expr::Literal(Literal::Boolean(_)) => "boolean",
+        Expr::Literal(Literal::Null(_)) => "null",
+        Expr::Attribute(_) => "attribute",
+        Expr::Param(_) => "param",
+        Expr::Filter(_) => "filter",
+        Expr::Slice(_) => "slice",
+        Expr::Element(_) => "element",
+        Expr::ArrayTraversal(_) => "array_traversal",
+        Expr::Dot(_) => "dot",
+        Expr::Projection(_) => "projection",
+        Expr::Pipe(_) => "pipe",
+        Expr::FunctionPipe(_) => "function_pipe",
+        Expr::Binary(_) => "binary",
+        Expr::Prefix(_) => "prefix",
+        Expr::Postfix(_) => "postfix",
+        Expr::FunctionCall(_) => "function_call",
+        Expr::Array(_) => "array",
+        Expr::Object(_) => "object",
+        Expr::Group(_) => "group",
+        Expr::Range(_) => "range",
+        Expr::Ellipsis(_) => "ellipsis",
+        Expr::Constraint(_) => "constraint",
+        Expr::Subscript(_) => "subscript",
+        Expr::Tuple(_) => "tuple",
+    }
+}
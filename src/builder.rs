@@ -0,0 +1,231 @@
+//! Programmatic construction of GROQ queries.
+//!
+//! [`Query`] assembles the syntax tree of a query step by step. Only the
+//! expressions handed to the builder, such as a filter's constraint or a
+//! projection's fields, are parsed; the steps themselves are built as AST
+//! nodes and the result is rendered through [`format_ast`](crate::format_ast),
+//! so callers always get a syntactically valid, formatted query.
+
+use std::ops::Range;
+
+use groq_parser::ast::{
+    Constraint, Everything, Expr, Filter, FunctionCall, FunctionPipe, Group, IntegerLiteral,
+    Literal, Object, Position, Projection, Slice, Subscript, Token,
+};
+
+use crate::{FormatError, FormatOptions, format_ast, parse_query};
+
+/// Position of the nodes the builder creates, which have no source text.
+const NOWHERE: Position = Position { start: 0, end: 0 };
+
+/// A GROQ query under construction.
+///
+/// Each step applies to the whole query built so far, so
+/// `Query::from("a || b").filter("x")` filters `(a || b)`, and a constraint
+/// that would otherwise be read as an index or slice, such as `0`, is
+/// parenthesised to keep it a filter.
+///
+/// # Example
+///
+/// ```
+/// use groq_format::{FormatOptions, Query};
+///
+/// let query = Query::all()
+///     .filter("_type == $type")
+///     .order(["publishedAt desc"])
+///     .slice(0..10)
+///     .project(["title", "\"slug\": slug.current"])
+///     .build(&FormatOptions::new(40))
+///     .unwrap();
+///
+/// assert_eq!(
+///     query,
+///     "*[_type == $type]
+///   | order(publishedAt desc)[0...10] {
+///   title,
+///   \"slug\": slug.current
+/// }"
+/// );
+/// ```
+#[derive(Debug, Clone)]
+pub struct Query {
+    /// The query so far, or the first fragment that failed to parse.
+    expr: Result<Expr, FormatError>,
+}
+
+impl Query {
+    /// Start from every document: `*`.
+    pub fn all() -> Self {
+        Query {
+            expr: Ok(Expr::Everything(Everything { pos: NOWHERE })),
+        }
+    }
+
+    /// Start from an arbitrary expression, such as `$ids` or `author->`.
+    pub fn from(expr: &str) -> Self {
+        Query {
+            expr: parse_fragment(expr).map(operand),
+        }
+    }
+
+    /// Append a filter: `…[constraint]`.
+    pub fn filter(self, constraint: &str) -> Self {
+        self.then(|lhs| {
+            let expression = parse_fragment(constraint)?;
+            // `[0]` and `[0...2]` are element access and slices, not filters.
+            let expression = match expression {
+                Expr::Literal(Literal::Integer(_)) | Expr::Range(_) => group(expression),
+                Expr::Prefix(ref prefix)
+                    if matches!(*prefix.rhs, Expr::Literal(Literal::Integer(_))) =>
+                {
+                    group(expression)
+                }
+                expression => expression,
+            };
+            Ok(Expr::Filter(Filter {
+                lhs: Box::new(lhs),
+                constraint: Constraint {
+                    expression: Box::new(expression),
+                    pos: NOWHERE,
+                },
+                pos: NOWHERE,
+            }))
+        })
+    }
+
+    /// Pipe through `order()` with the given sort expressions.
+    pub fn order<'a>(self, orderings: impl IntoIterator<Item = &'a str>) -> Self {
+        let arguments: Result<Vec<Expr>, FormatError> =
+            orderings.into_iter().map(parse_fragment).collect();
+        self.then(|lhs| {
+            Ok(Expr::FunctionPipe(FunctionPipe {
+                lhs: Box::new(lhs),
+                func: FunctionCall {
+                    namespace: String::new(),
+                    name: "order".to_string(),
+                    arguments: arguments?,
+                    pos: NOWHERE,
+                },
+                pos: NOWHERE,
+            }))
+        })
+    }
+
+    /// Append an exclusive slice: `…[start...end]`.
+    pub fn slice(self, range: Range<usize>) -> Self {
+        self.then(|lhs| {
+            let range = groq_parser::ast::Range {
+                start: Box::new(integer(range.start)?),
+                end: Box::new(integer(range.end)?),
+                inclusive: false,
+                pos: NOWHERE,
+            };
+            Ok(Expr::Slice(Slice {
+                lhs: Box::new(lhs),
+                range: Subscript {
+                    value: Box::new(Expr::Range(range)),
+                    pos: NOWHERE,
+                },
+                pos: NOWHERE,
+            }))
+        })
+    }
+
+    /// Append a projection with the given fields, each written as it would
+    /// appear inside `{ … }` (`title`, `"slug": slug.current`, `...`).
+    pub fn project<'a>(self, fields: impl IntoIterator<Item = &'a str>) -> Self {
+        let fields: Result<Vec<Expr>, FormatError> = fields.into_iter().map(parse_field).collect();
+        self.then(|lhs| {
+            Ok(Expr::Projection(Projection {
+                lhs: Box::new(lhs),
+                object: Object {
+                    expressions: fields?,
+                    pos: NOWHERE,
+                },
+                pos: NOWHERE,
+            }))
+        })
+    }
+
+    /// Render the query with the given options.
+    pub fn build(&self, options: &FormatOptions) -> Result<String, FormatError> {
+        match &self.expr {
+            Ok(expr) => Ok(format_ast(expr, options)),
+            Err(err) => Err(err.clone()),
+        }
+    }
+
+    /// Apply a step to the query, unless an earlier one failed.
+    fn then(self, step: impl FnOnce(Expr) -> Result<Expr, FormatError>) -> Self {
+        Query {
+            expr: self.expr.and_then(step),
+        }
+    }
+}
+
+/// Parse an expression handed to the builder.
+fn parse_fragment(fragment: &str) -> Result<Expr, FormatError> {
+    let result = parse_query(fragment)?;
+    if !result.functions.is_empty() {
+        return Err(FormatError::Parse(format!(
+            "expected an expression, got function definitions: {}",
+            fragment
+        )));
+    }
+    Ok(result.expr)
+}
+
+/// Parse a single field of a projection.
+fn parse_field(field: &str) -> Result<Expr, FormatError> {
+    match parse_fragment(&format!("{{{}}}", field))? {
+        Expr::Object(mut object) if object.expressions.len() == 1 => {
+            Ok(object.expressions.remove(0))
+        }
+        _ => Err(FormatError::Parse(format!(
+            "expected a single projection field: {}",
+            field
+        ))),
+    }
+}
+
+/// Wrap an expression that a following step would otherwise only apply part
+/// of, such as the `b` of `a || b`, in parentheses.
+fn operand(expr: Expr) -> Expr {
+    match expr {
+        Expr::Everything(_)
+        | Expr::This(_)
+        | Expr::Parent(_)
+        | Expr::Literal(_)
+        | Expr::Attribute(_)
+        | Expr::Param(_)
+        | Expr::Filter(_)
+        | Expr::Slice(_)
+        | Expr::Element(_)
+        | Expr::ArrayTraversal(_)
+        | Expr::Dot(_)
+        | Expr::Projection(_)
+        | Expr::FunctionPipe(_)
+        | Expr::FunctionCall(_)
+        | Expr::Array(_)
+        | Expr::Object(_)
+        | Expr::Group(_) => expr,
+        Expr::Postfix(ref postfix) if postfix.operator == Token::Arrow => expr,
+        expr => group(expr),
+    }
+}
+
+fn group(expr: Expr) -> Expr {
+    Expr::Group(Group {
+        expression: Box::new(expr),
+        pos: NOWHERE,
+    })
+}
+
+fn integer(value: usize) -> Result<Expr, FormatError> {
+    let value = i64::try_from(value)
+        .map_err(|_| FormatError::Parse(format!("slice bound {} is too large", value)))?;
+    Ok(Expr::Literal(Literal::Integer(IntegerLiteral {
+        value,
+        pos: NOWHERE,
+    })))
+}
@@ -0,0 +1,131 @@
+//! The built-in functions of GROQ.
+
+use crate::format::CallLayout;
+use crate::format::CallLayout::{Flat, OnePerLine, Wrap};
+
+/// A built-in GROQ function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BuiltinFunction {
+    /// Namespace of the function; `global` for functions called without one.
+    pub namespace: &'static str,
+    /// Name of the function.
+    pub name: &'static str,
+    /// Parameters and result, such as `(value: any) -> boolean`. Optional
+    /// parameters end in `?` and repeated ones start with `...`.
+    pub signature: &'static str,
+    /// The earliest Sanity API version that supports the function.
+    pub min_api_version: &'static str,
+    /// How calls to the function are laid out by default.
+    pub layout: CallLayout,
+    /// Whether the function is called after a pipe, as in `| order(title)`.
+    pub pipe: bool,
+}
+
+impl BuiltinFunction {
+    /// The name as written in a query: `name` for global functions,
+    /// `namespace::name` otherwise.
+    pub fn qualified_name(&self) -> String {
+        if self.namespace == "global" {
+            self.name.to_string()
+        } else {
+            format!("{}::{}", self.namespace, self.name)
+        }
+    }
+}
+
+/// Shorthand for the entries of [`BUILTIN_FUNCTIONS`].
+const fn function(
+    namespace: &'static str,
+    name: &'static str,
+    signature: &'static str,
+    min_api_version: &'static str,
+    layout: CallLayout,
+    pipe: bool,
+) -> BuiltinFunction {
+    BuiltinFunction {
+        namespace,
+        name,
+        signature,
+        min_api_version,
+        layout,
+        pipe,
+    }
+}
+
+const V1: &str = "v1";
+const V2021: &str = "v2021-03-25";
+const V2025: &str = "v2025-02-19";
+
+/// Every built-in GROQ function, sorted by namespace and name.
+#[rustfmt::skip]
+pub const BUILTIN_FUNCTIONS: &[BuiltinFunction] = &[
+    function("array",    "compact",       "(array: array) -> array",                         V2021, Wrap, false),
+    function("array",    "intersects",    "(a: array, b: array) -> boolean",                 V2021, Wrap, false),
+    function("array",    "join",          "(array: array, separator: string) -> string",     V2021, Wrap, false),
+    function("array",    "unique",        "(array: array) -> array",                         V2021, Wrap, false),
+    function("dateTime", "now",           "() -> datetime",                                  V2021, Flat, false),
+    function("delta",    "changedAny",    "(selector: path) -> boolean",                     V2021, Wrap, false),
+    function("delta",    "changedOnly",   "(selector: path) -> boolean",                     V2021, Wrap, false),
+    function("delta",    "operation",     "() -> string",                                    V2021, Flat, false),
+    function("diff",     "changedAny",    "(lhs: any, rhs: any, selector: path) -> boolean", V2021, Wrap, false),
+    function("diff",     "changedOnly",   "(lhs: any, rhs: any, selector: path) -> boolean", V2021, Wrap, false),
+    function("geo",      "contains",      "(a: geo, b: geo) -> boolean",                     V2021, Wrap, false),
+    function("geo",      "distance",      "(a: geo, b: geo) -> number",                      V2021, Wrap, false),
+    function("geo",      "intersects",    "(a: geo, b: geo) -> boolean",                     V2021, Wrap, false),
+    function("geo",      "latLng",        "(lat: number, lng: number) -> geo",               V2021, Flat, false),
+    function("global",   "boost",         "(predicate: boolean, weight: number) -> boolean", V1,    Flat, false),
+    function("global",   "coalesce",      "(...values: any) -> any",                         V1,    Wrap, false),
+    function("global",   "count",         "(array: array) -> number",                        V1,    Wrap, false),
+    function("global",   "dateTime",      "(value: string) -> datetime",                     V1,    Flat, false),
+    function("global",   "defined",       "(value: any) -> boolean",                         V1,    Wrap, false),
+    function("global",   "geo",           "(value: object) -> geo",                          V2021, Flat, false),
+    function("global",   "identity",      "() -> string",                                    V1,    Wrap, false),
+    function("global",   "length",        "(value: string | array) -> number",               V1,    Wrap, false),
+    function("global",   "lower",         "(value: string) -> string",                       V1,    Wrap, false),
+    function("global",   "now",           "() -> string",                                    V1,    Flat, false),
+    function("global",   "order",         "(...orderings: any) -> array",                    V1,    Wrap, true),
+    function("global",   "path",          "(pattern: string) -> path",                       V1,    Wrap, false),
+    function("global",   "references",    "(...ids: string | array) -> boolean",             V1,    Wrap, false),
+    function("global",   "round",         "(value: number, precision?: number) -> number",   V1,    Wrap, false),
+    function("global",   "score",         "(...predicates: boolean) -> array",               V1,    OnePerLine, true),
+    function("global",   "select",        "(...conditions: any) -> any",                     V1,    OnePerLine, false),
+    function("global",   "string",        "(value: any) -> string",                          V2021, Wrap, false),
+    function("global",   "upper",         "(value: string) -> string",                       V1,    Wrap, false),
+    function("math",     "avg",           "(values: array) -> number",                       V2021, Wrap, false),
+    function("math",     "max",           "(values: array) -> number",                       V2021, Wrap, false),
+    function("math",     "min",           "(values: array) -> number",                       V2021, Wrap, false),
+    function("math",     "sum",           "(values: array) -> number",                       V2021, Wrap, false),
+    function("pt",       "text",          "(blocks: array | object) -> string",              V2021, Flat, false),
+    function("releases", "all",           "() -> array",                                     V2025, Wrap, false),
+    function("sanity",   "dataset",       "() -> string",                                    V2021, Wrap, false),
+    function("sanity",   "partOfRelease", "(release: string) -> boolean",                    V2025, Wrap, false),
+    function("sanity",   "projectId",     "() -> string",                                    V2021, Wrap, false),
+    function("sanity",   "versionOf",     "(id: string) -> boolean",                         V2025, Wrap, false),
+    function("string",   "split",         "(value: string, separator: string) -> array",     V2021, Wrap, false),
+    function("string",   "startsWith",    "(value: string, prefix: string) -> boolean",      V2021, Wrap, false),
+];
+
+/// Look up a built-in function by namespace and name. A namespace of `""`
+/// means a global function called without one, as in `count(...)`.
+///
+/// # Example
+///
+/// ```
+/// use groq_format::builtin_function;
+///
+/// let count = builtin_function("", "count").unwrap();
+/// assert_eq!(count.signature, "(array: array) -> number");
+/// assert_eq!(builtin_function("math", "sum").unwrap().min_api_version, "v2021-03-25");
+/// assert!(builtin_function("math", "median").is_none());
+/// ```
+pub fn builtin_function(namespace: &str, name: &str) -> Option<&'static BuiltinFunction> {
+    let namespace = if namespace.is_empty() {
+        "global"
+    } else {
+        namespace
+    };
+    BUILTIN_FUNCTIONS
+        .binary_search_by(|function| (function.namespace, function.name).cmp(&(namespace, name)))
+        .ok()
+        .map(|index| &BUILTIN_FUNCTIONS[index])
+}
@@ -0,0 +1,92 @@
+//! Generation of a TypeScript module from a set of query files.
+
+use crate::metadata::query_metadata;
+
+/// A query to include in a generated module.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BundledQuery {
+    /// Name of the exported constant.
+    pub name: String,
+    /// Description for the constant's doc comment.
+    pub description: Option<String>,
+    /// The formatted query.
+    pub query: String,
+}
+
+/// The name to export a query under: the name given by a `// @name` header
+/// at the top of the query, or else the file name converted to camel case.
+///
+/// # Example
+///
+/// ```
+/// use groq_format::query_name;
+///
+/// assert_eq!(query_name("post-by-slug", "*[slug.current == $slug][0]"), "postBySlug");
+/// assert_eq!(query_name("post-by-slug", "// @name postQuery\n*[slug.current == $slug][0]"), "postQuery");
+/// ```
+pub fn query_name(file_stem: &str, query: &str) -> String {
+    query_metadata(query)
+        .name
+        .unwrap_or_else(|| camel_case(file_stem))
+}
+
+/// Convert a file name such as `post-by-slug` or `post_by_slug` to an
+/// identifier such as `postBySlug`.
+fn camel_case(name: &str) -> String {
+    let mut result = String::with_capacity(name.len());
+    let mut upper = false;
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() {
+            if upper && !result.is_empty() {
+                result.push(c.to_ascii_uppercase());
+            } else {
+                result.push(c);
+            }
+            upper = false;
+        } else {
+            upper = true;
+        }
+    }
+    if result.is_empty() || result.starts_with(|c: char| c.is_ascii_digit()) {
+        result.insert(0, '_');
+    }
+    result
+}
+
+/// A TypeScript module exporting each query as a `groq` tagged template
+/// literal, in the given order. Descriptions become doc comments.
+///
+/// # Example
+///
+/// ```
+/// use groq_format::{BundledQuery, typescript_module};
+///
+/// let module = typescript_module(&[BundledQuery {
+///     name: "allPosts".to_string(),
+///     description: None,
+///     query: r#"*[_type == "post"]"#.to_string(),
+/// }]);
+/// assert!(module.contains("export const allPosts = groq`*[_type == \"post\"]`;\n"));
+/// ```
+pub fn typescript_module(queries: &[BundledQuery]) -> String {
+    let mut module = String::from(
+        "// This file is generated by groq-format from .groq files. Do not edit.\n\n\
+         import groq from \"groq\";\n",
+    );
+    for query in queries {
+        module.push('\n');
+        if let Some(description) = &query.description {
+            module.push_str(&format!("/** {} */\n", description.replace("*/", "*\\/")));
+        }
+        module.push_str(&format!(
+            "export const {} = groq`{}`;\n",
+            query.name,
+            query
+                .query
+                .replace('\\', "\\\\")
+                .replace('`', "\\`")
+                .replace("${", "\\${")
+        ));
+    }
+    module
+}
@@ -0,0 +1,298 @@
+//! Caching of formatted output.
+//!
+//! [`FormatCache`] keys entries by the query text and the [`FormatOptions`]
+//! themselves, so a change to either produces a miss. [`DiskCache`] keys
+//! them by a SHA-256 hash of the content and of the options serialized as
+//! JSON, which is the same across runs, platforms and Rust versions and
+//! can't be made to collide.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::hash::Hasher;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+use sha2::{Digest, Sha256};
+
+use crate::{FormatError, FormatOptions, format_query_with_options};
+
+/// An opt-in, in-memory cache of formatting results.
+///
+/// Repeated formatting of unchanged input (watch modes, editor integrations)
+/// returns the stored result instead of parsing and rendering again. Parse
+/// errors are cached as well. Results are keyed by the query itself rather
+/// than a hash of it, so one query's result is never returned for another.
+///
+/// The cache holds at most [`capacity`](FormatCache::capacity) results;
+/// once full, the oldest result is dropped for each new one.
+///
+/// The cache is `Send + Sync` and all methods take `&self`, so one cache can
+/// be shared between threads, for example in an `Arc`.
+///
+/// # Example
+///
+/// ```
+/// use groq_format::{FormatCache, FormatOptions};
+///
+/// let cache = FormatCache::new();
+/// let options = FormatOptions::default();
+/// let first = cache.format("*[_type==\"post\"]", &options).unwrap();
+/// let second = cache.format("*[_type==\"post\"]", &options).unwrap();
+/// assert_eq!(first, second);
+/// assert_eq!(cache.len(), 1);
+/// ```
+#[derive(Debug)]
+pub struct FormatCache {
+    capacity: usize,
+    entries: Mutex<Entries>,
+}
+
+type Key = (String, FormatOptions);
+
+#[derive(Debug, Default)]
+struct Entries {
+    results: HashMap<Key, Result<String, FormatError>>,
+    /// Keys of `results`, oldest first.
+    order: VecDeque<Key>,
+}
+
+impl Default for FormatCache {
+    fn default() -> Self {
+        FormatCache::with_capacity(FormatCache::DEFAULT_CAPACITY)
+    }
+}
+
+impl FormatCache {
+    /// Number of results kept by a cache from [`FormatCache::new`].
+    pub const DEFAULT_CAPACITY: usize = 1024;
+
+    /// Create an empty cache holding up to
+    /// [`DEFAULT_CAPACITY`](FormatCache::DEFAULT_CAPACITY) results.
+    pub fn new() -> Self {
+        FormatCache::default()
+    }
+
+    /// Create an empty cache holding up to `capacity` results. A capacity of
+    /// zero caches nothing.
+    pub fn with_capacity(capacity: usize) -> Self {
+        FormatCache {
+            capacity,
+            entries: Mutex::default(),
+        }
+    }
+
+    /// Format a query, returning the cached result if this exact query was
+    /// already formatted with the same options.
+    ///
+    /// The lock is not held while formatting, so threads formatting
+    /// different queries don't wait for each other.
+    pub fn format(&self, query: &str, options: &FormatOptions) -> Result<String, FormatError> {
+        let key = (query.to_string(), options.clone());
+        if let Some(result) = self.entries().results.get(&key) {
+            return result.clone();
+        }
+        let result = format_query_with_options(query, options);
+        if self.capacity > 0 {
+            let mut entries = self.entries();
+            // Another thread may have formatted the same query meanwhile.
+            if entries
+                .results
+                .insert(key.clone(), result.clone())
+                .is_none()
+            {
+                entries.order.push_back(key);
+                if entries.order.len() > self.capacity
+                    && let Some(oldest) = entries.order.pop_front()
+                {
+                    entries.results.remove(&oldest);
+                }
+            }
+        }
+        result
+    }
+
+    /// Maximum number of cached results.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Number of cached results.
+    pub fn len(&self) -> usize {
+        self.entries().results.len()
+    }
+
+    /// Whether the cache holds no results.
+    pub fn is_empty(&self) -> bool {
+        self.entries().results.is_empty()
+    }
+
+    /// Drop all cached results.
+    pub fn clear(&self) {
+        let mut entries = self.entries();
+        entries.results.clear();
+        entries.order.clear();
+    }
+
+    fn entries(&self) -> MutexGuard<'_, Entries> {
+        // Entries are inserted whole, so a panic elsewhere can't leave the
+        // map inconsistent.
+        self.entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Default directory for [`DiskCache`], relative to the working directory.
+pub const DEFAULT_CACHE_DIR: &str = ".groqfmt-cache";
+
+/// Name of the file inside the cache directory.
+const CACHE_FILE: &str = "clean";
+
+/// A persistent record of inputs that are already formatted.
+///
+/// Each entry says that content with a given SHA-256 hash is left unchanged
+/// by formatting with given options, so tools can skip such files
+/// on later runs. The cache is stamped with the crate version and discarded
+/// when it was written by a different version, whose output may differ.
+///
+/// # Example
+///
+/// ```no_run
+/// use groq_format::{DiskCache, FormatOptions, DEFAULT_CACHE_DIR};
+///
+/// let options = FormatOptions::default();
+/// let mut cache = DiskCache::open(DEFAULT_CACHE_DIR).unwrap();
+/// let content = "*[_type == \"post\"]\n";
+/// if !cache.is_clean(content, &options) {
+///     // ... format and compare ...
+///     cache.mark_clean(content, &options);
+/// }
+/// cache.save().unwrap();
+/// ```
+#[derive(Debug)]
+pub struct DiskCache {
+    dir: PathBuf,
+    clean: HashSet<[u8; 32]>,
+    modified: bool,
+}
+
+impl DiskCache {
+    /// Load the cache stored in `dir`. A missing, unreadable-format or
+    /// outdated cache yields an empty one; other I/O errors are returned.
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        let mut clean = HashSet::new();
+        match fs::read_to_string(dir.join(CACHE_FILE)) {
+            Ok(contents) => {
+                let mut lines = contents.lines();
+                if lines.next() == Some(cache_header().as_str()) {
+                    clean.extend(lines.filter_map(parse_entry));
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        Ok(DiskCache {
+            dir,
+            clean,
+            modified: false,
+        })
+    }
+
+    /// Whether `content` is known to be unchanged by formatting with `options`.
+    pub fn is_clean(&self, content: &str, options: &FormatOptions) -> bool {
+        self.clean.contains(&entry_key(content, options))
+    }
+
+    /// Record that `content` is unchanged by formatting with `options`.
+    pub fn mark_clean(&mut self, content: &str, options: &FormatOptions) {
+        if self.clean.insert(entry_key(content, options)) {
+            self.modified = true;
+        }
+    }
+
+    /// Number of recorded entries.
+    pub fn len(&self) -> usize {
+        self.clean.len()
+    }
+
+    /// Whether the cache holds no entries.
+    pub fn is_empty(&self) -> bool {
+        self.clean.is_empty()
+    }
+
+    /// Write the cache back to disk, creating the directory if needed. Does
+    /// nothing if no entries were added since it was opened.
+    pub fn save(&self) -> io::Result<()> {
+        if !self.modified {
+            return Ok(());
+        }
+        fs::create_dir_all(&self.dir)?;
+        let mut contents = cache_header();
+        contents.push('\n');
+        for key in &self.clean {
+            for byte in key {
+                contents.push_str(&format!("{:02x}", byte));
+            }
+            contents.push('\n');
+        }
+        fs::write(self.dir.join(CACHE_FILE), contents)
+    }
+}
+
+fn cache_header() -> String {
+    format!("groq-format {}", env!("CARGO_PKG_VERSION"))
+}
+
+fn parse_entry(line: &str) -> Option<[u8; 32]> {
+    if line.len() != 64 {
+        return None;
+    }
+    let mut key = [0; 32];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(line.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(key)
+}
+
+/// SHA-256 hash of `content` and of `options` serialized as JSON, the
+/// options prefixed with their length so the two can't run together.
+fn entry_key(content: &str, options: &FormatOptions) -> [u8; 32] {
+    let options = serde_json::to_vec(options).expect("options serialize");
+    let mut hasher = Sha256::new();
+    hasher.update((options.len() as u64).to_le_bytes());
+    hasher.update(&options);
+    hasher.update(content.as_bytes());
+    hasher.finalize().into()
+}
+
+/// Stable 64-bit FNV-1a hash of a query's text, for fingerprints. It is
+/// quick but not collision resistant.
+pub fn content_hash(content: &str) -> u64 {
+    let mut hasher = Fnv64::default();
+    hasher.write(content.as_bytes());
+    hasher.finish()
+}
+
+/// 64-bit FNV-1a.
+struct Fnv64(u64);
+
+impl Default for Fnv64 {
+    fn default() -> Self {
+        Fnv64(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for Fnv64 {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+}
@@ -0,0 +1,237 @@
+//! A formatting daemon listening on a Unix socket, and its client.
+//!
+//! Starting a process for every file is slow when scripts and git hooks
+//! format hundreds of files one at a time. `groq-format --daemon` keeps a
+//! process running, and `groq-format --use-daemon` sends it the files
+//! instead of formatting them itself.
+//!
+//! Requests and responses are JSON objects, one per line. A request holds
+//! the client's command-line arguments, so the daemon formats with the
+//! client's options rather than its own.
+
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, MetadataExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use clap::Parser;
+use groq_format::format_document;
+use serde::{Deserialize, Serialize};
+
+use crate::{Cli, format_options};
+
+/// The socket used when `--socket` is not given: `groq-format.sock` in
+/// `$XDG_RUNTIME_DIR`, or else in a directory in the temporary directory
+/// that only the current user can use.
+pub fn default_socket() -> io::Result<PathBuf> {
+    if let Some(dir) = std::env::var_os("XDG_RUNTIME_DIR").filter(|dir| !dir.is_empty()) {
+        return Ok(PathBuf::from(dir).join("groq-format.sock"));
+    }
+    let dir = std::env::temp_dir().join(format!("groq-format-{}", current_uid()));
+    match fs::DirBuilder::new().mode(0o700).create(&dir) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+        Err(e) => return Err(e),
+    }
+    // Another user may have created the directory first.
+    let metadata = fs::symlink_metadata(&dir)?;
+    if !metadata.is_dir() || metadata.uid() != current_uid() || metadata.mode() & 0o077 != 0 {
+        return Err(io::Error::other(format!(
+            "{} is not a private directory of the current user",
+            dir.display()
+        )));
+    }
+    Ok(dir.join("groq-format.sock"))
+}
+
+fn current_uid() -> u32 {
+    // SAFETY: getuid has no preconditions and always succeeds.
+    unsafe { libc::getuid() }
+}
+
+/// Check that `socket` is a socket of the current user, so that queries
+/// aren't sent to another user's process and other files aren't removed.
+fn check_socket(socket: &Path) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(socket)?;
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::other(format!(
+            "{} exists and is not a socket",
+            socket.display()
+        )));
+    }
+    if metadata.uid() != current_uid() {
+        return Err(io::Error::other(format!(
+            "{} belongs to another user",
+            socket.display()
+        )));
+    }
+    Ok(())
+}
+
+/// A query to format.
+#[derive(Serialize, Deserialize)]
+struct Request {
+    /// The client's command-line arguments, without the program name.
+    args: Vec<String>,
+    /// Name of the input, for error messages.
+    name: String,
+    input: String,
+    /// Whether empty input formats to nothing rather than failing.
+    allow_empty: bool,
+}
+
+/// The answer to a [`Request`].
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Response {
+    /// The formatted input, with the queries that could not be formatted.
+    Formatted { output: String, errors: Vec<String> },
+    /// The input could not be formatted at all.
+    Failed { error: String },
+}
+
+/// The output for one input formatted by the daemon.
+pub struct Formatted {
+    pub output: String,
+    /// One message per query that could not be formatted and was kept
+    /// verbatim.
+    pub errors: Vec<String>,
+}
+
+/// Listen on `socket` and format the queries sent to it, until killed.
+pub fn serve(socket: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if fs::symlink_metadata(socket).is_ok() {
+        check_socket(socket)?;
+        if UnixStream::connect(socket).is_ok() {
+            return Err(format!("a daemon is already listening on {}", socket.display()).into());
+        }
+        // Left behind by a daemon that did not shut down cleanly.
+        fs::remove_file(socket)?;
+    }
+    // Create the socket without access for other users, rather than
+    // restricting it once it can already be connected to.
+    // SAFETY: umask has no preconditions, and no other threads are running
+    // yet that could create files in the meantime.
+    let umask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(socket);
+    // SAFETY: as above.
+    unsafe { libc::umask(umask) };
+    let listener = listener?;
+    eprintln!("groq-format: listening on {}", socket.display());
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("groq-format: {}", e);
+                continue;
+            }
+        };
+        thread::spawn(move || {
+            if let Err(e) = serve_client(stream) {
+                eprintln!("groq-format: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Answer the requests of one client until it disconnects.
+fn serve_client(stream: UnixStream) -> io::Result<()> {
+    let reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    for line in reader.lines() {
+        let response = match serde_json::from_str::<Request>(&line?) {
+            Ok(request) => respond(request),
+            Err(e) => Response::Failed {
+                error: format!("invalid request: {}", e),
+            },
+        };
+        serde_json::to_writer(&mut writer, &response)?;
+        writer.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+fn respond(request: Request) -> Response {
+    let args = std::iter::once("groq-format".to_string()).chain(request.args);
+    let cli = match Cli::try_parse_from(args) {
+        Ok(cli) => cli,
+        Err(e) => {
+            return Response::Failed {
+                error: e.render().to_string().trim().to_string(),
+            };
+        }
+    };
+    let options = format_options(&cli).with_allow_empty(request.allow_empty);
+
+    match format_document(&request.input, &options) {
+        Ok(document) => Response::Formatted {
+            output: document.output,
+            errors: document
+                .errors
+                .iter()
+                .map(|err| format!("{}:{}: {}", request.name, err.line, err.error))
+                .collect(),
+        },
+        Err(e) => Response::Failed {
+            error: format!("{}: {}", request.name, e),
+        },
+    }
+}
+
+/// A connection to a running daemon.
+pub struct Client {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+    args: Vec<String>,
+}
+
+impl Client {
+    /// Connect to the daemon on `socket`, which will format with the options
+    /// in `args`.
+    pub fn connect(socket: &Path, args: Vec<String>) -> Result<Client, Box<dyn std::error::Error>> {
+        let writer = check_socket(socket)
+            .and_then(|()| UnixStream::connect(socket))
+            .map_err(|e| {
+            format!(
+                "could not connect to a daemon on {}: {}; start one with `groq-format --daemon`",
+                socket.display(),
+                e
+            )
+        })?;
+        Ok(Client {
+            reader: BufReader::new(writer.try_clone()?),
+            writer,
+            args,
+        })
+    }
+
+    /// Have the daemon format `input`.
+    pub fn format(
+        &mut self,
+        name: &str,
+        input: &str,
+        allow_empty: bool,
+    ) -> Result<Formatted, Box<dyn std::error::Error>> {
+        let request = Request {
+            args: self.args.clone(),
+            name: name.to_string(),
+            input: input.to_string(),
+            allow_empty,
+        };
+        serde_json::to_writer(&mut self.writer, &request)?;
+        self.writer.write_all(b"\n")?;
+
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err("the daemon closed the connection".into());
+        }
+        match serde_json::from_str(&line)? {
+            Response::Formatted { output, errors } => Ok(Formatted { output, errors }),
+            Response::Failed { error } => Err(error.into()),
+        }
+    }
+}
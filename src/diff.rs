@@ -0,0 +1,119 @@
+//! Unified diffs between a file and its formatted version.
+
+/// Lines of unchanged context around each change.
+const CONTEXT: usize = 3;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Edit {
+    Keep,
+    Delete,
+    Insert,
+}
+
+/// A unified diff from `old` to `new`, in the format of `diff -u`, with the
+/// given file names in its header. Empty if the texts are the same.
+pub fn unified(old_name: &str, new_name: &str, old: &str, new: &str) -> String {
+    if old == new {
+        return String::new();
+    }
+    let old: Vec<&str> = old.split_inclusive('\n').collect();
+    let new: Vec<&str> = new.split_inclusive('\n').collect();
+    let edits = edits(&old, &new);
+
+    let mut diff = format!("--- {}\n+++ {}\n", old_name, new_name);
+    // Position in `old` and `new` at the start of each edit.
+    let mut positions = Vec::with_capacity(edits.len() + 1);
+    let (mut i, mut j) = (0, 0);
+    for edit in &edits {
+        positions.push((i, j));
+        match edit {
+            Edit::Keep => (i, j) = (i + 1, j + 1),
+            Edit::Delete => i += 1,
+            Edit::Insert => j += 1,
+        }
+    }
+    positions.push((i, j));
+
+    // Hunks as ranges of `edits`: each change with its context, merged
+    // with the previous hunk when their context touches.
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for (k, _) in edits.iter().enumerate().filter(|(_, e)| **e != Edit::Keep) {
+        let (start, end) = (
+            k.saturating_sub(CONTEXT),
+            (k + 1 + CONTEXT).min(edits.len()),
+        );
+        match hunks.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+
+    for (hunk_start, hunk_end) in hunks {
+        let (old_start, new_start) = positions[hunk_start];
+        let (old_end, new_end) = positions[hunk_end];
+        diff.push_str(&format!(
+            "@@ -{} +{} @@\n",
+            range(old_start, old_end - old_start),
+            range(new_start, new_end - new_start)
+        ));
+        for (edit, &(i, j)) in edits[hunk_start..hunk_end]
+            .iter()
+            .zip(&positions[hunk_start..hunk_end])
+        {
+            let (prefix, line) = match edit {
+                Edit::Keep => (' ', old[i]),
+                Edit::Delete => ('-', old[i]),
+                Edit::Insert => ('+', new[j]),
+            };
+            diff.push(prefix);
+            diff.push_str(line);
+            if !line.ends_with('\n') {
+                diff.push_str("\n\\ No newline at end of file\n");
+            }
+        }
+    }
+    diff
+}
+
+/// A hunk's line range as `start,count`, with the count left out when it
+/// is 1, as `diff` does.
+fn range(start: usize, count: usize) -> String {
+    match count {
+        0 => format!("{},0", start),
+        1 => format!("{}", start + 1),
+        _ => format!("{},{}", start + 1, count),
+    }
+}
+
+/// The shortest edit script turning `old` into `new`, from their longest
+/// common subsequence.
+fn edits(old: &[&str], new: &[&str]) -> Vec<Edit> {
+    // lcs[i][j] is the length of the longest common subsequence of
+    // old[i..] and new[j..].
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut edits = Vec::with_capacity(old.len() + new.len());
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            edits.push(Edit::Keep);
+            (i, j) = (i + 1, j + 1);
+        } else if j < new.len() && (i == old.len() || lcs[i][j + 1] > lcs[i + 1][j]) {
+            edits.push(Edit::Insert);
+            j += 1;
+        } else {
+            edits.push(Edit::Delete);
+            i += 1;
+        }
+    }
+    edits
+}
@@ -4,47 +4,158 @@
 //! - Text(s): literal text
 //! - Line: a potential line break (becomes newline or space depending on grouping)
 //! - Nest(i, d): indent nested content by i spaces
+//! - Align(d): indent nested content to the column where d starts
+//! - Width(w, d): lay out nested content as if lines were w columns wide
+//! - Tabs(w, d): write the indentation of nested content as tabs of w columns
 //! - Group(d): try to fit on one line, otherwise expand
-//! - Concat(d1, d2): concatenation
+//! - IfBreak(b, f): b if the enclosing group is expanded, f if it is on one line
+//! - Fill(d1, sep1, d2, ...): break only the separators needed to fit
+//! - Concat(d1, ..., dn): concatenation
+//! - Mark(id, d): renders as d, recording where it ends up in the output
+//! - Annotated(c, d): renders as d, tagged with a category for highlighting
+//!   by [`render_ansi`] and [`render_html`]
+//!
+//! Documents implement `Serialize` so tooling can inspect the layout tree as JSON.
+
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::ops::Range;
+
+use serde::Serialize;
 
 /// A document in Wadler's algebra.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Doc {
     /// Empty document.
     Nil,
-    /// Literal text.
-    Text(String),
+    /// Literal text. Fixed punctuation and operators are borrowed rather than
+    /// allocated for every node.
+    Text(Cow<'static, str>),
     /// A potential line break. In "flat" mode it becomes `space`; in "break" mode it becomes a newline.
-    Line { space: String },
+    Line { space: Cow<'static, str> },
     /// Increases indentation for nested content.
     Nest { indent: usize, doc: Box<Doc> },
+    /// Sets the indentation for nested content to the column at which the
+    /// document starts, so broken lines line up under its first character.
+    Align(Box<Doc>),
+    /// Lays out its contents as if the line width were `width`, so that one
+    /// kind of construct can have more or less room than the rest.
+    Width { width: usize, doc: Box<Doc> },
+    /// Writes the indentation of its contents with a tab for every `width`
+    /// columns, followed by spaces for the columns left over. Tabs count as
+    /// `width` columns when fitting.
+    Tabs { width: usize, doc: Box<Doc> },
     /// Tries to fit content on one line; if it doesn't fit, expands lines.
     Group(Box<Doc>),
-    /// Concatenation of two documents.
-    Concat { left: Box<Doc>, right: Box<Doc> },
+    /// Renders as `broken` when the enclosing group is expanded and as `flat`
+    /// when it fits on one line, for content that only belongs in one of the
+    /// layouts, such as a comment after a closing brace.
+    IfBreak { broken: Box<Doc>, flat: Box<Doc> },
+    /// Concatenation of documents. Built by [`Doc::concat`], which keeps
+    /// the parts flat: a part is never itself a `Concat`.
+    Concat(Vec<Doc>),
+    /// Alternating contents and separators (usually [`Doc::line`]). Each
+    /// separator is laid out flat if the content after it fits on the line,
+    /// and broken otherwise, so lines are filled like a paragraph.
+    Fill(Vec<Doc>),
+    /// Literal text that is not counted when deciding whether a group fits.
+    /// Used for string literals too long to fit on any line.
+    Unmeasured(Cow<'static, str>),
     /// A forced line break that always becomes a newline, even in flat mode.
     /// Used after line comments (`//`) where a newline is syntactically required.
     HardLine,
+    /// Renders as its contents. [`pretty_with_marks`] records the range of
+    /// output they produce under `id`.
+    Mark { id: usize, doc: Box<Doc> },
+    /// Renders as its contents, which are of the given kind of syntax.
+    /// Highlighting renderers such as [`render_ansi`] and [`render_html`]
+    /// style them by it.
+    Annotated(Category, Box<Doc>),
+}
+
+/// The kind of syntax a piece of a document is, for highlighting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Category {
+    /// `true`, `false`, `null`, `fn`, `asc`, `desc` and the `*`, `@` and
+    /// `^` that start a path.
+    Keyword,
+    /// A string literal, with its quotes.
+    String,
+    /// An integer or float literal.
+    Number,
+    /// An operator such as `==`, `&&` or `!`.
+    Operator,
+    /// An attribute name, such as `title`.
+    Field,
+    /// A parameter, such as `$slug`.
+    Param,
+    /// The name of a function being called or defined.
+    Function,
+    /// A comment.
+    Comment,
+}
+
+impl Category {
+    /// The name of the category in lowercase, such as `keyword`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Category::Keyword => "keyword",
+            Category::String => "string",
+            Category::Number => "number",
+            Category::Operator => "operator",
+            Category::Field => "field",
+            Category::Param => "param",
+            Category::Function => "function",
+            Category::Comment => "comment",
+        }
+    }
+
+    /// The ANSI escape sequence that starts text of this kind.
+    fn ansi(self) -> &'static str {
+        match self {
+            Category::Keyword => "\x1b[35m",
+            Category::String => "\x1b[32m",
+            Category::Number => "\x1b[33m",
+            Category::Operator => "\x1b[36m",
+            Category::Field => "\x1b[34m",
+            Category::Param => "\x1b[31m",
+            Category::Function => "\x1b[1;34m",
+            Category::Comment => "\x1b[90m",
+        }
+    }
 }
 
 impl Doc {
-    /// Create a text document.
-    pub fn text(s: impl Into<String>) -> Doc {
+    /// Create a text document. Pass a `&'static str` where possible; it is
+    /// stored without copying.
+    pub fn text(s: impl Into<Cow<'static, str>>) -> Doc {
         let s = s.into();
+        if let Cow::Owned(s) = &s {
+            charge(s.capacity());
+        }
         if s.is_empty() { Doc::Nil } else { Doc::Text(s) }
     }
 
+    /// Create text that is not counted when fitting, see [`Doc::Unmeasured`].
+    pub(crate) fn unmeasured(s: String) -> Doc {
+        charge(s.capacity());
+        Doc::Unmeasured(s.into())
+    }
+
     /// Create a line break that becomes a space in flat mode.
     pub fn line() -> Doc {
         Doc::Line {
-            space: " ".to_string(),
+            space: Cow::Borrowed(" "),
         }
     }
 
     /// Create a line break that becomes empty in flat mode.
     pub fn line_or_empty() -> Doc {
         Doc::Line {
-            space: String::new(),
+            space: Cow::Borrowed(""),
         }
     }
 
@@ -58,182 +169,1102 @@ impl Doc {
     pub fn nest(indent: usize, doc: Doc) -> Doc {
         Doc::Nest {
             indent,
-            doc: Box::new(doc),
+            doc: boxed(doc),
         }
     }
 
+    /// Align a document's line breaks under the column where it starts.
+    pub fn align(doc: Doc) -> Doc {
+        Doc::Align(boxed(doc))
+    }
+
+    /// Lay out a document as if the line width were `width`.
+    pub fn width(width: usize, doc: Doc) -> Doc {
+        Doc::Width {
+            width,
+            doc: boxed(doc),
+        }
+    }
+
+    /// Indent a document with tabs of `width` columns instead of spaces.
+    pub fn tabs(width: usize, doc: Doc) -> Doc {
+        Doc::Tabs {
+            width,
+            doc: boxed(doc),
+        }
+    }
+
+    /// Mark a document so its range in the output can be found.
+    pub fn mark(id: usize, doc: Doc) -> Doc {
+        Doc::Mark {
+            id,
+            doc: boxed(doc),
+        }
+    }
+
+    /// Tag a document with the kind of syntax it is.
+    pub fn annotated(category: Category, doc: Doc) -> Doc {
+        Doc::Annotated(category, boxed(doc))
+    }
+
     /// Group a document to try fitting on one line.
     pub fn group(doc: Doc) -> Doc {
-        Doc::Group(Box::new(doc))
+        Doc::Group(boxed(doc))
+    }
+
+    /// Render `broken` if the enclosing group is expanded, and `flat` if it
+    /// fits on one line.
+    pub fn if_break(broken: Doc, flat: Doc) -> Doc {
+        Doc::IfBreak {
+            broken: boxed(broken),
+            flat: boxed(flat),
+        }
+    }
+
+    /// Fill lines with contents: `parts` alternate between contents and
+    /// separators, starting and ending with a content.
+    pub fn fill(parts: Vec<Doc>) -> Doc {
+        charge(parts.capacity() * std::mem::size_of::<Doc>());
+        Doc::Fill(parts)
     }
 
     /// Concatenate multiple documents.
     pub fn concat(docs: impl IntoIterator<Item = Doc>) -> Doc {
-        let mut result = Doc::Nil;
+        let mut parts = Vec::new();
+        // Capacity of a reused concat, which was charged when it was built.
+        let mut reused = 0;
         for doc in docs {
-            result = match result {
-                Doc::Nil => doc,
-                _ => match doc {
-                    Doc::Nil => result,
-                    _ => Doc::Concat {
-                        left: Box::new(result),
-                        right: Box::new(doc),
-                    },
-                },
-            };
+            match doc {
+                Doc::Nil => {}
+                // Extending a leading concat in place keeps chains built
+                // from the left, like `a.b.c`, linear.
+                Doc::Concat(inner) if parts.is_empty() => {
+                    reused = inner.capacity();
+                    parts = inner;
+                }
+                Doc::Concat(inner) => parts.extend(inner),
+                doc => parts.push(doc),
+            }
+        }
+        charge((parts.capacity() - reused) * std::mem::size_of::<Doc>());
+        match parts.len() {
+            0 => Doc::Nil,
+            1 => parts.pop().unwrap(),
+            _ => Doc::Concat(parts),
         }
-        result
     }
 
     /// Join documents with a separator.
     pub fn join(sep: Doc, docs: Vec<Doc>) -> Doc {
-        if docs.is_empty() {
-            return Doc::Nil;
+        let mut parts = Vec::with_capacity(docs.len() * 2);
+        for (i, doc) in docs.into_iter().enumerate() {
+            if i > 0 {
+                parts.push(sep.clone());
+            }
+            parts.push(doc);
+        }
+        Doc::concat(parts)
+    }
+
+    /// Simplify a document without changing how it renders.
+    ///
+    /// Nested concatenations are flattened, adjacent texts merged, `Nil`s
+    /// dropped, and redundant nests and groups collapsed. Two documents that
+    /// render identically under every width usually normalize to equal trees,
+    /// which makes structural comparisons in tests practical.
+    pub fn normalize(self) -> Doc {
+        // Documents of generated queries can be nested deeper than the stack
+        // allows recursing, so the tree is rebuilt bottom-up with explicit
+        // stacks: `work` holds documents to visit and the constructors
+        // waiting for their parts, `done` the normalized parts.
+        enum Work {
+            Visit(Doc),
+            Build(Shape),
+        }
+        enum Shape {
+            Nest(usize),
+            Align,
+            Group,
+            Width(usize),
+            Tabs(usize),
+            IfBreak,
+            Mark(usize),
+            Annotated(Category),
+            Fill(usize),
+            Concat(usize),
+        }
+
+        let mut work = vec![Work::Visit(self)];
+        let mut done: Vec<Doc> = Vec::new();
+        while let Some(item) = work.pop() {
+            let doc = match item {
+                Work::Visit(doc) => {
+                    let (shape, parts) = match doc {
+                        Doc::Text(s) if s.is_empty() => {
+                            done.push(Doc::Nil);
+                            continue;
+                        }
+                        Doc::Nest { indent, doc } => (Shape::Nest(indent), vec![*doc]),
+                        Doc::Align(doc) => (Shape::Align, vec![*doc]),
+                        Doc::Group(doc) => (Shape::Group, vec![*doc]),
+                        Doc::Width { width, doc } => (Shape::Width(width), vec![*doc]),
+                        Doc::Tabs { width, doc } => (Shape::Tabs(width), vec![*doc]),
+                        Doc::IfBreak { broken, flat } => (Shape::IfBreak, vec![*broken, *flat]),
+                        Doc::Mark { id, doc } => (Shape::Mark(id), vec![*doc]),
+                        Doc::Annotated(category, doc) => (Shape::Annotated(category), vec![*doc]),
+                        Doc::Fill(parts) => (Shape::Fill(parts.len()), parts),
+                        Doc::Concat(parts) => (Shape::Concat(parts.len()), parts),
+                        doc => {
+                            done.push(doc);
+                            continue;
+                        }
+                    };
+                    work.push(Work::Build(shape));
+                    work.extend(parts.into_iter().rev().map(Work::Visit));
+                    continue;
+                }
+                Work::Build(Shape::IfBreak) => {
+                    let flat = done.pop().unwrap_or(Doc::Nil);
+                    let broken = done.pop().unwrap_or(Doc::Nil);
+                    match (broken, flat) {
+                        (Doc::Nil, Doc::Nil) => Doc::Nil,
+                        (broken, flat) if broken == flat => broken,
+                        (broken, flat) => Doc::if_break(broken, flat),
+                    }
+                }
+                // Parts are positional (content, separator, ...), so none are
+                // dropped or merged.
+                Work::Build(Shape::Fill(len)) => Doc::Fill(done.split_off(done.len() - len)),
+                Work::Build(Shape::Concat(len)) => {
+                    let mut parts: Vec<Doc> = Vec::with_capacity(len);
+                    for doc in done.drain(done.len() - len..) {
+                        // Normalizing a part can yield a concat of its own, so
+                        // splice its parts in to keep the result flat.
+                        for doc in doc.into_parts() {
+                            match (parts.last_mut(), doc) {
+                                (Some(Doc::Text(prev)), Doc::Text(s)) => prev.to_mut().push_str(&s),
+                                (_, doc) => parts.push(doc),
+                            }
+                        }
+                    }
+                    Doc::concat(parts)
+                }
+                Work::Build(shape) => {
+                    let doc = done.pop().unwrap_or(Doc::Nil);
+                    match (shape, doc) {
+                        // Kept even when empty, since the position is still
+                        // recorded.
+                        (Shape::Mark(id), doc) => Doc::mark(id, doc),
+                        (_, Doc::Nil) => Doc::Nil,
+                        (Shape::Nest(0), doc) => doc,
+                        (Shape::Nest(indent), Doc::Nest { indent: inner, doc }) => {
+                            Doc::nest(indent + inner, *doc)
+                        }
+                        (Shape::Nest(indent), doc) => Doc::nest(indent, doc),
+                        (Shape::Align, align @ Doc::Align(_)) => align,
+                        (Shape::Align, doc) => Doc::align(doc),
+                        (Shape::Group, group @ Doc::Group(_)) => group,
+                        (Shape::Group, doc) => Doc::group(doc),
+                        // The inner width is the one that applies.
+                        (Shape::Width(_), inner @ Doc::Width { .. }) => inner,
+                        (Shape::Width(width), doc) => Doc::width(width, doc),
+                        (Shape::Tabs(_), inner @ Doc::Tabs { .. }) => inner,
+                        (Shape::Tabs(width), doc) => Doc::tabs(width, doc),
+                        (Shape::Annotated(category), doc) => Doc::annotated(category, doc),
+                        (Shape::IfBreak | Shape::Fill(_) | Shape::Concat(_), _) => unreachable!(),
+                    }
+                }
+            };
+            done.push(doc);
         }
-        let mut iter = docs.into_iter();
-        let mut result = iter.next().unwrap();
-        for doc in iter {
-            result = Doc::concat([result, sep.clone(), doc]);
+        done.pop().unwrap_or(Doc::Nil)
+    }
+
+    /// Approximate number of bytes used by the document tree, including the
+    /// document itself.
+    pub fn memory_size(&self) -> usize {
+        let node = std::mem::size_of::<Doc>();
+        let mut size = node;
+        let mut stack = vec![self];
+        while let Some(doc) = stack.pop() {
+            match doc {
+                Doc::Nil | Doc::HardLine => {}
+                Doc::Text(s) | Doc::Unmeasured(s) | Doc::Line { space: s } => {
+                    if let Cow::Owned(s) = s {
+                        size += s.capacity();
+                    }
+                }
+                Doc::Nest { doc, .. }
+                | Doc::Width { doc, .. }
+                | Doc::Tabs { doc, .. }
+                | Doc::Align(doc)
+                | Doc::Group(doc)
+                | Doc::Mark { doc, .. }
+                | Doc::Annotated(_, doc) => {
+                    size += node;
+                    stack.push(doc);
+                }
+                Doc::IfBreak { broken, flat } => {
+                    size += 2 * node;
+                    stack.push(broken);
+                    stack.push(flat);
+                }
+                Doc::Concat(parts) | Doc::Fill(parts) => {
+                    size += parts.capacity() * node;
+                    stack.extend(parts);
+                }
+            }
         }
-        result
+        size
+    }
+
+    /// Drop the document without recursing. Dropping it normally recurses
+    /// once per level, and the groups of a long `||` chain nest deeper than
+    /// the stack allows.
+    pub(crate) fn discard(self) {
+        let mut stack = vec![self];
+        while let Some(doc) = stack.pop() {
+            match doc {
+                Doc::Nest { doc, .. }
+                | Doc::Width { doc, .. }
+                | Doc::Tabs { doc, .. }
+                | Doc::Align(doc)
+                | Doc::Group(doc)
+                | Doc::Mark { doc, .. }
+                | Doc::Annotated(_, doc) => stack.push(*doc),
+                Doc::IfBreak { broken, flat } => stack.extend([*broken, *flat]),
+                Doc::Concat(parts) | Doc::Fill(parts) => stack.extend(parts),
+                _ => {}
+            }
+        }
+    }
+
+    /// The parts of a concatenation, or the document itself.
+    fn into_parts(self) -> Vec<Doc> {
+        match self {
+            Doc::Nil => Vec::new(),
+            Doc::Concat(parts) => parts,
+            doc => vec![doc],
+        }
+    }
+}
+
+thread_local! {
+    /// Bytes allocated by the constructors of [`Doc`] on this thread since
+    /// the running [`Meter`] started, or `None` if none is running.
+    static ALLOCATED: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/// Counts the bytes allocated by the constructors of [`Doc`] on this
+/// thread while it is alive, so that formatting can stop as soon as the
+/// document outgrows [`FormatOptions::approximate_memory_limit`](crate::FormatOptions::approximate_memory_limit).
+pub(crate) struct Meter {
+    /// The count of the meter this one interrupted, which resumes with
+    /// this one's bytes added when it ends.
+    outer: Option<usize>,
+}
+
+impl Meter {
+    pub(crate) fn start() -> Meter {
+        Meter {
+            outer: ALLOCATED.replace(Some(0)),
+        }
+    }
+
+    /// Bytes allocated since the meter started.
+    pub(crate) fn allocated(&self) -> usize {
+        ALLOCATED.get().unwrap_or(0)
+    }
+}
+
+impl Drop for Meter {
+    fn drop(&mut self) {
+        let allocated = self.allocated();
+        ALLOCATED.set(self.outer.map(|outer| outer.saturating_add(allocated)));
     }
 }
 
+/// Count `bytes` towards the running [`Meter`], if any.
+fn charge(bytes: usize) {
+    if let Some(allocated) = ALLOCATED.get() {
+        ALLOCATED.set(Some(allocated.saturating_add(bytes)));
+    }
+}
+
+/// Box a document, counting the allocation.
+fn boxed(doc: Doc) -> Box<Doc> {
+    charge(std::mem::size_of::<Doc>());
+    Box::new(doc)
+}
+
 #[derive(Clone, Copy, PartialEq)]
 enum Mode {
     Flat,
     Break,
 }
 
-#[derive(Clone)]
-struct Item {
+#[derive(Clone, Copy)]
+struct Item<'d> {
+    /// Line width the document is laid out for.
+    width: usize,
+    /// Columns per tab in indentation, if it is written with tabs.
+    tabs: Option<usize>,
     indent: usize,
     mode: Mode,
-    doc: Doc,
+    doc: &'d Doc,
+    /// For a `Fill`, the index of the next part to lay out.
+    fill_from: usize,
+    /// For the item placed after the contents of a `Mark`, the index of the
+    /// mark's range to end.
+    mark_end: Option<usize>,
+    /// For the item placed after the contents of a traced `Group`, the index
+    /// of the group's trace to end.
+    group_end: Option<usize>,
+    /// Whether this is the item placed after the contents of an `Annotated`.
+    annotation_end: bool,
+}
+
+impl<'d> Item<'d> {
+    fn new(width: usize, indent: usize, mode: Mode, doc: &'d Doc) -> Self {
+        Item {
+            width,
+            tabs: None,
+            indent,
+            mode,
+            doc,
+            fill_from: 0,
+            mark_end: None,
+            group_end: None,
+            annotation_end: false,
+        }
+    }
+
+    /// An item for a document inside this one, laid out at the same width.
+    fn child(&self, indent: usize, mode: Mode, doc: &'d Doc) -> Self {
+        Item {
+            tabs: self.tabs,
+            ..Item::new(self.width, indent, mode, doc)
+        }
+    }
+}
+
+/// Document of the item that ends a mark, group or annotation.
+static NIL: Doc = Doc::Nil;
+
+/// The layout decision made for one group, as recorded by [`pretty_with_trace`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct GroupTrace {
+    /// Output line (1-based) on which the group starts.
+    pub line: usize,
+    /// Output column (1-based) at which the group starts.
+    pub column: usize,
+    /// Output line (1-based) on which the group ends.
+    pub end_line: usize,
+    /// Output column (1-based) just after the end of the group.
+    pub end_column: usize,
+    /// Width the group's content needs when laid out flat, or `None` if it
+    /// contains a hard line break and can never be flat.
+    pub flat_width: Option<usize>,
+    /// Columns left on the line when the group was tested.
+    pub available: usize,
+    /// Whether the group was rendered in break mode.
+    pub broke: bool,
 }
 
 /// Render a document to a string with a given width limit.
 pub fn pretty(width: usize, doc: Doc) -> String {
     let mut output = String::new();
-    let mut col = 0;
-    let mut items = vec![Item {
-        indent: 0,
-        mode: Mode::Flat,
+    render(width, &doc, &mut Vec::new(), &mut output, None, None);
+    doc.discard();
+    output
+}
+
+/// Render a document like [`pretty`], also returning the byte range of the
+/// output produced by each [`Doc::Mark`], with its id, in the order the
+/// marks start.
+pub fn pretty_with_marks(width: usize, doc: Doc) -> (String, Vec<(usize, Range<usize>)>) {
+    let mut output = String::new();
+    let mut marks = Vec::new();
+    render(
+        width,
+        &doc,
+        &mut Vec::new(),
+        &mut output,
+        None,
+        Some(&mut marks),
+    );
+    doc.discard();
+    (output, marks)
+}
+
+/// Render a document like [`pretty`], also recording the decision made for
+/// every group in the order the groups were encountered.
+pub fn pretty_with_trace(width: usize, doc: Doc) -> (String, Vec<GroupTrace>) {
+    let mut output = String::new();
+    let mut trace = Vec::new();
+    render(
+        width,
+        &doc,
+        &mut Vec::new(),
+        &mut output,
+        Some(&mut trace),
+        None,
+    );
+    doc.discard();
+    (output, trace)
+}
+
+/// A way of marking up the kinds of syntax in formatted output, see
+/// [`crate::highlight_document`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Highlight {
+    /// Colors for a terminal, see [`render_ansi`].
+    Ansi,
+    /// HTML with a `span` for each kind of syntax, see [`render_html`].
+    Html,
+}
+
+impl Highlight {
+    /// Render a document with this markup.
+    pub(crate) fn render(self, width: usize, doc: &Doc) -> String {
+        match self {
+            Highlight::Ansi => render_ansi(width, doc),
+            Highlight::Html => render_html(width, doc),
+        }
+    }
+
+    /// Text that is not marked up, such as a query that failed to parse,
+    /// written so it shows as it is.
+    pub(crate) fn escape(self, text: &str) -> String {
+        match self {
+            Highlight::Ansi => text.to_string(),
+            Highlight::Html => {
+                let mut escaped = String::with_capacity(text.len());
+                push_html(&mut escaped, text);
+                escaped
+            }
+        }
+    }
+}
+
+/// Render a document like [`pretty`], coloring the text of each
+/// [`Doc::Annotated`] with ANSI escape sequences for a terminal.
+///
+/// # Example
+///
+/// ```
+/// use groq_format::{Category, Doc, render_ansi};
+///
+/// let doc = Doc::concat([
+///     Doc::annotated(Category::Field, Doc::text("title")),
+///     Doc::text(", "),
+///     Doc::annotated(Category::String, Doc::text("\"x\"")),
+/// ]);
+/// assert_eq!(render_ansi(80, &doc), "\x1b[34mtitle\x1b[0m, \x1b[32m\"x\"\x1b[0m");
+/// ```
+pub fn render_ansi(width: usize, doc: &Doc) -> String {
+    let mut sink = Ansi::default();
+    render(width, doc, &mut Vec::new(), &mut sink, None, None);
+    sink.output
+}
+
+/// Render a document like [`pretty`] as HTML, escaping `&`, `<`, `>` and
+/// `"`, and wrapping the text of each [`Doc::Annotated`] in a `span` whose
+/// class is `groq-` followed by its category, such as `groq-keyword`. The
+/// output is meant to go in a `pre` element, styled by the page.
+///
+/// # Example
+///
+/// ```
+/// use groq_format::{Category, Doc, render_html};
+///
+/// let doc = Doc::concat([
+///     Doc::annotated(Category::Field, Doc::text("a")),
+///     Doc::text(" < "),
+///     Doc::annotated(Category::Number, Doc::text("3")),
+/// ]);
+/// assert_eq!(
+///     render_html(80, &doc),
+///     r#"<span class="groq-field">a</span> &lt; <span class="groq-number">3</span>"#
+/// );
+/// ```
+pub fn render_html(width: usize, doc: &Doc) -> String {
+    let mut output = String::new();
+    render(
+        width,
         doc,
-    }];
+        &mut Vec::new(),
+        &mut Html(&mut output),
+        None,
+        None,
+    );
+    output
+}
+
+/// Whether rendering the document produces exactly `expected`, checked as
+/// the output is produced so nothing is materialized and rendering stops at
+/// the first difference.
+pub(crate) fn renders_as(width: usize, doc: &Doc, expected: &str) -> bool {
+    let mut sink = Compare {
+        rest: expected,
+        mismatch: false,
+    };
+    render(width, doc, &mut Vec::new(), &mut sink, None, None);
+    !sink.mismatch && sink.rest.is_empty()
+}
+
+/// Destination for rendered text.
+trait Sink {
+    fn push_str(&mut self, s: &str);
+
+    /// Whether the rest of the output is no longer needed.
+    fn is_done(&self) -> bool {
+        false
+    }
+
+    /// Start the text of an annotated document.
+    fn start_annotation(&mut self, _category: Category) {}
+
+    /// End the text of the innermost annotated document.
+    fn end_annotation(&mut self) {}
+}
+
+impl Sink for String {
+    fn push_str(&mut self, s: &str) {
+        String::push_str(self, s);
+    }
+}
+
+/// A sink that compares the output against expected text.
+struct Compare<'a> {
+    rest: &'a str,
+    mismatch: bool,
+}
+
+impl Sink for Compare<'_> {
+    fn push_str(&mut self, s: &str) {
+        match self.rest.strip_prefix(s) {
+            Some(rest) => self.rest = rest,
+            None => self.mismatch = true,
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.mismatch
+    }
+}
+
+/// A sink that colors annotated text with ANSI escape sequences.
+#[derive(Default)]
+struct Ansi {
+    output: String,
+    /// The categories of the annotated documents being rendered, innermost
+    /// last.
+    categories: Vec<Category>,
+}
+
+impl Sink for Ansi {
+    fn push_str(&mut self, s: &str) {
+        self.output.push_str(s);
+    }
+
+    fn start_annotation(&mut self, category: Category) {
+        self.categories.push(category);
+        self.output.push_str(category.ansi());
+    }
+
+    fn end_annotation(&mut self) {
+        self.categories.pop();
+        self.output.push_str("\x1b[0m");
+        // Go back to the color of the annotation around this one.
+        if let Some(category) = self.categories.last() {
+            self.output.push_str(category.ansi());
+        }
+    }
+}
+
+/// A sink that writes HTML, with a `span` around annotated text.
+struct Html<'a>(&'a mut String);
+
+impl Sink for Html<'_> {
+    fn push_str(&mut self, s: &str) {
+        push_html(self.0, s);
+    }
+
+    fn start_annotation(&mut self, category: Category) {
+        self.0.push_str("<span class=\"groq-");
+        self.0.push_str(category.as_str());
+        self.0.push_str("\">");
+    }
+
+    fn end_annotation(&mut self) {
+        self.0.push_str("</span>");
+    }
+}
+
+/// Append text to HTML, escaping the characters that have a meaning there.
+fn push_html(output: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '&' => output.push_str("&amp;"),
+            '<' => output.push_str("&lt;"),
+            '>' => output.push_str("&gt;"),
+            '"' => output.push_str("&quot;"),
+            c => output.push(c),
+        }
+    }
+}
+
+/// Rendering state that is kept between documents so its buffers are reused.
+#[derive(Default)]
+pub(crate) struct Renderer {
+    /// Work stack, kept empty between calls. Items borrow the document being
+    /// rendered, so only the allocation is carried over.
+    items: Vec<Item<'static>>,
+}
+
+impl Renderer {
+    /// Render a document into `output`, which is cleared first. Callers that
+    /// know roughly how long the output will be can reserve capacity.
+    pub(crate) fn render(&mut self, width: usize, doc: &Doc, output: &mut String) {
+        output.clear();
+        let mut items = recycle(std::mem::take(&mut self.items));
+        render(width, doc, &mut items, output, None, None);
+        self.items = recycle(items);
+    }
+}
+
+/// Reuse the allocation of an empty item stack for a different document
+/// lifetime. The in-place `collect` keeps the buffer since the item types
+/// have the same layout; if it ever didn't, this would only cost an
+/// allocation.
+fn recycle<'a, 'b>(mut items: Vec<Item<'a>>) -> Vec<Item<'b>> {
+    items.clear();
+    items.into_iter().map(|_| unreachable!()).collect()
+}
+
+fn render<'d>(
+    width: usize,
+    doc: &'d Doc,
+    items: &mut Vec<Item<'d>>,
+    output: &mut impl Sink,
+    mut trace: Option<&mut Vec<GroupTrace>>,
+    mut marks: Option<&mut Vec<(usize, Range<usize>)>>,
+) {
+    let mut col = 0;
+    let mut line = 1;
+    // Bytes written so far, for the ranges of marks.
+    let mut pos = 0;
+    let mut widths = GroupWidths::new(doc);
+    items.clear();
+    items.push(Item::new(width, 0, Mode::Flat, doc));
 
     while let Some(item) = items.pop() {
+        if output.is_done() {
+            items.clear();
+            return;
+        }
+        if let (Some(index), Some(marks)) = (item.mark_end, marks.as_deref_mut()) {
+            marks[index].1.end = pos;
+        }
+        if let (Some(index), Some(trace)) = (item.group_end, trace.as_deref_mut()) {
+            trace[index].end_line = line;
+            trace[index].end_column = col + 1;
+        }
+        if item.annotation_end {
+            output.end_annotation();
+        }
         match item.doc {
             Doc::Nil => {}
-            Doc::Text(s) => {
-                col += s.len();
-                output.push_str(&s);
+            Doc::Text(s) | Doc::Unmeasured(s) => {
+                col += columns(s);
+                pos += s.len();
+                output.push_str(s);
             }
             Doc::Line { space } => {
                 if item.mode == Mode::Flat {
-                    col += space.len();
-                    output.push_str(&space);
+                    col += columns(space);
+                    pos += space.len();
+                    output.push_str(space);
                 } else {
-                    output.push('\n');
-                    output.push_str(&spaces(item.indent));
+                    pos += newline(output, item.indent, item.tabs);
                     col = item.indent;
+                    line += 1;
                 }
             }
             Doc::HardLine => {
-                output.push('\n');
-                output.push_str(&spaces(item.indent));
+                pos += newline(output, item.indent, item.tabs);
                 col = item.indent;
+                line += 1;
             }
-            Doc::Nest { indent, doc } => {
+            Doc::Mark { id, doc } => {
+                if let Some(marks) = marks.as_deref_mut() {
+                    items.push(Item {
+                        mark_end: Some(marks.len()),
+                        ..item.child(item.indent, item.mode, &NIL)
+                    });
+                    marks.push((*id, pos..pos));
+                }
+                items.push(item.child(item.indent, item.mode, doc));
+            }
+            Doc::Annotated(category, doc) => {
+                output.start_annotation(*category);
                 items.push(Item {
-                    indent: item.indent + indent,
-                    mode: item.mode,
-                    doc: *doc,
+                    annotation_end: true,
+                    ..item.child(item.indent, item.mode, &NIL)
                 });
+                items.push(item.child(item.indent, item.mode, doc));
             }
-            Doc::Concat { left, right } => {
+            Doc::Nest { indent, doc } => {
+                items.push(item.child(item.indent + indent, item.mode, doc));
+            }
+            Doc::Width { width, doc } => {
                 items.push(Item {
-                    indent: item.indent,
-                    mode: item.mode,
-                    doc: *right,
+                    width: *width,
+                    ..item.child(item.indent, item.mode, doc)
                 });
+            }
+            Doc::Tabs { width, doc } => {
                 items.push(Item {
-                    indent: item.indent,
-                    mode: item.mode,
-                    doc: *left,
+                    tabs: Some(*width).filter(|&width| width > 0),
+                    ..item.child(item.indent, item.mode, doc)
                 });
             }
+            Doc::Align(doc) => {
+                items.push(item.child(col, item.mode, doc));
+            }
+            Doc::IfBreak { broken, flat } => {
+                let doc = match item.mode {
+                    Mode::Break => broken,
+                    Mode::Flat => flat,
+                };
+                items.push(item.child(item.indent, item.mode, doc));
+            }
+            Doc::Concat(parts) => {
+                items.extend(
+                    parts
+                        .iter()
+                        .rev()
+                        .map(|doc| item.child(item.indent, item.mode, doc)),
+                );
+            }
+            Doc::Fill(parts) => {
+                let width = item.width;
+                let remaining = width.saturating_sub(col);
+                let at = item.fill_from;
+                if at == 0 {
+                    if fits_doc(remaining, item.doc, &mut widths) {
+                        items.extend(
+                            parts
+                                .iter()
+                                .rev()
+                                .map(|doc| item.child(item.indent, Mode::Flat, doc)),
+                        );
+                        continue;
+                    }
+                    // Lay out the first content, then come back for the rest
+                    // once the column after it is known.
+                    items.push(Item {
+                        fill_from: 1,
+                        ..item
+                    });
+                    if let Some(first) = parts.first() {
+                        items.push(item.child(
+                            item.indent,
+                            fits_mode(remaining, first, &mut widths),
+                            first,
+                        ));
+                    }
+                } else if let (Some(separator), Some(content)) = (parts.get(at), parts.get(at + 1))
+                {
+                    // Keep the separator flat if the content after it fits.
+                    let flat = fits_doc(remaining, separator, &mut widths)
+                        && fits_doc(
+                            remaining.saturating_sub(flat_width(separator).unwrap_or(0)),
+                            content,
+                            &mut widths,
+                        );
+                    items.push(Item {
+                        fill_from: at + 2,
+                        ..item
+                    });
+                    let content_mode = if flat {
+                        Mode::Flat
+                    } else {
+                        fits_mode(width.saturating_sub(item.indent), content, &mut widths)
+                    };
+                    items.push(item.child(item.indent, content_mode, content));
+                    let separator_mode = if flat { Mode::Flat } else { Mode::Break };
+                    items.push(item.child(item.indent, separator_mode, separator));
+                }
+            }
             Doc::Group(doc) => {
+                let width = item.width;
                 // Try flat mode first - check if it fits without cloning
-                if fits_doc(width.saturating_sub(col), &doc, Mode::Flat) {
+                let fits = fits_with_rest(width.saturating_sub(col), doc, items, &mut widths);
+                if let Some(trace) = trace.as_deref_mut() {
                     items.push(Item {
-                        indent: item.indent,
-                        mode: Mode::Flat,
-                        doc: *doc,
+                        group_end: Some(trace.len()),
+                        ..item.child(item.indent, item.mode, &NIL)
                     });
-                } else {
-                    // Fall back to break mode
-                    items.push(Item {
-                        indent: item.indent,
-                        mode: Mode::Break,
-                        doc: *doc,
+                    trace.push(GroupTrace {
+                        line,
+                        column: col + 1,
+                        end_line: line,
+                        end_column: col + 1,
+                        flat_width: flat_width(doc),
+                        available: width.saturating_sub(col),
+                        broke: !fits,
                     });
                 }
+                // Fall back to break mode if it doesn't fit
+                let mode = if fits { Mode::Flat } else { Mode::Break };
+                items.push(item.child(item.indent, mode, doc));
             }
         }
     }
-
-    output
 }
 
-/// Check if a document fits in the given width without cloning.
+/// Check if a document fits flat in the given width without cloning.
 /// This implements a stack-based fitting algorithm similar to Wadler's but without document cloning.
-fn fits_doc(width: usize, doc: &Doc, mode: Mode) -> bool {
-    let mut stack = vec![(doc, mode)];
+fn fits_doc(width: usize, doc: &Doc, widths: &mut GroupWidths) -> bool {
+    fits(width, doc, &[], widths)
+}
+
+/// Check if a group's content fits flat in the given width together with
+/// whatever follows it on the same line, such as the `]` closing a filter
+/// and the ` {` opening its projection. `rest` is the render stack: the
+/// text after the group, up to the next point where a line may break.
+fn fits_with_rest(width: usize, doc: &Doc, rest: &[Item], widths: &mut GroupWidths) -> bool {
+    fits(width, doc, rest, widths)
+}
+
+fn fits(width: usize, doc: &Doc, rest: &[Item], widths: &mut GroupWidths) -> bool {
+    let mut stack = vec![(doc, Mode::Flat)];
     let mut remaining_width = width;
+    let mut rest = rest.iter().rev();
+    // Whether the document is done and the stack holds what follows it.
+    let mut in_rest = false;
+    // Groups walked into so far.
+    let mut groups = 0;
 
-    while let Some((current_doc, current_mode)) = stack.pop() {
+    loop {
+        let Some((current_doc, current_mode)) = stack.pop() else {
+            match rest.next() {
+                // The next part of a fill starts with a separator, which
+                // can break.
+                Some(item) if item.fill_from > 0 => return true,
+                Some(item) => {
+                    stack.push((item.doc, item.mode));
+                    in_rest = true;
+                    continue;
+                }
+                None => return true,
+            }
+        };
         match current_doc {
-            Doc::Nil => {}
+            Doc::Nil | Doc::Unmeasured(_) => {}
             Doc::Text(s) => {
-                if s.len() > remaining_width {
+                if columns(s) > remaining_width {
                     return false;
                 }
-                remaining_width -= s.len();
+                remaining_width -= columns(s);
             }
             Doc::Line { space } => {
                 if current_mode == Mode::Flat {
-                    if space.len() > remaining_width {
+                    if columns(space) > remaining_width {
                         return false;
                     }
-                    remaining_width -= space.len();
+                    remaining_width -= columns(space);
+                } else if in_rest {
+                    // The line ends here.
+                    return true;
                 }
                 // In break mode, line breaks always fit
             }
             Doc::HardLine => {
                 // A hard line never fits in flat mode — forces the
-                // enclosing group into break mode.
-                return false;
+                // enclosing group into break mode. After the document it
+                // just ends the line.
+                return in_rest;
             }
-            Doc::Nest { doc, .. } => {
+            Doc::Nest { doc, .. }
+            | Doc::Width { doc, .. }
+            | Doc::Tabs { doc, .. }
+            | Doc::Align(doc)
+            | Doc::Mark { doc, .. }
+            | Doc::Annotated(_, doc) => {
                 // Nesting doesn't affect width calculation, just push the nested doc
                 stack.push((doc, current_mode));
             }
-            Doc::Concat { left, right } => {
-                // Push right first (stack is LIFO), then left
-                stack.push((right, current_mode));
-                stack.push((left, current_mode));
+            Doc::IfBreak { broken, flat } => {
+                let doc = match current_mode {
+                    Mode::Break => broken,
+                    Mode::Flat => flat,
+                };
+                stack.push((doc, current_mode));
+            }
+            Doc::Concat(parts) | Doc::Fill(parts) => {
+                // Push in reverse (stack is LIFO)
+                stack.extend(parts.iter().rev().map(|doc| (doc, current_mode)));
             }
+            // Deeply nested groups, such as those of a long `||` chain, are
+            // measured once rather than walked once per enclosing group.
+            Doc::Group(doc) if !in_rest && groups >= DEEP => match widths.get(current_doc) {
+                Some(width) if width <= remaining_width => remaining_width -= width,
+                Some(_) => return false,
+                None => stack.push((doc, Mode::Flat)),
+            },
             Doc::Group(doc) => {
-                // For groups, we try flat mode (most restrictive)
-                stack.push((doc, Mode::Flat));
+                // For groups, we try flat mode (most restrictive). Groups
+                // after the document get their own fitting decision, so
+                // only the text up to their first line break counts.
+                let mode = if in_rest { Mode::Break } else { Mode::Flat };
+                groups += 1;
+                stack.push((doc, mode));
+            }
+        }
+    }
+}
+
+/// Flat mode if the document fits in the given width, break mode otherwise.
+fn fits_mode(width: usize, doc: &Doc, widths: &mut GroupWidths) -> Mode {
+    if fits_doc(width, doc, widths) {
+        Mode::Flat
+    } else {
+        Mode::Break
+    }
+}
+
+/// Number of groups [`fits`] walks into before it looks the widths of the
+/// rest up in [`GroupWidths`]. Checking whether a line of a handwritten
+/// query fits doesn't take this many, so its document is never measured.
+const DEEP: usize = 64;
+
+/// The width each group of a document takes when laid out flat, as
+/// [`fits`] measures it, keyed by the group's address. The document is
+/// measured the first time a width is needed. Groups that contain a hard
+/// line break are left out and walked where they are tested.
+struct GroupWidths<'d> {
+    doc: &'d Doc,
+    widths: Option<HashMap<*const Doc, usize>>,
+}
+
+impl<'d> GroupWidths<'d> {
+    fn new(doc: &'d Doc) -> Self {
+        GroupWidths { doc, widths: None }
+    }
+
+    fn get(&mut self, group: &Doc) -> Option<usize> {
+        let doc = self.doc;
+        let widths = self.widths.get_or_insert_with(|| GroupWidths::measure(doc));
+        widths.get(&(group as *const Doc)).copied()
+    }
+
+    /// Measure every group of `doc` in one pass.
+    fn measure(doc: &Doc) -> HashMap<*const Doc, usize> {
+        enum Step<'d> {
+            Enter(&'d Doc),
+            /// Leave a group, with the width and hard line count measured
+            /// before it.
+            Leave(&'d Doc, usize, usize),
+        }
+
+        let mut widths = HashMap::new();
+        let mut width = 0;
+        let mut hard_lines = 0;
+        let mut stack = vec![Step::Enter(doc)];
+        while let Some(step) = stack.pop() {
+            let doc = match step {
+                Step::Enter(doc) => doc,
+                Step::Leave(group, start, hard_lines_before) => {
+                    if hard_lines == hard_lines_before {
+                        widths.insert(group as *const Doc, width - start);
+                    }
+                    continue;
+                }
+            };
+            match doc {
+                Doc::Nil | Doc::Unmeasured(_) => {}
+                Doc::Text(s) => width += columns(s),
+                Doc::Line { space } => width += columns(space),
+                Doc::HardLine => hard_lines += 1,
+                Doc::Nest { doc, .. }
+                | Doc::Width { doc, .. }
+                | Doc::Tabs { doc, .. }
+                | Doc::Align(doc)
+                | Doc::Mark { doc, .. }
+                | Doc::Annotated(_, doc) => stack.push(Step::Enter(doc)),
+                Doc::IfBreak { flat, .. } => stack.push(Step::Enter(flat)),
+                Doc::Concat(parts) | Doc::Fill(parts) => {
+                    stack.extend(parts.iter().rev().map(Step::Enter));
+                }
+                Doc::Group(inner) => {
+                    stack.push(Step::Leave(doc, width, hard_lines));
+                    stack.push(Step::Enter(inner));
+                }
             }
         }
+        widths
     }
+}
 
-    true
+/// Width of `text` in columns. Text is measured in characters everywhere,
+/// so layout agrees with [`crate::minimum_width`] and
+/// [`crate::overflowing_lines`].
+pub(crate) fn columns(text: &str) -> usize {
+    text.chars().count()
 }
 
-fn spaces(n: usize) -> String {
-    " ".repeat(n)
+/// Measure the width of a document laid out entirely flat.
+/// Returns `None` if the document contains a hard line break.
+fn flat_width(doc: &Doc) -> Option<usize> {
+    let mut stack = vec![doc];
+    let mut width = 0;
+
+    while let Some(current) = stack.pop() {
+        match current {
+            Doc::Nil => {}
+            Doc::Text(s) | Doc::Unmeasured(s) => width += columns(s),
+            Doc::Line { space } => width += columns(space),
+            Doc::HardLine => return None,
+            Doc::Nest { doc, .. }
+            | Doc::Width { doc, .. }
+            | Doc::Tabs { doc, .. }
+            | Doc::Align(doc)
+            | Doc::Group(doc)
+            | Doc::Mark { doc, .. }
+            | Doc::Annotated(_, doc) => stack.push(doc),
+            Doc::IfBreak { flat, .. } => stack.push(flat),
+            Doc::Concat(parts) | Doc::Fill(parts) => stack.extend(parts.iter().rev()),
+        }
+    }
+
+    Some(width)
+}
+
+/// Indentation is copied out of this buffer instead of being allocated for
+/// every line break.
+const SPACES: &str = "                                                                ";
+
+/// Start a new line indented by `indent` columns, with tabs of `tabs`
+/// columns if given and spaces otherwise. Returns the number of bytes
+/// written.
+fn newline(output: &mut impl Sink, mut indent: usize, tabs: Option<usize>) -> usize {
+    output.push_str("\n");
+    let mut written = 1;
+    if let Some(tab) = tabs {
+        for _ in 0..indent / tab {
+            output.push_str("\t");
+        }
+        written += indent / tab;
+        indent %= tab;
+    }
+    written += indent;
+    while indent > 0 {
+        let n = indent.min(SPACES.len());
+        output.push_str(&SPACES[..n]);
+        indent -= n;
+    }
+    written
 }
@@ -0,0 +1,287 @@
+//! Formatting of documents that contain several queries.
+
+use std::ops::Range;
+
+use crate::doc::Highlight;
+use crate::lexer::{TokenKind, tokenize};
+use crate::{FormatError, FormatOptions, format_query_with_options, format_range, highlight_query};
+
+/// The result of formatting a document with [`format_document`].
+#[derive(Debug, Clone)]
+pub struct DocumentOutput {
+    /// The formatted document. Queries that failed to parse are included
+    /// verbatim.
+    pub output: String,
+    /// One entry per query that could not be formatted.
+    pub errors: Vec<QueryError>,
+}
+
+/// A query in a document that could not be formatted.
+#[derive(Debug, Clone)]
+pub struct QueryError {
+    /// Line (1-based) in the input on which the query starts.
+    pub line: usize,
+    /// Why formatting failed.
+    pub error: FormatError,
+}
+
+/// Format a document that may contain several queries separated by blank
+/// lines or `;`.
+///
+/// The document is split at blank lines outside of brackets (or followed by
+/// an unindented line) and at `;` after a query, and each query is formatted
+/// on its own, keeping its `;`. A document with a single query is formatted
+/// exactly like [`format_query_with_options`]. If some part doesn't parse on
+/// its own but the whole document does, such as a query with a blank line
+/// before its projection, the document is formatted as one query. Otherwise
+/// queries that fail to parse are kept verbatim and reported in
+/// [`DocumentOutput::errors`] rather than failing the whole document.
+/// Function definitions and comment blocks stay attached to the query that
+/// follows them.
+///
+/// # Example
+///
+/// ```
+/// use groq_format::{format_document, FormatOptions};
+///
+/// let doc = format_document("*[a==1]\n\n*[b==\n\n*[c==3]", &FormatOptions::default()).unwrap();
+/// assert_eq!(doc.output, "*[a == 1]\n\n*[b==\n\n*[c == 3]");
+/// assert_eq!(doc.errors[0].line, 3);
+/// ```
+pub fn format_document(
+    source: &str,
+    options: &FormatOptions,
+) -> Result<DocumentOutput, FormatError> {
+    format_queries(
+        source,
+        |query| format_query_with_options(query, options),
+        str::to_string,
+    )
+}
+
+/// Format a document like [`format_document`], marking up the text of each
+/// query that was formatted with `highlight`, such as with colors for a
+/// terminal or as HTML. Queries that fail to parse are escaped for the
+/// markup but otherwise kept verbatim. Comments are not reflowed, even with
+/// [`FormatOptions::reflow_comments`].
+///
+/// # Example
+///
+/// ```
+/// use groq_format::{FormatOptions, Highlight, highlight_document};
+///
+/// let doc = highlight_document("*[a==1]", &FormatOptions::default(), Highlight::Ansi).unwrap();
+/// assert_eq!(
+///     doc.output,
+///     "\x1b[35m*\x1b[0m[\x1b[34ma\x1b[0m\x1b[36m == \x1b[0m\x1b[33m1\x1b[0m]"
+/// );
+/// ```
+pub fn highlight_document(
+    source: &str,
+    options: &FormatOptions,
+    highlight: Highlight,
+) -> Result<DocumentOutput, FormatError> {
+    format_queries(
+        source,
+        |query| highlight_query(query, options, highlight),
+        |query| highlight.escape(query),
+    )
+}
+
+/// Format the expressions that overlap the byte range `range` of a document
+/// that may contain several queries, like [`format_range`] does for a single
+/// query. The queries are found as in [`format_document`]. Queries outside
+/// the range are left exactly as they are, even if they don't parse.
+///
+/// # Example
+///
+/// ```
+/// use groq_format::{FormatOptions, format_document_range};
+///
+/// let document = "*[a==1]\n\n*[b==2]{x,y}";
+/// let start = document.find('{').unwrap();
+/// assert_eq!(
+///     format_document_range(document, start..start + 1, &FormatOptions::default()).unwrap(),
+///     "*[a==1]\n\n*[b==2]{ x, y }"
+/// );
+/// ```
+pub fn format_document_range(
+    source: &str,
+    range: Range<usize>,
+    options: &FormatOptions,
+) -> Result<String, FormatError> {
+    let segments = split_queries(source);
+    if segments.len() <= 1 {
+        return format_range(source, range, options);
+    }
+
+    let mut output = String::with_capacity(source.len());
+    let mut end = 0;
+    for segment in segments {
+        let touched = match range.is_empty() {
+            true => segment.contains(&range.start) || segment.end == range.start,
+            false => segment.start < range.end && range.start < segment.end,
+        };
+        if !touched {
+            continue;
+        }
+        let query = &source[segment.clone()];
+        let relative = range.start.saturating_sub(segment.start).min(query.len())
+            ..range.end.saturating_sub(segment.start).min(query.len());
+        match format_range(query, relative, options) {
+            Ok(formatted) => {
+                output.push_str(&source[end..segment.start]);
+                output.push_str(&formatted);
+                end = segment.end;
+            }
+            // The document may be one query with a blank line in it.
+            Err(err) => return format_range(source, range, options).map_err(|_| err),
+        }
+    }
+    output.push_str(&source[end..]);
+    Ok(output)
+}
+
+/// Format a document with `format` for each query, writing the queries that
+/// fail to parse with `verbatim`.
+fn format_queries(
+    source: &str,
+    format: impl Fn(&str) -> Result<String, FormatError>,
+    verbatim: impl Fn(&str) -> String,
+) -> Result<DocumentOutput, FormatError> {
+    let segments = split_queries(source);
+    let terminated = |range: &Range<usize>| source[range.end..].trim_start().starts_with(';');
+    if segments.len() <= 1 && !segments.first().is_some_and(terminated) {
+        // Nothing to split: format the document as a single query.
+        return Ok(DocumentOutput {
+            output: format(source)?,
+            errors: Vec::new(),
+        });
+    }
+
+    let results: Vec<_> = segments
+        .iter()
+        .map(|range| format(&source[range.clone()]))
+        .collect();
+    if results.iter().any(Result::is_err)
+        && let Ok(output) = format(source)
+    {
+        // A query that spans a blank line, such as a projection on a line of
+        // its own, was split apart.
+        return Ok(DocumentOutput {
+            output,
+            errors: Vec::new(),
+        });
+    }
+
+    let mut formatted = Vec::new();
+    let mut errors = Vec::new();
+    for (range, result) in segments.into_iter().zip(results) {
+        let mut output = match result {
+            Ok(output) => output,
+            Err(error) => {
+                errors.push(QueryError {
+                    line: line_of(source, range.start),
+                    error,
+                });
+                verbatim(source[range.clone()].trim_end())
+            }
+        };
+        if terminated(&range) {
+            output.push(';');
+        }
+        formatted.push(output);
+    }
+
+    Ok(DocumentOutput {
+        output: formatted.join("\n\n"),
+        errors,
+    })
+}
+
+/// Split a document into query ranges at blank lines outside brackets, and
+/// at `;` after a query. Ranges don't include the `;`.
+pub(crate) fn split_queries(source: &str) -> Vec<Range<usize>> {
+    let tokens = tokenize(source);
+    let mut segments = Vec::new();
+    let mut depth = 0usize;
+    let mut start = None;
+    // Whether the current segment contains code, and whether that code ends
+    // with the `;` of a function definition.
+    let mut has_code = false;
+    let mut ends_with_semicolon = false;
+    // Whether the code since the segment started or the last `;` is a
+    // function definition, where that code ends, and whether a comment
+    // follows it.
+    let mut in_definition = None;
+    let mut code_end = 0;
+    let mut comment_after_code = false;
+
+    for token in &tokens {
+        let text = token.text(source);
+        match token.kind {
+            TokenKind::Whitespace => {
+                let blank_line = text.matches('\n').count() >= 2;
+                // A blank line followed by an unindented line also ends a
+                // query, so an unclosed bracket doesn't swallow the rest.
+                let unindented = text.ends_with('\n');
+                if let Some(s) = start
+                    && blank_line
+                    && (depth == 0 || unindented)
+                    && has_code
+                    && !ends_with_semicolon
+                {
+                    segments.push(s..token.start);
+                    start = None;
+                    has_code = false;
+                    in_definition = None;
+                    depth = 0;
+                }
+                continue;
+            }
+            TokenKind::Comment => comment_after_code = true,
+            // A comment before the `;` would be lost, so the query is left
+            // for the parser to report instead.
+            _ if text == ";"
+                && depth == 0
+                && in_definition == Some(false)
+                && !comment_after_code =>
+            {
+                if let Some(s) = start {
+                    segments.push(s..code_end);
+                }
+                start = None;
+                has_code = false;
+                ends_with_semicolon = false;
+                in_definition = None;
+                continue;
+            }
+            _ => {
+                let definition = *in_definition.get_or_insert(text == "fn");
+                in_definition = match text {
+                    ";" if depth == 0 && definition => None,
+                    _ => Some(definition),
+                };
+                code_end = token.end;
+                comment_after_code = false;
+                has_code = true;
+                ends_with_semicolon = text == ";";
+                match text {
+                    "[" | "{" | "(" => depth += 1,
+                    "]" | "}" | ")" => depth = depth.saturating_sub(1),
+                    _ => {}
+                }
+            }
+        }
+        start.get_or_insert(token.start);
+    }
+
+    if let Some(s) = start {
+        segments.push(s..source.trim_end().len().max(s));
+    }
+    segments
+}
+
+fn line_of(source: &str, offset: usize) -> usize {
+    source[..offset].matches('\n').count() + 1
+}
@@ -0,0 +1,315 @@
+//! Extraction of queries embedded in JavaScript and TypeScript source.
+
+use std::iter::Peekable;
+use std::ops::Range;
+use std::str::CharIndices;
+
+use crate::metadata::query_metadata;
+use crate::{DocumentOutput, FormatOptions, QueryError, format_query_with_options};
+
+/// A query found in JavaScript or TypeScript source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EmbeddedQuery {
+    /// Line number (1-based) where the query's string literal starts.
+    pub line: usize,
+    /// The name given by a `// @name` header in the query, or else the name
+    /// of the constant it is assigned to.
+    pub name: Option<String>,
+    /// The query, with string escapes decoded.
+    pub query: String,
+}
+
+/// Find the queries in JavaScript or TypeScript source: `groq` tagged
+/// template literals and string arguments to `defineQuery()`.
+///
+/// Templates with `${}` interpolations are skipped, since their query is
+/// only known at runtime, and so are literals with legacy octal escapes or
+/// lone surrogates, which aren't decoded.
+///
+/// # Example
+///
+/// ```
+/// use groq_format::extract_embedded_queries;
+///
+/// let source = "export const allPosts = groq`*[_type == \"post\"]`;\n";
+/// let queries = extract_embedded_queries(source);
+/// assert_eq!(queries[0].name.as_deref(), Some("allPosts"));
+/// assert_eq!(queries[0].query, r#"*[_type == "post"]"#);
+/// ```
+pub fn extract_embedded_queries(source: &str) -> Vec<EmbeddedQuery> {
+    query_literals(source)
+        .into_iter()
+        .map(|literal| {
+            let start = literal.range.start;
+            let name = query_metadata(&literal.query)
+                .name
+                .or_else(|| assigned_name(&source[..start]).map(str::to_string));
+            EmbeddedQuery {
+                line: line_of(source, start),
+                name,
+                query: literal.query,
+            }
+        })
+        .collect()
+}
+
+/// Format the queries in JavaScript or TypeScript source found by
+/// [`extract_embedded_queries`], leaving the rest of the source as it is.
+///
+/// A query that fits on one line after the start of its literal stays on
+/// that line. Longer queries in template literals start on the line after
+/// the opening backtick, indented one level deeper than the line the literal
+/// starts on, with the closing backtick on a line of its own; they are
+/// formatted to fit the width from that indentation. A longer query in a
+/// quoted string becomes a template literal, since a string can't span
+/// lines. Queries that fail to parse are kept verbatim and reported in
+/// [`DocumentOutput::errors`].
+///
+/// # Example
+///
+/// ```
+/// use groq_format::{FormatOptions, format_embedded_queries};
+///
+/// let source = "  const posts = groq`*[_type==\"post\"]{title, \"author\": author->name, body}`;\n";
+/// let formatted = format_embedded_queries(source, &FormatOptions::new(40));
+/// assert_eq!(
+///     formatted.output,
+///     "  const posts = groq`\n    *[_type == \"post\"] {\n      title,\n      \"author\": author->name,\n      body\n    }\n  `;\n"
+/// );
+/// ```
+pub fn format_embedded_queries(source: &str, options: &FormatOptions) -> DocumentOutput {
+    let mut output = String::with_capacity(source.len());
+    let mut errors = Vec::new();
+    let mut end = 0;
+    for literal in query_literals(source) {
+        let start = literal.range.start;
+        let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+        let before = &source[line_start..start];
+        let column = before.chars().count();
+        let line_indent = &before[..before.len() - before.trim_start().len()];
+
+        // The query on the literal's own line, if it fits there.
+        let inline = format_query_with_options(&literal.query, options)
+            .ok()
+            .filter(|query| {
+                !query.contains('\n') && column + query.chars().count() + 2 <= options.width
+            })
+            .map(|query| escape(&query, literal.quote));
+
+        let replacement = match inline {
+            Some(query) => format!("{}{}{}", literal.quote, query, literal.quote),
+            None => {
+                let unit = match options.use_tabs {
+                    true => "\t".to_string(),
+                    false => " ".repeat(options.indent),
+                };
+                let indent = format!("{}{}", line_indent, unit);
+                let indent_width = indent
+                    .chars()
+                    .map(|c| if c == '\t' { options.indent } else { 1 })
+                    .sum::<usize>();
+                let block_options = FormatOptions {
+                    width: options.width.saturating_sub(indent_width),
+                    ..options.clone()
+                };
+                let query = match format_query_with_options(&literal.query, &block_options) {
+                    Ok(query) => query,
+                    Err(error) => {
+                        errors.push(QueryError {
+                            line: line_of(source, start),
+                            error,
+                        });
+                        continue;
+                    }
+                };
+                let lines: Vec<String> = query
+                    .lines()
+                    .map(|line| match line.is_empty() {
+                        true => String::new(),
+                        false => format!("{}{}", indent, escape(line, '`')),
+                    })
+                    .collect();
+                format!("`\n{}\n{}`", lines.join("\n"), line_indent)
+            }
+        };
+        output.push_str(&source[end..start]);
+        output.push_str(&replacement);
+        end = literal.range.end;
+    }
+    output.push_str(&source[end..]);
+    DocumentOutput { output, errors }
+}
+
+/// A string literal holding a query, without interpolations.
+struct QueryLiteral {
+    /// Byte range of the literal in the source, including its quotes.
+    range: Range<usize>,
+    quote: char,
+    /// The literal's contents, with string escapes decoded.
+    query: String,
+}
+
+/// The string literals in `source` that hold queries, in source order.
+fn query_literals(source: &str) -> Vec<QueryLiteral> {
+    let mut literals = Vec::new();
+    let mut pos = 0;
+    while let Some((start, quote)) = next_query_literal(source, pos) {
+        let Some((query, end)) = string_literal(source, start, quote) else {
+            break;
+        };
+        pos = end;
+        if let Some(query) = query {
+            literals.push(QueryLiteral {
+                range: start..end,
+                quote,
+                query,
+            });
+        }
+    }
+    literals
+}
+
+/// Line number (1-based) of the byte offset `pos` in `source`.
+fn line_of(source: &str, pos: usize) -> usize {
+    source[..pos].matches('\n').count() + 1
+}
+
+/// Escape `text` for a string literal quoted with `quote`.
+fn escape(text: &str, quote: char) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' if quote != '`' => escaped.push_str("\\t"),
+            '$' if quote == '`' && chars.peek() == Some(&'{') => escaped.push_str("\\$"),
+            c if c == quote => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The position and quote character of the next string literal holding a
+/// query, at or after `pos`.
+fn next_query_literal(source: &str, mut pos: usize) -> Option<(usize, char)> {
+    loop {
+        let rest = &source[pos..];
+        let (offset, literal) = [("groq`", 4), ("defineQuery(", 12)]
+            .into_iter()
+            .filter_map(|(tag, len)| rest.find(tag).map(|offset| (offset, offset + len)))
+            .min()?;
+        let start = pos + literal;
+        let preceded_by_identifier = source[..pos + offset]
+            .chars()
+            .next_back()
+            .is_some_and(|c| c.is_alphanumeric() || c == '_' || c == '$');
+        let after = &source[start..];
+        let quote_at = start + (after.len() - after.trim_start().len());
+        pos = quote_at;
+        match source[quote_at..].chars().next() {
+            Some(quote @ ('`' | '"' | '\'')) if !preceded_by_identifier => {
+                return Some((quote_at, quote));
+            }
+            Some(_) => {}
+            None => return None,
+        }
+    }
+}
+
+/// Read the string literal starting with `quote` at `start`. Returns its
+/// decoded contents, or `None` for a template with interpolations or a
+/// literal with an escape that isn't decoded (legacy octal escapes and lone
+/// surrogates), and the position after it. Returns `None` if the literal is
+/// not terminated.
+fn string_literal(source: &str, start: usize, quote: char) -> Option<(Option<String>, usize)> {
+    let mut contents = String::new();
+    let mut skipped = false;
+    let mut chars = source[start + 1..].char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => match chars.next()?.1 {
+                'n' => contents.push('\n'),
+                't' => contents.push('\t'),
+                'r' => contents.push('\r'),
+                'b' => contents.push('\u{8}'),
+                'f' => contents.push('\u{c}'),
+                'v' => contents.push('\u{b}'),
+                '0' if !chars.peek().is_some_and(|&(_, c)| c.is_ascii_digit()) => {
+                    contents.push('\0')
+                }
+                'x' => match hex_escape(&mut chars, Some(2)) {
+                    Some(c) => contents.push(c),
+                    None => skipped = true,
+                },
+                'u' => {
+                    let braced = chars.next_if(|&(_, c)| c == '{').is_some();
+                    match hex_escape(&mut chars, (!braced).then_some(4)) {
+                        Some(c) => contents.push(c),
+                        None => skipped = true,
+                    }
+                }
+                // Line continuations.
+                '\r' => {
+                    chars.next_if(|&(_, c)| c == '\n');
+                }
+                '\n' | '\u{2028}' | '\u{2029}' => {}
+                '0'..='9' => skipped = true,
+                escaped => contents.push(escaped),
+            },
+            '$' if quote == '`' && chars.peek().is_some_and(|&(_, c)| c == '{') => {
+                skipped = true;
+                contents.push(c);
+            }
+            c if c == quote => {
+                let end = start + 1 + i + c.len_utf8();
+                return Some(((!skipped).then_some(contents), end));
+            }
+            c => contents.push(c),
+        }
+    }
+    None
+}
+
+/// Decode the hex digits of a `\x` or `\u` escape: `digits` of them, or up
+/// to a closing `}` if `None`. Returns `None` if they don't make a character.
+fn hex_escape(chars: &mut Peekable<CharIndices>, digits: Option<usize>) -> Option<char> {
+    let mut value = 0u32;
+    let mut count = 0;
+    while digits.is_none_or(|digits| count < digits) {
+        if digits.is_none() && chars.next_if(|&(_, c)| c == '}').is_some() {
+            break;
+        }
+        let digit = chars.next_if(|&(_, c)| c.is_ascii_hexdigit())?.1;
+        value = value.checked_mul(16)? + digit.to_digit(16)?;
+        count += 1;
+    }
+    if count == 0 {
+        return None;
+    }
+    char::from_u32(value)
+}
+
+/// The name of the variable a value starting at the end of `before` is
+/// assigned to, as in `const allPosts = groq`.
+fn assigned_name(before: &str) -> Option<&str> {
+    let before = before.trim_end();
+    let before = before.strip_suffix("defineQuery(").unwrap_or(before);
+    let before = before.strip_suffix("groq").unwrap_or(before).trim_end();
+    let before = before.strip_suffix('=')?.trim_end();
+    // Skip a type annotation, as in `const allPosts: string = ...`.
+    let before = match before.rfind(':') {
+        Some(colon) if !before[colon..].contains(['\n', ';', '{', '(']) => {
+            before[..colon].trim_end()
+        }
+        _ => before,
+    };
+    let name_start = before
+        .rfind(|c: char| !(c.is_alphanumeric() || c == '_' || c == '$'))
+        .map_or(0, |i| i + 1);
+    let name = &before[name_start..];
+    (!name.is_empty()).then_some(name)
+}
@@ -0,0 +1,105 @@
+//! Formatted queries wrapped as source code for other languages.
+
+/// Languages a query can be emitted as a string constant for.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Language {
+    Go,
+    Php,
+    Python,
+    CSharp,
+}
+
+/// A declaration of a constant named `name` holding `query`.
+///
+/// Raw or verbatim string syntax is used where the language has one, so the
+/// query reads as formatted; queries that can't be written that way fall
+/// back to an escaped string.
+pub fn constant(language: Language, name: &str, query: &str) -> String {
+    match language {
+        Language::Go if !query.contains('`') => format!("const {} = `{}`", name, query),
+        Language::Go => format!("const {} = {}", name, escaped(query)),
+        Language::Php if !query.lines().any(|line| line.trim() == "GROQ") => {
+            format!("${} = <<<'GROQ'\n{}\nGROQ;", name, query)
+        }
+        Language::Php => format!(
+            "${} = '{}';",
+            name,
+            query.replace('\\', "\\\\").replace('\'', "\\'")
+        ),
+        // Newlines around the query keep a trailing quote or backslash away
+        // from the closing delimiter.
+        Language::Python if !query.contains("\"\"\"") => {
+            format!("{} = r\"\"\"\n{}\n\"\"\"", name, query)
+        }
+        Language::Python => format!("{} = {}", name, escaped(query)),
+        Language::CSharp => format!(
+            "const string {} = @\"{}\";",
+            name,
+            query.replace('"', "\"\"")
+        ),
+    }
+}
+
+/// Version of the Sanity HTTP API that curl commands are written against.
+const API_VERSION: &str = "v2021-10-21";
+
+/// The JSON body of a POST request to the query API that runs `query` with
+/// `params`, given as `(name, JSON value)` pairs.
+pub fn request(query: &str, params: &[(&str, &str)]) -> String {
+    let params: serde_json::Map<String, serde_json::Value> = params
+        .iter()
+        .map(|(name, value)| {
+            let value = serde_json::from_str(value).unwrap_or(serde_json::Value::Null);
+            (name.to_string(), value)
+        })
+        .collect();
+    format!(
+        "{:#}",
+        serde_json::json!({ "query": query, "params": params })
+    )
+}
+
+/// A curl command that runs `query` against the query API of a Sanity
+/// project, passing `params` as `(name, JSON value)` pairs.
+pub fn curl(project: &str, dataset: &str, query: &str, params: &[(&str, &str)]) -> String {
+    let mut url = format!(
+        "https://{}.api.sanity.io/{}/data/query/{}?query={}",
+        project,
+        API_VERSION,
+        url_encode(dataset),
+        url_encode(query)
+    );
+    for (name, value) in params {
+        url.push_str(&format!("&%24{}={}", name, url_encode(value)));
+    }
+    // Encoding leaves no quotes in the URL, so it can be single-quoted as is.
+    format!("curl '{}'", url)
+}
+
+/// Percent-encode everything but the characters that are unreserved in URLs.
+fn url_encode(text: &str) -> String {
+    let mut encoded = String::with_capacity(text.len());
+    for byte in text.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+/// Whether `name` can be used as a constant name in every language.
+pub fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// A double-quoted string literal with JSON escapes, which Go and Python
+/// accept as well.
+fn escaped(query: &str) -> String {
+    serde_json::Value::from(query).to_string()
+}
@@ -0,0 +1,189 @@
+//! Glob patterns, for inputs such as `'queries/**/*.groq'` and for the
+//! ignore file.
+
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use globset::{Glob, GlobBuilder, GlobMatcher, GlobSet, GlobSetBuilder};
+
+/// The file in the current directory listing the paths that are skipped
+/// when collecting files from directories.
+pub const IGNORE_FILE: &str = ".groqformatignore";
+
+/// Whether `input` is a glob pattern rather than the name of a file.
+pub fn is_glob(input: &str) -> bool {
+    input.contains(['*', '?', '['])
+}
+
+/// The directory to search for the files matching `pattern`: the part of it
+/// before the first component with a wildcard.
+pub fn glob_base(pattern: &str) -> PathBuf {
+    let mut base = PathBuf::new();
+    for component in pattern.split('/').take_while(|part| !is_glob(part)) {
+        base.push(if component.is_empty() { "/" } else { component });
+    }
+    match base.as_os_str().is_empty() {
+        true => PathBuf::from("."),
+        false => base,
+    }
+}
+
+/// A glob pattern to match paths against with [`Pattern::matches`]. In each
+/// component of the pattern, `*` matches any characters, `?` any single
+/// character and `[abc]` or `[a-z]` one of those characters, or any other
+/// with `[!abc]`. A component that is only `**` matches any number of
+/// components, including none.
+pub struct Pattern(GlobMatcher);
+
+impl Pattern {
+    /// Compile `pattern`, failing if it is malformed, such as with a `[`
+    /// that isn't closed.
+    pub fn new(pattern: &str) -> Result<Pattern, globset::Error> {
+        Ok(Pattern(glob(pattern)?.compile_matcher()))
+    }
+
+    /// Whether `path` matches the pattern. `.` components and the root are
+    /// left out of both, so `./queries/*.groq` matches `queries/a.groq`.
+    pub fn matches(&self, path: &Path) -> bool {
+        self.0.is_match(components(path))
+    }
+}
+
+/// Compile `pattern`, where wildcards other than `**` don't match `/`.
+fn glob(pattern: &str) -> Result<Glob, globset::Error> {
+    let pattern: Vec<&str> = pattern
+        .split('/')
+        .filter(|part| !part.is_empty() && *part != ".")
+        .collect();
+    GlobBuilder::new(&pattern.join("/"))
+        .literal_separator(true)
+        .backslash_escape(true)
+        .build()
+}
+
+/// The names of the normal components of `path`, joined by `/`.
+fn components(path: &Path) -> String {
+    let names: Vec<_> = path
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_string_lossy()),
+            _ => None,
+        })
+        .collect();
+    names.join("/")
+}
+
+/// `path` made absolute, with `.` and `..` components resolved by name.
+/// Symbolic links are not followed, so a `..` after a link leads to the
+/// directory that contains the link.
+fn normalize(path: &Path) -> io::Result<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in std::path::absolute(path)?.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    Ok(normalized)
+}
+
+/// The patterns of an ignore file, written like those of `.gitignore`: one
+/// per line, with `#` starting a comment. A pattern with a `/` at the start
+/// or in the middle matches paths from the directory of the ignore file;
+/// otherwise it matches files and directories of that name anywhere. A `/`
+/// at the end only matches directories, and a `!` in front includes paths
+/// that an earlier pattern ignores.
+pub struct Ignore {
+    /// The directory the patterns are relative to.
+    root: PathBuf,
+    globs: GlobSet,
+    /// For each pattern, whether it has a `!` in front and whether it only
+    /// matches directories.
+    patterns: Vec<(bool, bool)>,
+}
+
+impl Default for Ignore {
+    fn default() -> Self {
+        Ignore {
+            root: PathBuf::new(),
+            globs: GlobSet::empty(),
+            patterns: Vec::new(),
+        }
+    }
+}
+
+impl Ignore {
+    /// Read the ignore file at `path`. A missing file ignores nothing.
+    pub fn load(path: &Path) -> io::Result<Ignore> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Ignore::default()),
+            Err(e) => return Err(e),
+        };
+        let root = normalize(path)?
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        let mut globs = GlobSetBuilder::new();
+        let mut patterns = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (negated, line) = match line.strip_prefix('!') {
+                Some(line) => (true, line),
+                None => (false, line),
+            };
+            let (directories_only, line) = match line.strip_suffix('/') {
+                Some(line) => (true, line),
+                None => (false, line),
+            };
+            let pattern = match line.strip_prefix('/') {
+                Some(line) => line.to_string(),
+                None if line.contains('/') => line.to_string(),
+                None => format!("**/{}", line),
+            };
+            let glob = glob(&pattern).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {}: {}", number + 1, e),
+                )
+            })?;
+            globs.add(glob);
+            patterns.push((negated, directories_only));
+        }
+        let globs = globs
+            .build()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(Ignore {
+            root,
+            globs,
+            patterns,
+        })
+    }
+
+    /// Whether the file or directory at `path` is ignored. The last pattern
+    /// that matches decides.
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        if self.patterns.is_empty() {
+            return false;
+        }
+        let Ok(path) = normalize(path) else {
+            return false;
+        };
+        let Ok(path) = path.strip_prefix(&self.root) else {
+            return false;
+        };
+        self.globs
+            .matches(components(path))
+            .into_iter()
+            .filter(|&i| is_dir || !self.patterns[i].1)
+            .max()
+            .is_some_and(|i| !self.patterns[i].0)
+    }
+}
@@ -1,6 +1,15 @@
 //! GROQ expression formatting.
 
-use crate::doc::Doc;
+use std::borrow::Cow;
+
+use serde::Serialize;
+
+use crate::builtins::builtin_function;
+use crate::doc::{Category, Doc, Meter, columns};
+use crate::metadata;
+use crate::path::NodeIds;
+use crate::path::{Node, Resolved};
+use crate::{FormatError, FormatOptions, ast_memory};
 use groq_parser::ast::*;
 
 /// A formatter that tracks comment positions and emits them alongside AST nodes.
@@ -13,22 +22,80 @@ struct Formatter<'a> {
     comments: &'a [Comment],
     /// Index of the next unconsumed comment.
     cursor: usize,
-    /// When true, introduce additional line-break points (binary operators,
-    /// filter brackets, parentheses, single-argument function calls) so that
-    /// the formatter wraps more aggressively to honor the width limit.
-    force_wrap: bool,
+    /// Layout options; see [`FormatOptions`].
+    options: &'a FormatOptions,
+    /// Number of objects enclosing the node being formatted.
+    object_depth: usize,
+    /// Whether the next boolean operator is on the spine of a filter
+    /// constraint being aligned, see [`FormatOptions::align_conditions`].
+    align_logical: bool,
+    /// The ids of the expressions being formatted, if the document of every
+    /// expression is wrapped in a [`Doc::Mark`] identifying its node, see
+    /// [`crate::format_ast_with_spans`].
+    node_ids: Option<&'a NodeIds>,
+    /// Whether to tag the document of each token with its [`Category`], for
+    /// highlighting.
+    annotate: bool,
+    /// The meter counting the bytes allocated for the document and the
+    /// number it may reach, if [`FormatOptions::approximate_memory_limit`]
+    /// is set. Nodes are left out once it is exceeded.
+    budget: Option<(Meter, usize)>,
 }
 
 impl<'a> Formatter<'a> {
-    fn new(source: &'a str, comments: &'a [Comment], force_wrap: bool) -> Self {
+    fn new(source: &'a str, comments: &'a [Comment], options: &'a FormatOptions) -> Self {
         Formatter {
             source,
             comments,
             cursor: 0,
-            force_wrap,
+            options,
+            object_depth: 0,
+            align_logical: false,
+            node_ids: None,
+            annotate: false,
+            budget: None,
         }
     }
 
+    /// Whether the style edition is `edition` or a later one.
+    fn since(&self, edition: StyleEdition) -> bool {
+        self.options.style_edition >= edition
+    }
+
+    /// The bytes allocated for the document, if they exceed the budget.
+    fn over_budget(&self) -> Option<usize> {
+        let (meter, budget) = self.budget.as_ref()?;
+        Some(meter.allocated()).filter(|&allocated| allocated > *budget)
+    }
+
+    /// Take the comments between two operands, ending before `pos`. A comment
+    /// on the same line as the code before it stays on that line; the others
+    /// go on lines of their own. Returns `None` if there are no comments.
+    /// The caller must start a new line after the returned document.
+    fn take_operand_comments(&mut self, pos: usize) -> Option<Doc> {
+        let comments = self.take_comments_before(pos);
+        if comments.is_empty() {
+            return None;
+        }
+        let mut parts = Vec::new();
+        for (trailing, comment) in comments {
+            if trailing && parts.is_empty() {
+                parts.push(self.comment(format!(" {}", comment.text)));
+            } else {
+                parts.push(Doc::hard_line());
+                parts.push(self.comment(comment.text.clone()));
+            }
+        }
+        Some(Doc::concat(parts))
+    }
+
+    /// Whether an unconsumed comment starts before `pos`.
+    fn has_comment_before(&self, pos: usize) -> bool {
+        self.comments
+            .get(self.cursor)
+            .is_some_and(|comment| comment.pos.start < pos)
+    }
+
     /// Take all comments whose start position is before `pos`.
     /// Returns them classified as leading or trailing based on source context.
     fn take_comments_before(&mut self, pos: usize) -> Vec<(bool, &'a Comment)> {
@@ -64,7 +131,7 @@ impl<'a> Formatter<'a> {
     /// - Leading comments appear on their own line before the next item.
     fn format_comma_list_with_comments(
         &mut self,
-        exprs: &[Expr],
+        exprs: &[&Expr],
         end_pos: usize,
         as_object_field: bool,
     ) -> Doc {
@@ -73,7 +140,7 @@ impl<'a> Formatter<'a> {
         // the next item onto a new line (a // comment runs to end of line).
         let mut needs_hard_line = false;
 
-        for (i, expr) in exprs.iter().enumerate() {
+        for (i, &expr) in exprs.iter().enumerate() {
             let expr_start = expr.get_pos().start;
             let is_last = i == exprs.len() - 1;
 
@@ -83,16 +150,16 @@ impl<'a> Formatter<'a> {
             for (trailing, comment) in comments {
                 if trailing && !parts.is_empty() {
                     // Trailing comment on the previous item (after its comma)
-                    parts.push(Doc::text(format!(" {}", comment.text)));
+                    parts.push(self.comment(format!(" {}", comment.text)));
                     needs_hard_line = true;
                 } else if !parts.is_empty() {
                     // Leading comment before this item — starts on a new line
                     parts.push(Doc::hard_line());
-                    parts.push(Doc::text(&comment.text));
+                    parts.push(self.comment(comment.text.clone()));
                     needs_hard_line = true;
                 } else {
                     // Leading comment at the very start of the list
-                    parts.push(Doc::text(&comment.text));
+                    parts.push(self.comment(comment.text.clone()));
                     needs_hard_line = true;
                 }
             }
@@ -127,10 +194,10 @@ impl<'a> Formatter<'a> {
         let trailing = self.take_comments_before(end_pos);
         for (is_trailing, comment) in trailing {
             if is_trailing {
-                parts.push(Doc::text(format!(" {}", comment.text)));
+                parts.push(self.comment(format!(" {}", comment.text)));
             } else {
                 parts.push(Doc::hard_line());
-                parts.push(Doc::text(&comment.text));
+                parts.push(self.comment(comment.text.clone()));
             }
         }
 
@@ -138,56 +205,238 @@ impl<'a> Formatter<'a> {
     }
 
     fn format_expr(&mut self, expr: &Expr) -> Doc {
+        let doc = self.format_node(expr);
+        self.mark(expr, doc)
+    }
+
+    /// Mark a node's document if node marking is on.
+    fn mark(&self, expr: &Expr, doc: Doc) -> Doc {
+        match self.node_ids.and_then(|ids| ids.get(expr)) {
+            Some(id) => Doc::mark(id.index(), doc),
+            None => doc,
+        }
+    }
+
+    /// Tag a token's document with its category if annotation is on.
+    fn annotate(&self, category: Category, doc: Doc) -> Doc {
+        if self.annotate {
+            Doc::annotated(category, doc)
+        } else {
+            doc
+        }
+    }
+
+    /// The document of a comment's text.
+    fn comment(&self, text: String) -> Doc {
+        self.annotate(Category::Comment, Doc::text(text))
+    }
+
+    fn format_node(&mut self, expr: &Expr) -> Doc {
+        // The query is refused once the document outgrows the memory limit,
+        // so there is no point in building the rest.
+        if self.over_budget().is_some() {
+            return Doc::Nil;
+        }
+        if self.chain_lhs(expr).is_some() {
+            return self.format_chain(expr);
+        }
+        match expr {
+            Expr::Everything(_) => self.annotate(Category::Keyword, Doc::text("*")),
+            Expr::This(_) => self.annotate(Category::Keyword, Doc::text("@")),
+            Expr::Parent(_) => self.annotate(Category::Keyword, Doc::text("^")),
+            Expr::Literal(lit) => self.format_literal(lit),
+            Expr::Attribute(attr) => self.annotate(Category::Field, Doc::text(attr.name.clone())),
+            Expr::Param(param) => {
+                self.annotate(Category::Param, Doc::text(format!("${}", param.name)))
+            }
+            Expr::Pipe(_) | Expr::FunctionPipe(_) if self.is_long_pipeline(expr) => {
+                self.format_pipeline(expr)
+            }
+            Expr::Binary(bin) => {
+                let precedence = arithmetic_precedence(bin.operator.literal()).unwrap_or(0);
+                self.format_arithmetic_chain(bin, precedence)
+            }
+            Expr::Prefix(prefix) => self.format_prefix(prefix),
+            Expr::FunctionCall(func) => self.format_function_call(func),
+            Expr::Array(arr) => self.format_array(arr),
+            Expr::Object(obj) => self.format_object(obj),
+            Expr::Group(grp) if self.options.simplify && is_simple(&grp.expression) => {
+                self.format_expr(&grp.expression)
+            }
+            Expr::Group(grp) => {
+                let inner = self.format_expr(&grp.expression);
+                // Boolean groups always get break points inside the
+                // parentheses, like filters under force_wrap; other groups
+                // only with force_wrap.
+                if is_logical(&grp.expression) || self.options.force_wrap {
+                    Doc::group(Doc::concat([
+                        Doc::text("("),
+                        self.nest(Doc::concat([Doc::line_or_empty(), inner])),
+                        Doc::line_or_empty(),
+                        Doc::text(")"),
+                    ]))
+                } else {
+                    Doc::concat([Doc::text("("), inner, Doc::text(")")])
+                }
+            }
+            Expr::Range(range) => self.format_range(range),
+            Expr::Ellipsis(_) => Doc::text("..."),
+            Expr::Constraint(c) => self.format_expr(&c.expression),
+            Expr::Subscript(s) => self.format_expr(&s.value),
+            Expr::Tuple(t) => self.format_tuple(t),
+            // Chains are formatted by `format_chain`.
+            Expr::Filter(_)
+            | Expr::Slice(_)
+            | Expr::Element(_)
+            | Expr::ArrayTraversal(_)
+            | Expr::Dot(_)
+            | Expr::Projection(_)
+            | Expr::Pipe(_)
+            | Expr::FunctionPipe(_)
+            | Expr::Postfix(_) => unreachable!(),
+        }
+    }
+
+    /// The left operand of `expr` if it is a link of a chain built up on the
+    /// left, such as `a.b.c`, `*[a][b]{c}` or `a || b || c`.
+    fn chain_lhs<'e>(&self, expr: &'e Expr) -> Option<&'e Expr> {
+        match expr {
+            Expr::Filter(filter) => Some(&filter.lhs),
+            Expr::Slice(slice) => Some(&slice.lhs),
+            Expr::Element(elem) => Some(&elem.lhs),
+            Expr::ArrayTraversal(at) => Some(&at.expr),
+            Expr::Dot(dot) => Some(&dot.lhs),
+            Expr::Projection(proj) => Some(&proj.lhs),
+            // Longer pipelines are laid out as a whole by `format_pipeline`.
+            Expr::Pipe(_) | Expr::FunctionPipe(_) if self.is_long_pipeline(expr) => None,
+            Expr::Pipe(pipe) => Some(&pipe.lhs),
+            Expr::FunctionPipe(fp) => Some(&fp.lhs),
+            // Arithmetic under force_wrap is laid out by
+            // `format_arithmetic_chain`.
+            Expr::Binary(bin)
+                if self.options.force_wrap
+                    && self.since(StyleEdition::Edition2025)
+                    && arithmetic_precedence(bin.operator.literal()).is_some() =>
+            {
+                None
+            }
+            Expr::Binary(bin) => Some(&bin.lhs),
+            Expr::Postfix(postfix) => Some(&postfix.lhs),
+            _ => None,
+        }
+    }
+
+    /// Whether `expr` is a pipeline of three or more stages, which breaks
+    /// before every stage as of [`StyleEdition::Edition2025`].
+    fn is_long_pipeline(&self, expr: &Expr) -> bool {
+        self.since(StyleEdition::Edition2025) && pipe_count(expr) >= 3
+    }
+
+    /// Format a chain of links built up on the left. Generated queries can
+    /// have chains thousands of links long, so the links are formatted in a
+    /// loop from the innermost operand out rather than by recursion. Every
+    /// link but `expr` itself is marked.
+    fn format_chain(&mut self, expr: &Expr) -> Doc {
+        // Aligned continuations are passed down the left spine of boolean
+        // operators, whose continuations line up too.
+        let mut links = vec![(expr, std::mem::take(&mut self.align_logical))];
+        while let Some(&(link, align)) = links.last()
+            && let Some(lhs) = self.chain_lhs(link)
+        {
+            links.push((lhs, align && is_logical(link) && is_logical(lhs)));
+        }
+        let (operand, _) = links.pop().unwrap_or((expr, false));
+        let mut doc = self.format_expr(operand);
+        for (i, &(link, align)) in links.iter().enumerate().rev() {
+            if self.over_budget().is_some() {
+                break;
+            }
+            doc = self.format_link(link, doc, align);
+            if i > 0 {
+                doc = self.mark(link, doc);
+            }
+        }
+        doc
+    }
+
+    /// Format a link of a chain given the document of its left operand.
+    fn format_link(&mut self, expr: &Expr, lhs: Doc, align: bool) -> Doc {
         match expr {
-            Expr::Everything(_) => Doc::text("*"),
-            Expr::This(_) => Doc::text("@"),
-            Expr::Parent(_) => Doc::text("^"),
-            Expr::Literal(lit) => format_literal(lit),
-            Expr::Attribute(attr) => Doc::text(&attr.name),
-            Expr::Param(param) => Doc::text(format!("${}", param.name)),
             Expr::Filter(filter) => {
-                let lhs = self.format_expr(&filter.lhs);
-                let constraint = self.format_expr(&filter.constraint.expression);
-                if self.force_wrap {
+                let aligned = self.options.align_conditions;
+                self.align_logical = aligned && is_logical(&filter.constraint.expression);
+                // Marked below, since the layout depends on the document.
+                let constraint = self.format_node(&filter.constraint.expression);
+                self.align_logical = false;
+                // A comment after the constraint stays inside the brackets,
+                // with the closing bracket on the next line.
+                let comments = self.take_operand_comments(filter.constraint.pos.end);
+
+                let hanging = !self.options.force_wrap;
+                let align = |doc| {
+                    if aligned && hanging {
+                        Doc::align(doc)
+                    } else {
+                        doc
+                    }
+                };
+                let constraint = match constraint {
+                    // The closing bracket breaks along with the conditions.
+                    Doc::Group(conditions)
+                        if self.options.closing_bracket_on_own_line
+                            && hanging
+                            && comments.is_none() =>
+                    {
+                        let conditions = self.mark(&filter.constraint.expression, *conditions);
+                        Doc::group(Doc::concat([align(conditions), Doc::line_or_empty()]))
+                    }
+                    constraint => align(self.mark(&filter.constraint.expression, constraint)),
+                };
+                let brackets = if let Some(comments) = comments {
+                    Doc::concat([
+                        Doc::text("["),
+                        constraint,
+                        comments,
+                        Doc::hard_line(),
+                        Doc::text("]"),
+                    ])
+                } else if self.options.force_wrap {
                     Doc::concat([
-                        lhs,
                         Doc::text("["),
                         Doc::group(Doc::concat([
-                            Doc::nest(2, Doc::concat([Doc::line_or_empty(), constraint])),
+                            self.nest(Doc::concat([Doc::line_or_empty(), constraint])),
                             Doc::line_or_empty(),
                         ])),
                         Doc::text("]"),
                     ])
                 } else {
                     Doc::concat([
-                        lhs,
                         Doc::group(Doc::concat([Doc::text("["), constraint])),
                         Doc::text("]"),
                     ])
-                }
+                };
+                Doc::concat([lhs, within(self.options.filter_width, brackets)])
             }
             Expr::Slice(slice) => {
-                let lhs = self.format_expr(&slice.lhs);
                 let range = self.format_expr(&slice.range.value);
                 Doc::concat([lhs, Doc::text("["), range, Doc::text("]")])
             }
             Expr::Element(elem) => {
-                let lhs = self.format_expr(&elem.lhs);
                 let idx = self.format_expr(&elem.idx.value);
                 Doc::concat([lhs, Doc::text("["), idx, Doc::text("]")])
             }
-            Expr::ArrayTraversal(at) => Doc::concat([self.format_expr(&at.expr), Doc::text("[]")]),
-            Expr::Dot(dot) => self.format_dot(dot),
+            Expr::ArrayTraversal(_) => Doc::concat([lhs, Doc::text("[]")]),
+            Expr::Dot(dot) => self.format_dot(dot, lhs),
             Expr::Projection(proj) => {
-                let lhs = self.format_expr(&proj.lhs);
                 let mid_comments = self.take_comments_before(proj.object.pos.start);
                 let obj = self.format_object(&proj.object);
+                let obj = within(self.options.projection_width, obj);
                 if mid_comments.is_empty() {
                     Doc::concat([lhs, Doc::text(" "), obj])
                 } else {
                     let mut parts = vec![lhs];
                     for (_, comment) in mid_comments {
-                        parts.push(Doc::text(format!(" {}", comment.text)));
+                        parts.push(self.comment(format!(" {}", comment.text)));
                     }
                     parts.push(Doc::hard_line());
                     parts.push(obj);
@@ -195,54 +444,34 @@ impl<'a> Formatter<'a> {
                 }
             }
             Expr::Pipe(pipe) => {
-                let lhs = self.format_expr(&pipe.lhs);
                 let rhs = self.format_expr(&pipe.rhs);
                 Doc::group(Doc::concat([
                     lhs,
-                    Doc::nest(2, Doc::concat([Doc::line(), Doc::text("| "), rhs])),
+                    self.nest(Doc::concat([Doc::line(), Doc::text("| "), rhs])),
                 ]))
             }
             Expr::FunctionPipe(fp) => {
-                let lhs = self.format_expr(&fp.lhs);
                 let func = self.format_function_call(&fp.func);
                 Doc::group(Doc::concat([
                     lhs,
-                    Doc::nest(2, Doc::concat([Doc::line(), Doc::text("| "), func])),
+                    self.nest(Doc::concat([Doc::line(), Doc::text("| "), func])),
                 ]))
             }
-            Expr::Binary(bin) => self.format_binary(bin),
-            Expr::Prefix(prefix) => self.format_prefix(prefix),
-            Expr::Postfix(postfix) => self.format_postfix(postfix),
-            Expr::FunctionCall(func) => self.format_function_call(func),
-            Expr::Array(arr) => self.format_array(arr),
-            Expr::Object(obj) => self.format_object(obj),
-            Expr::Group(grp) => {
-                let inner = self.format_expr(&grp.expression);
-                if self.force_wrap {
-                    Doc::group(Doc::concat([
-                        Doc::text("("),
-                        Doc::nest(2, Doc::concat([Doc::line_or_empty(), inner])),
-                        Doc::line_or_empty(),
-                        Doc::text(")"),
-                    ]))
-                } else {
-                    Doc::concat([Doc::text("("), inner, Doc::text(")")])
-                }
-            }
-            Expr::Range(range) => self.format_range(range),
-            Expr::Ellipsis(_) => Doc::text("..."),
-            Expr::Constraint(c) => self.format_expr(&c.expression),
-            Expr::Subscript(s) => self.format_expr(&s.value),
-            Expr::Tuple(t) => {
-                let members: Vec<Doc> = t.members.iter().map(|m| self.format_expr(m)).collect();
-                let content = Doc::join(Doc::concat([Doc::text(","), Doc::line()]), members);
-                Doc::concat([Doc::text("("), Doc::group(content), Doc::text(")")])
-            }
+            Expr::Binary(bin) => self.format_binary(bin, lhs, align),
+            Expr::Postfix(postfix) => self.format_postfix(postfix, lhs),
+            _ => unreachable!(),
         }
     }
 
-    fn format_dot(&mut self, dot: &DotOperator) -> Doc {
-        let lhs = self.format_expr(&dot.lhs);
+    /// Indent a document by one level.
+    fn nest(&self, doc: Doc) -> Doc {
+        Doc::nest(self.options.indent, doc)
+    }
+
+    /// Format attribute access. Traversal chains (`a.b`, `a->b`, `a[]`,
+    /// `a[0]`) and parent chains (`^.^._id`) contain no break points: they
+    /// stay on one line and only projections attached to them break.
+    fn format_dot(&mut self, dot: &DotOperator, lhs: Doc) -> Doc {
         let rhs = self.format_expr(&dot.rhs);
 
         if let Expr::Postfix(post) = dot.lhs.as_ref()
@@ -254,56 +483,177 @@ impl<'a> Formatter<'a> {
         Doc::concat([lhs, Doc::text("."), rhs])
     }
 
-    fn format_binary(&mut self, bin: &BinaryOperator) -> Doc {
-        let op = bin.operator.literal();
-        let left = self.format_expr(&bin.lhs);
-        let right = self.format_expr(&bin.rhs);
+    /// Format a pipeline of three or more stages as one group, so that when
+    /// it doesn't fit every stage starts a line. Slices and subscripts stay
+    /// attached to the stage they follow.
+    fn format_pipeline(&mut self, expr: &Expr) -> Doc {
+        let stages = pipe_stages(expr);
+        let first = match stages.last() {
+            Some(Expr::Pipe(pipe)) => &pipe.lhs,
+            Some(Expr::FunctionPipe(fp)) => &fp.lhs,
+            _ => return self.format_expr(expr),
+        };
+
+        let mut parts = vec![self.format_expr(first)];
+        for stage in stages.iter().rev() {
+            match stage {
+                Expr::Pipe(pipe) => {
+                    let rhs = self.format_expr(&pipe.rhs);
+                    parts.push(self.nest(Doc::concat([Doc::line(), Doc::text("| "), rhs])));
+                }
+                Expr::FunctionPipe(fp) => {
+                    let func = self.format_function_call(&fp.func);
+                    parts.push(self.nest(Doc::concat([Doc::line(), Doc::text("| "), func])));
+                }
+                Expr::Slice(slice) => {
+                    let range = self.format_expr(&slice.range.value);
+                    parts.push(Doc::concat([Doc::text("["), range, Doc::text("]")]));
+                }
+                Expr::Element(elem) => {
+                    let idx = self.format_expr(&elem.idx.value);
+                    parts.push(Doc::concat([Doc::text("["), idx, Doc::text("]")]));
+                }
+                _ => {}
+            }
+            if self.node_ids.is_some() {
+                // Give every stage a range covering the stages before it,
+                // as if it had been formatted on its own.
+                parts = vec![self.mark(stage, Doc::concat(parts))];
+            }
+        }
+        Doc::group(Doc::concat(parts))
+    }
 
-        if bin.operator == Token::And || bin.operator == Token::Or {
+    /// Format a binary operation given the document of its left operand.
+    /// With `align`, the continuation of a boolean operator lines up under
+    /// the left operand instead of hanging.
+    fn format_binary(&mut self, bin: &BinaryOperator, left: Doc, align: bool) -> Doc {
+        let op = bin.operator.literal();
+        let logical = bin.operator == Token::And || bin.operator == Token::Or;
+        if logical {
+            let hang = if align { 0 } else { self.options.indent };
+            // Take comments before the right operand is formatted, so they
+            // attach here rather than to the first list inside it.
+            let comments = self.take_operand_comments(leftmost_pos(&bin.rhs));
+            let right = self.format_expr(&bin.rhs);
+            let op = if bin.operator == Token::And {
+                "&& "
+            } else {
+                "|| "
+            };
+            if let Some(comments) = comments {
+                return Doc::concat([
+                    left,
+                    Doc::nest(
+                        hang,
+                        Doc::concat([
+                            comments,
+                            Doc::hard_line(),
+                            self.annotate(Category::Operator, Doc::text(op)),
+                            right,
+                        ]),
+                    ),
+                ]);
+            }
             return Doc::group(Doc::concat([
                 left,
                 Doc::nest(
-                    2,
-                    Doc::concat([Doc::line(), Doc::text(format!("{} ", op)), right]),
+                    hang,
+                    Doc::concat([
+                        Doc::line(),
+                        self.annotate(Category::Operator, Doc::text(op)),
+                        right,
+                    ]),
                 ),
             ]));
         }
 
+        let right = self.format_expr(&bin.rhs);
+
         if bin.operator == Token::Colon {
             return Doc::concat([left, Doc::text(": "), right]);
         }
 
-        if self.force_wrap {
+        if op == "=>" && self.since(StyleEdition::Edition2025) {
+            // A conditional body that doesn't fit moves to its own line,
+            // nested under the condition, rather than breaking after `=> {`.
+            if self.options.spacing == Spacing::Tight {
+                return Doc::group(Doc::concat([
+                    left,
+                    self.annotate(Category::Operator, Doc::text("=>")),
+                    self.nest(Doc::concat([Doc::line_or_empty(), right])),
+                ]));
+            }
             return Doc::group(Doc::concat([
                 left,
-                Doc::nest(
-                    2,
-                    Doc::concat([Doc::line(), Doc::text(format!("{} ", op)), right]),
-                ),
+                self.annotate(Category::Operator, Doc::text(" =>")),
+                self.nest(Doc::concat([Doc::line(), right])),
+            ]));
+        }
+
+        if self.options.force_wrap {
+            return Doc::group(Doc::concat([
+                left,
+                self.nest(Doc::concat([
+                    Doc::line(),
+                    self.annotate(Category::Operator, Doc::text(spaced_operator(op, false))),
+                    right,
+                ])),
             ]));
         }
 
-        Doc::concat([left, Doc::text(format!(" {} ", op)), right])
+        let op = self.annotate(Category::Operator, Doc::text(spaced_operator(op, true)));
+        Doc::concat([left, op, right])
+    }
+
+    /// Format a chain of arithmetic operators of the same precedence, such as
+    /// `a - b + c`, as a fill: operands stay on a line until it is full,
+    /// instead of the chain breaking at every operator.
+    fn format_arithmetic_chain(&mut self, bin: &BinaryOperator, precedence: u8) -> Doc {
+        // Walk down the left-associative spine to the first operand.
+        let mut spine = vec![bin];
+        while let Expr::Binary(lhs) = &*spine[spine.len() - 1].lhs
+            && arithmetic_precedence(lhs.operator.literal()) == Some(precedence)
+            && precedence != EXPONENT_PRECEDENCE
+        {
+            let lhs: &BinaryOperator = lhs;
+            spine.push(lhs);
+        }
+
+        let first = &spine[spine.len() - 1].lhs;
+        let mut parts = vec![self.format_expr(first)];
+        for bin in spine.iter().rev() {
+            let op = spaced_operator(bin.operator.literal(), false);
+            let operand = self.format_expr(&bin.rhs);
+            parts.push(Doc::line());
+            parts.push(Doc::concat([
+                self.annotate(Category::Operator, Doc::text(op)),
+                operand,
+            ]));
+        }
+        self.nest(Doc::fill(parts))
     }
 
     fn format_prefix(&mut self, prefix: &PrefixOperator) -> Doc {
         let op = prefix.operator.literal();
         let operand = self.format_expr(&prefix.rhs);
-        Doc::concat([Doc::text(op), operand])
+        if op == "!" && self.options.spacing == Spacing::Spacious {
+            return Doc::concat([self.annotate(Category::Operator, Doc::text("! ")), operand]);
+        }
+        Doc::concat([self.annotate(Category::Operator, Doc::text(op)), operand])
     }
 
-    fn format_postfix(&mut self, postfix: &PostfixOperator) -> Doc {
-        let operand = self.format_expr(&postfix.lhs);
+    fn format_postfix(&mut self, postfix: &PostfixOperator, operand: Doc) -> Doc {
         let op = postfix.operator.literal();
 
         let op_text =
             if postfix.operator == Token::AscOperator || postfix.operator == Token::DescOperator {
-                format!(" {}", op)
+                self.annotate(Category::Keyword, Doc::text(format!(" {}", op)))
             } else {
-                op.to_string()
+                self.annotate(Category::Operator, Doc::text(op))
             };
 
-        Doc::concat([operand, Doc::text(op_text)])
+        Doc::concat([operand, op_text])
     }
 
     fn format_function_call(&mut self, func: &FunctionCall) -> Doc {
@@ -312,28 +662,60 @@ impl<'a> Formatter<'a> {
         } else {
             format!("{}::{}", func.namespace, func.name)
         };
+        let name = self.annotate(Category::Function, Doc::text(name));
 
         if func.arguments.is_empty() {
-            return Doc::text(format!("{}()", name));
+            return Doc::concat([name, Doc::text("()")]);
         }
 
         let args: Vec<Doc> = func.arguments.iter().map(|a| self.format_expr(a)).collect();
-        let arg_list = Doc::join(Doc::concat([Doc::text(","), Doc::line()]), args);
+        let layout = call_layout(self.options, &func.namespace, &func.name);
+        if layout == CallLayout::Flat {
+            return Doc::concat([
+                name,
+                Doc::text("("),
+                Doc::join(Doc::text(", "), args),
+                Doc::text(")"),
+            ]);
+        }
+        if layout == CallLayout::Fill {
+            let last = args.len() - 1;
+            let mut parts = Vec::with_capacity(args.len() * 2);
+            for (i, arg) in args.into_iter().enumerate() {
+                if i < last {
+                    parts.push(Doc::concat([arg, Doc::text(",")]));
+                    parts.push(Doc::line());
+                } else {
+                    parts.push(arg);
+                }
+            }
+            let call = Doc::concat([
+                name,
+                Doc::text("("),
+                self.nest(Doc::fill(parts)),
+                Doc::text(")"),
+            ]);
+            return within(self.options.argument_width, call);
+        }
 
-        if self.force_wrap {
+        let arg_list = Doc::join(Doc::concat([Doc::text(","), Doc::line()]), args);
+        let call = if layout == CallLayout::OnePerLine || self.options.force_wrap {
             Doc::group(Doc::concat([
-                Doc::text(format!("{}(", name)),
-                Doc::nest(2, Doc::concat([Doc::line_or_empty(), arg_list])),
+                name,
+                Doc::text("("),
+                self.nest(Doc::concat([Doc::line_or_empty(), arg_list])),
                 Doc::line_or_empty(),
                 Doc::text(")"),
             ]))
         } else {
             Doc::concat([
-                Doc::text(format!("{}(", name)),
-                Doc::nest(2, Doc::group(arg_list)),
+                name,
+                Doc::text("("),
+                self.nest(Doc::group(arg_list)),
                 Doc::text(")"),
             ])
-        }
+        };
+        within(self.options.argument_width, call)
     }
 
     fn format_array(&mut self, arr: &Array) -> Doc {
@@ -341,51 +723,165 @@ impl<'a> Formatter<'a> {
             return Doc::text("[]");
         }
 
-        let content = self.format_comma_list_with_comments(&arr.expressions, arr.pos.end, false);
+        let items: Vec<&Expr> = arr.expressions.iter().collect();
+        let content = self.format_comma_list_with_comments(&items, arr.pos.end, false);
 
         Doc::group(Doc::concat([
             Doc::text("["),
-            Doc::nest(2, Doc::concat([Doc::line_or_empty(), content])),
+            self.nest(Doc::concat([Doc::line_or_empty(), content])),
             Doc::line_or_empty(),
             Doc::text("]"),
         ]))
     }
 
+    /// Format a tuple like an array: flat if it fits, otherwise one member
+    /// per line with the closing parenthesis on its own line.
+    fn format_tuple(&mut self, tuple: &Tuple) -> Doc {
+        if !self.since(StyleEdition::Edition2025) {
+            // Members wrap where the line is full, without nesting.
+            let members: Vec<Doc> = tuple.members.iter().map(|m| self.format_expr(m)).collect();
+            let content = Doc::join(Doc::concat([Doc::text(","), Doc::line()]), members);
+            return Doc::concat([Doc::text("("), Doc::group(content), Doc::text(")")]);
+        }
+        let members: Vec<&Expr> = tuple.members.iter().collect();
+        let content = self.format_comma_list_with_comments(&members, tuple.pos.end, false);
+
+        Doc::group(Doc::concat([
+            Doc::text("("),
+            self.nest(Doc::concat([Doc::line_or_empty(), content])),
+            Doc::line_or_empty(),
+            Doc::text(")"),
+        ]))
+    }
+
+    /// Format an object or projection body. Fields keep their source order,
+    /// which decides which of two fields with the same name wins.
     fn format_object(&mut self, obj: &Object) -> Doc {
         if obj.expressions.is_empty() {
             return Doc::text("{}");
         }
 
-        let content = self.format_comma_list_with_comments(&obj.expressions, obj.pos.end, true);
+        if self.options.inline_single_field
+            && let [field @ Expr::Attribute(_)] = obj.expressions.as_slice()
+            && !self.has_comment_before(obj.pos.end)
+        {
+            return Doc::concat([
+                Doc::text("{ "),
+                self.format_object_field(field),
+                Doc::text(" }"),
+            ]);
+        }
+
+        let fields = self.field_order(obj);
+        self.object_depth += 1;
+        let content = self.format_comma_list_with_comments(&fields, obj.pos.end, true);
+        let depth = self.object_depth;
+        self.object_depth -= 1;
+
+        // Past the break depth the object never fits flat, so it and the
+        // objects around it break.
+        if self.options.break_depth.is_some_and(|limit| depth > limit) {
+            return Doc::concat([
+                Doc::text("{"),
+                self.nest(Doc::concat([Doc::hard_line(), content])),
+                Doc::hard_line(),
+                Doc::text("}"),
+            ]);
+        }
 
         Doc::group(Doc::concat([
             Doc::text("{"),
-            Doc::nest(2, Doc::concat([Doc::line(), content])),
+            self.nest(Doc::concat([Doc::line(), content])),
             Doc::line(),
             Doc::text("}"),
         ]))
     }
 
+    /// The fields of an object in the order they are written out. With
+    /// [`FormatOptions::sort_fields`], each run of bare attributes is sorted
+    /// by name. Other fields stay where they are and end runs, so no field
+    /// moves past one that could have the same key, and which of two fields
+    /// wins doesn't change. Attributes next to a comment stay too, so the
+    /// comment stays with them.
+    fn field_order<'e>(&self, obj: &'e Object) -> Vec<&'e Expr> {
+        let fields = &obj.expressions;
+        let mut order: Vec<&Expr> = fields.iter().collect();
+        if !self.options.sort_fields {
+            return order;
+        }
+        let name = |field: &'e Expr| match field {
+            Expr::Attribute(attr) => Some(attr.name.as_str()),
+            _ => None,
+        };
+        let movable = |i: usize| {
+            let before = match i {
+                0 => obj.pos.start,
+                _ => fields[i - 1].get_pos().end,
+            };
+            let after = fields
+                .get(i + 1)
+                .map_or(obj.pos.end, |next| next.get_pos().start);
+            name(&fields[i]).is_some()
+                && !self.has_comment_in(before..fields[i].get_pos().start)
+                && !self.has_comment_in(fields[i].get_pos().end..after)
+        };
+        let mut start = 0;
+        while start < order.len() {
+            let mut end = start;
+            while end < order.len() && movable(end) {
+                end += 1;
+            }
+            // Stable, so repeated attributes keep their order.
+            order[start..end].sort_by_key(|&field| name(field));
+            start = end + 1;
+        }
+        order
+    }
+
+    /// Whether a comment starts in a range of the source.
+    fn has_comment_in(&self, range: std::ops::Range<usize>) -> bool {
+        self.comments
+            .iter()
+            .any(|comment| range.contains(&comment.pos.start))
+    }
+
     fn format_object_field(&mut self, expr: &Expr) -> Doc {
-        match expr {
+        let doc = match expr {
+            // `"title": title` is the same as `title`.
+            Expr::Binary(bin)
+                if bin.operator == Token::Colon
+                    && self.options.simplify
+                    && let (Expr::Literal(Literal::String(key)), Expr::Attribute(attr)) =
+                        (bin.lhs.as_ref(), bin.rhs.as_ref())
+                    && key.value == attr.name =>
+            {
+                let name = self.annotate(Category::Field, Doc::text(attr.name.clone()));
+                self.mark(&bin.rhs, name)
+            }
             Expr::Binary(bin) if bin.operator == Token::Colon => {
                 let key = self.format_expr(&bin.lhs);
                 let value = self.format_expr(&bin.rhs);
                 Doc::concat([key, Doc::text(": "), value])
             }
-            Expr::Attribute(attr) => Doc::text(&attr.name),
+            Expr::Attribute(attr) => self.annotate(Category::Field, Doc::text(attr.name.clone())),
             Expr::Ellipsis(_) => Doc::text("..."),
-            _ => self.format_expr(expr),
-        }
+            _ => self.format_node(expr),
+        };
+        self.mark(expr, doc)
     }
 
     fn format_range(&mut self, range: &Range) -> Doc {
         let start = self.format_expr(&range.start);
         let end = self.format_expr(&range.end);
 
-        let op = if range.inclusive { ".." } else { "..." };
+        let op = match (range.inclusive, self.options.spacing) {
+            (true, Spacing::Spacious) => " .. ",
+            (false, Spacing::Spacious) => " ... ",
+            (true, _) => "..",
+            (false, _) => "...",
+        };
 
-        Doc::concat([start, Doc::text(op), end])
+        Doc::concat([start, self.annotate(Category::Operator, Doc::text(op)), end])
     }
 
     fn format_function_definition(&mut self, func: &FunctionDefinition) -> Doc {
@@ -395,31 +891,301 @@ impl<'a> Formatter<'a> {
             .iter()
             .map(|p| format!("${}", p.name))
             .collect();
-        let params_str = params.join(", ");
+        let params: Vec<Doc> = params
+            .into_iter()
+            .map(|param| self.annotate(Category::Param, Doc::text(param)))
+            .collect();
 
         Doc::concat([
-            Doc::text(format!("fn {}({}) = ", name, params_str)),
+            self.annotate(Category::Keyword, Doc::text("fn")),
+            Doc::text(" "),
+            self.annotate(Category::Function, Doc::text(name)),
+            Doc::text("("),
+            Doc::join(Doc::text(", "), params),
+            Doc::text(") = "),
             self.format_expr(&func.body),
             Doc::text(";"),
         ])
     }
+
+    fn format_literal(&self, lit: &Literal) -> Doc {
+        match lit {
+            Literal::String(s) => {
+                let quote = match self.options.quote_style {
+                    QuoteStyle::Double => '"',
+                    QuoteStyle::Single => '\'',
+                    // The source is empty for expressions formatted on their
+                    // own, which get the default.
+                    QuoteStyle::Preserve => match self.source.get(s.pos.start..) {
+                        Some(rest) if rest.starts_with('\'') => '\'',
+                        _ => '"',
+                    },
+                };
+                let text = format!("{}{}{}", quote, escape_string(&s.value, quote), quote);
+                // A string wider than the whole line overflows wherever it
+                // goes, so optionally keep it from breaking enclosing groups.
+                let doc = if self.options.exempt_long_strings && columns(&text) > self.options.width
+                {
+                    Doc::unmeasured(text)
+                } else {
+                    Doc::text(text)
+                };
+                self.annotate(Category::String, doc)
+            }
+            Literal::Integer(i) => self.annotate(Category::Number, Doc::text(i.value.to_string())),
+            Literal::Float(f) => self.annotate(Category::Number, Doc::text(format_float(f.value))),
+            Literal::Boolean(b) => self.annotate(
+                Category::Keyword,
+                Doc::text(if b.value { "true" } else { "false" }),
+            ),
+            Literal::Null(_) => self.annotate(Category::Keyword, Doc::text("null")),
+        }
+    }
+}
+
+/// The stages of a pipeline, outermost first: the `|` operations on its left
+/// spine, along with slices and subscripts applied to the result of a stage.
+fn pipe_stages(expr: &Expr) -> Vec<&Expr> {
+    let mut stages = Vec::new();
+    let mut current = expr;
+    loop {
+        let next = match current {
+            Expr::Pipe(pipe) => &pipe.lhs,
+            Expr::FunctionPipe(fp) => &fp.lhs,
+            Expr::Slice(slice) if is_pipe(&slice.lhs) => &slice.lhs,
+            Expr::Element(elem) if is_pipe(&elem.lhs) => &elem.lhs,
+            _ => break,
+        };
+        stages.push(current);
+        current = next;
+    }
+    stages
+}
+
+/// Number of `|` operations in a pipeline.
+fn pipe_count(expr: &Expr) -> usize {
+    pipe_stages(expr)
+        .into_iter()
+        .filter(|stage| is_pipe(stage))
+        .count()
+}
+
+fn is_pipe(expr: &Expr) -> bool {
+    matches!(expr, Expr::Pipe(_) | Expr::FunctionPipe(_))
+}
+
+/// Whether an expression is a `&&` or `||` operation.
+fn is_logical(expr: &Expr) -> bool {
+    matches!(expr, Expr::Binary(bin) if bin.operator == Token::And || bin.operator == Token::Or)
 }
 
-fn format_literal(lit: &Literal) -> Doc {
-    match lit {
-        Literal::String(s) => Doc::text(format!("\"{}\"", escape_string(&s.value))),
-        Literal::Integer(i) => Doc::text(i.value.to_string()),
-        Literal::Float(f) => Doc::text(format_float(f.value)),
-        Literal::Boolean(b) => Doc::text(if b.value { "true" } else { "false" }),
-        Literal::Null(_) => Doc::text("null"),
+/// Precedence of `**`, which is right-associative.
+const EXPONENT_PRECEDENCE: u8 = 3;
+
+/// Precedence level of an arithmetic operator, or `None` for other operators.
+fn arithmetic_precedence(op: &str) -> Option<u8> {
+    match op {
+        "+" | "-" => Some(1),
+        "*" | "/" | "%" => Some(2),
+        "**" => Some(EXPONENT_PRECEDENCE),
+        _ => None,
     }
 }
 
-fn escape_string(s: &str) -> String {
-    let mut result = String::with_capacity(s.len());
-    for ch in s.chars() {
+/// Spacing around the operators whose spacing is a matter of taste.
+///
+/// `->` is never surrounded by spaces in any profile, and comparison,
+/// arithmetic and boolean operators are always surrounded by them.
+///
+/// | Profile    | Conditional | Slice       | Negation       |
+/// |------------|-------------|-------------|----------------|
+/// | `Standard` | `a => b`    | `[0..10]`   | `!defined(x)`  |
+/// | `Tight`    | `a=>b`      | `[0..10]`   | `!defined(x)`  |
+/// | `Spacious` | `a => b`    | `[0 .. 10]` | `! defined(x)` |
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize)]
+pub enum Spacing {
+    /// The default profile.
+    #[default]
+    Standard,
+    /// No optional spaces.
+    Tight,
+    /// Spaces wherever they are allowed.
+    Spacious,
+}
+
+/// The quotes string literals are written with. Quotes inside a string
+/// are escaped as needed, so `"it's"` becomes `'it\'s'` with single quotes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize)]
+pub enum QuoteStyle {
+    /// Double quotes, as in `"post"`. The default.
+    #[default]
+    Double,
+    /// Single quotes, as in `'post'`.
+    Single,
+    /// The quotes each string was written with.
+    Preserve,
+}
+
+/// How the arguments of a function call are laid out when they don't fit.
+///
+/// Calls to built-in functions have a suitable layout by default; it can be
+/// changed per function with [`FormatOptions::call_layouts`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+pub enum CallLayout {
+    /// Arguments wrap inside the parentheses as needed.
+    Wrap,
+    /// Like an object: one argument per line, with the closing parenthesis
+    /// on its own line.
+    OnePerLine,
+    /// Never broken between arguments, for short calls whose arguments
+    /// belong together.
+    Flat,
+    /// Arguments fill lines like a paragraph, breaking only before the
+    /// arguments that don't fit on the current line.
+    Fill,
+}
+
+/// A version of the formatting style.
+///
+/// Layout changes that would reformat existing queries come with a new
+/// edition, and projects pinned to an older one with
+/// [`FormatOptions::with_style_edition`] keep the layout they were
+/// formatted with. Fixes can still change the output of any edition between
+/// releases.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[non_exhaustive]
+pub enum StyleEdition {
+    /// The original style.
+    Edition2024,
+    /// Moves a conditional body that doesn't fit onto its own line under
+    /// `=>`, breaks long tuples one member per line, breaks pipelines of
+    /// three or more stages before every stage, keeps `dateTime()` and
+    /// `now()` calls whole, and fills arithmetic chains under
+    /// [`FormatOptions::force_wrap`].
+    #[default]
+    Edition2025,
+}
+
+impl StyleEdition {
+    /// The newest edition.
+    pub const LATEST: StyleEdition = StyleEdition::Edition2025;
+
+    /// Every edition, oldest first.
+    pub const ALL: &[StyleEdition] = &[StyleEdition::Edition2024, StyleEdition::Edition2025];
+
+    /// The edition's name, such as `2025`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            StyleEdition::Edition2024 => "2024",
+            StyleEdition::Edition2025 => "2025",
+        }
+    }
+}
+
+impl std::fmt::Display for StyleEdition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for StyleEdition {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        StyleEdition::ALL
+            .iter()
+            .copied()
+            .find(|edition| edition.as_str() == name)
+            .ok_or_else(|| {
+                let names: Vec<&str> = StyleEdition::ALL.iter().map(|e| e.as_str()).collect();
+                format!(
+                    "unknown style edition `{}`, expected one of: {}",
+                    name,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+/// The layout for calls to a function: the one registered in
+/// [`FormatOptions::call_layouts`] under `name` or `namespace::name`, or
+/// else the default for the style edition.
+fn call_layout(options: &FormatOptions, namespace: &str, name: &str) -> CallLayout {
+    let global = matches!(namespace, "" | "global");
+    options
+        .call_layouts
+        .iter()
+        .find(|(function, _)| match function.split_once("::") {
+            Some((ns, function)) => {
+                function == name && (ns == namespace || (ns == "global" && global))
+            }
+            None => *function == name && global,
+        })
+        .map_or_else(
+            || default_call_layout(options.style_edition, namespace, name),
+            |&(_, layout)| layout,
+        )
+}
+
+/// The default layout for calls to a function, from its entry in
+/// [`BUILTIN_FUNCTIONS`](crate::BUILTIN_FUNCTIONS). Before
+/// [`StyleEdition::Edition2025`], `dateTime()` and `now()` wrap like any
+/// other call.
+fn default_call_layout(edition: StyleEdition, namespace: &str, name: &str) -> CallLayout {
+    match (namespace, name) {
+        ("" | "global", "dateTime" | "now") if edition < StyleEdition::Edition2025 => {
+            CallLayout::Wrap
+        }
+        _ => builtin_function(namespace, name).map_or(CallLayout::Wrap, |function| function.layout),
+    }
+}
+
+/// Binary operators surrounded by spaces, so the common ones are not
+/// formatted into a new string for every node.
+const SPACED_OPERATORS: &[(&str, &str)] = &[
+    ("==", " == "),
+    ("!=", " != "),
+    ("<", " < "),
+    ("<=", " <= "),
+    (">", " > "),
+    (">=", " >= "),
+    ("+", " + "),
+    ("-", " - "),
+    ("*", " * "),
+    ("/", " / "),
+    ("%", " % "),
+    ("**", " ** "),
+    ("in", " in "),
+    ("match", " match "),
+    ("=>", " => "),
+];
+
+/// An operator followed by a space and, if `leading`, preceded by one.
+fn spaced_operator(op: &str, leading: bool) -> Cow<'static, str> {
+    match SPACED_OPERATORS.iter().find(|(name, _)| *name == op) {
+        Some((_, spaced)) if leading => Cow::Borrowed(spaced),
+        Some((_, spaced)) => Cow::Borrowed(&spaced[1..]),
+        None if leading => Cow::Owned(format!(" {} ", op)),
+        None => Cow::Owned(format!("{} ", op)),
+    }
+}
+
+/// The contents of a string literal quoted with `quote`, with that quote,
+/// backslashes and control characters escaped. Most strings need no
+/// escaping and are returned as-is.
+fn escape_string(s: &str, quote: char) -> Cow<'_, str> {
+    let needs_escape = |c: char| c == quote || c == '\\' || c.is_control();
+    let Some(first) = s.find(needs_escape) else {
+        return Cow::Borrowed(s);
+    };
+    let mut result = String::with_capacity(s.len() + 8);
+    result.push_str(&s[..first]);
+    for ch in s[first..].chars() {
         match ch {
-            '"' => result.push_str("\\\""),
+            c if c == quote => {
+                result.push('\\');
+                result.push(c);
+            }
             '\\' => result.push_str("\\\\"),
             '\n' => result.push_str("\\n"),
             '\r' => result.push_str("\\r"),
@@ -430,7 +1196,7 @@ fn escape_string(s: &str) -> String {
             c => result.push(c),
         }
     }
-    result
+    Cow::Owned(result)
 }
 
 fn format_float(value: f64) -> String {
@@ -442,6 +1208,29 @@ fn format_float(value: f64) -> String {
     }
 }
 
+/// Lay out `doc` at `width` instead of the width of its surroundings, if a
+/// width is given.
+fn within(width: Option<usize>, doc: Doc) -> Doc {
+    match width {
+        Some(width) => Doc::width(width, doc),
+        None => doc,
+    }
+}
+
+/// Whether parentheses around an expression never change its meaning.
+fn is_simple(expr: &Expr) -> bool {
+    matches!(
+        expr,
+        Expr::Attribute(_)
+            | Expr::Literal(_)
+            | Expr::Param(_)
+            | Expr::This(_)
+            | Expr::Parent(_)
+            | Expr::FunctionCall(_)
+            | Expr::Group(_)
+    )
+}
+
 /// Get the leftmost source position of an expression by walking down the left spine.
 /// Many compound nodes (Projection, Filter, Pipe, etc.) have `pos` set to the
 /// operator position rather than the start of the whole expression.
@@ -479,10 +1268,76 @@ fn is_trailing_comment(source: &str, comment: &Comment) -> bool {
 /// When `force_wrap` is true, additional break points are introduced so the
 /// formatter wraps more aggressively when content exceeds the width.
 pub fn format_parse_result(result: &ParseResult, source: &str, force_wrap: bool) -> Doc {
-    let mut fmt = Formatter::new(source, &result.comments, force_wrap);
+    let options = FormatOptions::default().with_force_wrap(force_wrap);
+    format_parse_result_with(result, source, &options).expect("no memory limit is set")
+}
+
+/// Format a full parse result, honoring all layout-related [`FormatOptions`].
+/// Fails with [`FormatError::MemoryLimit`] if the document outgrows
+/// [`FormatOptions::approximate_memory_limit`].
+pub(crate) fn format_parse_result_with(
+    result: &ParseResult,
+    source: &str,
+    options: &FormatOptions,
+) -> Result<Doc, FormatError> {
+    format_parse_result_doc(result, source, options, None, false)
+}
+
+/// Format a full parse result like [`format_parse_result_with`], marking the
+/// document of every node of the expression with its [`NodeId`](crate::NodeId)
+/// from `node_ids`.
+pub(crate) fn format_parse_result_marked(
+    result: &ParseResult,
+    source: &str,
+    options: &FormatOptions,
+    node_ids: &NodeIds,
+) -> Result<Doc, FormatError> {
+    format_parse_result_doc(result, source, options, Some(node_ids), false)
+}
+
+/// Format a full parse result like [`format_parse_result_with`], tagging
+/// the document of every token with its [`Category`].
+pub(crate) fn format_parse_result_annotated(
+    result: &ParseResult,
+    source: &str,
+    options: &FormatOptions,
+) -> Result<Doc, FormatError> {
+    format_parse_result_doc(result, source, options, None, true)
+}
+
+fn format_parse_result_doc(
+    result: &ParseResult,
+    source: &str,
+    options: &FormatOptions,
+    node_ids: Option<&NodeIds>,
+    annotate: bool,
+) -> Result<Doc, FormatError> {
+    let mut fmt = Formatter::new(source, &result.comments, options);
+    fmt.node_ids = node_ids;
+    fmt.annotate = annotate;
+    // The syntax tree takes its share of the limit, and the document may
+    // allocate the rest.
+    let ast = ast_memory(source);
+    if let Some(limit) = options.approximate_memory_limit {
+        fmt.budget = Some((Meter::start(), limit.saturating_sub(ast)));
+    }
 
     let mut parts: Vec<Doc> = Vec::new();
 
+    // A metadata header stays at the top, separated from the query by a
+    // blank line if it was in the source.
+    if let Some(end) = metadata::header_end(source) {
+        for (_, comment) in fmt.take_comments_before(end) {
+            parts.push(fmt.comment(comment.text.clone()));
+            parts.push(Doc::hard_line());
+        }
+        let rest = &source[end..];
+        let space = &rest[..rest.len() - rest.trim_start().len()];
+        if space.matches('\n').count() > 1 {
+            parts.push(Doc::hard_line());
+        }
+    }
+
     // Format function definitions, interleaving comments
     for (i, func) in result.functions.iter().enumerate() {
         if i > 0 {
@@ -491,7 +1346,7 @@ pub fn format_parse_result(result: &ParseResult, source: &str, force_wrap: bool)
         let func_start = func.pos.start;
         let comments = fmt.take_comments_before(func_start);
         for (_, comment) in &comments {
-            parts.push(Doc::text(&comment.text));
+            parts.push(fmt.comment(comment.text.clone()));
             parts.push(Doc::hard_line());
         }
         parts.push(fmt.format_function_definition(func));
@@ -505,7 +1360,7 @@ pub fn format_parse_result(result: &ParseResult, source: &str, force_wrap: bool)
     let expr_start = leftmost_pos(&result.expr);
     let comments = fmt.take_comments_before(expr_start);
     for (_, comment) in &comments {
-        parts.push(Doc::text(&comment.text));
+        parts.push(fmt.comment(comment.text.clone()));
         parts.push(Doc::hard_line());
     }
 
@@ -515,19 +1370,64 @@ pub fn format_parse_result(result: &ParseResult, source: &str, force_wrap: bool)
     let remaining = fmt.take_remaining_comments();
     for (trailing, comment) in &remaining {
         if *trailing {
-            parts.push(Doc::text(format!(" {}", comment.text)));
+            parts.push(fmt.comment(format!(" {}", comment.text)));
         } else {
             parts.push(Doc::hard_line());
-            parts.push(Doc::text(&comment.text));
+            parts.push(fmt.comment(comment.text.clone()));
         }
     }
 
-    Doc::concat(parts)
+    let doc = indent_with_tabs(options, Doc::concat(parts));
+    if let (Some(limit), Some(allocated)) = (options.approximate_memory_limit, fmt.over_budget()) {
+        doc.discard();
+        return Err(FormatError::MemoryLimit {
+            limit,
+            needed: ast.saturating_add(allocated),
+        });
+    }
+    Ok(doc)
 }
 
 /// Format a GROQ expression as a document (without comment handling).
 /// This is the public API for formatting a standalone expression.
 pub fn format_expr(expr: &Expr) -> Doc {
-    let mut fmt = Formatter::new("", &[], false);
-    fmt.format_expr(expr)
+    format_expr_with(expr, &FormatOptions::default())
+}
+
+/// Format a standalone expression, honoring layout-related [`FormatOptions`].
+pub(crate) fn format_expr_with(expr: &Expr, options: &FormatOptions) -> Doc {
+    let mut fmt = Formatter::new("", &[], options);
+    indent_with_tabs(options, fmt.format_expr(expr))
+}
+
+/// Format the node a path led to on its own, as it would be formatted in
+/// place, honoring layout-related [`FormatOptions`].
+pub(crate) fn format_resolved(resolved: &Resolved, options: &FormatOptions) -> Doc {
+    let mut fmt = Formatter::new("", &[], options);
+    fmt.object_depth = resolved.object_depth;
+    let doc = match resolved.node {
+        Node::Expr(expr) => fmt.format_expr(expr),
+        Node::Object(obj) => fmt.format_object(obj),
+        Node::Call(call) => fmt.format_function_call(call),
+        Node::Field(field) => fmt.format_object_field(field),
+    };
+    indent_with_tabs(options, doc)
+}
+
+/// Format a standalone expression like [`format_expr_with`], marking the
+/// document of every node with its [`NodeId`](crate::NodeId).
+pub(crate) fn format_expr_marked(expr: &Expr, options: &FormatOptions) -> Doc {
+    let node_ids = NodeIds::new(expr);
+    let mut fmt = Formatter::new("", &[], options);
+    fmt.node_ids = Some(&node_ids);
+    indent_with_tabs(options, fmt.format_expr(expr))
+}
+
+/// Write the indentation of a whole document with tabs if
+/// [`FormatOptions::use_tabs`] is set.
+fn indent_with_tabs(options: &FormatOptions, doc: Doc) -> Doc {
+    match options.use_tabs {
+        true => Doc::tabs(options.indent, doc),
+        false => doc,
+    }
 }
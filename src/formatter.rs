@@ -0,0 +1,87 @@
+//! A reusable formatter for hot paths.
+
+use crate::doc::Renderer;
+use crate::{FormatError, FormatOptions, query_to_doc, render_doc};
+
+/// A formatter configured once and reused for many queries.
+///
+/// Servers and editor integrations that format on every request can keep one
+/// `Formatter` around: the options are set up once and the rendering buffers
+/// are retained between calls instead of being reallocated.
+///
+/// `Formatter` is `Send + Sync`, but [`format`](Formatter::format) takes
+/// `&mut self`; concurrent servers should keep one formatter per thread or
+/// worker. The options themselves are cheap to clone, as the call layouts
+/// are shared.
+///
+/// # Example
+///
+/// ```
+/// use groq_format::{FormatOptions, Formatter};
+///
+/// let mut formatter = Formatter::new(FormatOptions::new(80));
+/// assert_eq!(formatter.format("*[a==1]").unwrap(), "*[a == 1]");
+/// assert_eq!(formatter.format("*[b==2]").unwrap(), "*[b == 2]");
+/// ```
+#[derive(Default)]
+pub struct Formatter {
+    options: FormatOptions,
+    renderer: Renderer,
+    output: String,
+}
+
+impl Formatter {
+    /// Create a formatter with the given options.
+    pub fn new(options: FormatOptions) -> Self {
+        Formatter {
+            options,
+            renderer: Renderer::default(),
+            output: String::new(),
+        }
+    }
+
+    /// The options this formatter was created with.
+    pub fn options(&self) -> &FormatOptions {
+        &self.options
+    }
+
+    /// Format a query. The returned string borrows the formatter's output
+    /// buffer and is valid until the next call.
+    pub fn format(&mut self, query: &str) -> Result<&str, FormatError> {
+        let doc = query_to_doc(query, &self.options)?;
+        let rendered = render_doc(&doc, &self.options, &mut self.renderer, &mut self.output);
+        doc.discard();
+        rendered?;
+        Ok(&self.output)
+    }
+}
+
+/// Format a batch of queries with the same options, yielding one result per
+/// query in order.
+///
+/// Each query is formatted as by
+/// [`format_query_with_options`](crate::format_query_with_options) into a
+/// string of its own, lazily as the iterator is consumed. To reuse the output
+/// buffer as well, format with a [`Formatter`] and use each result before
+/// formatting the next query.
+///
+/// # Example
+///
+/// ```
+/// use groq_format::{format_many, FormatOptions};
+///
+/// let options = FormatOptions::default();
+/// let results: Vec<_> = format_many(["*[a==1]", "*[", "*[b==2]"], &options).collect();
+/// assert_eq!(results[0].as_ref().unwrap(), "*[a == 1]");
+/// assert!(results[1].is_err());
+/// assert_eq!(results[2].as_ref().unwrap(), "*[b == 2]");
+/// ```
+pub fn format_many<'q>(
+    queries: impl IntoIterator<Item = &'q str>,
+    options: &FormatOptions,
+) -> impl Iterator<Item = Result<String, FormatError>> {
+    let mut formatter = Formatter::new(options.clone());
+    queries
+        .into_iter()
+        .map(move |query| formatter.format(query).map(str::to_string))
+}
@@ -0,0 +1,119 @@
+//! Access to the files staged in a git repository, for `--staged`.
+//!
+//! Pre-commit hooks must format what is about to be committed, which is the
+//! staged version of each file rather than the working tree. Files are read
+//! from and written back to the index directly, so changes that are not
+//! staged stay out of the commit.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// A file whose staged version differs from that in `HEAD`.
+pub struct StagedFile {
+    /// Path relative to the root of the repository.
+    pub path: String,
+    /// File mode in the index, such as `100644`.
+    mode: String,
+    /// Object ID of the staged contents.
+    object: String,
+}
+
+/// The root of the repository the current directory is in.
+pub fn toplevel() -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let root = git(Path::new("."), &["rev-parse", "--show-toplevel"], None)?;
+    Ok(PathBuf::from(String::from_utf8(root)?.trim_end()))
+}
+
+/// The regular files added, copied or modified in the index of the
+/// repository at `root`, whose names end in one of `extensions`.
+pub fn staged_files(
+    root: &Path,
+    extensions: &[&str],
+) -> Result<Vec<StagedFile>, Box<dyn std::error::Error>> {
+    let raw = git(
+        root,
+        &[
+            "diff",
+            "--cached",
+            "--raw",
+            "-z",
+            "--no-renames",
+            "--diff-filter=ACM",
+        ],
+        None,
+    )?;
+    let raw = String::from_utf8(raw)?;
+
+    // Records are `:<old mode> <new mode> <old id> <new id> <status>` and
+    // the path, each ended by a NUL.
+    let mut fields = raw.split('\0');
+    let mut files = Vec::new();
+    while let (Some(info), Some(path)) = (fields.next(), fields.next()) {
+        let info: Vec<&str> = info.split(' ').collect();
+        let (Some(mode), Some(object)) = (info.get(1), info.get(3)) else {
+            continue;
+        };
+        // Symlinks and submodules have other modes.
+        let regular = *mode == "100644" || *mode == "100755";
+        let matches = Path::new(path)
+            .extension()
+            .is_some_and(|ext| extensions.iter().any(|e| ext == *e));
+        if regular && matches {
+            files.push(StagedFile {
+                path: path.to_string(),
+                mode: mode.to_string(),
+                object: object.to_string(),
+            });
+        }
+    }
+    Ok(files)
+}
+
+impl StagedFile {
+    /// The staged contents of the file, as stored.
+    pub fn contents(&self, root: &Path) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        git(root, &["cat-file", "blob", &self.object], None)
+    }
+
+    /// Replace the staged contents of the file, leaving the working tree
+    /// alone.
+    pub fn stage(&self, root: &Path, contents: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        let object = git(root, &["hash-object", "-w", "--stdin"], Some(contents))?;
+        let object = String::from_utf8(object)?;
+        let cacheinfo = format!("{},{},{}", self.mode, object.trim_end(), self.path);
+        git(root, &["update-index", "--cacheinfo", &cacheinfo], None)?;
+        Ok(())
+    }
+}
+
+/// Run git in `dir` and return its output, failing with its error message if
+/// it exits unsuccessfully.
+fn git(
+    dir: &Path,
+    args: &[&str],
+    input: Option<&[u8]>,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut child = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("could not run git: {}", e))?;
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        stdin.write_all(input)?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        let message = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("git {}: {}", args[0], message.trim()).into());
+    }
+    Ok(output.stdout)
+}
@@ -0,0 +1,215 @@
+//! Extraction of repeated projections into function definitions.
+
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+
+use groq_parser::ast::{Expr, Projection};
+
+use crate::lexer::{self, tokenize};
+use crate::path::{Node, children, node_range};
+use crate::{FormatError, parse_query, trim_for_addressing};
+
+/// Namespace of the functions that projections are hoisted into.
+const NAMESPACE: &str = "local";
+
+/// Objects shorter than this, ignoring whitespace, are not worth replacing
+/// with a function call.
+const MIN_OBJECT_LEN: usize = 20;
+
+/// Rewrite a query so that projection objects it repeats verbatim, such as
+/// the same `{ asset->{ url }, alt }` for every image, are written once in a
+/// function definition, which each place they were used calls instead:
+///
+/// ```groq
+/// fn local::projection($value) = $value{ asset->{ url }, alt };
+/// *[_type == "post"] { "cover": local::projection(cover), "thumb": local::projection(thumb) }
+/// ```
+///
+/// Functions are named after the attribute that all their uses project, if
+/// there is one, and `projection` otherwise.
+///
+/// Objects are the same if they have the same tokens, ignoring whitespace
+/// and comments. Short objects, and objects that refer to parameters or to
+/// the enclosing scope with `^`, which would mean something else inside a
+/// function, are left alone. Returns the query unchanged if nothing is
+/// repeated.
+///
+/// # Example
+///
+/// ```
+/// use groq_format::hoist_projections;
+///
+/// let query = r#"{"a": a->{name, "url": slug.current}, "b": b->{name, "url": slug.current}}"#;
+/// assert_eq!(
+///     hoist_projections(query).unwrap(),
+///     r#"fn local::projection($value) = $value{name, "url": slug.current};
+/// {"a": local::projection(a->), "b": local::projection(b->)}"#
+/// );
+/// ```
+pub fn hoist_projections(query: &str) -> Result<String, FormatError> {
+    let (leading, source) = trim_for_addressing(query)?;
+    let result = parse_query(source)?;
+    let tokens = tokenize(source);
+
+    let mut projections = Vec::new();
+    collect_projections(Node::Expr(&result.expr), &mut projections);
+
+    // Group the projections by their object, in order of first use.
+    let mut groups: Vec<(String, Vec<&Projection>)> = Vec::new();
+    let mut group_of: HashMap<String, usize> = HashMap::new();
+    for proj in projections {
+        let range = node_range(Node::Object(&proj.object), source, &tokens);
+        let key = significant_text(&range, source, &tokens);
+        if key.len() < MIN_OBJECT_LEN || !is_hoistable(Node::Object(&proj.object)) {
+            continue;
+        }
+        match group_of.get(&key) {
+            Some(&i) => groups[i].1.push(proj),
+            None => {
+                group_of.insert(key.clone(), groups.len());
+                groups.push((key, vec![proj]));
+            }
+        }
+    }
+
+    // Larger objects first, so a repeated object inside one that is hoisted
+    // is only hoisted if it is also repeated elsewhere.
+    let mut order: Vec<usize> = (0..groups.len()).collect();
+    order.sort_by_key(|&i| std::cmp::Reverse(groups[i].0.len()));
+    let mut taken: Vec<Range<usize>> = Vec::new();
+    let mut names: HashSet<String> = result
+        .functions
+        .iter()
+        .filter(|func| func.id.namespace == NAMESPACE)
+        .map(|func| func.id.name.clone())
+        .collect();
+    let mut hoisted = Vec::new();
+    for i in order {
+        let sites: Vec<(Range<usize>, &Projection)> = groups[i]
+            .1
+            .iter()
+            .map(|&proj| {
+                let range = node_range(Node::Expr(&proj.lhs), source, &tokens);
+                let object = node_range(Node::Object(&proj.object), source, &tokens);
+                (range.start..object.end, proj)
+            })
+            .filter(|(range, _)| {
+                !taken
+                    .iter()
+                    .any(|t| t.start <= range.start && range.end <= t.end)
+            })
+            .collect();
+        if sites.len() < 2 {
+            continue;
+        }
+
+        let param = common_attribute(sites.iter().map(|(_, proj)| &*proj.lhs));
+        let name = unique_name(param.unwrap_or("projection"), &mut names);
+        let param = param.unwrap_or("value");
+        let object = node_range(Node::Object(&sites[0].1.object), source, &tokens);
+        let definition = format!(
+            "fn {}::{}(${}) = ${}{};",
+            NAMESPACE, name, param, param, &source[object]
+        );
+        for (range, proj) in &sites {
+            let lhs = node_range(Node::Expr(&proj.lhs), source, &tokens);
+            let call = format!("{}::{}({})", NAMESPACE, name, &source[lhs]);
+            hoisted.push((range.clone(), call));
+            taken.push(range.clone());
+        }
+        hoisted.push((0..0, definition));
+    }
+    if hoisted.is_empty() {
+        return Ok(query.to_string());
+    }
+
+    // Definitions go first, then the query with each site replaced.
+    let (definitions, mut calls): (Vec<_>, Vec<_>) =
+        hoisted.into_iter().partition(|(range, _)| range.is_empty());
+    calls.sort_by_key(|(range, _)| range.start);
+    let mut output = String::with_capacity(query.len());
+    output.push_str(&query[..leading]);
+    for (_, definition) in &definitions {
+        output.push_str(definition);
+        output.push('\n');
+    }
+    let mut end = 0;
+    for (range, call) in calls {
+        output.push_str(&source[end..range.start]);
+        output.push_str(&call);
+        end = range.end;
+    }
+    output.push_str(&source[end..]);
+    output.push_str(&query[leading + source.len()..]);
+    Ok(output)
+}
+
+/// Collect the projections in `node` and its descendants, in source order.
+fn collect_projections<'a>(node: Node<'a>, projections: &mut Vec<&'a Projection>) {
+    if let Node::Expr(Expr::Projection(proj)) | Node::Field(Expr::Projection(proj)) = node {
+        projections.push(proj);
+    }
+    for (_, child) in children(node) {
+        collect_projections(child, projections);
+    }
+}
+
+/// Whether a node means the same inside a function as where it is: it
+/// doesn't refer to parameters or to enclosing scopes.
+fn is_hoistable(node: Node<'_>) -> bool {
+    if let Node::Expr(expr) | Node::Field(expr) = node
+        && matches!(expr, Expr::Param(_) | Expr::Parent(_))
+    {
+        return false;
+    }
+    children(node)
+        .into_iter()
+        .all(|(_, child)| is_hoistable(child))
+}
+
+/// The tokens in `range` without whitespace and comments, separated by
+/// spaces.
+fn significant_text(range: &Range<usize>, source: &str, tokens: &[lexer::Token]) -> String {
+    let tokens = tokens
+        .iter()
+        .filter(|token| range.start <= token.start && token.end <= range.end)
+        .filter(|token| !token.is_trivia());
+    tokens
+        .map(|token| token.text(source))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The attribute every projection applies to, such as `image` for
+/// `image{...}` and `image->{...}`, if they all apply to the same one.
+fn common_attribute<'a>(lhs: impl Iterator<Item = &'a Expr>) -> Option<&'a str> {
+    let mut common = None;
+    for expr in lhs {
+        let name = match expr {
+            Expr::Attribute(attr) => attr.name.as_str(),
+            Expr::Postfix(postfix) => match &*postfix.lhs {
+                Expr::Attribute(attr) => attr.name.as_str(),
+                _ => return None,
+            },
+            _ => return None,
+        };
+        if common.is_some_and(|common| common != name) {
+            return None;
+        }
+        common = Some(name);
+    }
+    common
+}
+
+/// `base`, or `base` followed by a number, whichever isn't in `names` yet.
+fn unique_name(base: &str, names: &mut HashSet<String>) -> String {
+    let name = (1..)
+        .map(|n| match n {
+            1 => base.to_string(),
+            n => format!("{}{}", base, n),
+        })
+        .find(|name| !names.contains(name))
+        .unwrap_or_default();
+    names.insert(name.clone());
+    name
+}
@@ -0,0 +1,359 @@
+//! Source-level cleanup applied to a query before it is parsed.
+
+use serde::Serialize;
+
+use crate::lexer::{Token, TokenKind, tokenize};
+
+/// A transport encoding a query may have picked up on its way to the
+/// formatter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+pub enum InputEncoding {
+    /// Percent-encoded, as in a URL query string (`*%5B_type%3D%3D...`).
+    Url,
+    /// Backslash-escaped, as when copied out of a JSON or JavaScript string
+    /// literal (`*[_type == \"post\"]`).
+    Escaped,
+    /// A whole string literal, quotes included, as when copied out of JSON
+    /// or JavaScript code (`"*[_type == \"post\"]"`). Double quotes, single
+    /// quotes and backticks are recognized.
+    Quoted,
+}
+
+impl std::fmt::Display for InputEncoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InputEncoding::Url => write!(f, "URL-encoded"),
+            InputEncoding::Escaped => write!(f, "string-escaped"),
+            InputEncoding::Quoted => write!(f, "quoted"),
+        }
+    }
+}
+
+/// Guess whether a query is URL-encoded or string-escaped.
+///
+/// # Example
+///
+/// ```
+/// use groq_format::{detect_encoding, InputEncoding};
+///
+/// assert_eq!(detect_encoding("*%5B_type%3D%3D%22post%22%5D"), Some(InputEncoding::Url));
+/// assert_eq!(detect_encoding(r#"*[_type == \"post\"]"#), Some(InputEncoding::Escaped));
+/// assert_eq!(detect_encoding(r#""*[_type == \"post\"]""#), Some(InputEncoding::Quoted));
+/// assert_eq!(detect_encoding(r#"*[_type == "post"]"#), None);
+/// ```
+pub fn detect_encoding(source: &str) -> Option<InputEncoding> {
+    // A lone string literal is a valid query, but one holding a query was
+    // almost certainly copied out of code.
+    if let Some(contents) = quoted_contents(source.trim())
+        && looks_like_query(&unescape(contents))
+    {
+        return Some(InputEncoding::Quoted);
+    }
+
+    let tokens = tokenize(source);
+    // A backslash can only appear inside string literals; the lexer reports
+    // one outside a string as an unknown token.
+    let stray_backslash = tokens
+        .iter()
+        .any(|t| t.kind == TokenKind::Unknown && t.text(source) == "\\");
+    if stray_backslash {
+        return Some(InputEncoding::Escaped);
+    }
+
+    let bytes = source.as_bytes();
+    let percent_escapes = bytes
+        .windows(3)
+        .filter(|w| w[0] == b'%' && w[1].is_ascii_hexdigit() && w[2].is_ascii_hexdigit())
+        .count();
+    if percent_escapes >= 2 {
+        return Some(InputEncoding::Url);
+    }
+
+    None
+}
+
+/// Undo the given encoding.
+pub(crate) fn decode(source: &str, encoding: InputEncoding) -> String {
+    match encoding {
+        InputEncoding::Url => url_decode(source),
+        InputEncoding::Escaped => unescape(source),
+        InputEncoding::Quoted => match quoted_contents(source.trim()) {
+            Some(contents) => unescape(contents),
+            None => unescape(source),
+        },
+    }
+}
+
+/// The text between the quotes of a string literal spanning all of
+/// `source`, or `None` if `source` is not a single string literal.
+fn quoted_contents(source: &str) -> Option<&str> {
+    let quote = source
+        .chars()
+        .next()
+        .filter(|c| matches!(c, '"' | '\'' | '`'))?;
+    let contents = source.get(1..)?.strip_suffix(quote)?;
+    // The closing quote must be the only unescaped quote.
+    let mut escaped = false;
+    for c in contents.chars() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            c if c == quote => return None,
+            _ => {}
+        }
+    }
+    (!escaped).then_some(contents)
+}
+
+/// Whether text reads as a query rather than prose: several tokens,
+/// including brackets.
+fn looks_like_query(text: &str) -> bool {
+    let tokens = tokenize(text);
+    let significant = tokens
+        .iter()
+        .filter(|t| !matches!(t.kind, TokenKind::Whitespace | TokenKind::Comment))
+        .count();
+    significant > 1 && text.contains(['[', '{', '('])
+}
+
+pub(crate) fn url_decode(source: &str) -> String {
+    let bytes = source.as_bytes();
+    let mut output = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if is_hex_pair(&bytes[i + 1..]) => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap();
+                output.push(u8::from_str_radix(hex, 16).unwrap());
+                i += 3;
+            }
+            b'+' => {
+                output.push(b' ');
+                i += 1;
+            }
+            b => {
+                output.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&output).into_owned()
+}
+
+fn is_hex_pair(bytes: &[u8]) -> bool {
+    bytes.len() >= 2 && bytes[0].is_ascii_hexdigit() && bytes[1].is_ascii_hexdigit()
+}
+
+fn unescape(source: &str) -> String {
+    let mut output = String::with_capacity(source.len());
+    let mut chars = source.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            output.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => output.push('\n'),
+            Some('t') => output.push('\t'),
+            Some('r') => output.push('\r'),
+            Some('u') => {
+                let hex: String = chars.clone().take(4).collect();
+                match u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32) {
+                    Some(decoded) if hex.len() == 4 => {
+                        output.push(decoded);
+                        chars.nth(3);
+                    }
+                    _ => output.push_str("\\u"),
+                }
+            }
+            Some(other) => output.push(other),
+            None => output.push('\\'),
+        }
+    }
+    output
+}
+
+/// An invisible or non-standard whitespace character found outside a string
+/// literal, typically left behind by copying a query from a web page.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvisibleChar {
+    /// Byte offset in the query.
+    pub offset: usize,
+    /// Line (1-based).
+    pub line: usize,
+    /// Column (1-based, in characters).
+    pub column: usize,
+    /// The character itself.
+    pub ch: char,
+}
+
+impl InvisibleChar {
+    /// The Unicode name of the character, e.g. `ZERO WIDTH SPACE`.
+    pub fn name(&self) -> &'static str {
+        invisible_char_name(self.ch).unwrap_or("INVISIBLE CHARACTER")
+    }
+}
+
+impl std::fmt::Display for InvisibleChar {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "U+{:04X} {} at {}:{}",
+            self.ch as u32,
+            self.name(),
+            self.line,
+            self.column
+        )
+    }
+}
+
+/// Characters that are invisible or look like a plain space, with their
+/// names. Zero-width characters are removed when stripping; the others are
+/// replaced by a space.
+const INVISIBLE_CHARS: &[(char, &str)] = &[
+    ('\u{00A0}', "NO-BREAK SPACE"),
+    ('\u{00AD}', "SOFT HYPHEN"),
+    ('\u{2000}', "EN QUAD"),
+    ('\u{2001}', "EM QUAD"),
+    ('\u{2002}', "EN SPACE"),
+    ('\u{2003}', "EM SPACE"),
+    ('\u{2004}', "THREE-PER-EM SPACE"),
+    ('\u{2005}', "FOUR-PER-EM SPACE"),
+    ('\u{2006}', "SIX-PER-EM SPACE"),
+    ('\u{2007}', "FIGURE SPACE"),
+    ('\u{2008}', "PUNCTUATION SPACE"),
+    ('\u{2009}', "THIN SPACE"),
+    ('\u{200A}', "HAIR SPACE"),
+    ('\u{200B}', "ZERO WIDTH SPACE"),
+    ('\u{200C}', "ZERO WIDTH NON-JOINER"),
+    ('\u{200D}', "ZERO WIDTH JOINER"),
+    ('\u{2028}', "LINE SEPARATOR"),
+    ('\u{2029}', "PARAGRAPH SEPARATOR"),
+    ('\u{202F}', "NARROW NO-BREAK SPACE"),
+    ('\u{205F}', "MEDIUM MATHEMATICAL SPACE"),
+    ('\u{2060}', "WORD JOINER"),
+    ('\u{3000}', "IDEOGRAPHIC SPACE"),
+    ('\u{FEFF}', "ZERO WIDTH NO-BREAK SPACE"),
+];
+
+fn invisible_char_name(ch: char) -> Option<&'static str> {
+    INVISIBLE_CHARS
+        .iter()
+        .find(|(c, _)| *c == ch)
+        .map(|(_, name)| *name)
+}
+
+fn is_zero_width(ch: char) -> bool {
+    matches!(
+        ch,
+        '\u{00AD}' | '\u{200B}' | '\u{200C}' | '\u{200D}' | '\u{2060}' | '\u{FEFF}'
+    )
+}
+
+/// Find invisible and non-standard whitespace characters outside string
+/// literals and comments.
+///
+/// # Example
+///
+/// ```
+/// use groq_format::find_invisible_chars;
+///
+/// let found = find_invisible_chars("*[_type\u{200B} == \"post\"]");
+/// assert_eq!(found[0].to_string(), "U+200B ZERO WIDTH SPACE at 1:8");
+/// ```
+pub fn find_invisible_chars(source: &str) -> Vec<InvisibleChar> {
+    let mut found = Vec::new();
+    let mut line = 1;
+    let mut line_start = 0;
+
+    for token in tokenize(source) {
+        let skip = matches!(token.kind, TokenKind::String | TokenKind::Comment);
+        for (i, ch) in token.text(source).char_indices() {
+            let offset = token.start + i;
+            if ch == '\n' {
+                line += 1;
+                line_start = offset + 1;
+            } else if !skip && invisible_char_name(ch).is_some() {
+                found.push(InvisibleChar {
+                    offset,
+                    line,
+                    column: source[line_start..offset].chars().count() + 1,
+                    ch,
+                });
+            }
+        }
+    }
+
+    found
+}
+
+/// Remove zero-width characters and turn other invisible spaces into plain
+/// spaces, outside string literals and comments.
+pub(crate) fn strip_invisible_chars(source: &str) -> String {
+    let mut output = String::with_capacity(source.len());
+    for token in tokenize(source) {
+        let text = token.text(source);
+        if matches!(token.kind, TokenKind::String | TokenKind::Comment) {
+            output.push_str(text);
+            continue;
+        }
+        for ch in text.chars() {
+            if invisible_char_name(ch).is_none() {
+                output.push(ch);
+            } else if !is_zero_width(ch) {
+                output.push(' ');
+            }
+        }
+    }
+    output
+}
+
+/// Remove commas that directly precede a closing `}`, `]` or `)` (ignoring
+/// whitespace and comments in between), as commonly found in queries copied
+/// from JavaScript. Returns the cleaned query and the byte offsets of the
+/// removed commas in the original input.
+pub(crate) fn remove_trailing_commas(source: &str) -> (String, Vec<usize>) {
+    let tokens = tokenize(source);
+    let mut output = String::with_capacity(source.len());
+    let mut removed = Vec::new();
+
+    for (i, token) in tokens.iter().enumerate() {
+        if is_punctuation(token, source, ",")
+            && next_significant(&tokens[i + 1..])
+                .is_some_and(|next| matches!(next.text(source), "}" | "]" | ")"))
+        {
+            removed.push(token.start);
+            continue;
+        }
+        output.push_str(token.text(source));
+    }
+
+    (output, removed)
+}
+
+fn is_punctuation(token: &Token, source: &str, text: &str) -> bool {
+    token.kind == TokenKind::Punctuation && token.text(source) == text
+}
+
+fn next_significant(tokens: &[Token]) -> Option<&Token> {
+    tokens.iter().find(|t| !t.is_trivia())
+}
+
+/// Whether a source file that failed to parse looks like JavaScript or
+/// TypeScript rather than GROQ.
+pub(crate) fn looks_like_javascript(source: &str) -> bool {
+    const LINE_PREFIXES: &[&str] = &[
+        "import ",
+        "export const ",
+        "export default ",
+        "export function ",
+        "const ",
+        "let ",
+    ];
+    source.contains("groq`")
+        || source.contains("defineQuery(")
+        || source.lines().any(|line| {
+            let line = line.trim_start();
+            LINE_PREFIXES.iter().any(|prefix| line.starts_with(prefix))
+        })
+}
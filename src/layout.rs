@@ -0,0 +1,79 @@
+//! Measurements of formatted output, for reporting on layout quality.
+
+use std::collections::HashMap;
+use std::ops::Range;
+
+use groq_parser::ast::Expr;
+
+use crate::NodeId;
+use crate::path::NodeIds;
+
+/// A part of the top-level expression of a query, as in
+/// `*[_type == "post"] { title } | order(title)[0...10]`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Construct {
+    /// The expression the query starts from, such as `*`, an object, or a
+    /// function call.
+    Base,
+    /// A filter, `[_type == "post"]`.
+    Filter,
+    /// A projection, `{ title }`.
+    Projection,
+    /// A slice or element access, `[0...10]` or `[0]`.
+    Slice,
+    /// A pipe stage, `| order(title)`.
+    Pipe,
+}
+
+/// Facts about the layout of a formatted query.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LayoutMetadata {
+    /// Number of lines in the output.
+    pub line_count: usize,
+    /// Width in characters of the longest output line.
+    pub max_line_width: usize,
+    /// The top-level constructs that were broken over several lines, in
+    /// output order. A construct only counts as broken if a line break falls
+    /// in its own text, not in the constructs before it.
+    pub broken: Vec<Construct>,
+    /// Whether any output line is longer than the width.
+    pub overflowed: bool,
+}
+
+/// The top-level constructs of `expr` that contain a line break in `output`,
+/// given the ids of its nodes and the range each was formatted to.
+pub(crate) fn broken_constructs(
+    expr: &Expr,
+    output: &str,
+    spans: &HashMap<NodeId, Range<usize>>,
+    ids: &NodeIds,
+) -> Vec<Construct> {
+    let span = |expr: &Expr| ids.get(expr).and_then(|id| spans.get(&id));
+    let mut broken = Vec::new();
+    let mut expr = expr;
+    loop {
+        let (construct, lhs) = match expr {
+            Expr::Filter(filter) => (Construct::Filter, &*filter.lhs),
+            Expr::Projection(proj) => (Construct::Projection, &*proj.lhs),
+            Expr::Slice(slice) => (Construct::Slice, &*slice.lhs),
+            Expr::Element(elem) => (Construct::Slice, &*elem.lhs),
+            Expr::Pipe(pipe) => (Construct::Pipe, &*pipe.lhs),
+            Expr::FunctionPipe(fp) => (Construct::Pipe, &*fp.lhs),
+            _ => {
+                if span(expr).is_some_and(|range| output[range.clone()].contains('\n')) {
+                    broken.push(Construct::Base);
+                }
+                break;
+            }
+        };
+        // The construct's own text follows that of the expression it applies to.
+        if let (Some(range), Some(lhs_range)) = (span(expr), span(lhs))
+            && output[lhs_range.end..range.end].contains('\n')
+        {
+            broken.push(construct);
+        }
+        expr = lhs;
+    }
+    broken.reverse();
+    broken
+}
@@ -0,0 +1,230 @@
+//! A lossless GROQ tokenizer.
+//!
+//! Unlike the parser, this lexer never fails: every byte of the input belongs
+//! to exactly one token, including whitespace and comments, and anything it
+//! does not recognize becomes an `Unknown` token. That makes it suitable for
+//! source-level fixups before parsing, and for tools such as syntax
+//! highlighters that need to handle incomplete queries.
+
+use std::ops::Range;
+
+use serde::Serialize;
+
+/// The kind of a lexical token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenKind {
+    /// Spaces, tabs and newlines.
+    Whitespace,
+    /// A `//` comment, up to (not including) the end of the line.
+    Comment,
+    /// A bare name such as `_type` or `count`.
+    Identifier,
+    /// A reserved word: `true`, `false`, `null`, `in`, `match`, `asc`, `desc`, `fn`.
+    Keyword,
+    /// A parameter reference such as `$slug`.
+    Parameter,
+    /// A single- or double-quoted string, including its quotes.
+    String,
+    /// An integer or floating point number.
+    Number,
+    /// An operator such as `==`, `&&`, `->`, `|` or `*`.
+    Operator,
+    /// Brackets, braces, parentheses, `,`, `:`, `;`, `.` and `::`.
+    Punctuation,
+    /// A character that cannot start any GROQ token.
+    Unknown,
+}
+
+/// A token and its byte range in the source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Token {
+    /// What kind of token this is.
+    pub kind: TokenKind,
+    /// Byte offset of the first character.
+    pub start: usize,
+    /// Byte offset just past the last character.
+    pub end: usize,
+}
+
+impl Token {
+    /// The source text of this token.
+    pub fn text<'a>(&self, source: &'a str) -> &'a str {
+        &source[self.start..self.end]
+    }
+
+    /// The byte range of this token in the source.
+    pub fn span(&self) -> Range<usize> {
+        self.start..self.end
+    }
+
+    /// Whether this token carries no syntax (whitespace or a comment).
+    pub fn is_trivia(&self) -> bool {
+        matches!(self.kind, TokenKind::Whitespace | TokenKind::Comment)
+    }
+}
+
+const KEYWORDS: &[&str] = &["true", "false", "null", "in", "match", "asc", "desc", "fn"];
+
+/// Multi-character operators and punctuation, longest first.
+const SYMBOLS: &[(&str, TokenKind)] = &[
+    ("...", TokenKind::Operator),
+    ("**", TokenKind::Operator),
+    ("..", TokenKind::Operator),
+    ("->", TokenKind::Operator),
+    ("=>", TokenKind::Operator),
+    ("==", TokenKind::Operator),
+    ("!=", TokenKind::Operator),
+    ("<=", TokenKind::Operator),
+    (">=", TokenKind::Operator),
+    ("&&", TokenKind::Operator),
+    ("||", TokenKind::Operator),
+    ("::", TokenKind::Punctuation),
+];
+
+/// Split a query into tokens covering the whole input.
+///
+/// Tokens are returned in source order, and their spans cover every byte
+/// exactly once, so concatenating their text gives back the input. The
+/// query does not need to parse.
+///
+/// # Example
+///
+/// ```
+/// use groq_format::{TokenKind, tokenize};
+///
+/// let query = "*[_type == $type";
+/// let tokens: Vec<_> = tokenize(query)
+///     .into_iter()
+///     .filter(|token| !token.is_trivia())
+///     .map(|token| (token.kind, token.text(query)))
+///     .collect();
+/// assert_eq!(
+///     tokens,
+///     vec![
+///         (TokenKind::Operator, "*"),
+///         (TokenKind::Punctuation, "["),
+///         (TokenKind::Identifier, "_type"),
+///         (TokenKind::Operator, "=="),
+///         (TokenKind::Parameter, "$type"),
+///     ]
+/// );
+/// ```
+pub fn tokenize(source: &str) -> Vec<Token> {
+    Tokens::new(source).collect()
+}
+
+/// The tokens of a query, lexed one at a time, for callers that only need
+/// to look at them once. See [`tokenize`].
+pub(crate) struct Tokens<'a> {
+    source: &'a str,
+    pos: usize,
+}
+
+impl<'a> Tokens<'a> {
+    pub(crate) fn new(source: &'a str) -> Self {
+        Tokens { source, pos: 0 }
+    }
+}
+
+impl Iterator for Tokens<'_> {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Token> {
+        let source = self.source;
+        let start = self.pos;
+        let rest = &source[start..];
+        let c = rest.chars().next()?;
+        let pos = &mut self.pos;
+
+        let kind = if c.is_whitespace() {
+            *pos += rest
+                .find(|c: char| !c.is_whitespace())
+                .unwrap_or(rest.len());
+            TokenKind::Whitespace
+        } else if rest.starts_with("//") {
+            *pos += rest.find('\n').unwrap_or(rest.len());
+            TokenKind::Comment
+        } else if c == '"' || c == '\'' {
+            *pos += string_len(rest, c);
+            TokenKind::String
+        } else if c.is_ascii_digit() {
+            *pos += number_len(rest);
+            TokenKind::Number
+        } else if c == '$' {
+            *pos += 1 + identifier_len(&rest[1..]);
+            TokenKind::Parameter
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            *pos += identifier_len(rest);
+            if KEYWORDS.contains(&&source[start..*pos]) {
+                TokenKind::Keyword
+            } else {
+                TokenKind::Identifier
+            }
+        } else if let Some((symbol, kind)) = SYMBOLS.iter().find(|(s, _)| rest.starts_with(s)) {
+            *pos += symbol.len();
+            *kind
+        } else {
+            *pos += c.len_utf8();
+            match c {
+                '[' | ']' | '{' | '}' | '(' | ')' | ',' | ':' | ';' | '.' => TokenKind::Punctuation,
+                '*' | '@' | '^' | '|' | '+' | '-' | '/' | '%' | '<' | '>' | '!' | '=' => {
+                    TokenKind::Operator
+                }
+                _ => TokenKind::Unknown,
+            }
+        };
+
+        Some(Token {
+            kind,
+            start,
+            end: self.pos,
+        })
+    }
+}
+
+/// Length of a string literal starting with `quote`, including both quotes.
+/// An unterminated string runs to the end of the input.
+fn string_len(rest: &str, quote: char) -> usize {
+    let mut escaped = false;
+    for (i, c) in rest.char_indices().skip(1) {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == quote {
+            return i + c.len_utf8();
+        }
+    }
+    rest.len()
+}
+
+fn number_len(rest: &str) -> usize {
+    let bytes = rest.as_bytes();
+    let digits = |from: usize| {
+        bytes[from..]
+            .iter()
+            .position(|b| !b.is_ascii_digit())
+            .map_or(bytes.len(), |n| from + n)
+    };
+    let mut len = digits(0);
+    // A fraction needs a digit after the dot, so `1..5` stays a range.
+    if bytes.get(len) == Some(&b'.') && bytes.get(len + 1).is_some_and(u8::is_ascii_digit) {
+        len = digits(len + 1);
+    }
+    if matches!(bytes.get(len), Some(b'e' | b'E')) {
+        let mut exp = len + 1;
+        if matches!(bytes.get(exp), Some(b'+' | b'-')) {
+            exp += 1;
+        }
+        if bytes.get(exp).is_some_and(u8::is_ascii_digit) {
+            len = digits(exp);
+        }
+    }
+    len
+}
+
+fn identifier_len(rest: &str) -> usize {
+    rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .unwrap_or(rest.len())
+}
@@ -12,41 +12,460 @@
 //! let formatted = format_query(query, 80).unwrap();
 //! println!("{}", formatted);
 //! ```
+//!
+//! # Guarantees
+//!
+//! Formatting only changes whitespace. In particular, the fields of objects
+//! and projections are never reordered: GROQ gives later fields precedence,
+//! so in `{ ..., "title": coalesce(title, name) }` the explicit field
+//! overrides the spread only because it comes after it. The only option
+//! that reorders fields is [`FormatOptions::sort_fields`], which is off by
+//! default and only sorts bare attributes among themselves, where the order
+//! can't change which field wins. It, [`FormatOptions::simplify`], which
+//! removes redundant syntax, and [`FormatOptions::quote_style`], which
+//! changes the quotes of string literals, are the options that change more
+//! than whitespace.
+
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::Arc;
 
+use serde::Serialize;
+
+mod ast_json;
+mod builder;
+mod builtins;
+mod bundle;
+mod cache;
 mod doc;
+mod document;
+mod embedded;
 mod format;
+mod formatter;
+mod hoist;
+mod input;
+mod layout;
+mod lexer;
+mod lint;
+mod metadata;
+mod minify;
+mod path;
+mod pipeline;
+mod range;
+mod reflow;
+mod requests;
 
-pub use doc::Doc;
-pub use format::{format_expr, format_parse_result};
+pub use ast_json::parse_to_json;
+pub use builder::Query;
+pub use builtins::{BUILTIN_FUNCTIONS, BuiltinFunction, builtin_function};
+pub use bundle::{BundledQuery, query_name, typescript_module};
+pub use cache::{DEFAULT_CACHE_DIR, DiskCache, FormatCache, content_hash};
+use doc::Renderer;
+pub use doc::{Category, Doc, GroupTrace, Highlight, render_ansi, render_html};
+pub use document::{
+    DocumentOutput, QueryError, format_document, format_document_range, highlight_document,
+};
+pub use embedded::{EmbeddedQuery, extract_embedded_queries, format_embedded_queries};
+pub use format::{CallLayout, QuoteStyle, Spacing, StyleEdition, format_expr, format_parse_result};
+pub use formatter::{Formatter, format_many};
+use groq_parser::ast::{Expr, ParseResult};
 use groq_parser::parser::{Parser, ParserConfig};
+pub use hoist::hoist_projections;
+pub use input::{InputEncoding, InvisibleChar, detect_encoding, find_invisible_chars};
+pub use layout::{Construct, LayoutMetadata};
+pub use lexer::{Token, TokenKind, tokenize};
+pub use lint::{Lint, fix_query, lint_query};
+pub use metadata::{QueryMetadata, query_metadata};
+pub use minify::minify_query;
+pub use path::{AstPath, Step};
+pub use pipeline::Pipeline;
+pub use range::format_range;
+pub use requests::{RequestQuery, extract_request_queries};
 
 /// Options that control how a query is formatted.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
 pub struct FormatOptions {
-    /// Maximum line width.
+    /// Maximum line width, in characters.
     pub width: usize,
+    /// Columns each level of nesting is indented by. Defaults to 2.
+    pub indent: usize,
+    /// When true, indentation is written with a tab for every
+    /// [`indent`](Self::indent) columns, and spaces for any columns left over
+    /// where a line lines up under something. A tab counts as `indent`
+    /// columns towards the width.
+    pub use_tabs: bool,
     /// When true, the formatter introduces additional break points
     /// (binary operators, filter brackets, parenthesised groups,
     /// single-argument function calls) so it wraps more aggressively
     /// to honor the `width` limit. Expressions that would otherwise
     /// be emitted on a single overflowing line will be broken.
     pub force_wrap: bool,
+    /// When true, syntax that the parser rejects but whose intent is clear
+    /// is repaired before parsing instead of producing an error. Currently
+    /// this removes trailing commas before `}`, `]` and `)`.
+    pub lenient: bool,
+    /// When true, zero-width characters outside string literals are removed
+    /// and non-standard spaces (such as no-break spaces) become plain spaces
+    /// before parsing. See [`find_invisible_chars`].
+    pub strip_invisible: bool,
+    /// Decode the query from the given transport encoding before parsing.
+    /// See [`detect_encoding`].
+    pub decode: Option<InputEncoding>,
+    /// When true, empty or whitespace-only input formats to an empty string
+    /// instead of failing with [`FormatError::EmptyQuery`]. Useful for
+    /// editors that format empty buffers on save.
+    pub allow_empty: bool,
+    /// When true, a width below the query's [`minimum_width`] is raised to
+    /// that minimum, so the output is the layout for the narrowest width at
+    /// which no line overflows. When false (the default), the requested width
+    /// is used as-is and lines that cannot be broken simply overflow.
+    pub clamp_width: bool,
+    /// When true, formatting fails with [`FormatError::WidthExceeded`] if
+    /// any output line is longer than the width.
+    pub strict_width: bool,
+    /// When true, string literals longer than the width are not counted when
+    /// deciding whether an enclosing group fits on one line. Such strings
+    /// overflow wherever they are placed, so breaking around them only
+    /// spreads the rest of the query out.
+    pub exempt_long_strings: bool,
+    /// Rough upper limit in bytes on the memory used for the syntax tree and
+    /// layout document of a single query. Input over the limit fails with
+    /// [`FormatError::MemoryLimit`] instead of exhausting memory, which
+    /// matters when formatting untrusted queries in a server. `None` means no
+    /// limit.
+    ///
+    /// The syntax tree is estimated from the number of tokens in the input
+    /// before it is parsed, and building the layout document stops as soon
+    /// as the bytes allocated for it exceed the rest of the limit. The limit
+    /// is not a hard bound: the estimate can be low, and rendering and the
+    /// output are not counted.
+    pub approximate_memory_limit: Option<usize>,
+    /// Objects and projections nested deeper than this many levels are
+    /// always broken, one field per line, even if they would fit on one
+    /// line. Keeps deeply nested projections such as `asset-> { url }`
+    /// vertically structured in large queries, which gives smaller diffs.
+    /// `None` (the default) breaks objects only when they don't fit.
+    pub break_depth: Option<usize>,
+    /// When true, objects and projections with a single bare attribute,
+    /// such as `category-> { title }`, always stay on one line, even when
+    /// the enclosing object breaks or [`break_depth`](Self::break_depth)
+    /// would break them.
+    pub inline_single_field: bool,
+    /// Spacing around `=>`, `..`/`...` and `!`; see [`Spacing`].
+    pub spacing: Spacing,
+    /// The quotes string literals are written with; see [`QuoteStyle`].
+    pub quote_style: QuoteStyle,
+    /// When true, runs of `//` comments on their own lines that have a line
+    /// longer than the width are re-wrapped to the width, keeping their
+    /// indentation and the paragraphs separated by empty `//` lines.
+    pub reflow_comments: bool,
+    /// When true, the `&&` and `||` continuations of a broken filter line
+    /// up under its first condition instead of hanging by two spaces.
+    pub align_conditions: bool,
+    /// When true, the closing `]` of a filter whose conditions break starts
+    /// a new line at the filter's indentation, followed by any projection,
+    /// so it is clear where a long filter ends.
+    pub closing_bracket_on_own_line: bool,
+    /// Layouts for calls to particular functions, overriding the defaults.
+    /// Functions are named as in queries, such as `coalesce` or `pt::text`;
+    /// a name without a namespace means a global function. Empty by default.
+    pub call_layouts: Arc<[(String, CallLayout)]>,
+    /// When true, redundant syntax is removed: keys that repeat the
+    /// attribute they project, as in `"title": title`, and parentheses
+    /// around attributes, literals, parameters and function calls. Off by
+    /// default, since it changes more than whitespace.
+    pub simplify: bool,
+    /// When true, the bare attributes of objects and projections, such as
+    /// `title` in `{ title, body }`, are sorted alphabetically. Other
+    /// fields, such as `...`, `"key": value` and conditionals, stay in
+    /// place, and only the attributes between two of them are sorted among
+    /// themselves. Attributes next to a comment stay in place too. Off by
+    /// default.
+    pub sort_fields: bool,
+    /// The edition of the formatting style; see [`StyleEdition`]. Defaults
+    /// to the latest edition.
+    pub style_edition: StyleEdition,
+    /// Line width for filter constraints, such as `_type == "post" &&
+    /// defined(slug)` in `*[...]`, in place of [`width`](Self::width).
+    /// `None` (the default) uses `width`.
+    pub filter_width: Option<usize>,
+    /// Line width for projection bodies, the `{...}` after an expression, in
+    /// place of [`width`](Self::width). `None` (the default) uses `width`.
+    pub projection_width: Option<usize>,
+    /// Line width for the arguments of function calls, in place of
+    /// [`width`](Self::width). `None` (the default) uses `width`.
+    pub argument_width: Option<usize>,
 }
 
 impl FormatOptions {
-    /// Construct options with the given width and `force_wrap = false`.
+    /// Construct options with the given width and all other options off.
     pub fn new(width: usize) -> Self {
         FormatOptions {
             width,
+            indent: 2,
+            use_tabs: false,
             force_wrap: false,
+            lenient: false,
+            strip_invisible: false,
+            decode: None,
+            allow_empty: false,
+            clamp_width: false,
+            strict_width: false,
+            exempt_long_strings: false,
+            approximate_memory_limit: None,
+            break_depth: None,
+            inline_single_field: false,
+            spacing: Spacing::Standard,
+            quote_style: QuoteStyle::Double,
+            reflow_comments: false,
+            align_conditions: false,
+            closing_bracket_on_own_line: false,
+            call_layouts: Arc::from([]),
+            simplify: false,
+            sort_fields: false,
+            style_edition: StyleEdition::Edition2025,
+            filter_width: None,
+            projection_width: None,
+            argument_width: None,
         }
     }
 
+    /// Set the number of columns per level of indentation.
+    pub fn with_indent(mut self, indent: usize) -> Self {
+        self.indent = indent;
+        self
+    }
+
+    /// Enable or disable indenting with tabs.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use groq_format::{FormatOptions, format_query_with_options};
+    ///
+    /// let options = FormatOptions::new(20).with_indent(4).with_use_tabs(true);
+    /// let formatted = format_query_with_options("*[_type == \"post\"]{title, body}", &options).unwrap();
+    /// assert_eq!(formatted, "*[_type == \"post\"] {\n\ttitle,\n\tbody\n}");
+    /// ```
+    pub fn with_use_tabs(mut self, use_tabs: bool) -> Self {
+        self.use_tabs = use_tabs;
+        self
+    }
+
     /// Enable or disable force-wrap mode.
     pub fn with_force_wrap(mut self, force_wrap: bool) -> Self {
         self.force_wrap = force_wrap;
         self
     }
+
+    /// Enable or disable lenient parsing.
+    pub fn with_lenient(mut self, lenient: bool) -> Self {
+        self.lenient = lenient;
+        self
+    }
+
+    /// Enable or disable stripping of invisible characters.
+    pub fn with_strip_invisible(mut self, strip_invisible: bool) -> Self {
+        self.strip_invisible = strip_invisible;
+        self
+    }
+
+    /// Decode URL-encoded or string-escaped input before parsing.
+    pub fn with_decode(mut self, decode: Option<InputEncoding>) -> Self {
+        self.decode = decode;
+        self
+    }
+
+    /// Accept empty input, formatting it to an empty string.
+    pub fn with_allow_empty(mut self, allow_empty: bool) -> Self {
+        self.allow_empty = allow_empty;
+        self
+    }
+
+    /// Enable or disable raising too-small widths to the minimum width.
+    pub fn with_clamp_width(mut self, clamp_width: bool) -> Self {
+        self.clamp_width = clamp_width;
+        self
+    }
+
+    /// Enable or disable failing on output lines that exceed the width.
+    pub fn with_strict_width(mut self, strict_width: bool) -> Self {
+        self.strict_width = strict_width;
+        self
+    }
+
+    /// Enable or disable exempting overlong string literals from fitting.
+    pub fn with_exempt_long_strings(mut self, exempt_long_strings: bool) -> Self {
+        self.exempt_long_strings = exempt_long_strings;
+        self
+    }
+
+    /// Roughly limit the memory used for a single query, in bytes.
+    pub fn with_approximate_memory_limit(
+        mut self,
+        approximate_memory_limit: Option<usize>,
+    ) -> Self {
+        self.approximate_memory_limit = approximate_memory_limit;
+        self
+    }
+
+    /// Always break objects nested deeper than the given number of levels.
+    pub fn with_break_depth(mut self, break_depth: Option<usize>) -> Self {
+        self.break_depth = break_depth;
+        self
+    }
+
+    /// Enable or disable keeping single-field objects on one line.
+    pub fn with_inline_single_field(mut self, inline_single_field: bool) -> Self {
+        self.inline_single_field = inline_single_field;
+        self
+    }
+
+    /// Set the operator spacing profile.
+    pub fn with_spacing(mut self, spacing: Spacing) -> Self {
+        self.spacing = spacing;
+        self
+    }
+
+    /// Set the quotes string literals are written with.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use groq_format::{FormatOptions, QuoteStyle, format_query_with_options};
+    ///
+    /// let options = FormatOptions::default().with_quote_style(QuoteStyle::Single);
+    /// let formatted = format_query_with_options(r#"*[_type == "post" && title != "it's"]"#, &options).unwrap();
+    /// assert_eq!(formatted, r"*[_type == 'post' && title != 'it\'s']");
+    /// ```
+    pub fn with_quote_style(mut self, quote_style: QuoteStyle) -> Self {
+        self.quote_style = quote_style;
+        self
+    }
+
+    /// Enable or disable re-wrapping of long comments.
+    pub fn with_reflow_comments(mut self, reflow_comments: bool) -> Self {
+        self.reflow_comments = reflow_comments;
+        self
+    }
+
+    /// Enable or disable aligning broken filter conditions.
+    pub fn with_align_conditions(mut self, align_conditions: bool) -> Self {
+        self.align_conditions = align_conditions;
+        self
+    }
+
+    /// Enable or disable putting the closing bracket of broken filters on
+    /// its own line.
+    pub fn with_closing_bracket_on_own_line(mut self, closing_bracket_on_own_line: bool) -> Self {
+        self.closing_bracket_on_own_line = closing_bracket_on_own_line;
+        self
+    }
+
+    /// Set the layouts for calls to particular functions.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use groq_format::{CallLayout, FormatOptions, format_query_with_options};
+    ///
+    /// const LAYOUTS: &[(&str, CallLayout)] = &[("references", CallLayout::Flat)];
+    /// let options = FormatOptions::new(20).with_call_layouts(LAYOUTS);
+    /// let formatted = format_query_with_options("*[references($ids, $other)]", &options).unwrap();
+    /// assert_eq!(formatted, "*[references($ids, $other)]");
+    /// ```
+    pub fn with_call_layouts<'a, S: AsRef<str> + 'a>(
+        mut self,
+        call_layouts: impl IntoIterator<Item = &'a (S, CallLayout)>,
+    ) -> Self {
+        self.call_layouts = call_layouts
+            .into_iter()
+            .map(|(function, layout)| (function.as_ref().to_string(), *layout))
+            .collect();
+        self
+    }
+
+    /// Enable or disable removing redundant syntax.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use groq_format::{FormatOptions, format_query_with_options};
+    ///
+    /// let options = FormatOptions::default().with_simplify(true);
+    /// let formatted = format_query_with_options(r#"*[(published)]{"title": title}"#, &options).unwrap();
+    /// assert_eq!(formatted, "*[published] { title }");
+    /// ```
+    pub fn with_simplify(mut self, simplify: bool) -> Self {
+        self.simplify = simplify;
+        self
+    }
+
+    /// Enable or disable sorting the bare attributes of objects.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use groq_format::{FormatOptions, format_query_with_options};
+    ///
+    /// let options = FormatOptions::default().with_sort_fields(true);
+    /// let formatted = format_query_with_options("*{slug, body, ..., title, _id}", &options).unwrap();
+    /// assert_eq!(formatted, "* { body, slug, ..., _id, title }");
+    /// ```
+    pub fn with_sort_fields(mut self, sort_fields: bool) -> Self {
+        self.sort_fields = sort_fields;
+        self
+    }
+
+    /// Set the edition of the formatting style.
+    pub fn with_style_edition(mut self, style_edition: StyleEdition) -> Self {
+        self.style_edition = style_edition;
+        self
+    }
+
+    /// Set the line width for filter constraints.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use groq_format::{FormatOptions, format_query_with_options};
+    ///
+    /// let query = r#"*[_type == "post" && defined(slug)]{title, slug, author, publishedAt}"#;
+    /// let options = FormatOptions::new(40).with_filter_width(Some(60));
+    /// assert_eq!(
+    ///     format_query_with_options(query, &options).unwrap(),
+    ///     "*[_type == \"post\" && defined(slug)] {\n  title,\n  slug,\n  author,\n  publishedAt\n}"
+    /// );
+    /// ```
+    pub fn with_filter_width(mut self, filter_width: Option<usize>) -> Self {
+        self.filter_width = filter_width;
+        self
+    }
+
+    /// Set the line width for projection bodies.
+    pub fn with_projection_width(mut self, projection_width: Option<usize>) -> Self {
+        self.projection_width = projection_width;
+        self
+    }
+
+    /// Set the line width for function arguments.
+    pub fn with_argument_width(mut self, argument_width: Option<usize>) -> Self {
+        self.argument_width = argument_width;
+        self
+    }
+
+    /// The widest any line may be: [`width`](Self::width), or a wider width
+    /// for filters, projections or arguments.
+    pub fn max_width(&self) -> usize {
+        [
+            self.filter_width,
+            self.projection_width,
+            self.argument_width,
+        ]
+        .into_iter()
+        .flatten()
+        .fold(self.width, usize::max)
+    }
 }
 
 impl Default for FormatOptions {
@@ -93,19 +512,844 @@ pub fn format_query_with_options(
     query: &str,
     options: &FormatOptions,
 ) -> Result<String, FormatError> {
+    let doc = query_to_doc(query, options)?;
+    // Formatting mostly adds whitespace, so the input length is a good
+    // estimate of the output length.
+    let mut output = String::with_capacity(query.len() + query.len() / 2);
+    let rendered = render_doc(&doc, options, &mut Renderer::default(), &mut output);
+    doc.discard();
+    rendered?;
+    Ok(output)
+}
+
+/// A formatted query together with facts about its layout.
+#[derive(Debug, Clone)]
+pub struct FormattedQuery {
+    /// The formatted query, identical to what [`format_query_with_options`] returns.
+    pub output: String,
+    /// Line count, widths and the constructs that broke.
+    pub metadata: LayoutMetadata,
+    /// Things worth telling the user about that did not stop the query from
+    /// being formatted, in the order they were found.
+    pub warnings: Vec<Warning>,
+}
+
+/// A problem that did not stop a query from being formatted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Warning {
+    /// An invisible character was removed, see
+    /// [`FormatOptions::strip_invisible`].
+    InvisibleChar(InvisibleChar),
+    /// A trailing comma was removed, see [`FormatOptions::lenient`].
+    TrailingComma {
+        /// Line (1-based) of the comma.
+        line: usize,
+    },
+    /// An output line is longer than the width.
+    Overflow(Overflow),
+}
+
+impl std::fmt::Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Warning::InvisibleChar(ch) => write!(f, "removed {}", ch),
+            Warning::TrailingComma { line } => {
+                write!(f, "removed trailing comma on line {}", line)
+            }
+            Warning::Overflow(overflow) => write!(f, "output {} exceeds the width", overflow),
+        }
+    }
+}
+
+/// Format a query like [`format_query_with_options`], also reporting how many
+/// lines the output has, how wide it is, which top-level constructs were
+/// broken over several lines, and whether any line overflows the width.
+///
+/// Input repairs made by [`FormatOptions::strip_invisible`] and
+/// [`FormatOptions::lenient`], and output lines that exceed the width, are
+/// reported as [`Warning`]s. Positions of repairs refer to the query as
+/// given, or to the decoded query with [`FormatOptions::decode`].
+///
+/// # Example
+///
+/// ```
+/// use groq_format::{format_query_with_metadata, Construct, FormatOptions};
+///
+/// let query = r#"*[_type == "post"]{title, body, "author": author->name}"#;
+/// let formatted = format_query_with_metadata(query, &FormatOptions::new(40)).unwrap();
+/// assert_eq!(formatted.metadata.line_count, 5);
+/// assert_eq!(formatted.metadata.broken, vec![Construct::Projection]);
+/// assert!(!formatted.metadata.overflowed);
+///
+/// let options = FormatOptions::new(40).with_lenient(true);
+/// let formatted = format_query_with_metadata("*[_type == \"post\"]{title,}", &options).unwrap();
+/// assert_eq!(formatted.warnings[0].to_string(), "removed trailing comma on line 1");
+/// ```
+pub fn format_query_with_metadata(
+    query: &str,
+    options: &FormatOptions,
+) -> Result<FormattedQuery, FormatError> {
+    let mut warnings = Vec::new();
+    let Some(query) = prepare_query_with_warnings(query, options, Some(&mut warnings))? else {
+        return Ok(FormattedQuery {
+            output: String::new(),
+            metadata: LayoutMetadata::default(),
+            warnings,
+        });
+    };
+    let result = parse_prepared(&query, options)?;
+    let ids = path::NodeIds::new(&result.expr);
+    let doc = format::format_parse_result_marked(&result, &query, options, &ids)?.normalize();
+
+    let mut output = String::with_capacity(query.len() + query.len() / 2);
+    let width = render_doc(&doc, options, &mut Renderer::default(), &mut output)?;
+    // Marks don't affect the layout, so this renders the same output before
+    // any comments are reflowed, which doesn't move line breaks in the code.
+    let (marked, marks) = doc::pretty_with_marks(width, doc);
+    let broken = layout::broken_constructs(&result.expr, &marked, &span_map(marks), &ids);
+
+    let max_width = max_line_width(&output);
+    warnings.extend(
+        overflowing_lines(&output, options.max_width())
+            .into_iter()
+            .map(Warning::Overflow),
+    );
+    Ok(FormattedQuery {
+        metadata: LayoutMetadata {
+            line_count: output.lines().count(),
+            max_line_width: max_width,
+            broken,
+            overflowed: max_width > options.max_width(),
+        },
+        output,
+        warnings,
+    })
+}
+
+/// Render a formatted query's document, applying the width policies in
+/// `options`. Returns the width it was rendered at.
+pub(crate) fn render_doc(
+    doc: &Doc,
+    options: &FormatOptions,
+    renderer: &mut Renderer,
+    output: &mut String,
+) -> Result<usize, FormatError> {
+    let width = render_part(doc, options, 0, renderer, output)?;
+    #[cfg(feature = "self-check")]
+    self_check(output)?;
+    Ok(width)
+}
+
+/// Render the document of part of a query like [`render_doc`], for output
+/// that is indented by `indent` columns, which come off the width. The output
+/// is not checked to parse, since a part such as an object's field doesn't on
+/// its own.
+pub(crate) fn render_part(
+    doc: &Doc,
+    options: &FormatOptions,
+    indent: usize,
+    renderer: &mut Renderer,
+    output: &mut String,
+) -> Result<usize, FormatError> {
+    let max_width = options.max_width().saturating_sub(indent);
+    let mut width = options.width.saturating_sub(indent);
+    renderer.render(width, doc, output);
+    if options.clamp_width && max_line_width(output) > max_width {
+        width = minimum_doc_width(doc, renderer).max(width);
+        renderer.render(width, doc, output);
+    }
+    if options.reflow_comments {
+        *output = reflow::reflow_comments(output, width, options.indent);
+    }
+
+    if options.strict_width {
+        let lines = overflowing_lines(output, max_width);
+        if !lines.is_empty() {
+            return Err(FormatError::WidthExceeded {
+                width: options.max_width(),
+                lines: lines
+                    .into_iter()
+                    .map(|overflow| Overflow {
+                        width: overflow.width + indent,
+                        ..overflow
+                    })
+                    .collect(),
+            });
+        }
+    }
+    Ok(width)
+}
+
+/// Check that formatted output parses. Output that doesn't is a bug in the
+/// formatter, so debug builds panic to stop at the query that caused it.
+#[cfg(feature = "self-check")]
+pub(crate) fn self_check(output: &str) -> Result<(), FormatError> {
+    if output.trim().is_empty() {
+        return Ok(());
+    }
+    match parse_query(output) {
+        Ok(_) => Ok(()),
+        Err(err) if cfg!(debug_assertions) => {
+            panic!("formatted output does not parse: {}\n{}", err, output)
+        }
+        Err(err) => Err(FormatError::SelfCheck {
+            message: err.to_string(),
+            output: output.to_string(),
+        }),
+    }
+}
+
+/// Whether `query` is already formatted, that is, formatting it with
+/// `options` would return it unchanged.
+///
+/// This is faster than formatting and comparing: the output is checked as it
+/// is rendered, without being built, and rendering stops at the first
+/// difference.
+///
+/// # Example
+///
+/// ```
+/// use groq_format::{is_formatted, FormatOptions};
+///
+/// let options = FormatOptions::default();
+/// assert!(is_formatted("*[a == 1]", &options).unwrap());
+/// assert!(!is_formatted("*[a==1]", &options).unwrap());
+/// ```
+pub fn is_formatted(query: &str, options: &FormatOptions) -> Result<bool, FormatError> {
+    if options.clamp_width || options.strict_width || options.reflow_comments {
+        // These depend on the complete output.
+        return Ok(format_query_with_options(query, options)? == query);
+    }
+    let doc = query_to_doc(query, options)?;
+    Ok(doc::renders_as(options.width, &doc, query))
+}
+
+/// Format `query` and check that formatting the output again leaves it
+/// unchanged, returning the output if it does. Formatting should always
+/// converge in one pass; output that changes when formatted again fails with
+/// [`FormatError::NotIdempotent`], which is a bug in the formatter.
+///
+/// # Example
+///
+/// ```
+/// use groq_format::{verify_idempotent, FormatOptions};
+///
+/// let output = verify_idempotent("*[a==1]{b}", &FormatOptions::default()).unwrap();
+/// assert_eq!(output, "*[a == 1] { b }");
+/// ```
+pub fn verify_idempotent(query: &str, options: &FormatOptions) -> Result<String, FormatError> {
+    let output = format_query_with_options(query, options)?;
+    // The output is already decoded.
+    let options = options.clone().with_decode(None);
+    if output.is_empty() || is_formatted(&output, &options)? {
+        return Ok(output);
+    }
+    let reformatted = format_query_with_options(&output, &options)?;
+    Err(FormatError::NotIdempotent {
+        output,
+        reformatted,
+    })
+}
+
+/// The narrowest width at which formatting `query` produces no line longer
+/// than that width.
+///
+/// Long identifiers and string literals cannot be broken, so a query may need
+/// more room than requested. With [`FormatOptions::clamp_width`], widths
+/// below this value are raised to it.
+///
+/// # Example
+///
+/// ```
+/// use groq_format::{minimum_width, FormatOptions};
+///
+/// let query = r#"*[_type == "post"]{title, "averyveryverylongfieldname": x}"#;
+/// let width = minimum_width(query, &FormatOptions::default()).unwrap();
+/// assert_eq!(width, 33);
+/// ```
+pub fn minimum_width(query: &str, options: &FormatOptions) -> Result<usize, FormatError> {
+    let doc = query_to_doc(query, options)?;
+    Ok(minimum_doc_width(&doc, &mut Renderer::default()))
+}
+
+/// Binary search for the narrowest width at which the document renders
+/// without overflow. Rendering everything flat never overflows, so the
+/// flat width is an upper bound.
+fn minimum_doc_width(doc: &Doc, renderer: &mut Renderer) -> usize {
+    let mut output = String::new();
+    renderer.render(usize::MAX, doc, &mut output);
+    let mut low = 0;
+    let mut high = max_line_width(&output);
+    while low < high {
+        let mid = low + (high - low) / 2;
+        renderer.render(mid, doc, &mut output);
+        if max_line_width(&output) <= mid {
+            high = mid;
+        } else {
+            low = mid + 1;
+        }
+    }
+    high
+}
+
+/// Width in characters of the longest line.
+fn max_line_width(text: &str) -> usize {
+    text.lines().map(doc::columns).max().unwrap_or(0)
+}
+
+/// An output line that is longer than the requested width.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Overflow {
+    /// Line number (1-based) in the output.
+    pub line: usize,
+    /// Width of the line in characters.
+    pub width: usize,
+}
+
+impl std::fmt::Display for Overflow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {} ({} columns)", self.line, self.width)
+    }
+}
+
+/// Find the lines of formatted output that exceed `width`.
+///
+/// The formatter never breaks identifiers or string literals, so a query can
+/// still overflow after formatting. This lets callers tell the user that the
+/// width could not be achieved.
+///
+/// # Example
+///
+/// ```
+/// use groq_format::{format_query, overflowing_lines, Overflow};
+///
+/// let output = format_query(r#"*[title == "a rather long title"]"#, 20).unwrap();
+/// assert_eq!(overflowing_lines(&output, 20), vec![Overflow { line: 1, width: 33 }]);
+/// ```
+pub fn overflowing_lines(output: &str, width: usize) -> Vec<Overflow> {
+    output
+        .lines()
+        .enumerate()
+        .map(|(i, line)| Overflow {
+            line: i + 1,
+            width: doc::columns(line),
+        })
+        .filter(|overflow| overflow.width > width)
+        .collect()
+}
+
+/// The names of the parameters a query refers to, without the `$`, in
+/// order of first use.
+///
+/// The query does not need to parse, so this also works on queries that are
+/// still being written.
+///
+/// # Example
+///
+/// ```
+/// use groq_format::query_parameters;
+///
+/// let query = r#"*[_type == $type && slug.current == $slug && _type != $type]"#;
+/// assert_eq!(query_parameters(query), vec!["type", "slug"]);
+/// ```
+pub fn query_parameters(query: &str) -> Vec<&str> {
+    let mut names: Vec<&str> = Vec::new();
+    for token in tokenize(query) {
+        let name = &token.text(query)[1..];
+        if token.kind == TokenKind::Parameter && !name.is_empty() && !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    names
+}
+
+/// A hash of a query that ignores whitespace and comments, so two copies of
+/// a query have the same fingerprint however they are formatted.
+///
+/// # Example
+///
+/// ```
+/// use groq_format::query_fingerprint;
+///
+/// let formatted = "// All posts\n*[_type == \"post\"] {\n  title\n}";
+/// assert_eq!(query_fingerprint(formatted), query_fingerprint(r#"*[_type=="post"]{title}"#));
+/// assert_ne!(query_fingerprint(formatted), query_fingerprint(r#"*[_type=="page"]{title}"#));
+/// ```
+pub fn query_fingerprint(query: &str) -> u64 {
+    let tokens: Vec<&str> = tokenize(query)
+        .iter()
+        .filter(|token| !token.is_trivia())
+        .map(|token| token.text(query))
+        .collect();
+    // Separate tokens so that `a b` and `ab` differ.
+    content_hash(&tokens.join(" "))
+}
+
+/// A formatted query together with the layout decisions that produced it.
+#[derive(Debug, Clone)]
+pub struct Explanation {
+    /// The formatted query, identical to what [`format_query_with_options`] returns.
+    pub output: String,
+    /// Every group the renderer tested, in output order.
+    pub groups: Vec<GroupTrace>,
+}
+
+impl Explanation {
+    /// The formatted output with numbered lines, each followed by markers for
+    /// the groups on it: a `^` where a group starts, with whether it broke
+    /// and why, and another where a group that spans several lines ends.
+    /// Groups on a single line are underlined instead.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use groq_format::{explain_query, FormatOptions};
+    ///
+    /// let explanation = explain_query("*[a && b]", &FormatOptions::new(6)).unwrap();
+    /// assert_eq!(
+    ///     explanation.annotated(),
+    ///     "\
+    /// 1 | *[a
+    ///   |  ^ group 1 broken: needs 7 cols, 5 available
+    ///   |   ^ group 2 broken: needs 6 cols, 4 available
+    /// 2 |   && b]
+    ///   |      ^ end of group 2
+    ///   |      ^ end of group 1
+    /// "
+    /// );
+    /// ```
+    pub fn annotated(&self) -> String {
+        let lines: Vec<&str> = self.output.lines().collect();
+        let gutter = lines.len().to_string().len();
+        // The number of characters before a 1-based byte column.
+        let offset = |text: &str, column: usize| {
+            let end = column.saturating_sub(1);
+            text.get(..end).map_or(end, |prefix| prefix.chars().count())
+        };
+
+        let mut annotated = String::with_capacity(self.output.len() * 2);
+        for (i, text) in lines.iter().enumerate() {
+            let line = i + 1;
+            annotated.push_str(&format!("{:>gutter$} | {}\n", line, text));
+            for (n, group) in self.groups.iter().enumerate() {
+                if group.line != line {
+                    continue;
+                }
+                let start = offset(text, group.column);
+                let underline = match group.end_line == line {
+                    true => offset(text, group.end_column).saturating_sub(start + 1),
+                    false => 0,
+                };
+                let needed = match group.flat_width {
+                    Some(width) => format!("needs {} cols", width),
+                    None => "contains a hard line break".to_string(),
+                };
+                annotated.push_str(&format!(
+                    "{:gutter$} | {}^{} group {} {}: {}, {} available\n",
+                    "",
+                    " ".repeat(start),
+                    "~".repeat(underline),
+                    n + 1,
+                    if group.broke { "broken" } else { "flat" },
+                    needed,
+                    group.available
+                ));
+            }
+            for (n, group) in self.groups.iter().enumerate().rev() {
+                if group.end_line != line || group.line == line {
+                    continue;
+                }
+                let end = offset(text, group.end_column).saturating_sub(1);
+                annotated.push_str(&format!(
+                    "{:gutter$} | {}^ end of group {}\n",
+                    "",
+                    " ".repeat(end),
+                    n + 1
+                ));
+            }
+        }
+        annotated
+    }
+}
+
+/// Format a query and record, for every group, where it started, how wide it
+/// was when flat, how much room was left, and whether it broke.
+///
+/// # Example
+///
+/// ```
+/// use groq_format::{explain_query, FormatOptions};
+///
+/// let explanation = explain_query("*[a && b]", &FormatOptions::new(6)).unwrap();
+/// assert!(explanation.groups.iter().any(|g| g.broke));
+/// ```
+pub fn explain_query(query: &str, options: &FormatOptions) -> Result<Explanation, FormatError> {
+    let doc = query_to_doc(query, options)?;
+    let mut output = String::new();
+    let width = render_doc(&doc, options, &mut Renderer::default(), &mut output)?;
+    // Tracing doesn't affect the layout, so this renders the same output
+    // before any comments are reflowed, which only moves lines around them.
+    let (traced, mut groups) = doc::pretty_with_trace(width, doc);
+    if options.reflow_comments {
+        let (_, moved_to) = reflow::reflow_comments_mapped(&traced, width, options.indent);
+        for group in &mut groups {
+            group.line = moved_to[group.line - 1] + 1;
+            group.end_line = moved_to[group.end_line - 1] + 1;
+        }
+    }
+    Ok(Explanation { output, groups })
+}
+
+/// Parse a GROQ query and build its layout [`Doc`] without rendering it.
+///
+/// Useful for inspecting layout decisions; the returned document can be
+/// serialized to JSON.
+///
+/// # Example
+///
+/// ```
+/// use groq_format::{query_to_doc, FormatOptions};
+///
+/// let doc = query_to_doc("*[_type == \"post\"]", &FormatOptions::default()).unwrap();
+/// let json = serde_json::to_string(&doc).unwrap();
+/// assert!(json.contains("\"group\""));
+/// ```
+pub fn query_to_doc(query: &str, options: &FormatOptions) -> Result<Doc, FormatError> {
+    let Some(query) = prepare_query(query, options)? else {
+        return Ok(Doc::Nil);
+    };
+    let result = parse_prepared(&query, options)?;
+    let doc = format::format_parse_result_with(&result, &query, options)?;
+    // Release the AST before normalizing, which builds a second tree.
+    drop(result);
+    Ok(doc.normalize())
+}
+
+/// Format a query like [`format_query_with_options`], marking up its text
+/// with `highlight`.
+pub(crate) fn highlight_query(
+    query: &str,
+    options: &FormatOptions,
+    highlight: Highlight,
+) -> Result<String, FormatError> {
+    let Some(query) = prepare_query(query, options)? else {
+        return Ok(String::new());
+    };
+    let result = parse_prepared(&query, options)?;
+    let doc = format::format_parse_result_annotated(&result, &query, options)?;
+    drop(result);
+    let doc = doc.normalize();
+    // Lay out the plain text first, for the width it ends up with and the
+    // checks made on it. Annotations don't change the layout.
+    let width = render_doc(&doc, options, &mut Renderer::default(), &mut String::new());
+    let output = width.map(|width| highlight.render(width, &doc));
+    doc.discard();
+    output
+}
+
+/// Trim the query and apply the input repairs enabled in `options`. Returns
+/// `None` for empty input that `options` allows.
+pub(crate) fn prepare_query(
+    query: &str,
+    options: &FormatOptions,
+) -> Result<Option<String>, FormatError> {
+    prepare_query_with_warnings(query, options, None)
+}
+
+/// Prepare a query like [`prepare_query`], adding a warning to `warnings`
+/// for every repair made.
+fn prepare_query_with_warnings(
+    query: &str,
+    options: &FormatOptions,
+    mut warnings: Option<&mut Vec<Warning>>,
+) -> Result<Option<String>, FormatError> {
+    let untrimmed = query;
     let query = query.trim();
+    // Positions found after trimming are moved past the trimmed text, so
+    // they refer to the query as given.
+    let leading = &untrimmed[..untrimmed.len() - untrimmed.trim_start().len()];
+    let leading_lines = leading.matches('\n').count();
+    let leading_columns = leading
+        .rsplit('\n')
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .count();
     if query.is_empty() {
+        return if options.allow_empty {
+            Ok(None)
+        } else {
+            Err(FormatError::EmptyQuery)
+        };
+    }
+
+    let mut query = query.to_string();
+    if let Some(encoding) = options.decode {
+        query = input::decode(&query, encoding).trim().to_string();
+    }
+    if options.strip_invisible {
+        if let Some(warnings) = warnings.as_deref_mut() {
+            for mut ch in find_invisible_chars(&query) {
+                ch.offset += leading.len();
+                if ch.line == 1 {
+                    ch.column += leading_columns;
+                }
+                ch.line += leading_lines;
+                warnings.push(Warning::InvisibleChar(ch));
+            }
+        }
+        query = input::strip_invisible_chars(&query);
+    }
+    if options.lenient {
+        let (repaired, removed) = input::remove_trailing_commas(&query);
+        if let Some(warnings) = warnings {
+            for offset in removed {
+                warnings.push(Warning::TrailingComma {
+                    line: query[..offset].matches('\n').count() + 1 + leading_lines,
+                });
+            }
+        }
+        query = repaired;
+    }
+    Ok(Some(query))
+}
+
+/// Parse a prepared query, within the memory limit and with diagnosed errors.
+pub(crate) fn parse_prepared(
+    query: &str,
+    options: &FormatOptions,
+) -> Result<ParseResult, FormatError> {
+    if let Some(limit) = options.approximate_memory_limit {
+        // Refuse input that is too large to parse within the limit before
+        // building anything.
+        let needed = ast_memory(query);
+        if needed > limit {
+            return Err(FormatError::MemoryLimit { limit, needed });
+        }
+    }
+
+    parse_query(query).map_err(|err| match err {
+        FormatError::Parse(message) => diagnose_parse_error(query, message),
+        err => err,
+    })
+}
+
+/// Estimate of the bytes of syntax tree parsing `query` builds: a node for
+/// each of its tokens, and a copy of its text.
+pub(crate) fn ast_memory(query: &str) -> usize {
+    let nodes = lexer::Tokens::new(query)
+        .filter(|token| !token.is_trivia())
+        .count();
+    nodes
+        .saturating_mul(std::mem::size_of::<Expr>())
+        .saturating_add(query.len())
+}
+
+/// Format an already-parsed expression with the given options.
+///
+/// Comments are not part of the AST, so none are emitted.
+pub fn format_ast(expr: &Expr, options: &FormatOptions) -> String {
+    let doc = format::format_expr_with(expr, options).normalize();
+    doc::pretty(options.width, doc)
+}
+
+/// Identifies an expression of a parsed AST, for finding it in
+/// [`AstOutput`].
+///
+/// The id is the expression's position in a pre-order walk of the AST, the
+/// top-level expression being 0, so a node gets the same id in every parse
+/// of the same query.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(usize);
+
+impl NodeId {
+    /// The id of the expression at `path` in the AST `root`, or `None` if
+    /// there is none, or the path leads to a projection's object or a pipe
+    /// stage's function, which are not expressions of their own.
+    pub fn of(root: &Expr, path: &AstPath) -> Option<NodeId> {
+        let expr = match path::resolve(root, path)?.node {
+            path::Node::Expr(expr) | path::Node::Field(expr) => expr,
+            path::Node::Object(_) | path::Node::Call(_) => return None,
+        };
+        path::NodeIds::new(root).get(expr)
+    }
+
+    /// The position of the expression in a pre-order walk of its AST.
+    pub fn index(self) -> usize {
+        self.0
+    }
+}
+
+/// A formatted expression together with where each of its nodes ended up.
+#[derive(Debug, Clone)]
+pub struct AstOutput {
+    /// The formatted expression, identical to what [`format_ast`] returns.
+    pub output: String,
+    /// The byte range in `output` of each node of the expression.
+    pub spans: HashMap<NodeId, Range<usize>>,
+}
+
+impl AstOutput {
+    /// The byte range in the output of an AST node, or `None` if it has no
+    /// range of its own.
+    pub fn span(&self, id: NodeId) -> Option<Range<usize>> {
+        self.spans.get(&id).cloned()
+    }
+}
+
+/// Format an already-parsed expression like [`format_ast`], also recording
+/// the range of the output each AST node was formatted to, so tools can
+/// highlight a particular filter or field in the formatted text.
+///
+/// Nodes that are laid out together with their parent, such as the inner
+/// operations of a chain like `a + b + c` or the stages of a pipeline, may
+/// have no range of their own.
+pub fn format_ast_with_spans(expr: &Expr, options: &FormatOptions) -> AstOutput {
+    let doc = format::format_expr_marked(expr, options).normalize();
+    let (output, marks) = doc::pretty_with_marks(options.width, doc);
+    AstOutput {
+        output,
+        spans: span_map(marks),
+    }
+}
+
+/// The range of each marked node. A node formatted more than once keeps the
+/// range of its first appearance.
+fn span_map(marks: Vec<(usize, Range<usize>)>) -> HashMap<NodeId, Range<usize>> {
+    let mut spans = HashMap::with_capacity(marks.len());
+    for (id, range) in marks {
+        spans.entry(NodeId(id)).or_insert(range);
+    }
+    spans
+}
+
+/// Format the node at `path` in a query on its own, as if it started at
+/// column `column` of a line, so that editors can re-render one part of a
+/// query for previews and hovers.
+///
+/// The first line gets the room left after `column`; the lines after it are
+/// indented as if the node started a line of its own, and callers add any
+/// indentation of their own, taking it off the width. Like [`format_ast`],
+/// comments are not included. Use [`node_at`] to find the path of the node
+/// at a position in the query.
+///
+/// # Example
+///
+/// ```
+/// use groq_format::{format_subexpression, AstPath, FormatOptions, Step};
+///
+/// let query = r#"*[_type == "post"]{title, "author": author->{name, bio, image}} | order(title)"#;
+/// // The projection's object, in the first stage of the pipe.
+/// let path = AstPath::new([Step::Lhs, Step::Object]);
+/// let formatted = format_subexpression(query, &path, 60, &FormatOptions::new(80)).unwrap();
+/// assert_eq!(formatted, "{\n  title,\n  \"author\": author-> { name, bio, image }\n}");
+/// ```
+pub fn format_subexpression(
+    query: &str,
+    path: &AstPath,
+    column: usize,
+    options: &FormatOptions,
+) -> Result<String, FormatError> {
+    let query = prepare_query(query, options)?.ok_or(FormatError::EmptyQuery)?;
+    let result = parse_prepared(&query, options)?;
+    let resolved =
+        path::resolve(&result.expr, path).ok_or_else(|| FormatError::NodeNotFound(path.clone()))?;
+    // Text in front of the node takes up the start of the first line.
+    let doc = Doc::concat([
+        Doc::text(" ".repeat(column)),
+        format::format_resolved(&resolved, options),
+    ])
+    .normalize();
+    let mut output = String::new();
+    let rendered = render_part(&doc, options, 0, &mut Renderer::default(), &mut output);
+    doc.discard();
+    rendered?;
+    // A field of an object is not a query on its own.
+    #[cfg(feature = "self-check")]
+    if !matches!(resolved.node, path::Node::Field(_)) {
+        self_check(&output)?;
+    }
+    output.drain(..column);
+    Ok(output)
+}
+
+/// The path of the innermost AST node whose source text contains the byte at
+/// `offset`, such as the node under the cursor in an editor. Returns `None`
+/// if the offset is outside the query's expression, for example in a
+/// comment before it.
+///
+/// Paths found here lead to the same nodes in [`select`] and
+/// [`format_subexpression`].
+///
+/// # Example
+///
+/// ```
+/// use groq_format::{node_at, select};
+///
+/// let query = r#"*[_type == "post"]{title, "author": author->name}"#;
+/// let path = node_at(query, query.find("author->").unwrap()).unwrap().unwrap();
+/// assert_eq!(&query[select(query, &path).unwrap()], "author");
+/// ```
+pub fn node_at(query: &str, offset: usize) -> Result<Option<AstPath>, FormatError> {
+    let (leading, trimmed) = trim_for_addressing(query)?;
+    let result = parse_query(trimmed)?;
+    Ok(offset
+        .checked_sub(leading)
+        .and_then(|offset| path::path_at(&result.expr, trimmed, offset)))
+}
+
+/// The byte range in `query` of the AST node at `path`.
+///
+/// # Example
+///
+/// ```
+/// use groq_format::select;
+///
+/// let query = r#"*[_type == "post"]{title, "author": author->name} | order(title)"#;
+/// let range = select(query, &"lhs.object.item[1]".parse().unwrap()).unwrap();
+/// assert_eq!(&query[range], r#""author": author->name"#);
+/// ```
+pub fn select(query: &str, path: &AstPath) -> Result<Range<usize>, FormatError> {
+    let (leading, trimmed) = trim_for_addressing(query)?;
+    let result = parse_query(trimmed)?;
+    let range = path::path_range(&result.expr, trimmed, path)
+        .ok_or_else(|| FormatError::NodeNotFound(path.clone()))?;
+    Ok(range.start + leading..range.end + leading)
+}
+
+/// The query without surrounding whitespace, and the length of the
+/// whitespace before it, so that positions can be mapped back.
+fn trim_for_addressing(query: &str) -> Result<(usize, &str), FormatError> {
+    let trimmed = query.trim();
+    if trimmed.is_empty() {
         return Err(FormatError::EmptyQuery);
     }
+    Ok((query.len() - query.trim_start().len(), trimmed))
+}
 
+/// Replace a bare parse error with a more helpful one when the input shows a
+/// recognizable problem.
+fn diagnose_parse_error(query: &str, message: String) -> FormatError {
+    if input::looks_like_javascript(query) {
+        return FormatError::NotGroq(message);
+    }
+    if let Some(encoding) = detect_encoding(query) {
+        return FormatError::Encoded { message, encoding };
+    }
+    let chars = find_invisible_chars(query);
+    if !chars.is_empty() {
+        return FormatError::InvisibleChars { message, chars };
+    }
+    FormatError::Parse(message)
+}
+
+/// Parse a (trimmed, non-empty) query, keeping comments.
+pub(crate) fn parse_query(query: &str) -> Result<ParseResult, FormatError> {
     let config = ParserConfig::without_param_validation().with_comments();
     let mut parser = Parser::new_with_config(query, config);
-    let result = parser
+    parser
         .parse()
-        .map_err(|e| FormatError::Parse(e.to_string()))?;
-
-    let doc = format_parse_result(&result, query, options.force_wrap);
-    Ok(doc::pretty(options.width, doc))
+        .map_err(|e| FormatError::Parse(e.to_string()))
 }
 
 /// Errors that can occur during formatting.
@@ -115,6 +1359,61 @@ pub enum FormatError {
     EmptyQuery,
     /// Failed to parse the query.
     Parse(String),
+    /// Failed to parse a query that contains invisible characters, which
+    /// are the likely cause.
+    InvisibleChars {
+        /// The parser's error message.
+        message: String,
+        /// The invisible characters found.
+        chars: Vec<InvisibleChar>,
+    },
+    /// Failed to parse input that looks like JavaScript or TypeScript
+    /// source rather than a GROQ query. Holds the parser's error message.
+    NotGroq(String),
+    /// Failed to parse a query that appears to be URL-encoded or
+    /// string-escaped.
+    Encoded {
+        /// The parser's error message.
+        message: String,
+        /// The encoding the query appears to be in.
+        encoding: InputEncoding,
+    },
+    /// Formatting the query would use more memory than
+    /// [`FormatOptions::approximate_memory_limit`] allows.
+    MemoryLimit {
+        /// The configured limit in bytes.
+        limit: usize,
+        /// The (estimated) number of bytes needed.
+        needed: usize,
+    },
+    /// A path given to [`format_subexpression`] does not lead to a node of
+    /// the query.
+    NodeNotFound(AstPath),
+    /// The formatted query has lines longer than the width and
+    /// [`FormatOptions::strict_width`] is set.
+    WidthExceeded {
+        /// The requested width.
+        width: usize,
+        /// The output lines that exceed it.
+        lines: Vec<Overflow>,
+    },
+    /// Formatting the formatted query again changes it, which is a bug in the
+    /// formatter. Returned by [`verify_idempotent`].
+    NotIdempotent {
+        /// The query formatted once.
+        output: String,
+        /// The query formatted twice.
+        reformatted: String,
+    },
+    /// The formatted query does not parse, which is a bug in the formatter.
+    /// Only checked with the `self-check` feature, in release builds; debug
+    /// builds panic instead.
+    SelfCheck {
+        /// The parser's error message for the output.
+        message: String,
+        /// The output that does not parse.
+        output: String,
+    },
 }
 
 impl std::fmt::Display for FormatError {
@@ -122,6 +1421,72 @@ impl std::fmt::Display for FormatError {
         match self {
             FormatError::EmptyQuery => write!(f, "no query provided"),
             FormatError::Parse(msg) => write!(f, "parse error: {}", msg),
+            FormatError::InvisibleChars { message, chars } => {
+                write!(
+                    f,
+                    "parse error: {}; the query contains invisible characters (",
+                    message
+                )?;
+                for (i, ch) in chars.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", ch)?;
+                }
+                write!(f, "), use --strip-invisible to remove them")
+            }
+            FormatError::Encoded { message, encoding } => write!(
+                f,
+                "parse error: {}; the query appears to be {}, use --decode {} to decode it first",
+                message,
+                encoding,
+                match encoding {
+                    InputEncoding::Url => "url",
+                    InputEncoding::Escaped => "escaped",
+                    InputEncoding::Quoted => "quoted",
+                }
+            ),
+            FormatError::MemoryLimit { limit, needed } => write!(
+                f,
+                "query needs about {} bytes to format, exceeding the memory limit of {} bytes",
+                needed, limit
+            ),
+            FormatError::WidthExceeded { width, lines } => {
+                write!(f, "output exceeds the width of {} columns on ", width)?;
+                for (i, line) in lines.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", line)?;
+                }
+                write!(f, "; shorten long identifiers or strings")
+            }
+            FormatError::NodeNotFound(path) => write!(f, "the query has no node at `{}`", path),
+            FormatError::NotIdempotent {
+                output,
+                reformatted,
+            } => {
+                let line = output
+                    .lines()
+                    .zip(reformatted.lines())
+                    .position(|(a, b)| a != b)
+                    .unwrap_or_else(|| output.lines().count().min(reformatted.lines().count()));
+                write!(
+                    f,
+                    "internal error: formatting the formatted query again changes line {}; please report this as a bug",
+                    line + 1
+                )
+            }
+            FormatError::SelfCheck { message, .. } => write!(
+                f,
+                "internal error: the formatted query does not parse ({}); please report this as a bug",
+                message
+            ),
+            FormatError::NotGroq(message) => write!(
+                f,
+                "parse error: {}; this looks like a JavaScript/TypeScript file, not a GROQ query, use --embedded js,ts to format the queries inside it",
+                message
+            ),
         }
     }
 }
@@ -0,0 +1,167 @@
+//! Suggestions for writing queries more idiomatically.
+
+use std::ops::Range;
+
+use groq_parser::ast::{Expr, Literal};
+
+use crate::document::split_queries;
+use crate::lexer::{self, tokenize};
+use crate::path::{Node, children, node_range};
+use crate::{FormatError, parse_query, trim_for_addressing};
+
+/// A suggested change to a query.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lint {
+    /// Byte range in the query of the code to replace.
+    pub range: Range<usize>,
+    /// Line (1-based) on which the range starts.
+    pub line: usize,
+    /// Column (1-based, in characters) at which the range starts.
+    pub column: usize,
+    /// The code suggested instead.
+    pub replacement: String,
+    /// What is suggested and why, such as ``use `defined(slug)` instead of
+    /// `slug != null` ``.
+    pub message: String,
+}
+
+impl std::fmt::Display for Lint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Find the code in `query` that has a more idiomatic equivalent: `x != null`
+/// and `x == null`, which read better as `defined(x)` and `!defined(x)`.
+///
+/// A document of several queries, as accepted by
+/// [`format_document`](crate::format_document), is linted one query at a
+/// time, skipping those that fail to parse. Lints are in source order.
+///
+/// # Example
+///
+/// ```
+/// use groq_format::lint_query;
+///
+/// let lints = lint_query(r#"*[_type == "post" && slug != null]"#).unwrap();
+/// assert_eq!(lints[0].replacement, "defined(slug)");
+/// assert_eq!(lints[0].column, 22);
+/// ```
+pub fn lint_query(query: &str) -> Result<Vec<Lint>, FormatError> {
+    let error = match lint_single(query, 0..query.len()) {
+        Ok(lints) => return Ok(lints),
+        Err(error) => error,
+    };
+    let segments = split_queries(query);
+    if segments.len() <= 1 {
+        return Err(error);
+    }
+    let mut lints = Vec::new();
+    for range in segments {
+        lints.extend(lint_single(query, range).unwrap_or_default());
+    }
+    Ok(lints)
+}
+
+/// Apply every suggestion of [`lint_query`] to `query`. Where suggestions
+/// overlap, only the outermost is applied.
+///
+/// # Example
+///
+/// ```
+/// use groq_format::fix_query;
+///
+/// assert_eq!(
+///     fix_query("*[image == null || null != author]").unwrap(),
+///     "*[!defined(image) || defined(author)]"
+/// );
+/// ```
+pub fn fix_query(query: &str) -> Result<String, FormatError> {
+    let mut fixed = String::with_capacity(query.len());
+    let mut end = 0;
+    for lint in lint_query(query)? {
+        if lint.range.start < end {
+            continue;
+        }
+        fixed.push_str(&query[end..lint.range.start]);
+        fixed.push_str(&lint.replacement);
+        end = lint.range.end;
+    }
+    fixed.push_str(&query[end..]);
+    Ok(fixed)
+}
+
+/// Lint the query in `range` of `source`.
+fn lint_single(source: &str, range: Range<usize>) -> Result<Vec<Lint>, FormatError> {
+    let (leading, query) = trim_for_addressing(&source[range.clone()])?;
+    let result = parse_query(query)?;
+    let tokens = tokenize(query);
+    let offset = range.start + leading;
+
+    let mut lints = Vec::new();
+    visit(
+        Node::Expr(&result.expr),
+        query,
+        &tokens,
+        &mut |node_range, replacement, message| {
+            let start = node_range.start + offset;
+            let before = &source[..start];
+            let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+            lints.push(Lint {
+                range: start..node_range.end + offset,
+                line: before.matches('\n').count() + 1,
+                column: before[line_start..].chars().count() + 1,
+                replacement,
+                message,
+            });
+        },
+    );
+    Ok(lints)
+}
+
+/// Report the lints for `node` and its descendants, outermost first.
+fn visit(
+    node: Node<'_>,
+    source: &str,
+    tokens: &[lexer::Token],
+    report: &mut impl FnMut(Range<usize>, String, String),
+) {
+    if let Node::Expr(expr) | Node::Field(expr) = node
+        && let Some((operand, equal)) = null_comparison(expr)
+    {
+        let range = node_range(node, source, tokens);
+        let operand = &source[node_range(Node::Expr(operand), source, tokens)];
+        let replacement = match equal {
+            true => format!("!defined({})", operand),
+            false => format!("defined({})", operand),
+        };
+        let message = format!(
+            "use `{}` instead of `{}`",
+            replacement,
+            &source[range.clone()]
+        );
+        report(range, replacement, message);
+    }
+    for (_, child) in children(node) {
+        visit(child, source, tokens, report);
+    }
+}
+
+/// For `x == null` or `x != null`, either way around, the operand compared
+/// with `null` and whether the comparison is `==`.
+fn null_comparison(expr: &Expr) -> Option<(&Expr, bool)> {
+    let Expr::Binary(bin) = expr else {
+        return None;
+    };
+    let equal = match bin.operator.literal() {
+        "==" => true,
+        "!=" => false,
+        _ => return None,
+    };
+    let is_null = |expr: &Expr| matches!(expr, Expr::Literal(Literal::Null(_)));
+    match (is_null(&bin.lhs), is_null(&bin.rhs)) {
+        (false, true) => Some((&bin.lhs, equal)),
+        (true, false) => Some((&bin.rhs, equal)),
+        _ => None,
+    }
+}
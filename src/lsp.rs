@@ -0,0 +1,285 @@
+//! A language server that formats GROQ documents for editors, started with
+//! `groq-format --lsp`.
+//!
+//! Editors that speak the Language Server Protocol keep one server running
+//! and ask it to format a document or a selection, instead of starting the
+//! formatter for every save. The server keeps the text of the documents the
+//! editor has open and answers `textDocument/formatting` and
+//! `textDocument/rangeFormatting`; it has no other features.
+//!
+//! Messages are JSON-RPC objects on stdin and stdout, each preceded by a
+//! `Content-Length` header.
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+use groq_format::{FormatOptions, format_document, format_document_range};
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+/// Error code for a message that isn't valid JSON.
+const PARSE_ERROR: i64 = -32700;
+/// Error code for a request the server doesn't know.
+const METHOD_NOT_FOUND: i64 = -32601;
+/// Error code for request parameters that don't have the expected shape.
+const INVALID_PARAMS: i64 = -32602;
+/// Error code for a request that was understood but failed, such as
+/// formatting a document that doesn't parse.
+const REQUEST_FAILED: i64 = -32803;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TextDocumentIdentifier {
+    uri: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TextDocumentItem {
+    uri: String,
+    text: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DidOpenParams {
+    text_document: TextDocumentItem,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DidChangeParams {
+    text_document: TextDocumentIdentifier,
+    /// With full document sync, each change holds the whole new text.
+    content_changes: Vec<ContentChange>,
+}
+
+#[derive(Deserialize)]
+struct ContentChange {
+    text: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DidCloseParams {
+    text_document: TextDocumentIdentifier,
+}
+
+/// The editor's indentation settings for the document.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FormattingOptions {
+    tab_size: usize,
+    insert_spaces: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FormattingParams {
+    text_document: TextDocumentIdentifier,
+    options: FormattingOptions,
+    /// The selection, for `textDocument/rangeFormatting`.
+    range: Option<Range>,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+struct Range {
+    start: Position,
+    end: Position,
+}
+
+/// A position in a document, with the character counted in UTF-16 code
+/// units as the protocol specifies.
+#[derive(Clone, Copy, Deserialize)]
+struct Position {
+    line: usize,
+    character: usize,
+}
+
+/// Serve an editor on stdin and stdout until it sends `exit`. Documents are
+/// formatted with `options`, indented the way the editor's settings for
+/// each document say.
+pub fn serve(options: &FormatOptions) -> Result<(), Box<dyn std::error::Error>> {
+    let mut reader = io::stdin().lock();
+    let mut writer = io::stdout().lock();
+    let mut documents: HashMap<String, String> = HashMap::new();
+
+    while let Some(body) = read_message(&mut reader)? {
+        let message: Value = match serde_json::from_slice(&body) {
+            Ok(message) => message,
+            Err(e) => {
+                // Without a message there is no id to answer to.
+                let response = json!({
+                    "jsonrpc": "2.0",
+                    "id": null,
+                    "error": { "code": PARSE_ERROR, "message": e.to_string() },
+                });
+                write_message(&mut writer, &response)?;
+                continue;
+            }
+        };
+        let method = message["method"].as_str().unwrap_or_default();
+        let params = message["params"].clone();
+        let result = match method {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    // The editor sends the whole document on every change.
+                    "textDocumentSync": 1,
+                    "documentFormattingProvider": true,
+                    "documentRangeFormattingProvider": true,
+                },
+                "serverInfo": { "name": "groq-format", "version": env!("CARGO_PKG_VERSION") },
+            })),
+            "shutdown" => Ok(Value::Null),
+            "exit" => return Ok(()),
+            "textDocument/didOpen" => {
+                if let Ok(params) = serde_json::from_value::<DidOpenParams>(params) {
+                    documents.insert(params.text_document.uri, params.text_document.text);
+                }
+                continue;
+            }
+            "textDocument/didChange" => {
+                if let Ok(params) = serde_json::from_value::<DidChangeParams>(params)
+                    && let Some(change) = params.content_changes.into_iter().last()
+                {
+                    documents.insert(params.text_document.uri, change.text);
+                }
+                continue;
+            }
+            "textDocument/didClose" => {
+                if let Ok(params) = serde_json::from_value::<DidCloseParams>(params) {
+                    documents.remove(&params.text_document.uri);
+                }
+                continue;
+            }
+            "textDocument/formatting" | "textDocument/rangeFormatting" => {
+                serde_json::from_value::<FormattingParams>(params)
+                    .map_err(|e| (INVALID_PARAMS, e.to_string()))
+                    .and_then(|params| format(&documents, &params, options))
+            }
+            _ => Err((METHOD_NOT_FOUND, format!("unknown method {}", method))),
+        };
+
+        // Notifications, which have no id, get no response.
+        let Some(id) = message.get("id").cloned() else {
+            continue;
+        };
+        let response = match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err((code, message)) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": code, "message": message },
+            }),
+        };
+        write_message(&mut writer, &response)?;
+    }
+    Ok(())
+}
+
+/// The edits that format a document, or the part of it in the requested
+/// range: none if it is already formatted, or one replacing the whole text.
+fn format(
+    documents: &HashMap<String, String>,
+    params: &FormattingParams,
+    options: &FormatOptions,
+) -> Result<Value, (i64, String)> {
+    let uri = &params.text_document.uri;
+    let text = documents
+        .get(uri)
+        .ok_or_else(|| (REQUEST_FAILED, format!("{} is not open", uri)))?;
+    let options = options
+        .clone()
+        .with_indent(params.options.tab_size)
+        .with_use_tabs(!params.options.insert_spaces)
+        .with_allow_empty(true);
+
+    let formatted = match params.range {
+        Some(range) => {
+            let range = offset(text, range.start)..offset(text, range.end);
+            format_document_range(text, range, &options)
+        }
+        None => format_document(text, &options).map(|document| match document.output.is_empty() {
+            true => document.output,
+            false => format!("{}\n", document.output),
+        }),
+    }
+    .map_err(|e| (REQUEST_FAILED, e.to_string()))?;
+
+    if formatted == *text {
+        return Ok(json!([]));
+    }
+    let (line, character) = position(text, text.len());
+    Ok(json!([{
+        "range": {
+            "start": { "line": 0, "character": 0 },
+            "end": { "line": line, "character": character },
+        },
+        "newText": formatted,
+    }]))
+}
+
+/// The byte offset in `text` of a position. Positions past the end of a
+/// line are at its end, and those past the last line at the end of the text.
+fn offset(text: &str, position: Position) -> usize {
+    let mut line_start = 0;
+    for _ in 0..position.line {
+        match text[line_start..].find('\n') {
+            Some(i) => line_start += i + 1,
+            None => return text.len(),
+        }
+    }
+    let line = &text[line_start..];
+    let line = &line[..line.find('\n').unwrap_or(line.len())];
+    let mut units = 0;
+    for (i, c) in line.char_indices() {
+        if units >= position.character {
+            return line_start + i;
+        }
+        units += c.len_utf16();
+    }
+    line_start + line.len()
+}
+
+/// The line (0-based) and character, in UTF-16 code units, of a byte offset
+/// in `text`.
+fn position(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    (
+        before.matches('\n').count(),
+        before[line_start..].encode_utf16().count(),
+    )
+}
+
+/// Read the body of the next message, or `None` at the end of the input.
+fn read_message(reader: &mut impl BufRead) -> io::Result<Option<Vec<u8>>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':')
+            && name.eq_ignore_ascii_case("Content-Length")
+        {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let length = length.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "message without Content-Length")
+    })?;
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    Ok(Some(body))
+}
+
+fn write_message(writer: &mut impl Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    writer.flush()
+}
@@ -3,22 +3,78 @@
 //! Usage:
 //!     groq-format query.groq                    # Format file to stdout
 //!     groq-format -w query.groq                 # Format file in-place
+//!     groq-format -l queries/*.groq             # List files that are not formatted
+//!     groq-format -w queries/                   # Format every .groq file in a directory
+//!     groq-format --check queries/*.groq        # Fail if any file is not formatted
+//!     groq-format -d query.groq                 # Show the changes formatting would make
 //!     echo '*[_type == "article"]' | groq-format  # Format from stdin
+//!     groq-format --range L3:L5 query.groq      # Format only what lines 3 to 5 touch
+//!     groq-format --minify query.groq           # Print the query on one line, without spaces
+//!     groq-format --embedded ts -w src/         # Format the queries in TypeScript files
+//!     groq-format --emit doc query.groq         # Dump the layout document as JSON
+//!     groq-format --emit ast-json query.groq    # Dump the syntax tree, with spans, as JSON
+//!     groq-format --color query.groq | less -R  # Page through the query with colors
+//!     groq-format --emit html query.groq        # Highlight the query for a web page
+//!     groq-format --emit go --name q query.groq # Print a Go constant holding the query
+//!     groq-format --emit curl --project abc123 --dataset production --param slug=hello query.groq
+//!     groq-format --params params.json --emit request query.groq  # Build a query API request body
+//!     groq-format --explain query.groq          # Trace why each group broke (stderr)
+//!     groq-format --cache -w queries/*.groq     # Skip files already formatted last run
+//!     groq-format --staged                      # Format and re-stage staged files
+//!     groq-format --tui query.groq              # Preview the layout at adjustable widths
+//!     groq-format --from-clipboard --to-clipboard # Format the query on the clipboard
+//!     groq-format --requests session.har        # Format every query a page sent
+//!     groq-format --daemon &                    # Keep a formatter running in the background
+//!     groq-format --use-daemon -w query.groq    # Format using the running daemon
+//!     groq-format --lsp                         # Format for editors, as a language server
+//!     groq-format bundle queries/ --out src/queries.generated.ts
+//!     groq-format drift queries/ src/          # Find embedded copies that diverged
+//!     groq-format clean query.groq < query.groq  # Format as a git clean filter
+//!     groq-format explain query.groq            # Mark where each group broke and why
+
+#[cfg(unix)]
+mod daemon;
+mod diff;
+mod emit;
+mod files;
+mod git;
+mod lsp;
+mod params;
+mod summary;
+mod textfile;
+mod tui;
 
 use std::fs;
 use std::io::{self, Read, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use clap::Parser;
-use groq_format::{DEFAULT_WIDTH, FormatOptions, format_query_with_options};
+use clap::{Parser, Subcommand, ValueEnum};
+use emit::Language;
+use groq_format::{
+    BundledQuery, CallLayout, DEFAULT_CACHE_DIR, DEFAULT_WIDTH, DiskCache, FormatError,
+    FormatOptions, Highlight, InputEncoding, QueryError, QueryMetadata, QuoteStyle, Spacing,
+    StyleEdition, detect_encoding, explain_query, extract_embedded_queries,
+    extract_request_queries, find_invisible_chars, fix_query, format_document,
+    format_embedded_queries, format_query_with_options, format_range, highlight_document,
+    hoist_projections, is_formatted, lint_query, minify_query, minimum_width, overflowing_lines,
+    parse_to_json, query_fingerprint, query_metadata, query_name, query_parameters, query_to_doc,
+    typescript_module,
+};
+use summary::Summary;
 use tempfile::NamedTempFile;
+use textfile::TextFile;
+use walkdir::WalkDir;
 
 #[derive(Parser)]
 #[command(name = "groq-format")]
 #[command(about = "Format GROQ queries with adaptive line wrapping")]
 #[command(version)]
 struct Cli {
-    /// Files to format. If empty, reads from stdin.
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Files to format, directories to format the query files in, or glob
+    /// patterns such as 'queries/**/*.groq'. If empty, reads from stdin.
     #[arg(value_name = "FILE")]
     inputs: Vec<String>,
 
@@ -26,15 +82,632 @@ struct Cli {
     #[arg(short = 'w', long = "write")]
     write: bool,
 
+    /// Leave files that don't end in a line break without one, instead of
+    /// adding it
+    #[arg(long = "preserve-final-newline")]
+    preserve_final_newline: bool,
+
     /// Maximum line width
     #[arg(short = 'W', long = "width", default_value_t = DEFAULT_WIDTH)]
     width: usize,
 
+    /// Columns per level of indentation
+    #[arg(long = "indent", value_name = "N", default_value_t = 2)]
+    indent: usize,
+
+    /// Indent with tabs, each standing for --indent columns, instead of
+    /// spaces
+    #[arg(long = "use-tabs")]
+    use_tabs: bool,
+
+    /// List the files whose formatting differs instead of printing the
+    /// formatted output, like `gofmt -l`
+    #[arg(short = 'l', long = "list", conflicts_with_all = ["tui", "requests", "to_clipboard"])]
+    list: bool,
+
+    /// Print a diff of the changes formatting would make instead of the
+    /// formatted output, like `gofmt -d`. With --write, the changes written;
+    /// with --check, those of each file listed
+    #[arg(short = 'd', long = "diff", conflicts_with_all = ["tui", "requests", "to_clipboard"])]
+    diff: bool,
+
+    /// List the files whose formatting differs, like --list, and exit with
+    /// status 1 if there are any. Nothing is written. The exit status is 0 if
+    /// every file is formatted, and 1 if any is not or could not be formatted
+    #[arg(long = "check", conflicts_with_all = ["write", "tui", "requests", "to_clipboard"])]
+    check: bool,
+
+    /// Remove redundant syntax: keys that repeat the attribute they project,
+    /// as in `"title": title`, and parentheses around attributes, literals,
+    /// parameters and calls, like `gofmt -s`
+    #[arg(short = 's', long = "simplify")]
+    simplify: bool,
+
+    /// Sort the bare attributes of projections alphabetically, leaving
+    /// `...`, `"key": value` fields and conditionals where they are
+    #[arg(long = "sort-fields")]
+    sort_fields: bool,
+
     /// Wrap more aggressively: introduce break points at binary operators,
     /// filter brackets, parentheses and single-argument function calls so
     /// long expressions are broken to honor the width limit.
     #[arg(long = "force-wrap")]
     force_wrap: bool,
+
+    /// Accept trailing commas before `}`, `]` and `)` (as in queries copied
+    /// from JavaScript) and remove them instead of failing to parse
+    #[arg(long = "lenient")]
+    lenient: bool,
+
+    /// Remove zero-width characters and replace non-breaking and other
+    /// unusual spaces outside strings, reporting each one on stderr
+    #[arg(long = "strip-invisible")]
+    strip_invisible: bool,
+
+    /// Report code with a more idiomatic equivalent on stderr, such as
+    /// `slug != null`, which reads better as `defined(slug)`
+    #[arg(long = "lint")]
+    lint: bool,
+
+    /// Rewrite the code reported by `--lint` to its idiomatic equivalent
+    /// before formatting
+    #[arg(long = "fix")]
+    fix: bool,
+
+    /// Move projection objects that a query repeats verbatim into `fn`
+    /// definitions, called wherever the objects were used
+    #[arg(long = "hoist")]
+    hoist: bool,
+
+    /// Decode input that was copied out of a URL or a string literal before
+    /// formatting. `auto` decodes only input that looks encoded, with a notice
+    #[arg(long = "decode", value_enum, value_name = "ENCODING")]
+    decode: Option<Decode>,
+
+    /// Print the formatted query as a string literal, ready to paste back
+    /// into code
+    #[arg(
+        long = "escape",
+        value_enum,
+        value_name = "SYNTAX",
+        conflicts_with = "write"
+    )]
+    escape: Option<Escape>,
+
+    /// If a line cannot be made to fit the width, format at the narrowest
+    /// width at which no line overflows instead
+    #[arg(long = "clamp-width")]
+    clamp_width: bool,
+
+    /// Print the narrowest width at which no line overflows instead of the
+    /// formatted query
+    #[arg(long = "minimum-width")]
+    minimum_width: bool,
+
+    /// Warn on stderr about output lines that still exceed the width, such as
+    /// lines holding long identifiers or strings
+    #[arg(long = "report-overflow")]
+    report_overflow: bool,
+
+    /// Fail if any output line exceeds the width, listing the offending lines
+    #[arg(long = "strict-width")]
+    strict_width: bool,
+
+    /// Don't let string literals longer than the width break the groups
+    /// around them; they overflow either way
+    #[arg(long = "exempt-long-strings")]
+    exempt_long_strings: bool,
+
+    /// Always break objects and projections nested deeper than this many
+    /// levels, even if they fit on one line
+    #[arg(long = "break-depth", value_name = "N")]
+    break_depth: Option<usize>,
+
+    /// Line width for filter constraints, in place of --width
+    #[arg(long = "filter-width", value_name = "WIDTH")]
+    filter_width: Option<usize>,
+
+    /// Line width for projection bodies, in place of --width
+    #[arg(long = "projection-width", value_name = "WIDTH")]
+    projection_width: Option<usize>,
+
+    /// Line width for function arguments, in place of --width
+    #[arg(long = "argument-width", value_name = "WIDTH")]
+    argument_width: Option<usize>,
+
+    /// Keep objects and projections with a single bare attribute, such as
+    /// `category-> { title }`, on one line
+    #[arg(long = "inline-single-field")]
+    inline_single_field: bool,
+
+    /// Spacing around `=>`, `..`/`...` in slices and after `!`
+    #[arg(long = "spacing", value_enum, default_value_t = SpacingProfile::Standard)]
+    spacing: SpacingProfile,
+
+    /// Quotes to write string literals with
+    #[arg(long = "quote-style", value_enum, default_value_t = Quotes::Double)]
+    quote_style: Quotes,
+
+    /// Re-wrap runs of `//` comments with lines longer than the width
+    #[arg(long = "reflow-comments")]
+    reflow_comments: bool,
+
+    /// Line up the `&&`/`||` continuations of broken filters under the first
+    /// condition
+    #[arg(long = "align-conditions")]
+    align_conditions: bool,
+
+    /// Put the closing `]` of a filter whose conditions break on its own line
+    #[arg(long = "closing-bracket-on-own-line")]
+    closing_bracket_on_own_line: bool,
+
+    /// Lay out calls to a function differently, such as `coalesce=fill`.
+    /// Layouts are `wrap`, `one-per-line`, `flat` and `fill`. May be repeated
+    #[arg(long = "call-layout", value_name = "FUNCTION=LAYOUT", value_parser = parse_call_layout)]
+    call_layouts: Vec<(String, CallLayout)>,
+
+    /// Edition of the formatting style: 2024 keeps the layout from before
+    /// conditional bodies, long tuples and long pipelines got their own
+    #[arg(long = "style-edition", value_name = "EDITION", default_value_t = StyleEdition::default())]
+    style_edition: StyleEdition,
+
+    /// Remember which files are already formatted and skip them on later
+    /// runs while their content and the options are unchanged
+    #[arg(long = "cache")]
+    cache: bool,
+
+    /// Directory for the cache (implies --cache)
+    #[arg(long = "cache-location", value_name = "DIR")]
+    cache_location: Option<PathBuf>,
+
+    /// What to output
+    #[arg(long = "emit", value_enum, default_value_t = Emit::Text)]
+    emit: Emit,
+
+    /// Color keywords, strings, numbers, operators, field names and the
+    /// rest of the formatted query for a terminal
+    #[arg(
+        long = "color",
+        conflicts_with_all = [
+            "write", "check", "list", "diff", "escape", "minimum_width", "minify", "embedded",
+            "range", "verify", "cache", "tui", "requests", "staged", "to_clipboard", "use_daemon",
+        ]
+    )]
+    color: bool,
+
+    /// Name of the constant declared by `--emit go|php|python|csharp`
+    #[arg(
+        long = "name",
+        value_name = "NAME",
+        default_value = "query",
+        value_parser = parse_constant_name
+    )]
+    name: String,
+
+    /// Sanity project ID to query with `--emit curl`
+    #[arg(long = "project", value_name = "ID", required_if_eq("emit", "curl"))]
+    project: Option<String>,
+
+    /// Dataset to query with `--emit curl`
+    #[arg(long = "dataset", value_name = "NAME", required_if_eq("emit", "curl"))]
+    dataset: Option<String>,
+
+    /// Value of a query parameter for `--emit curl` and `--emit request`, as
+    /// JSON, overriding `--params`. Values that are not valid JSON are passed
+    /// as strings. Parameters the query uses but that are not given are
+    /// passed as `null`
+    #[arg(long = "param", value_name = "NAME=VALUE", value_parser = parse_param)]
+    params: Vec<(String, String)>,
+
+    /// JSON file with the values of the query's parameters. Warns about
+    /// parameters the query uses that are missing from the file, and about
+    /// those it doesn't use. The values are passed by `--emit curl` and
+    /// `--emit request`. With -w, -l or -d, the file is formatted as well
+    #[arg(long = "params", value_name = "FILE", conflicts_with_all = ["staged", "requests"])]
+    params_file: Option<PathBuf>,
+
+    /// Name of the file whose contents are given on stdin, for messages, as
+    /// editors pass it when they format a buffer
+    #[arg(
+        long = "stdin-filepath",
+        value_name = "PATH",
+        conflicts_with = "inputs"
+    )]
+    stdin_filepath: Option<String>,
+
+    /// Format only the expressions that overlap part of a single input,
+    /// such as an editor's selection, and leave the rest as it is. Given as
+    /// byte offsets, START:END, with END excluded, or as lines, LSTART:LEND,
+    /// with both included
+    #[arg(
+        long = "range",
+        value_name = "START:END",
+        value_parser = parse_range,
+        conflicts_with_all = [
+            "embedded", "minify", "minimum_width", "explain", "fix", "hoist", "params_file",
+            "verify", "escape", "tui", "requests", "staged",
+        ]
+    )]
+    range: Option<Selection>,
+
+    /// Read the query from the clipboard instead of stdin
+    #[arg(long = "from-clipboard", conflicts_with_all = ["inputs", "write"])]
+    from_clipboard: bool,
+
+    /// Put the formatted query on the clipboard instead of printing it
+    #[arg(long = "to-clipboard", conflicts_with_all = ["inputs", "write"])]
+    to_clipboard: bool,
+
+    /// Read inputs as HAR files or lists of request URLs, and format every
+    /// query sent in them under a comment naming its URL
+    #[arg(long = "requests", conflicts_with_all = ["write", "tui"])]
+    requests: bool,
+
+    /// Preview the formatted query full-screen and adjust the width with the
+    /// arrow keys. Enter prints the query formatted at the chosen width
+    #[arg(long = "tui")]
+    tui: bool,
+
+    /// Show faint vertical guides at each level of indentation in the
+    /// `--tui` preview, which can also be toggled there with `g`
+    #[arg(long = "indent-guides", requires = "tui")]
+    indent_guides: bool,
+
+    /// Print the query on one line without any whitespace or comments that
+    /// can be left out, for sending it in a URL, instead of formatting it
+    #[arg(long = "minify", conflicts_with_all = ["minimum_width", "explain", "tui"])]
+    minify: bool,
+
+    /// Format the queries in `groq` templates and `defineQuery()` calls in
+    /// JavaScript or TypeScript files, leaving the rest of the code as it
+    /// is. Directories are searched for files in the languages given,
+    /// separated by commas
+    #[arg(
+        long = "embedded",
+        value_name = "LANG",
+        value_delimiter = ',',
+        conflicts_with_all = [
+            "minify", "minimum_width", "explain", "lint", "fix", "hoist", "params_file",
+            "verify", "escape", "tui", "requests",
+        ]
+    )]
+    embedded: Vec<Script>,
+
+    /// Print a trace of every layout group to stderr: where it starts, its
+    /// flat width, the room available, and whether it broke
+    #[arg(long = "explain")]
+    explain: bool,
+
+    /// Check that formatting the output again leaves it unchanged, and fail
+    /// if it doesn't, which is a bug in the formatter
+    #[arg(long = "verify")]
+    verify: bool,
+
+    /// Run in the background, formatting the inputs sent by `--use-daemon`
+    /// over a Unix socket
+    #[arg(long = "daemon", conflicts_with_all = ["inputs", "write", "use_daemon"])]
+    daemon: bool,
+
+    /// Run as a language server on stdin and stdout, formatting documents
+    /// and selections for editors
+    #[arg(long = "lsp", conflicts_with_all = ["inputs", "write", "daemon", "use_daemon"])]
+    lsp: bool,
+
+    /// Have the daemon started with `--daemon` format the inputs, which
+    /// saves starting a formatter for every file. The formatting options
+    /// given here are used
+    #[arg(long = "use-daemon", conflicts_with_all = ["tui", "requests"])]
+    use_daemon: bool,
+
+    /// Socket of the daemon, for `--daemon` and `--use-daemon`. Defaults to
+    /// `groq-format.sock` in `$XDG_RUNTIME_DIR`, or in a directory of the
+    /// temporary directory that only the current user can use
+    #[arg(long = "socket", value_name = "PATH")]
+    socket: Option<PathBuf>,
+
+    /// Format the .groq files staged in the current git repository, or the
+    /// files with the extensions given with `--ext` or `--embedded`, and
+    /// stage the results, for pre-commit hooks. Unstaged changes are left
+    /// out; with `--list` or `--diff`, nothing is changed
+    #[arg(
+        long = "staged",
+        conflicts_with_all = ["inputs", "write", "tui", "requests", "from_clipboard", "to_clipboard", "daemon", "use_daemon"]
+    )]
+    staged: bool,
+
+    /// In GitHub Actions, add a Markdown report of the files that need
+    /// formatting and the queries that failed to parse to the job summary,
+    /// the file named by `$GITHUB_STEP_SUMMARY`. Does nothing elsewhere
+    #[arg(long = "github-summary", requires = "inputs")]
+    github_summary: bool,
+
+    /// Extensions of the query files collected from directories given as
+    /// inputs or to `bundle`, `inventory` and `drift`, separated by commas
+    #[arg(
+        long = "ext",
+        value_name = "EXT",
+        value_delimiter = ',',
+        default_value = "groq"
+    )]
+    extensions: Vec<String>,
+
+    /// Follow symbolic links when collecting files from directories, which
+    /// are otherwise skipped
+    #[arg(long = "follow-symlinks")]
+    follow_symlinks: bool,
+
+    /// Collect hidden files and directories, whose names start with a dot,
+    /// from directories too
+    #[arg(long = "hidden")]
+    hidden: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Generate a TypeScript module from a directory of .groq files
+    ///
+    /// Every query is formatted and exported as a `groq` template literal,
+    /// named after its `// @name` header or else its file name.
+    Bundle {
+        /// Directory to collect .groq files from, including subdirectories
+        #[arg(value_name = "DIR")]
+        dir: PathBuf,
+
+        /// File to write the module to instead of stdout
+        #[arg(long = "out", value_name = "FILE")]
+        out: Option<PathBuf>,
+    },
+
+    /// List the .groq files in a directory with their names and descriptions
+    ///
+    /// Each line holds a query's name, file and `// @description`, separated
+    /// by tabs. Fails if two queries have the same name.
+    Inventory {
+        /// Directory to collect .groq files from, including subdirectories
+        #[arg(value_name = "DIR")]
+        dir: PathBuf,
+    },
+
+    /// Report copies of queries in JavaScript and TypeScript code that have
+    /// diverged from the .groq files
+    ///
+    /// A `groq` template or `defineQuery()` string is a copy of a .groq file
+    /// if its `// @name` header or the constant it is assigned to has the
+    /// file's name. It has diverged if it differs in more than whitespace and
+    /// comments. Fails if any copy has diverged.
+    Drift {
+        /// Directory to collect .groq files from, including subdirectories
+        #[arg(value_name = "DIR")]
+        dir: PathBuf,
+
+        /// JavaScript and TypeScript files, or directories to search for them
+        #[arg(value_name = "SOURCE", required = true)]
+        sources: Vec<PathBuf>,
+    },
+
+    /// Format a query from stdin as a git clean filter
+    ///
+    /// Git runs the filter on files as they are staged, so the repository
+    /// only ever holds formatted queries. Input that cannot be formatted is
+    /// passed through unchanged with a warning, so it can still be committed.
+    Clean {
+        /// Path of the file being filtered, for messages (git's `%f`)
+        #[arg(value_name = "PATH")]
+        path: Option<PathBuf>,
+    },
+
+    /// Pass a query from stdin through unchanged as a git smudge filter
+    ///
+    /// Files are checked out as they were committed, which is already
+    /// formatted by the clean filter.
+    Smudge {
+        /// Path of the file being filtered (git's `%f`)
+        #[arg(value_name = "PATH")]
+        path: Option<PathBuf>,
+    },
+
+    /// Print a query formatted, with markers under each line showing where
+    /// every layout group starts and ends and why it broke or stayed flat
+    ///
+    /// Each marker gives the width the group needs flat and the room that
+    /// was left for it, which is what to include when reporting a layout
+    /// that looks wrong.
+    Explain {
+        /// File to explain (reads stdin if omitted)
+        #[arg(value_name = "FILE")]
+        path: Option<PathBuf>,
+    },
+}
+
+/// Output kinds selectable with `--emit`.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Emit {
+    /// The formatted query
+    Text,
+    /// The layout document tree as JSON, for debugging layout decisions
+    Doc,
+    /// The parsed syntax tree as JSON, with the source range of each node
+    AstJson,
+    /// The formatted query as HTML, in a `pre` element with a `span` for
+    /// each kind of syntax
+    Html,
+    /// A Go constant holding the formatted query
+    Go,
+    /// A PHP variable holding the formatted query
+    Php,
+    /// A Python variable holding the formatted query
+    Python,
+    /// A C# constant holding the formatted query
+    Csharp,
+    /// A curl command running the query against the Sanity query API
+    Curl,
+    /// A JSON request body for the Sanity query API, holding the query and
+    /// its parameters
+    Request,
+}
+
+impl Emit {
+    /// The language to wrap the formatted query in, if any.
+    fn language(self) -> Option<Language> {
+        match self {
+            Emit::Text | Emit::Doc | Emit::AstJson | Emit::Html | Emit::Curl | Emit::Request => {
+                None
+            }
+            Emit::Go => Some(Language::Go),
+            Emit::Php => Some(Language::Php),
+            Emit::Python => Some(Language::Python),
+            Emit::Csharp => Some(Language::CSharp),
+        }
+    }
+}
+
+fn parse_call_layout(value: &str) -> Result<(String, CallLayout), String> {
+    let (function, layout) = value
+        .split_once('=')
+        .ok_or_else(|| "must be FUNCTION=LAYOUT".to_string())?;
+    let layout = match layout {
+        "wrap" => CallLayout::Wrap,
+        "one-per-line" => CallLayout::OnePerLine,
+        "flat" => CallLayout::Flat,
+        "fill" => CallLayout::Fill,
+        _ => return Err(format!("unknown layout `{}`", layout)),
+    };
+    Ok((function.to_string(), layout))
+}
+
+fn parse_param(param: &str) -> Result<(String, String), String> {
+    let (name, value) = param
+        .split_once('=')
+        .ok_or_else(|| "must be NAME=VALUE".to_string())?;
+    let name = name.strip_prefix('$').unwrap_or(name);
+    if !emit::is_identifier(name) {
+        return Err(format!("invalid parameter name `{}`", name));
+    }
+    let value = match serde_json::from_str::<serde_json::Value>(value) {
+        Ok(_) => value.to_string(),
+        Err(_) => serde_json::Value::from(value).to_string(),
+    };
+    Ok((name.to_string(), value))
+}
+
+fn parse_range(value: &str) -> Result<Selection, String> {
+    let invalid = || "must be START:END or LSTART:LEND".to_string();
+    let (start, end) = value.split_once(':').ok_or_else(invalid)?;
+    let selection = match (start.strip_prefix('L'), end.strip_prefix('L')) {
+        (Some(start), Some(end)) => Selection::Lines(
+            start.parse().map_err(|_| invalid())?,
+            end.parse().map_err(|_| invalid())?,
+        ),
+        (None, None) => Selection::Bytes(
+            start.parse().map_err(|_| invalid())?,
+            end.parse().map_err(|_| invalid())?,
+        ),
+        _ => return Err(invalid()),
+    };
+    match selection {
+        Selection::Lines(0, _) => Err("lines are numbered from 1".to_string()),
+        Selection::Bytes(start, end) | Selection::Lines(start, end) if start > end => {
+            Err("the start is after the end".to_string())
+        }
+        selection => Ok(selection),
+    }
+}
+
+fn parse_constant_name(name: &str) -> Result<String, String> {
+    if emit::is_identifier(name) {
+        Ok(name.to_string())
+    } else {
+        Err("must be letters, digits and underscores, not starting with a digit".to_string())
+    }
+}
+
+/// Part of an input selected with `--range`.
+#[derive(Clone, Copy)]
+enum Selection {
+    /// Byte offsets, the end excluded.
+    Bytes(usize, usize),
+    /// Line numbers (1-based), both included.
+    Lines(usize, usize),
+}
+
+impl Selection {
+    /// The byte range of the selection in `input`.
+    fn byte_range(self, input: &str) -> std::ops::Range<usize> {
+        match self {
+            Selection::Bytes(start, end) => start.min(input.len())..end.min(input.len()),
+            Selection::Lines(start, end) => {
+                let line_starts: Vec<usize> = std::iter::once(0)
+                    .chain(input.match_indices('\n').map(|(i, _)| i + 1))
+                    .collect();
+                let line_start = |line: usize| line_starts.get(line - 1).copied();
+                let start = line_start(start).unwrap_or(input.len());
+                let end = line_start(end + 1).map_or(input.len(), |next| next - 1);
+                start..end.max(start)
+            }
+        }
+    }
+}
+
+/// Operator spacing profiles selectable with `--spacing`.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum SpacingProfile {
+    /// `a => b`, `[0..10]`, `!a`
+    Standard,
+    /// `a=>b`, `[0..10]`, `!a`
+    Tight,
+    /// `a => b`, `[0 .. 10]`, `! a`
+    Spacious,
+}
+
+/// Quote styles selectable with `--quote-style`.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Quotes {
+    /// `"post"`
+    Double,
+    /// `'post'`
+    Single,
+    /// The quotes each string was written with
+    Preserve,
+}
+
+/// Input encodings selectable with `--decode`.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Decode {
+    /// Detect the encoding per input
+    Auto,
+    /// Percent-encoded, as in a URL query string
+    Url,
+    /// Backslash-escaped, as in a JSON or JavaScript string
+    Escaped,
+    /// A whole quoted string literal, as copied from JSON or JavaScript code
+    Quoted,
+}
+
+/// Languages whose files `--embedded` formats the queries in.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Script {
+    /// JavaScript: .js, .jsx, .mjs and .cjs files
+    Js,
+    /// TypeScript: .ts, .tsx, .mts and .cts files
+    Ts,
+}
+
+impl Script {
+    fn extensions(self) -> &'static [&'static str] {
+        match self {
+            Script::Js => &SCRIPT_EXTENSIONS[..4],
+            Script::Ts => &SCRIPT_EXTENSIONS[4..],
+        }
+    }
+}
+
+/// String literal syntaxes selectable with `--escape`.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Escape {
+    /// A JavaScript template literal, keeping line breaks
+    Js,
+    /// A JSON string
+    Json,
 }
 
 fn main() {
@@ -45,41 +718,1202 @@ fn main() {
 }
 
 fn run() -> Result<(), Box<dyn std::error::Error>> {
-    let cli = Cli::parse();
-    let options = FormatOptions::new(cli.width).with_force_wrap(cli.force_wrap);
+    let mut cli = Cli::parse();
+    // Checking lists the files like -l, only with a different exit status.
+    cli.list |= cli.check;
+    let options = format_options(&cli);
+
+    // Query files are searched for, or with --embedded, scripts.
+    let extensions: Vec<&str> = match cli.embedded.as_slice() {
+        [] => cli
+            .extensions
+            .iter()
+            .map(|ext| ext.trim_start_matches('.'))
+            .collect(),
+        scripts => scripts
+            .iter()
+            .flat_map(|script| script.extensions())
+            .copied()
+            .collect(),
+    };
+    let ignore = files::Ignore::load(Path::new(files::IGNORE_FILE))
+        .map_err(|e| format!("{}: {}", files::IGNORE_FILE, e))?;
+    let walk = Walk {
+        extensions: &extensions,
+        follow_symlinks: cli.follow_symlinks,
+        hidden: cli.hidden,
+        ignore: &ignore,
+    };
+
+    if cli.daemon {
+        return serve_daemon(&cli);
+    }
+    if cli.use_daemon {
+        return use_daemon(&cli, walk);
+    }
+    if cli.lsp {
+        return lsp::serve(&options);
+    }
+    match &cli.command {
+        Some(Command::Bundle { dir, out }) => return bundle(dir, out.as_deref(), &options, walk),
+        Some(Command::Inventory { dir }) => return inventory(dir, walk),
+        Some(Command::Drift { dir, sources }) => return drift(dir, sources, walk),
+        Some(Command::Clean { path }) => return clean(path.as_deref(), &options),
+        Some(Command::Smudge { .. }) => {
+            io::copy(&mut io::stdin(), &mut io::stdout())?;
+            return Ok(());
+        }
+        Some(Command::Explain { path }) => return explain(path.as_deref(), &options),
+        None => {}
+    }
+
+    if cli.escape.is_some() && cli.emit != Emit::Text {
+        return Err("--escape can only be used with --emit text".into());
+    }
+    if cli.color && cli.emit != Emit::Text {
+        return Err("--color can only be used with --emit text".into());
+    }
+    if cli.minify && cli.emit != Emit::Text {
+        return Err("--minify can only be used with --emit text".into());
+    }
+    if !cli.embedded.is_empty() && cli.emit != Emit::Text {
+        return Err("--embedded can only be used with --emit text".into());
+    }
+    if cli.range.is_some() {
+        if cli.emit != Emit::Text {
+            return Err("--range can only be used with --emit text".into());
+        }
+        if cli.inputs.len() > 1 || cli.inputs.iter().any(|input| Path::new(input).is_dir()) {
+            return Err("--range can only be used with a single file".into());
+        }
+    }
+    // Whether any input needs formatting, for --check.
+    let mut unformatted = false;
+    if (cli.list || cli.diff)
+        && (cli.emit != Emit::Text || cli.escape.is_some() || cli.minimum_width)
+    {
+        return Err("--list and --diff can only be used with --emit text".into());
+    }
+    if let Some(path) = &cli.params_file {
+        if cli.inputs.len() > 1 || cli.inputs.iter().any(|input| Path::new(input).is_dir()) {
+            return Err("--params can only be used with a single query".into());
+        }
+        unformatted |= format_params_file(&cli, path)?;
+    }
+
+    if cli.staged {
+        return format_staged(&cli, &options, &extensions);
+    }
+
+    if cli.tui {
+        let input = match cli.inputs.as_slice() {
+            [] => {
+                let mut input = String::new();
+                io::stdin().read_to_string(&mut input)?;
+                input
+            }
+            [path] => fs::read_to_string(path)?,
+            _ => return Err("--tui takes a single input".into()),
+        };
+        if let Some(formatted) = tui::run(&input, options, cli.indent_guides)? {
+            println!("{}", formatted);
+        }
+        return Ok(());
+    }
+
+    if cli.requests {
+        return format_requests(&cli, &options);
+    }
 
     if cli.inputs.is_empty() {
+        let (input, name) = if cli.from_clipboard {
+            (arboard::Clipboard::new()?.get_text()?, "<clipboard>")
+        } else {
+            let mut input = String::new();
+            io::stdin().read_to_string(&mut input)?;
+            (input, cli.stdin_filepath.as_deref().unwrap_or("<stdin>"))
+        };
+
+        // Editors pipe whole buffers through stdin, including empty ones.
+        let options = options.with_allow_empty(true);
+        let rendered = render(&input, name, &cli, &options)?;
+        if cli.to_clipboard {
+            arboard::Clipboard::new()?.set_text(rendered.output.as_str())?;
+        } else if cli.list || cli.diff {
+            let formatted = format!("{}\n", rendered.output);
+            unformatted |= report_changes(&cli, name, &input, &formatted);
+        } else if !rendered.output.is_empty() {
+            println!("{}", rendered.output);
+        }
+        rendered.result()?;
+    } else {
+        let mut cache = if cli.cache || cli.cache_location.is_some() {
+            let dir = cli
+                .cache_location
+                .clone()
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_CACHE_DIR));
+            Some(DiskCache::open(dir)?)
+        } else {
+            None
+        };
+
+        let summary_path = std::env::var_os("GITHUB_STEP_SUMMARY")
+            .filter(|_| cli.github_summary)
+            .map(PathBuf::from);
+        let mut summary = summary_path.as_ref().map(|_| Summary::default());
+
+        let paths = input_files(&cli.inputs, walk)?;
+        let mut tally = Tally::default();
+        for path in &paths {
+            match process_file(path, &cli, &options, cache.as_mut(), summary.as_mut()) {
+                Ok(changed) => tally.add(changed),
+                Err(e) => {
+                    eprintln!("groq-format: {}", e);
+                    tally.failed += 1;
+                }
+            }
+        }
+        unformatted |= tally.changed > 0;
+        if (cli.write || cli.check) && paths.len() > 1 {
+            eprintln!("groq-format: {}", tally.report(cli.check));
+        }
+        if let (Some(summary), Some(path)) = (&summary, &summary_path)
+            && let Err(e) = summary.append_to(path, paths.len())
+        {
+            eprintln!(
+                "groq-format: warning: could not write the job summary: {}",
+                e
+            );
+        }
+        if let Some(cache) = &cache
+            && let Err(e) = cache.save()
+        {
+            eprintln!("groq-format: warning: could not save cache: {}", e);
+        }
+        if tally.failed > 0 {
+            std::process::exit(1);
+        }
+    }
+
+    if cli.check && unformatted {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// The formatting options selected on the command line.
+fn format_options(cli: &Cli) -> FormatOptions {
+    FormatOptions::new(cli.width)
+        .with_indent(cli.indent)
+        .with_use_tabs(cli.use_tabs)
+        .with_force_wrap(cli.force_wrap)
+        .with_lenient(cli.lenient)
+        .with_strip_invisible(cli.strip_invisible)
+        .with_clamp_width(cli.clamp_width)
+        .with_strict_width(cli.strict_width)
+        .with_exempt_long_strings(cli.exempt_long_strings)
+        .with_break_depth(cli.break_depth)
+        .with_filter_width(cli.filter_width)
+        .with_projection_width(cli.projection_width)
+        .with_argument_width(cli.argument_width)
+        .with_inline_single_field(cli.inline_single_field)
+        .with_reflow_comments(cli.reflow_comments)
+        .with_align_conditions(cli.align_conditions)
+        .with_closing_bracket_on_own_line(cli.closing_bracket_on_own_line)
+        .with_style_edition(cli.style_edition)
+        .with_simplify(cli.simplify)
+        .with_sort_fields(cli.sort_fields)
+        .with_call_layouts(&cli.call_layouts)
+        .with_spacing(match cli.spacing {
+            SpacingProfile::Standard => Spacing::Standard,
+            SpacingProfile::Tight => Spacing::Tight,
+            SpacingProfile::Spacious => Spacing::Spacious,
+        })
+        .with_quote_style(match cli.quote_style {
+            Quotes::Double => QuoteStyle::Double,
+            Quotes::Single => QuoteStyle::Single,
+            Quotes::Preserve => QuoteStyle::Preserve,
+        })
+        .with_decode(match cli.decode {
+            Some(Decode::Url) => Some(InputEncoding::Url),
+            Some(Decode::Escaped) => Some(InputEncoding::Escaped),
+            Some(Decode::Quoted) => Some(InputEncoding::Quoted),
+            Some(Decode::Auto) | None => None,
+        })
+}
+
+/// Format the inputs sent by clients on the daemon socket.
+#[cfg(unix)]
+fn serve_daemon(cli: &Cli) -> Result<(), Box<dyn std::error::Error>> {
+    let socket = match &cli.socket {
+        Some(socket) => socket.clone(),
+        None => daemon::default_socket()?,
+    };
+    daemon::serve(&socket)
+}
+
+/// Format the inputs with the daemon, like plain formatting without it.
+#[cfg(unix)]
+fn use_daemon(cli: &Cli, walk: Walk) -> Result<(), Box<dyn std::error::Error>> {
+    if cli.emit != Emit::Text
+        || cli.escape.is_some()
+        || cli.minimum_width
+        || cli.explain
+        || cli.lint
+        || cli.fix
+        || cli.hoist
+        || cli.params_file.is_some()
+        || cli.verify
+        || cli.minify
+        || !cli.embedded.is_empty()
+        || cli.range.is_some()
+    {
+        return Err("--use-daemon can only be used to format, with --emit text".into());
+    }
+    let socket = match &cli.socket {
+        Some(socket) => socket.clone(),
+        None => daemon::default_socket()?,
+    };
+    let mut client = daemon::Client::connect(&socket, std::env::args().skip(1).collect())?;
+
+    if cli.inputs.is_empty() {
+        let mut input = String::new();
+        io::stdin().read_to_string(&mut input)?;
+        let name = cli.stdin_filepath.as_deref().unwrap_or("<stdin>");
+        let formatted = client.format(name, &input, true)?;
+        if !formatted.output.is_empty() {
+            println!("{}", formatted.output);
+        }
+        for error in &formatted.errors {
+            eprintln!("{}", error);
+        }
+        if !formatted.errors.is_empty() {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let paths = input_files(&cli.inputs, walk)?;
+    let mut tally = Tally::default();
+    for path in &paths {
+        let name = path.display().to_string();
+        let source = match textfile::read(path, cli.preserve_final_newline) {
+            Ok(source) => source,
+            Err(e) => {
+                eprintln!("groq-format: {}: {}", name, e);
+                tally.failed += 1;
+                continue;
+            }
+        };
+        let formatted = match client.format(&name, &source.text, false) {
+            Ok(formatted) => formatted,
+            Err(e) => {
+                eprintln!("groq-format: {}", e);
+                tally.failed += 1;
+                continue;
+            }
+        };
+        for error in &formatted.errors {
+            eprintln!("{}", error);
+        }
+        let changed = finish_file(cli, path, &source, &format!("{}\n", formatted.output))?;
+        match formatted.errors.is_empty() {
+            true => tally.add(changed),
+            false => tally.failed += 1,
+        }
+    }
+    if (cli.write || cli.check) && paths.len() > 1 {
+        eprintln!("groq-format: {}", tally.report(cli.check));
+    }
+    if tally.failed > 0 || (cli.check && tally.changed > 0) {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn serve_daemon(_cli: &Cli) -> Result<(), Box<dyn std::error::Error>> {
+    Err("the daemon is only supported on Unix".into())
+}
+
+#[cfg(not(unix))]
+fn use_daemon(_cli: &Cli, _walk: Walk) -> Result<(), Box<dyn std::error::Error>> {
+    Err("the daemon is only supported on Unix".into())
+}
+
+/// Write a TypeScript module exporting the formatted queries in `dir`.
+fn bundle(
+    dir: &Path,
+    out: Option<&Path>,
+    options: &FormatOptions,
+    walk: Walk,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut queries: Vec<BundledQuery> = Vec::new();
+    for named in named_queries(dir, walk)? {
+        let query = format_query_with_options(&named.source, options)
+            .map_err(|e| format!("{}: {}", named.path.display(), e))?;
+        queries.push(BundledQuery {
+            name: named.name,
+            description: named.metadata.description,
+            query,
+        });
+    }
+
+    let module = typescript_module(&queries);
+    match out {
+        Some(out) => fs::write(out, module)?,
+        None => print!("{}", module),
+    }
+    Ok(())
+}
+
+/// Format stdin to stdout for git's clean filter. Input that cannot be
+/// formatted is passed through unchanged rather than failing, since git would
+/// then refuse to stage the file.
+fn clean(path: Option<&Path>, options: &FormatOptions) -> Result<(), Box<dyn std::error::Error>> {
+    let name = path.map_or("<stdin>".to_string(), |path| path.display().to_string());
+    let mut input = String::new();
+    io::stdin().read_to_string(&mut input)?;
+
+    let options = options.clone().with_allow_empty(true);
+    let output = match format_document(&input, &options) {
+        Ok(document) if document.errors.is_empty() && !document.output.is_empty() => {
+            format!("{}\n", document.output)
+        }
+        Ok(document) if document.errors.is_empty() => document.output,
+        Ok(document) => {
+            for err in &document.errors {
+                eprintln!(
+                    "{}:{}: warning: left unformatted: {}",
+                    name, err.line, err.error
+                );
+            }
+            input
+        }
+        Err(e) => {
+            eprintln!("{}: warning: left unformatted: {}", name, e);
+            input
+        }
+    };
+    io::stdout().write_all(output.as_bytes())?;
+    Ok(())
+}
+
+/// Print the query in the file at `path`, or on stdin, annotated with the
+/// layout decision made for each group.
+fn explain(path: Option<&Path>, options: &FormatOptions) -> Result<(), Box<dyn std::error::Error>> {
+    let input = match path {
+        Some(path) => {
+            textfile::read(path, false)
+                .map_err(|e| format!("{}: {}", path.display(), e))?
+                .text
+        }
+        None => {
+            let mut input = String::new();
+            io::stdin().read_to_string(&mut input)?;
+            input
+        }
+    };
+    print!("{}", explain_query(&input, options)?.annotated());
+    Ok(())
+}
+
+/// Print the name, file and description of each query in `dir`, one per
+/// line, separated by tabs.
+fn inventory(dir: &Path, walk: Walk) -> Result<(), Box<dyn std::error::Error>> {
+    for named in named_queries(dir, walk)? {
+        println!(
+            "{}\t{}\t{}",
+            named.name,
+            named.path.display(),
+            named.metadata.description.unwrap_or_default()
+        );
+    }
+    Ok(())
+}
+
+/// Report the copies of the queries in `dir` found in `sources` that have
+/// diverged from them, exiting with an error if there are any.
+fn drift(dir: &Path, sources: &[PathBuf], walk: Walk) -> Result<(), Box<dyn std::error::Error>> {
+    let queries = named_queries(dir, walk)?;
+    let mut paths = Vec::new();
+    for source in sources {
+        if source.is_dir() {
+            collect_files(
+                source,
+                &|path| has_extension(path, SCRIPT_EXTENSIONS),
+                walk,
+                &mut paths,
+            )?;
+        } else {
+            paths.push(source.clone());
+        }
+    }
+    paths.sort();
+
+    let mut diverged = 0;
+    for path in &paths {
+        let source = fs::read_to_string(path)?;
+        for embedded in extract_embedded_queries(&source) {
+            let Some(name) = &embedded.name else {
+                continue;
+            };
+            let Some(original) = queries.iter().find(|query| &query.name == name) else {
+                continue;
+            };
+            if query_fingerprint(&embedded.query) != query_fingerprint(&original.source) {
+                eprintln!(
+                    "{}:{}: `{}` has diverged from {}",
+                    path.display(),
+                    embedded.line,
+                    name,
+                    original.path.display()
+                );
+                diverged += 1;
+            }
+        }
+    }
+    match diverged {
+        0 => Ok(()),
+        1 => Err("1 copy has diverged".into()),
+        n => Err(format!("{} copies have diverged", n).into()),
+    }
+}
+
+/// Extensions of the JavaScript and TypeScript files searched for queries.
+const SCRIPT_EXTENSIONS: &[&str] = &["js", "jsx", "mjs", "cjs", "ts", "tsx", "mts", "cts"];
+
+/// A query file and the name it is exported under.
+struct NamedQuery {
+    path: PathBuf,
+    source: String,
+    name: String,
+    metadata: QueryMetadata,
+}
+
+/// Read the query files in `dir`, sorted by path, checking that every
+/// query has a unique name that is a valid identifier.
+fn named_queries(dir: &Path, walk: Walk) -> Result<Vec<NamedQuery>, Box<dyn std::error::Error>> {
+    let mut paths = Vec::new();
+    collect_files(
+        dir,
+        &|path| has_extension(path, walk.extensions),
+        walk,
+        &mut paths,
+    )?;
+    paths.sort();
+
+    let mut queries: Vec<NamedQuery> = Vec::with_capacity(paths.len());
+    for path in paths {
+        let source = fs::read_to_string(&path)?;
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let name = query_name(&stem, &source);
+        if !emit::is_identifier(&name) {
+            return Err(format!(
+                "{}: `{}` is not a valid constant name",
+                path.display(),
+                name
+            )
+            .into());
+        }
+        if let Some(other) = queries.iter().find(|query| query.name == name) {
+            return Err(format!(
+                "{}: the name `{}` is already used by {}",
+                path.display(),
+                name,
+                other.path.display()
+            )
+            .into());
+        }
+        let metadata = query_metadata(&source);
+        queries.push(NamedQuery {
+            path,
+            source,
+            name,
+            metadata,
+        });
+    }
+    Ok(queries)
+}
+
+/// How directories are searched for files.
+#[derive(Clone, Copy)]
+struct Walk<'a> {
+    /// Extensions of query files, without the dot.
+    extensions: &'a [&'a str],
+    follow_symlinks: bool,
+    /// Whether files and directories whose names start with a dot are
+    /// searched too.
+    hidden: bool,
+    /// Files and directories that are skipped.
+    ignore: &'a files::Ignore,
+}
+
+/// Find the files that `include` accepts in a directory and its
+/// subdirectories, skipping `node_modules` and what the ignore file lists.
+fn collect_files(
+    dir: &Path,
+    include: &dyn Fn(&Path) -> bool,
+    walk: Walk,
+    paths: &mut Vec<PathBuf>,
+) -> io::Result<()> {
+    let entries = WalkDir::new(dir)
+        .follow_links(walk.follow_symlinks)
+        .into_iter()
+        .filter_entry(|entry| {
+            if entry.depth() == 0 {
+                return true;
+            }
+            let name = entry.file_name();
+            let is_dir = entry.file_type().is_dir();
+            // Without --follow-symlinks, links are skipped.
+            let skipped_link = entry.path_is_symlink() && !walk.follow_symlinks;
+            let hidden = !walk.hidden && name.to_string_lossy().starts_with('.');
+            let dependencies = is_dir && name == "node_modules";
+            !(skipped_link
+                || hidden
+                || dependencies
+                || walk.ignore.is_ignored(entry.path(), is_dir))
+        });
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            // Links can lead back to a directory that is already being
+            // searched, or to nothing.
+            Err(e)
+                if e.loop_ancestor().is_some()
+                    || e.path()
+                        .is_some_and(|path| path.is_symlink() && fs::metadata(path).is_err()) =>
+            {
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if !entry.file_type().is_dir() && include(entry.path()) {
+            paths.push(entry.into_path());
+        }
+    }
+    Ok(())
+}
+
+/// Whether the file at `path` has one of `extensions`.
+fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .is_some_and(|ext| extensions.iter().any(|e| ext == *e))
+}
+
+/// The files to format for the inputs given: files as they are, the query
+/// files in directories, and the files matching glob patterns such as
+/// `'queries/**/*.groq'`, quoted so the shell leaves them alone. Files from
+/// directories and patterns are in order of their paths.
+fn input_files(inputs: &[String], walk: Walk) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let mut paths = Vec::with_capacity(inputs.len());
+    for input in inputs {
+        let path = Path::new(input);
+        let start = paths.len();
+        if path.is_dir() {
+            collect_files(
+                path,
+                &|path| has_extension(path, walk.extensions),
+                walk,
+                &mut paths,
+            )
+            .map_err(|e| format!("{}: {}", input, e))?;
+        } else if !path.exists() && files::is_glob(input) {
+            let pattern = files::Pattern::new(input).map_err(|e| format!("{}: {}", input, e))?;
+            let base = files::glob_base(input);
+            if base.is_dir() {
+                collect_files(&base, &|path| pattern.matches(path), walk, &mut paths)
+                    .map_err(|e| format!("{}: {}", input, e))?;
+            }
+            if paths.len() == start {
+                return Err(format!("{}: no files match the pattern", input).into());
+            }
+        } else {
+            paths.push(path.to_path_buf());
+        }
+        paths[start..].sort();
+    }
+    Ok(paths)
+}
+
+/// How many files formatting changed, left unchanged, and failed to format.
+#[derive(Default)]
+struct Tally {
+    changed: usize,
+    unchanged: usize,
+    failed: usize,
+}
+
+impl Tally {
+    fn add(&mut self, changed: bool) {
+        match changed {
+            true => self.changed += 1,
+            false => self.unchanged += 1,
+        }
+    }
+
+    /// A line such as `2 files formatted, 5 unchanged`, or with `check`,
+    /// `2 files need formatting, 5 formatted`.
+    fn report(&self, check: bool) -> String {
+        let files = |n: usize| match n {
+            1 => "1 file".to_string(),
+            n => format!("{} files", n),
+        };
+        let mut parts = Vec::new();
+        match check {
+            true => {
+                parts.push(format!("{} need formatting", files(self.changed)));
+                parts.push(format!("{} formatted", self.unchanged));
+            }
+            false => {
+                parts.push(format!("{} formatted", files(self.changed)));
+                parts.push(format!("{} unchanged", self.unchanged));
+            }
+        }
+        if self.failed > 0 {
+            parts.push(format!("{} failed", self.failed));
+        }
+        parts.join(", ")
+    }
+}
+
+/// Format the queries found in recorded requests, each under a comment
+/// naming the URL it was sent to.
+fn format_requests(cli: &Cli, options: &FormatOptions) -> Result<(), Box<dyn std::error::Error>> {
+    let inputs = if cli.inputs.is_empty() {
         let mut input = String::new();
         io::stdin().read_to_string(&mut input)?;
+        vec![input]
+    } else {
+        cli.inputs
+            .iter()
+            .map(fs::read_to_string)
+            .collect::<Result<_, _>>()?
+    };
+
+    let mut failed = false;
+    let mut first = true;
+    for request in inputs
+        .iter()
+        .flat_map(|input| extract_request_queries(input))
+    {
+        match render(&request.query, &request.url, cli, options) {
+            Ok(rendered) => {
+                if !first {
+                    println!();
+                }
+                first = false;
+                println!("// {}\n{}", request.url, rendered.output);
+                if let Err(e) = rendered.result() {
+                    eprintln!("groq-format: {}", e);
+                    failed = true;
+                }
+            }
+            Err(e) => {
+                eprintln!("groq-format: {}: {}", request.url, e);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// Output produced for one input, and the queries that were skipped.
+struct Rendered {
+    output: String,
+    name: String,
+    /// Queries that could not be formatted and were kept verbatim.
+    errors: Vec<QueryError>,
+}
+
+impl Rendered {
+    /// An error if any query had to be skipped.
+    fn result(&self) -> Result<(), Box<dyn std::error::Error>> {
+        match self.errors.len() {
+            0 => Ok(()),
+            1 => Err(format!("{}: 1 query could not be formatted", self.name).into()),
+            n => Err(format!("{}: {} queries could not be formatted", self.name, n).into()),
+        }
+    }
+}
+
+/// Produce the requested output for one input. Queries that fail to parse
+/// are reported on stderr and kept verbatim.
+fn render(
+    input: &str,
+    name: &str,
+    cli: &Cli,
+    options: &FormatOptions,
+) -> Result<Rendered, Box<dyn std::error::Error>> {
+    if !cli.embedded.is_empty() {
+        let document = format_embedded_queries(input, options);
+        for err in &document.errors {
+            eprintln!("{}:{}: {}", name, err.line, err.error);
+        }
+        // The code keeps its own final line break, which is added back when
+        // the output is printed or written.
+        let output = document
+            .output
+            .strip_suffix('\n')
+            .unwrap_or(&document.output);
+        return Ok(Rendered {
+            output: output.to_string(),
+            name: name.to_string(),
+            errors: document.errors,
+        });
+    }
+    if let Some(selection) = cli.range {
+        let output = format_range(input, selection.byte_range(input), options)?;
+        let output = output.strip_suffix('\n').unwrap_or(&output);
+        return Ok(Rendered {
+            output: output.to_string(),
+            name: name.to_string(),
+            errors: Vec::new(),
+        });
+    }
+
+    let detected;
+    let options = if cli.decode == Some(Decode::Auto)
+        && let Some(encoding) = detect_encoding(input)
+    {
+        eprintln!("{}: note: decoding {} input", name, encoding);
+        detected = options.clone().with_decode(Some(encoding));
+        &detected
+    } else {
+        options
+    };
+
+    if options.strip_invisible {
+        for ch in find_invisible_chars(input) {
+            eprintln!(
+                "{}:{}:{}: warning: removed U+{:04X} {}",
+                name,
+                ch.line,
+                ch.column,
+                ch.ch as u32,
+                ch.name()
+            );
+        }
+    }
+
+    if cli.lint {
+        // Queries that fail to parse are reported by formatting.
+        for lint in lint_query(input).unwrap_or_default() {
+            eprintln!("{}:{}:{}: warning: {}", name, lint.line, lint.column, lint);
+        }
+    }
+    let params_file = cli.params_file.as_deref().map(params::read).transpose()?;
+    if let (Some(path), Some(params)) = (&cli.params_file, &params_file) {
+        params.check(name, path, &query_parameters(input));
+    }
 
-        let formatted = format_query_with_options(&input, &options)?;
-        println!("{}", formatted);
+    let fixed;
+    let input = if cli.fix {
+        fixed = fix_query(input).unwrap_or_else(|_| input.to_string());
+        &fixed
     } else {
-        for input in &cli.inputs {
-            process_file(Path::new(input), cli.write, &options)?;
+        input
+    };
+    let hoisted;
+    let input = if cli.hoist {
+        hoisted = hoist_projections(input).unwrap_or_else(|_| input.to_string());
+        &hoisted
+    } else {
+        input
+    };
+
+    if cli.explain {
+        print_explanation(input, options)?;
+    }
+
+    if cli.minify {
+        let output = minify_query(input)?;
+        return Ok(Rendered {
+            output: match cli.escape {
+                Some(escape) => escape_output(&output, escape)?,
+                None => output,
+            },
+            name: name.to_string(),
+            errors: Vec::new(),
+        });
+    }
+
+    if cli.minimum_width {
+        return Ok(Rendered {
+            output: minimum_width(input, options)?.to_string(),
+            name: name.to_string(),
+            errors: Vec::new(),
+        });
+    }
+
+    let (output, errors) = match cli.emit {
+        Emit::Text | Emit::Go | Emit::Php | Emit::Python | Emit::Csharp => {
+            let document = match cli.color {
+                true => highlight_document(input, options, Highlight::Ansi)?,
+                false => format_document(input, options)?,
+            };
+            for err in &document.errors {
+                eprintln!("{}:{}: {}", name, err.line, err.error);
+            }
+            if cli.verify {
+                verify_document(&document.output, options)?;
+            }
+            if cli.report_overflow {
+                for overflow in overflowing_lines(&document.output, options.max_width()) {
+                    eprintln!(
+                        "{}:{}: warning: line is {} columns, exceeding the width of {}",
+                        name,
+                        overflow.line,
+                        overflow.width,
+                        options.max_width()
+                    );
+                }
+            }
+            let output = match (cli.emit.language(), cli.escape) {
+                (Some(language), _) => emit::constant(language, &cli.name, &document.output),
+                (None, Some(escape)) => escape_output(&document.output, escape)?,
+                (None, None) => document.output,
+            };
+            (output, document.errors)
+        }
+        Emit::Doc => {
+            let doc = query_to_doc(input, options)?;
+            (serde_json::to_string_pretty(&doc)?, Vec::new())
+        }
+        Emit::AstJson => (parse_to_json(input)?, Vec::new()),
+        Emit::Html => {
+            let document = highlight_document(input, options, Highlight::Html)?;
+            for err in &document.errors {
+                eprintln!("{}:{}: {}", name, err.line, err.error);
+            }
+            let output = format!("<pre class=\"groq\">{}</pre>", document.output);
+            (output, document.errors)
+        }
+        Emit::Curl | Emit::Request => {
+            let formatted = format_query_with_options(input, options)?;
+            let values: Vec<(&str, String)> = query_parameters(&formatted)
+                .into_iter()
+                .map(|name| {
+                    let value = cli
+                        .params
+                        .iter()
+                        .find(|(param, _)| param == name)
+                        .map(|(_, value)| value.clone())
+                        .or_else(|| Some(params_file.as_ref()?.values.get(name)?.to_string()))
+                        .unwrap_or_else(|| "null".to_string());
+                    (name, value)
+                })
+                .collect();
+            let params: Vec<(&str, &str)> = values
+                .iter()
+                .map(|(name, value)| (*name, value.as_str()))
+                .collect();
+            let output = if cli.emit == Emit::Request {
+                emit::request(&formatted, &params)
+            } else {
+                let project = cli.project.as_deref().unwrap_or_default();
+                let dataset = cli.dataset.as_deref().unwrap_or_default();
+                emit::curl(project, dataset, &formatted, &params)
+            };
+            (output, Vec::new())
         }
+    };
+
+    Ok(Rendered {
+        output,
+        name: name.to_string(),
+        errors,
+    })
+}
+
+/// Check that formatting the formatted output of a document again leaves it
+/// unchanged.
+fn verify_document(output: &str, options: &FormatOptions) -> Result<(), FormatError> {
+    // The output is already decoded.
+    let again = format_document(output, &options.clone().with_decode(None))?;
+    if again.output != output {
+        return Err(FormatError::NotIdempotent {
+            output: output.to_string(),
+            reformatted: again.output,
+        });
     }
+    Ok(())
+}
 
+/// Write formatted output as a string literal in the given syntax.
+fn escape_output(output: &str, escape: Escape) -> Result<String, serde_json::Error> {
+    match escape {
+        Escape::Json => serde_json::to_string(output),
+        Escape::Js => Ok(format!(
+            "`{}`",
+            output
+                .replace('\\', "\\\\")
+                .replace('`', "\\`")
+                .replace("${", "\\${")
+        )),
+    }
+}
+
+/// Print the layout trace for a query to stderr.
+fn print_explanation(
+    input: &str,
+    options: &FormatOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let explanation = explain_query(input, options)?;
+    for group in &explanation.groups {
+        let needed = match group.flat_width {
+            Some(width) => format!("needs {} cols", width),
+            None => "contains a hard line break".to_string(),
+        };
+        eprintln!(
+            "{}:{}: group {}, {} available -> {}",
+            group.line,
+            group.column,
+            needed,
+            group.available,
+            if group.broke { "broken" } else { "flat" }
+        );
+    }
     Ok(())
 }
 
-fn process_file(
-    path: &Path,
-    write: bool,
+/// Format the files with `extensions` staged in the current repository and
+/// stage the results, exiting with an error if any could not be formatted.
+fn format_staged(
+    cli: &Cli,
     options: &FormatOptions,
+    extensions: &[&str],
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let input = fs::read_to_string(path)?;
-    let formatted = format_query_with_options(&input, options)?;
-
-    if write {
-        // Write atomically: write to temp file in same dir, then rename
-        let dir = path.parent().unwrap_or(Path::new("."));
-        let mut temp = NamedTempFile::new_in(dir)?;
-        writeln!(temp, "{}", formatted)?;
-        temp.persist(path)?;
+    if cli.emit != Emit::Text || cli.escape.is_some() || cli.minimum_width {
+        return Err("--staged can only be used with --emit text".into());
+    }
+    let root = git::toplevel()?;
+    let mut failed = false;
+    for file in git::staged_files(&root, extensions)? {
+        match format_staged_file(&root, &file, cli, options) {
+            Ok(changed) => failed |= cli.check && changed,
+            Err(e) => {
+                eprintln!("groq-format: {}", e);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// Format the staged version of a file and stage the result. The working
+/// tree copy is formatted too, unless it has changes that are not staged.
+/// Returns whether the staged version needed formatting.
+fn format_staged_file(
+    root: &Path,
+    file: &git::StagedFile,
+    cli: &Cli,
+    options: &FormatOptions,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let contents = file.contents(root)?;
+    let staged = textfile::from_bytes(&contents, cli.preserve_final_newline)
+        .map_err(|e| format!("{}: {}", file.path, e))?;
+    let rendered = render(&staged.text, &file.path, cli, options)
+        .map_err(|e| format!("{}: {}", file.path, e))?;
+    // A file with queries that were kept verbatim is left as it is.
+    rendered.result()?;
+    let formatted = format!("{}\n", rendered.output);
+    let changed = report_changes(cli, &file.path, &staged.text, &formatted);
+    if !changed || cli.list || cli.diff {
+        return Ok(changed);
+    }
+
+    // Both copies keep the encoding and line endings the file was staged in.
+    let formatted = staged.style.encode(&formatted);
+    file.stage(root, &formatted)?;
+    let path = root.join(&file.path);
+    if fs::read(&path).is_ok_and(|working| working == contents) {
+        write_file(&path, &formatted)?;
     } else {
-        println!("{}", formatted);
+        eprintln!(
+            "{}: note: only the staged version was formatted, as the file has unstaged changes",
+            file.path
+        );
+    }
+    Ok(true)
+}
+
+/// Format a file as requested. Returns whether it needed formatting.
+fn process_file(
+    path: &Path,
+    cli: &Cli,
+    options: &FormatOptions,
+    mut cache: Option<&mut DiskCache>,
+    summary: Option<&mut Summary>,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let file = textfile::read(path, cli.preserve_final_newline)?;
+    let input = &file.text;
+
+    if cli.write && (cli.emit != Emit::Text || cli.minimum_width) {
+        return Err("--write can only be used with --emit text".into());
+    }
+
+    // The cache only records files that format to themselves, so it can
+    // only stand in for plain formatting without diagnostics.
+    if !uses_cache(cli) {
+        cache = None;
+    }
+    if let Some(cache) = &cache
+        && cache.is_clean(input, options)
+    {
+        if prints_output(cli) {
+            print!("{}", input);
+        }
+        return Ok(false);
+    }
+
+    // Most files in a formatted tree are unchanged; check that without
+    // building the output. Documents with several queries, or that fail to
+    // parse, go through the full path below.
+    if let Some(query) = input.strip_suffix('\n')
+        && uses_cache(cli)
+        && matches!(is_formatted(query, options), Ok(true))
+    {
+        if let Some(cache) = cache {
+            cache.mark_clean(input, options);
+        }
+        if prints_output(cli) {
+            print!("{}", input);
+        }
+        return Ok(false);
+    }
+
+    let name = path.display().to_string();
+    let rendered = match render(input, &name, cli, options) {
+        Ok(rendered) => rendered,
+        Err(e) => {
+            if let Some(summary) = summary {
+                summary.error(&name, input, 1, &e.to_string());
+            }
+            return Err(format!("{}: {}", name, e).into());
+        }
+    };
+    let formatted = format!("{}\n", rendered.output);
+    if let Some(cache) = cache
+        && rendered.errors.is_empty()
+        && formatted == *input
+    {
+        cache.mark_clean(&formatted, options);
+    }
+    if let Some(summary) = summary {
+        summary.file(&name, input, &formatted, &rendered.errors);
     }
 
+    let changed = finish_file(cli, path, &file, &formatted)?;
+    rendered.result()?;
+    Ok(changed)
+}
+
+/// Write, list, diff or print a formatted file, as requested. Files are
+/// written back in the encoding and line endings they were read in. Returns
+/// whether formatting changed the file.
+fn finish_file(
+    cli: &Cli,
+    path: &Path,
+    input: &TextFile,
+    formatted: &str,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let changed = report_changes(cli, &path.display().to_string(), &input.text, formatted);
+    if cli.write {
+        if changed {
+            write_file(path, &input.style.encode(formatted))?;
+        }
+    } else if prints_output(cli) {
+        print!("{}", formatted);
+    }
+    Ok(changed)
+}
+
+/// With -w, -l or -d, format the params file at `path` like the query.
+/// Returns whether it needed formatting.
+fn format_params_file(cli: &Cli, path: &Path) -> Result<bool, Box<dyn std::error::Error>> {
+    if prints_output(cli) {
+        return Ok(false);
+    }
+    let params = params::read(path)?;
+    finish_file(cli, path, &params.text, &params.formatted()?)
+}
+
+/// With `--list`, print the name of an input that formatting changes, and
+/// with `--diff`, the changes, in the style of `gofmt`. Returns whether
+/// formatting changes the input.
+fn report_changes(cli: &Cli, name: &str, input: &str, formatted: &str) -> bool {
+    if input == formatted {
+        return false;
+    }
+    if cli.list {
+        println!("{}", name);
+    }
+    if cli.diff {
+        let original = format!("{}.orig", name);
+        println!("diff -u {} {}", original, name);
+        print!("{}", diff::unified(&original, name, input, formatted));
+    }
+    true
+}
+
+/// Whether the formatted output is printed, rather than written back or
+/// replaced by a list or diff.
+fn prints_output(cli: &Cli) -> bool {
+    !cli.write && !cli.list && !cli.diff
+}
+
+/// Replace the contents of a file atomically: write a temporary file in the
+/// same directory, then rename it over the file. A symbolic link is followed
+/// so the file it points to is written and the link kept. The file keeps its
+/// permissions and, where the temporary file can be given them, its owner
+/// and group; otherwise it is written in place, which keeps them too.
+fn write_file(path: &Path, contents: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    let path = fs::canonicalize(path)?;
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut temp = NamedTempFile::new_in(dir)?;
+    temp.write_all(contents)?;
+    let metadata = fs::metadata(&path)?;
+    temp.as_file().set_permissions(metadata.permissions())?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{MetadataExt, fchown};
+        let temp_metadata = temp.as_file().metadata()?;
+        if (temp_metadata.uid(), temp_metadata.gid()) != (metadata.uid(), metadata.gid())
+            && fchown(temp.as_file(), Some(metadata.uid()), Some(metadata.gid())).is_err()
+        {
+            // Only the owner can be kept by writing over the file itself.
+            fs::write(&path, contents)?;
+            return Ok(());
+        }
+    }
+    temp.persist(&path)?;
     Ok(())
 }
+
+/// Whether the output for a file is fully determined by whether it is
+/// already formatted, so cached results can be used.
+fn uses_cache(cli: &Cli) -> bool {
+    cli.emit == Emit::Text
+        && !cli.minimum_width
+        && !cli.explain
+        && !cli.report_overflow
+        && cli.decode != Some(Decode::Auto)
+        && cli.escape.is_none()
+        && !cli.lint
+        && !cli.fix
+        && !cli.hoist
+        && cli.params_file.is_none()
+        && !cli.verify
+        && !cli.minify
+        && cli.embedded.is_empty()
+        && cli.range.is_none()
+        && !cli.color
+}
@@ -0,0 +1,94 @@
+//! Metadata headers at the top of query files.
+//!
+//! A query can start with a block of `//` comments naming and describing it:
+//!
+//! ```text
+//! // @name postBySlug
+//! // @description Fetch a published post by its slug,
+//! //   with its author expanded.
+//! *[_type == "post" && slug.current == $slug][0] { ..., author-> }
+//! ```
+
+/// The metadata declared in the header of a query.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QueryMetadata {
+    /// The name given by `@name`.
+    pub name: Option<String>,
+    /// The text of `@description`, including the comment lines after it up
+    /// to the next tag, joined with spaces.
+    pub description: Option<String>,
+}
+
+/// Read the metadata header at the top of a query.
+///
+/// Only the comment lines before the first line of code are read, so tags
+/// in comments further down are ignored. Unknown tags are ignored too.
+///
+/// # Example
+///
+/// ```
+/// use groq_format::query_metadata;
+///
+/// let metadata = query_metadata("// @name allPosts\n// @description Every post.\n*[_type == \"post\"]");
+/// assert_eq!(metadata.name.as_deref(), Some("allPosts"));
+/// assert_eq!(metadata.description.as_deref(), Some("Every post."));
+/// ```
+pub fn query_metadata(query: &str) -> QueryMetadata {
+    let mut metadata = QueryMetadata::default();
+    // Whether untagged comment lines continue a description.
+    let mut continues_description = false;
+
+    for line in header_lines(query) {
+        let text = line.trim()[2..].trim();
+        if let Some(tagged) = text.strip_prefix('@') {
+            let (tag, value) = tagged
+                .split_once(char::is_whitespace)
+                .unwrap_or((tagged, ""));
+            let value = value.trim();
+            continues_description = tag == "description";
+            match tag {
+                "name" if !value.is_empty() => metadata.name = Some(value.to_string()),
+                "description" if !value.is_empty() => {
+                    metadata.description = Some(value.to_string())
+                }
+                _ => {}
+            }
+        } else if continues_description && !text.is_empty() {
+            let description = metadata.description.get_or_insert_with(String::new);
+            if !description.is_empty() {
+                description.push(' ');
+            }
+            description.push_str(text);
+        } else {
+            continues_description = false;
+        }
+    }
+    metadata
+}
+
+/// The byte offset just past the metadata header of a query: the end of the
+/// last leading comment line, if any of the leading comments is a tag.
+pub(crate) fn header_end(query: &str) -> Option<usize> {
+    let mut end = None;
+    let mut tagged = false;
+    let mut offset = 0;
+    for line in query.split_inclusive('\n') {
+        if let Some(text) = line.trim().strip_prefix("//") {
+            tagged |= text.trim_start().starts_with('@');
+            end = Some(offset + line.trim_end().len());
+        } else if end.is_some() || !line.trim().is_empty() {
+            break;
+        }
+        offset += line.len();
+    }
+    end.filter(|_| tagged)
+}
+
+/// The leading `//` comment lines of a query, skipping blank lines before
+/// the first one.
+fn header_lines(query: &str) -> impl Iterator<Item = &str> {
+    query
+        .lines()
+        .skip_while(|line| line.trim().is_empty())
+        .take_while(|line| line.trim_start().starts_with("//"))
+}
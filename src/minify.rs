@@ -0,0 +1,49 @@
+//! Queries written as compactly as possible, for URLs.
+
+use crate::lexer::tokenize;
+use crate::{FormatError, FormatOptions, parse_prepared};
+
+/// Write `query` on one line without any whitespace that can be left out.
+/// Comments are removed. A space is kept only between tokens that would
+/// otherwise run together, as in `_id in path("drafts.**")`.
+///
+/// Useful for queries sent in the URL of a GET request, where every byte of
+/// whitespace is encoded.
+///
+/// # Example
+///
+/// ```
+/// use groq_format::minify_query;
+///
+/// let query = "*[_type == \"post\" && !(_id in path(\"drafts.**\"))] {\n  title, // the title\n  body\n}";
+/// assert_eq!(
+///     minify_query(query).unwrap(),
+///     r#"*[_type=="post"&&!(_id in path("drafts.**"))]{title,body}"#
+/// );
+/// ```
+pub fn minify_query(query: &str) -> Result<String, FormatError> {
+    let source = query.trim();
+    if source.is_empty() {
+        return Err(FormatError::EmptyQuery);
+    }
+    parse_prepared(source, &FormatOptions::default())?;
+
+    let mut minified = String::with_capacity(source.len());
+    let mut previous = None;
+    for token in tokenize(source).iter().filter(|token| !token.is_trivia()) {
+        let text = token.text(source);
+        if previous.is_some_and(|previous| runs_together(previous, text)) {
+            minified.push(' ');
+        }
+        minified.push_str(text);
+        previous = Some(text);
+    }
+    Ok(minified)
+}
+
+/// Whether two tokens written without a space between them would be read as
+/// different tokens, such as `in` and `path` or `-` and `-`.
+fn runs_together(first: &str, second: &str) -> bool {
+    let joined = format!("{}{}", first, second);
+    !matches!(tokenize(&joined).as_slice(), [token, _] if token.end == first.len())
+}
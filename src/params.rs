@@ -0,0 +1,55 @@
+//! Parameter files given with `--params`: a JSON object holding the values
+//! of a query's parameters, as sent to the query API alongside it.
+
+use std::path::Path;
+
+use serde_json::{Map, Value};
+
+use crate::textfile;
+
+/// The parameters in a params file, by name without the `$`.
+pub struct Params {
+    pub values: Map<String, Value>,
+    /// The file's text as read, to compare with [`Params::formatted`].
+    pub text: textfile::TextFile,
+}
+
+/// Read the params file at `path`, which must hold a JSON object.
+pub fn read(path: &Path) -> Result<Params, Box<dyn std::error::Error>> {
+    let error = |message: String| format!("{}: {}", path.display(), message);
+    let text = textfile::read(path, false).map_err(|e| error(e.to_string()))?;
+    let values = match serde_json::from_str(&text.text).map_err(|e| error(e.to_string()))? {
+        Value::Object(values) => values,
+        _ => return Err(error("parameters must be a JSON object".to_string()).into()),
+    };
+    Ok(Params { values, text })
+}
+
+impl Params {
+    /// The file formatted consistently: indented by two spaces, with the
+    /// parameters in alphabetical order.
+    pub fn formatted(&self) -> Result<String, serde_json::Error> {
+        Ok(format!("{}\n", serde_json::to_string_pretty(&self.values)?))
+    }
+
+    /// Warn on stderr about parameters that the query in the file `name`
+    /// uses, given as `used`, but that are not in the params file at `path`,
+    /// and about those in the file that the query doesn't use.
+    pub fn check(&self, name: &str, path: &Path, used: &[&str]) {
+        for param in used {
+            if !self.values.contains_key(*param) {
+                eprintln!("{}: warning: ${} is not in {}", name, param, path.display());
+            }
+        }
+        for param in self.values.keys() {
+            if !used.contains(&param.as_str()) {
+                eprintln!(
+                    "{}: warning: ${} is not used by {}",
+                    path.display(),
+                    param,
+                    name
+                );
+            }
+        }
+    }
+}
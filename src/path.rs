@@ -0,0 +1,374 @@
+//! Addressing of nodes in a query's AST.
+
+use std::collections::HashMap;
+use std::ops::Range;
+
+use groq_parser::ast::{Expr, FunctionCall, Object, Token};
+
+use crate::NodeId;
+use crate::lexer::{self, tokenize};
+
+/// One step from an AST node to one of its children.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Step {
+    /// The expression an operation applies to: the left side of a filter,
+    /// projection, slice, pipe, attribute access, binary or postfix
+    /// operator, or the array of a traversal such as `tags[]`.
+    Lhs,
+    /// The right side of a pipe, attribute access, binary or prefix operator.
+    Rhs,
+    /// The condition of a filter.
+    Constraint,
+    /// The object of a projection.
+    Object,
+    /// The function called by a pipe stage, such as `order(title)`.
+    Function,
+    /// An argument of a function call, counting from zero.
+    Arg(usize),
+    /// An element of an array or tuple, or a field of an object, counting
+    /// from zero.
+    Item(usize),
+    /// The range of a slice or the index of an element access.
+    Index,
+    /// The expression inside parentheses.
+    Inner,
+    /// The start of a range.
+    Start,
+    /// The end of a range.
+    End,
+}
+
+impl std::fmt::Display for Step {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Step::Lhs => write!(f, "lhs"),
+            Step::Rhs => write!(f, "rhs"),
+            Step::Constraint => write!(f, "constraint"),
+            Step::Object => write!(f, "object"),
+            Step::Function => write!(f, "function"),
+            Step::Arg(i) => write!(f, "arg[{}]", i),
+            Step::Item(i) => write!(f, "item[{}]", i),
+            Step::Index => write!(f, "index"),
+            Step::Inner => write!(f, "inner"),
+            Step::Start => write!(f, "start"),
+            Step::End => write!(f, "end"),
+        }
+    }
+}
+
+/// The location of a node in a query's AST, as the steps from the top-level
+/// expression down to it. The empty path is the top-level expression.
+///
+/// Displays as its steps separated by dots, such as `lhs.object.item[1]`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct AstPath(Vec<Step>);
+
+impl AstPath {
+    /// A path made of the given steps.
+    pub fn new(steps: impl IntoIterator<Item = Step>) -> Self {
+        AstPath(steps.into_iter().collect())
+    }
+
+    /// The steps of the path, from the top-level expression down.
+    pub fn steps(&self) -> &[Step] {
+        &self.0
+    }
+}
+
+impl From<Vec<Step>> for AstPath {
+    fn from(steps: Vec<Step>) -> Self {
+        AstPath(steps)
+    }
+}
+
+impl std::fmt::Display for AstPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, step) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ".")?;
+            }
+            write!(f, "{}", step)?;
+        }
+        Ok(())
+    }
+}
+
+/// A node that a path can lead to. Projection objects, the functions of
+/// pipe stages and object fields are formatted differently from ordinary
+/// expressions, so they are told apart.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Node<'a> {
+    Expr(&'a Expr),
+    Object(&'a Object),
+    Call(&'a FunctionCall),
+    /// A field of an object, such as `"author": author->name`.
+    Field(&'a Expr),
+}
+
+/// A node found by [`resolve`], with the number of objects around it.
+pub(crate) struct Resolved<'a> {
+    pub node: Node<'a>,
+    pub object_depth: usize,
+}
+
+/// Follow `path` from `expr`. Returns `None` if a step doesn't apply to the
+/// node it is taken from.
+pub(crate) fn resolve<'a>(expr: &'a Expr, path: &AstPath) -> Option<Resolved<'a>> {
+    let mut node = Node::Expr(expr);
+    let mut object_depth = 0;
+    for &step in path.steps() {
+        node = children(node)
+            .into_iter()
+            .find_map(|(child_step, child)| (child_step == step).then_some(child))?;
+        object_depth += usize::from(matches!(node, Node::Field(_)));
+    }
+    Some(Resolved { node, object_depth })
+}
+
+/// The [`NodeId`] of each expression of an AST by its address, for finding
+/// the ids of nodes while the AST is borrowed.
+pub(crate) struct NodeIds(HashMap<*const Expr, NodeId>);
+
+impl NodeIds {
+    /// Number the expressions of `expr` in pre-order, following
+    /// [`children`].
+    pub(crate) fn new(expr: &Expr) -> Self {
+        let mut ids = HashMap::new();
+        let mut stack = vec![Node::Expr(expr)];
+        while let Some(node) = stack.pop() {
+            if let Node::Expr(expr) | Node::Field(expr) = node {
+                // The arguments of a pipe stage are children of both the
+                // stage and its function; they are numbered once.
+                if ids.contains_key(&(expr as *const Expr)) {
+                    continue;
+                }
+                ids.insert(expr as *const Expr, NodeId(ids.len()));
+            }
+            stack.extend(children(node).into_iter().rev().map(|(_, child)| child));
+        }
+        NodeIds(ids)
+    }
+
+    /// The id of an expression of the AST, or `None` if it is not part of it.
+    pub(crate) fn get(&self, expr: &Expr) -> Option<NodeId> {
+        self.0.get(&(expr as *const Expr)).copied()
+    }
+}
+
+/// The children of a node in source order, with the steps leading to them.
+pub(crate) fn children(node: Node<'_>) -> Vec<(Step, Node<'_>)> {
+    fn expr(step: Step, expr: &Expr) -> (Step, Node<'_>) {
+        (step, Node::Expr(expr))
+    }
+    fn fields(fields: &[Expr]) -> Vec<(Step, Node<'_>)> {
+        let fields = fields.iter().enumerate();
+        fields
+            .map(|(i, field)| (Step::Item(i), Node::Field(field)))
+            .collect()
+    }
+    fn arguments(arguments: &[Expr]) -> Vec<(Step, Node<'_>)> {
+        let arguments = arguments.iter().enumerate();
+        arguments.map(|(i, arg)| expr(Step::Arg(i), arg)).collect()
+    }
+
+    match node {
+        Node::Object(obj) => fields(&obj.expressions),
+        Node::Call(call) => arguments(&call.arguments),
+        Node::Field(Expr::Binary(bin)) if bin.operator == Token::Colon => {
+            vec![expr(Step::Lhs, &bin.lhs), expr(Step::Rhs, &bin.rhs)]
+        }
+        // A field without a key, such as `title` or `author->{ name }`.
+        Node::Field(field) => children(Node::Expr(field)),
+        Node::Expr(node) => match node {
+            Expr::Filter(filter) => vec![
+                expr(Step::Lhs, &filter.lhs),
+                expr(Step::Constraint, &filter.constraint.expression),
+            ],
+            Expr::Slice(slice) => vec![
+                expr(Step::Lhs, &slice.lhs),
+                expr(Step::Index, &slice.range.value),
+            ],
+            Expr::Element(elem) => vec![
+                expr(Step::Lhs, &elem.lhs),
+                expr(Step::Index, &elem.idx.value),
+            ],
+            Expr::ArrayTraversal(at) => vec![expr(Step::Lhs, &at.expr)],
+            Expr::Dot(dot) => vec![expr(Step::Lhs, &dot.lhs), expr(Step::Rhs, &dot.rhs)],
+            Expr::Projection(proj) => vec![
+                expr(Step::Lhs, &proj.lhs),
+                (Step::Object, Node::Object(&proj.object)),
+            ],
+            Expr::Pipe(pipe) => vec![expr(Step::Lhs, &pipe.lhs), expr(Step::Rhs, &pipe.rhs)],
+            Expr::FunctionPipe(fp) => {
+                let mut children = vec![
+                    expr(Step::Lhs, &fp.lhs),
+                    (Step::Function, Node::Call(&fp.func)),
+                ];
+                // The arguments can also be reached without going through
+                // the function.
+                children.extend(arguments(&fp.func.arguments));
+                children
+            }
+            Expr::Binary(bin) => vec![expr(Step::Lhs, &bin.lhs), expr(Step::Rhs, &bin.rhs)],
+            Expr::Prefix(prefix) => vec![expr(Step::Rhs, &prefix.rhs)],
+            Expr::Postfix(postfix) => vec![expr(Step::Lhs, &postfix.lhs)],
+            Expr::FunctionCall(call) => arguments(&call.arguments),
+            Expr::Array(arr) => {
+                let items = arr.expressions.iter().enumerate();
+                items.map(|(i, item)| expr(Step::Item(i), item)).collect()
+            }
+            Expr::Object(obj) => fields(&obj.expressions),
+            Expr::Tuple(tuple) => {
+                let members = tuple.members.iter().enumerate();
+                members
+                    .map(|(i, member)| expr(Step::Item(i), member))
+                    .collect()
+            }
+            Expr::Group(group) => vec![expr(Step::Inner, &group.expression)],
+            Expr::Constraint(c) => vec![expr(Step::Inner, &c.expression)],
+            Expr::Subscript(s) => vec![expr(Step::Inner, &s.value)],
+            Expr::Range(range) => {
+                vec![expr(Step::Start, &range.start), expr(Step::End, &range.end)]
+            }
+            _ => Vec::new(),
+        },
+    }
+}
+
+/// The path of the innermost node of `expr` whose source range contains
+/// `offset`, or `None` if `expr` doesn't contain it.
+pub(crate) fn path_at(expr: &Expr, source: &str, offset: usize) -> Option<AstPath> {
+    let tokens = tokenize(source);
+    let contains = |node| node_range(node, source, &tokens).contains(&offset);
+    let mut node = Node::Expr(expr);
+    if !contains(node) {
+        return None;
+    }
+    let mut steps = Vec::new();
+    while let Some((step, child)) = children(node)
+        .into_iter()
+        .find(|&(_, child)| contains(child))
+    {
+        steps.push(step);
+        node = child;
+    }
+    Some(AstPath(steps))
+}
+
+/// The source range of the node at `path` in `expr`.
+pub(crate) fn path_range(expr: &Expr, source: &str, path: &AstPath) -> Option<Range<usize>> {
+    let resolved = resolve(expr, path)?;
+    Some(node_range(resolved.node, source, &tokenize(source)))
+}
+
+/// The range of source text a node was parsed from, given the tokens of the
+/// source. Compound nodes may only record the position of their operator,
+/// so the range covers the node's own position and those of its children,
+/// widened to take in the brackets around it.
+pub(crate) fn node_range(node: Node<'_>, source: &str, tokens: &[lexer::Token]) -> Range<usize> {
+    let (pos, brackets) = match node {
+        Node::Expr(expr) | Node::Field(expr) => {
+            let brackets = match expr {
+                Expr::Filter(_) | Expr::Slice(_) | Expr::Element(_) => Some(("[", "]")),
+                Expr::Array(_) => Some(("[", "]")),
+                Expr::Object(_) => Some(("{", "}")),
+                Expr::Group(_) | Expr::Tuple(_) => Some(("(", ")")),
+                Expr::FunctionCall(_) => Some(("(", ")")),
+                _ => None,
+            };
+            (expr.get_pos(), brackets)
+        }
+        Node::Object(obj) => (obj.pos, Some(("{", "}"))),
+        Node::Call(call) => (call.pos, Some(("(", ")"))),
+    };
+    let mut range = pos.start..pos.end;
+    for (_, child) in children(node) {
+        let child = node_range(child, source, tokens);
+        range.start = range.start.min(child.start);
+        range.end = range.end.max(child.end);
+    }
+
+    // Take in brackets whose partner is already in the range.
+    if let Some((open, close)) = brackets {
+        let mut balance: isize = 0;
+        for token in tokens
+            .iter()
+            .filter(|token| range.start <= token.start && token.end <= range.end)
+        {
+            match token.text(source) {
+                text if text == open => balance += 1,
+                text if text == close => balance -= 1,
+                _ => {}
+            }
+        }
+        let significant = |token: &&lexer::Token| !token.is_trivia();
+        while balance > 0
+            && let Some(token) = tokens
+                .iter()
+                .filter(|token| token.start >= range.end)
+                .find(significant)
+            && token.text(source) == close
+        {
+            range.end = token.end;
+            balance -= 1;
+        }
+        while balance < 0
+            && let Some(token) = tokens
+                .iter()
+                .rev()
+                .filter(|token| token.end <= range.start)
+                .find(significant)
+            && token.text(source) == open
+        {
+            range.start = token.start;
+            balance += 1;
+        }
+    }
+    range
+}
+
+impl std::str::FromStr for AstPath {
+    type Err = String;
+
+    /// Parse a path written as by [`Display`](std::fmt::Display), such as
+    /// `lhs.object.item[1]`. The empty string is the top-level expression.
+    fn from_str(path: &str) -> Result<Self, Self::Err> {
+        if path.is_empty() {
+            return Ok(AstPath::default());
+        }
+        path.split('.')
+            .map(parse_step)
+            .collect::<Result<_, _>>()
+            .map(AstPath)
+    }
+}
+
+fn parse_step(step: &str) -> Result<Step, String> {
+    let indexed = |name: &str| {
+        let index = step
+            .strip_prefix(name)?
+            .strip_prefix('[')?
+            .strip_suffix(']')?;
+        index.parse::<usize>().ok()
+    };
+    Ok(match step {
+        "lhs" => Step::Lhs,
+        "rhs" => Step::Rhs,
+        "constraint" => Step::Constraint,
+        "object" => Step::Object,
+        "function" => Step::Function,
+        "index" => Step::Index,
+        "inner" => Step::Inner,
+        "start" => Step::Start,
+        "end" => Step::End,
+        _ => {
+            if let Some(i) = indexed("arg") {
+                Step::Arg(i)
+            } else if let Some(i) = indexed("item") {
+                Step::Item(i)
+            } else {
+                return Err(format!("invalid path step `{}`", step));
+            }
+        }
+    })
+}
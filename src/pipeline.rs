@@ -0,0 +1,59 @@
+//! All formatting stages of a query from a single parse.
+
+use groq_parser::ast::ParseResult;
+
+use crate::doc::{Doc, Renderer};
+use crate::lexer::{Token, tokenize};
+use crate::{FormatError, FormatOptions, format, parse_prepared, prepare_query, render_doc};
+
+/// The intermediate results of formatting a query.
+///
+/// Tools that need several artifacts for the same query, such as tokens for
+/// highlighting, the AST for linting and the formatted text, can get all of
+/// them from one parse instead of running each stage separately.
+///
+/// # Example
+///
+/// ```
+/// use groq_format::{FormatOptions, Pipeline};
+///
+/// let pipeline = Pipeline::run("*[a==1]", &FormatOptions::default()).unwrap();
+/// assert_eq!(pipeline.tokens.len(), 6);
+/// assert_eq!(pipeline.output, "*[a == 1]");
+/// ```
+pub struct Pipeline {
+    /// The query that was parsed: the input, trimmed, with any repairs
+    /// enabled in the options applied. Token positions refer to this text.
+    pub source: String,
+    /// Lossless tokens of `source`, including whitespace and comments.
+    pub tokens: Vec<Token>,
+    /// The parsed query.
+    pub ast: ParseResult,
+    /// The normalized layout document.
+    pub doc: Doc,
+    /// The formatted query.
+    pub output: String,
+}
+
+impl Pipeline {
+    /// Run every stage on `query`. Empty input is an error even with
+    /// [`FormatOptions::allow_empty`], as there is nothing to parse.
+    pub fn run(query: &str, options: &FormatOptions) -> Result<Pipeline, FormatError> {
+        let source = prepare_query(query, options)?.ok_or(FormatError::EmptyQuery)?;
+        let ast = parse_prepared(&source, options)?;
+        let tokens = tokenize(&source);
+
+        let doc = format::format_parse_result_with(&ast, &source, options)?.normalize();
+
+        let mut output = String::with_capacity(source.len() + source.len() / 2);
+        render_doc(&doc, options, &mut Renderer::default(), &mut output)?;
+
+        Ok(Pipeline {
+            source,
+            tokens,
+            ast,
+            doc,
+            output,
+        })
+    }
+}
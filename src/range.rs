@@ -0,0 +1,167 @@
+//! Formatting of part of a query, such as an editor's selection.
+
+use std::ops::Range;
+
+use crate::doc::{Doc, Renderer};
+use crate::format;
+use crate::lexer::{self, TokenKind, tokenize};
+use crate::path::{self, AstPath, Node, children, node_range};
+use crate::{FormatError, FormatOptions, Overflow, parse_query, render_part, trim_for_addressing};
+
+/// Format the expressions in `query` that overlap the byte range `range`,
+/// leaving the rest of the query exactly as it is.
+///
+/// Each expression that the range touches is formatted whole: when the
+/// range falls inside one expression, the innermost one containing it is
+/// formatted, and when it spans several, such as some of the fields of an
+/// object, each of them is. A formatted expression keeps its position on
+/// its line, and the lines after its first are indented like the line it
+/// starts on. Expressions with comments in them are not formatted whole,
+/// since their comments would be lost; the expressions inside them are
+/// formatted instead.
+///
+/// # Example
+///
+/// ```
+/// use groq_format::{FormatOptions, format_range};
+///
+/// let query = "*[_type=='post'] {\n  title,\n  'author':author->{name,bio}\n}";
+/// // The selection from `'author'` to the end of the line.
+/// let start = query.find("'author'").unwrap();
+/// let end = query.find("bio}").unwrap() + 4;
+/// assert_eq!(
+///     format_range(query, start..end, &FormatOptions::default()).unwrap(),
+///     "*[_type=='post'] {\n  title,\n  \"author\": author-> { name, bio }\n}"
+/// );
+/// ```
+pub fn format_range(
+    query: &str,
+    range: Range<usize>,
+    options: &FormatOptions,
+) -> Result<String, FormatError> {
+    let (leading, source) = trim_for_addressing(query)?;
+    let result = parse_query(source)?;
+    let tokens = tokenize(source);
+    let range = range.start.saturating_sub(leading)..range.end.saturating_sub(leading);
+
+    let mut selected = Vec::new();
+    select(
+        Node::Expr(&result.expr),
+        Vec::new(),
+        &range,
+        source,
+        &tokens,
+        &mut selected,
+    );
+
+    let mut output = String::with_capacity(query.len());
+    output.push_str(&query[..leading]);
+    let mut end = 0;
+    for (steps, node_range) in selected {
+        let Some(resolved) = path::resolve(&result.expr, &AstPath::from(steps)) else {
+            continue;
+        };
+        let before = &query[..leading + node_range.start];
+        let before = &before[before.rfind('\n').map_or(0, |i| i + 1)..];
+        let indent = &before[..before.len() - before.trim_start().len()];
+        let indent_width = indent
+            .chars()
+            .map(|c| if c == '\t' { options.indent } else { 1 })
+            .sum::<usize>();
+        let column = before.chars().count() - indent.chars().count();
+
+        // Text in front of the node takes up the start of the first line.
+        let doc = Doc::concat([
+            Doc::text(" ".repeat(column)),
+            format::format_resolved(&resolved, options),
+        ])
+        .normalize();
+        let mut formatted = String::new();
+        let rendered = render_part(
+            &doc,
+            options,
+            indent_width,
+            &mut Renderer::default(),
+            &mut formatted,
+        );
+        doc.discard();
+        // Report overflowing lines by their line in the query.
+        let first_line = query[..leading + node_range.start].matches('\n').count();
+        rendered.map_err(|err| match err {
+            FormatError::WidthExceeded { width, lines } => FormatError::WidthExceeded {
+                width,
+                lines: lines
+                    .into_iter()
+                    .map(|overflow| Overflow {
+                        line: overflow.line + first_line,
+                        ..overflow
+                    })
+                    .collect(),
+            },
+            err => err,
+        })?;
+        let formatted = formatted[column..].replace('\n', &format!("\n{}", indent));
+
+        output.push_str(&source[end..node_range.start]);
+        output.push_str(&formatted);
+        end = node_range.end;
+    }
+    output.push_str(&source[end..]);
+    output.push_str(&query[leading + source.len()..]);
+    #[cfg(feature = "self-check")]
+    crate::self_check(&output)?;
+    Ok(output)
+}
+
+/// Collect the path and source range of the nodes to format for `range`
+/// from `node`, at `steps`, and its descendants, in source order.
+fn select(
+    node: Node<'_>,
+    steps: Vec<path::Step>,
+    range: &Range<usize>,
+    source: &str,
+    tokens: &[lexer::Token],
+    selected: &mut Vec<(Vec<path::Step>, Range<usize>)>,
+) {
+    let own = node_range(node, source, tokens);
+    if !overlaps(&own, range) {
+        return;
+    }
+    let overlapping: Vec<_> = children(node)
+        .into_iter()
+        .filter(|&(_, child)| overlaps(&node_range(child, source, tokens), range))
+        .collect();
+    let has_comments = tokens
+        .iter()
+        .any(|token| token.kind == TokenKind::Comment && own.contains(&token.start));
+    let covered = range.start <= own.start && own.end <= range.end;
+
+    if overlapping.is_empty() || (covered && !has_comments) {
+        if !has_comments {
+            selected.push((steps, own));
+        }
+        return;
+    }
+    // A range inside one child narrows down to it; otherwise each child the
+    // range touches is formatted whole.
+    let narrow = !covered && overlapping.len() == 1;
+    for (step, child) in overlapping {
+        let mut child_steps = steps.clone();
+        child_steps.push(step);
+        let child_range = match narrow {
+            true => range.clone(),
+            false => node_range(child, source, tokens),
+        };
+        select(child, child_steps, &child_range, source, tokens, selected);
+    }
+}
+
+/// Whether a node's source range and the range to format share any text.
+/// An empty range to format, a cursor position, overlaps the node around
+/// it.
+fn overlaps(node: &Range<usize>, range: &Range<usize>) -> bool {
+    match range.is_empty() {
+        true => node.start <= range.start && range.start < node.end,
+        false => node.start < range.end && range.start < node.end,
+    }
+}
@@ -0,0 +1,107 @@
+//! Re-wrapping of `//` comments in formatted output.
+
+use crate::doc::columns;
+
+/// Re-wrap runs of own-line `//` comments that have a line longer than
+/// `width`.
+///
+/// A run is a sequence of consecutive lines that hold only a comment, at the
+/// same indentation. Within a run, empty comment lines (`//`) separate
+/// paragraphs and lines starting with a list marker (`-`, `*`) start a new
+/// paragraph; each paragraph is filled to the width. Runs whose lines all fit
+/// are left as written. Words longer than the line are not split. Reflowed
+/// lines keep the run's indentation, and a tab in it counts as `tab_width`
+/// columns.
+pub(crate) fn reflow_comments(output: &str, width: usize, tab_width: usize) -> String {
+    reflow_comments_mapped(output, width, tab_width).0
+}
+
+/// Re-wrap comments like [`reflow_comments`], also returning the 0-based line
+/// of the result each line of `output` moved to. The lines of a reflowed run
+/// all move to the run's first line.
+pub(crate) fn reflow_comments_mapped(
+    output: &str,
+    width: usize,
+    tab_width: usize,
+) -> (String, Vec<usize>) {
+    let lines: Vec<&str> = output.split('\n').collect();
+    let mut result: Vec<String> = Vec::with_capacity(lines.len());
+    let mut moved_to = Vec::with_capacity(lines.len());
+
+    let mut i = 0;
+    while i < lines.len() {
+        let Some(indent) = comment_indent(lines[i]) else {
+            moved_to.push(result.len());
+            result.push(lines[i].to_string());
+            i += 1;
+            continue;
+        };
+        let start = i;
+        while i < lines.len() && comment_indent(lines[i]) == Some(indent) {
+            i += 1;
+        }
+        let run = &lines[start..i];
+        let indent_width: usize = indent
+            .chars()
+            .map(|c| if c == '\t' { tab_width } else { 1 })
+            .sum();
+        let line_width = |line: &&str| indent_width + columns(&line[indent.len()..]);
+        if run.iter().all(|line| line_width(line) <= width) {
+            moved_to.extend(result.len()..result.len() + run.len());
+            result.extend(run.iter().map(|line| line.to_string()));
+        } else {
+            moved_to.extend(std::iter::repeat_n(result.len(), run.len()));
+            let width = width.saturating_sub(indent_width);
+            reflow_run(run, indent, width, &mut result);
+        }
+    }
+
+    (result.join("\n"), moved_to)
+}
+
+/// The indentation of a line holding only a `//` comment.
+fn comment_indent(line: &str) -> Option<&str> {
+    let text = line.trim_start_matches([' ', '\t']);
+    text.starts_with("//")
+        .then(|| &line[..line.len() - text.len()])
+}
+
+/// Reflow a run of comments indented by `indent`, filling the text after the
+/// indentation to `width` columns.
+fn reflow_run(run: &[&str], indent: &str, width: usize, result: &mut Vec<String>) {
+    let mut paragraph: Vec<&str> = Vec::new();
+
+    for line in run {
+        let text = line.trim_start()[2..].trim();
+        let list_item = text.starts_with("- ") || text.starts_with("* ");
+        if text.is_empty() || list_item {
+            fill(&paragraph, indent, width, result);
+            paragraph.clear();
+        }
+        if text.is_empty() {
+            result.push(format!("{}//", indent));
+        } else {
+            paragraph.extend(text.split_whitespace());
+        }
+    }
+    fill(&paragraph, indent, width, result);
+}
+
+/// Fill `//` comment lines indented by `indent` with `words`, up to `width`
+/// columns after the indentation.
+fn fill(words: &[&str], indent: &str, width: usize, result: &mut Vec<String>) {
+    let mut line = String::new();
+    for word in words {
+        if !line.is_empty() && 3 + columns(&line) + 1 + columns(word) > width {
+            result.push(format!("{}// {}", indent, line));
+            line.clear();
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(word);
+    }
+    if !line.is_empty() {
+        result.push(format!("{}// {}", indent, line));
+    }
+}
@@ -0,0 +1,101 @@
+//! Extraction of queries from recorded HTTP requests.
+
+use serde_json::Value;
+
+use crate::input::url_decode;
+
+/// A query found in a recorded request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestQuery {
+    /// The URL the request was sent to.
+    pub url: String,
+    /// The query, decoded.
+    pub query: String,
+}
+
+/// Find the GROQ queries sent by a set of HTTP requests.
+///
+/// `requests` is either a HAR file, as exported by browser developer tools,
+/// or a list of request URLs, one per line. Queries are taken from `query=`
+/// URL parameters and, in a HAR file, from the `query` field of JSON or
+/// form-encoded POST bodies. Requests without a query are skipped.
+///
+/// # Example
+///
+/// ```
+/// use groq_format::extract_request_queries;
+///
+/// let urls = "https://abc.api.sanity.io/v2021-10-21/data/query/production?query=*%5B_type%3D%3D%22post%22%5D\n";
+/// let queries = extract_request_queries(urls);
+/// assert_eq!(queries[0].query, r#"*[_type=="post"]"#);
+/// ```
+pub fn extract_request_queries(requests: &str) -> Vec<RequestQuery> {
+    match serde_json::from_str::<Value>(requests) {
+        Ok(har) => har_queries(&har),
+        Err(_) => requests
+            .lines()
+            .map(str::trim)
+            .filter_map(|url| {
+                url_query(url).map(|query| RequestQuery {
+                    url: url.to_string(),
+                    query,
+                })
+            })
+            .collect(),
+    }
+}
+
+fn har_queries(har: &Value) -> Vec<RequestQuery> {
+    let Some(entries) = har.pointer("/log/entries").and_then(Value::as_array) else {
+        return Vec::new();
+    };
+    entries
+        .iter()
+        .filter_map(|entry| {
+            let request = entry.get("request")?;
+            let url = request.get("url")?.as_str()?;
+            let query = url_query(url).or_else(|| body_query(request.get("postData")?))?;
+            Some(RequestQuery {
+                url: url.to_string(),
+                query,
+            })
+        })
+        .collect()
+}
+
+/// The decoded `query` parameter of a URL.
+fn url_query(url: &str) -> Option<String> {
+    let (_, parameters) = url.split_once('?')?;
+    let parameters = parameters.split('#').next().unwrap_or_default();
+    form_query(parameters)
+}
+
+/// The decoded `query` field of form-encoded parameters.
+fn form_query(parameters: &str) -> Option<String> {
+    parameters
+        .split('&')
+        .find_map(|parameter| parameter.strip_prefix("query="))
+        .map(url_decode)
+}
+
+/// The query in a HAR `postData` object: the `query` field of a JSON body,
+/// or a `query` form parameter.
+fn body_query(post_data: &Value) -> Option<String> {
+    let text = post_data
+        .get("text")
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+    if let Ok(body) = serde_json::from_str::<Value>(text) {
+        return body.get("query")?.as_str().map(str::to_string);
+    }
+    if let Some(params) = post_data.get("params").and_then(Value::as_array) {
+        return params.iter().find_map(|param| {
+            if param.get("name")?.as_str()? == "query" {
+                param.get("value")?.as_str().map(url_decode)
+            } else {
+                None
+            }
+        });
+    }
+    form_query(text)
+}
@@ -0,0 +1,109 @@
+//! Markdown reports for the job summary of GitHub Actions, written with
+//! `--github-summary`.
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+
+use groq_format::QueryError;
+
+use crate::diff;
+
+/// Lines of a query shown with its parse error.
+const SNIPPET_LINES: usize = 5;
+
+/// What was found while formatting the inputs.
+#[derive(Default)]
+pub struct Summary {
+    /// Files whose formatting differs, with the number of lines removed and
+    /// added by formatting them.
+    unformatted: Vec<(String, usize, usize)>,
+    errors: Vec<Failure>,
+}
+
+/// A query that could not be formatted.
+struct Failure {
+    file: String,
+    /// Line (1-based) on which the query starts.
+    line: usize,
+    message: String,
+    /// The first lines of the query.
+    snippet: String,
+}
+
+impl Summary {
+    /// Record a formatted file, with the queries in it that could not be
+    /// formatted.
+    pub fn file(&mut self, name: &str, input: &str, formatted: &str, errors: &[QueryError]) {
+        if input != formatted {
+            let diff = diff::unified("", "", input, formatted);
+            // Skip the `---` and `+++` header lines.
+            let lines = diff.lines().skip(2);
+            let (mut removed, mut added) = (0, 0);
+            for line in lines {
+                removed += usize::from(line.starts_with('-'));
+                added += usize::from(line.starts_with('+'));
+            }
+            self.unformatted.push((name.to_string(), removed, added));
+        }
+        for err in errors {
+            self.error(name, input, err.line, &err.error.to_string());
+        }
+    }
+
+    /// Record a query starting on `line` of `input` that could not be
+    /// formatted.
+    pub fn error(&mut self, name: &str, input: &str, line: usize, message: &str) {
+        let snippet = input
+            .lines()
+            .skip(line.saturating_sub(1))
+            .take(SNIPPET_LINES)
+            .collect::<Vec<_>>()
+            .join("\n");
+        self.errors.push(Failure {
+            file: name.to_string(),
+            line,
+            message: message.to_string(),
+            snippet,
+        });
+    }
+
+    /// The report as Markdown, given the number of files checked.
+    pub fn markdown(&self, checked: usize) -> String {
+        let mut md = String::from("## groq-format\n\n");
+        match self.unformatted.len() {
+            0 => md.push_str(&format!("All {} files are formatted.\n", checked)),
+            n => {
+                md.push_str(&format!("{} of {} files need formatting.\n\n", n, checked));
+                md.push_str("| File | Lines removed | Lines added |\n");
+                md.push_str("|------|--------------:|------------:|\n");
+                for (file, removed, added) in &self.unformatted {
+                    md.push_str(&format!(
+                        "| `{}` | {} | {} |\n",
+                        file.replace('|', "\\|"),
+                        removed,
+                        added
+                    ));
+                }
+            }
+        }
+
+        if !self.errors.is_empty() {
+            md.push_str("\n### Parse errors\n");
+            for failure in &self.errors {
+                md.push_str(&format!(
+                    "\n`{}`, line {}: {}\n\n```groq\n{}\n```\n",
+                    failure.file, failure.line, failure.message, failure.snippet
+                ));
+            }
+        }
+        md
+    }
+
+    /// Append the report to the file at `path`, which GitHub Actions shows
+    /// on the job's summary page.
+    pub fn append_to(&self, path: &Path, checked: usize) -> std::io::Result<()> {
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(file, "{}", self.markdown(checked))
+    }
+}
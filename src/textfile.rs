@@ -0,0 +1,118 @@
+//! Reading and writing files in their own encoding and line endings.
+//!
+//! The formatter works on UTF-8 text with LF line endings. Files stored
+//! otherwise are converted when read, and converted back when written in
+//! place, so formatting a file never changes more than its formatting.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// How a file's text is stored.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TextStyle {
+    encoding: Encoding,
+    /// Lines end in CRLF rather than LF.
+    crlf: bool,
+    /// The text does not end in a line break, and was given one when read.
+    missing_final_newline: bool,
+}
+
+/// The encodings recognized by their byte order mark.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum Encoding {
+    #[default]
+    Utf8,
+    Utf8Bom,
+    Utf16Le,
+    Utf16Be,
+}
+
+/// The text of a file, converted to UTF-8 with LF line endings, and how to
+/// convert it back.
+pub struct TextFile {
+    pub text: String,
+    pub style: TextStyle,
+}
+
+/// Read the text of the file at `path`. With `keep_missing_final_newline`,
+/// text without a line break at the end is given one, which is removed again
+/// when it is written back; otherwise formatting adds it.
+pub fn read(path: &Path, keep_missing_final_newline: bool) -> io::Result<TextFile> {
+    from_bytes(&fs::read(path)?, keep_missing_final_newline)
+}
+
+/// Decode the stored contents of a file, like [`read`].
+pub fn from_bytes(bytes: &[u8], keep_missing_final_newline: bool) -> io::Result<TextFile> {
+    let (encoding, text) = decode(bytes)?;
+    let crlf = text.find('\n').is_some_and(|i| text[..i].ends_with('\r'));
+    let mut text = if crlf {
+        text.replace("\r\n", "\n")
+    } else {
+        text
+    };
+    let missing_final_newline =
+        keep_missing_final_newline && !text.is_empty() && !text.ends_with('\n');
+    if missing_final_newline {
+        text.push('\n');
+    }
+    Ok(TextFile {
+        text,
+        style: TextStyle {
+            encoding,
+            crlf,
+            missing_final_newline,
+        },
+    })
+}
+
+fn decode(bytes: &[u8]) -> io::Result<(Encoding, String)> {
+    let invalid = |message| io::Error::new(io::ErrorKind::InvalidData, message);
+    let utf16 = |bytes: &[u8], from_bytes: fn([u8; 2]) -> u16| {
+        if !bytes.len().is_multiple_of(2) {
+            return Err(invalid("stream did not contain valid UTF-16"));
+        }
+        let units: Vec<u16> = bytes
+            .chunks_exact(2)
+            .map(|pair| from_bytes([pair[0], pair[1]]))
+            .collect();
+        String::from_utf16(&units).map_err(|_| invalid("stream did not contain valid UTF-16"))
+    };
+    let utf8 = |bytes: &[u8]| {
+        String::from_utf8(bytes.to_vec()).map_err(|_| invalid("stream did not contain valid UTF-8"))
+    };
+
+    match bytes {
+        [0xEF, 0xBB, 0xBF, rest @ ..] => Ok((Encoding::Utf8Bom, utf8(rest)?)),
+        [0xFF, 0xFE, rest @ ..] => Ok((Encoding::Utf16Le, utf16(rest, u16::from_le_bytes)?)),
+        [0xFE, 0xFF, rest @ ..] => Ok((Encoding::Utf16Be, utf16(rest, u16::from_be_bytes)?)),
+        _ => Ok((Encoding::Utf8, utf8(bytes)?)),
+    }
+}
+
+impl TextStyle {
+    /// Convert text read as UTF-8 with LF line endings back to the way the
+    /// file was stored.
+    pub fn encode(&self, text: &str) -> Vec<u8> {
+        let text = match self.missing_final_newline {
+            true => text.strip_suffix('\n').unwrap_or(text),
+            false => text,
+        };
+        let text = match self.crlf {
+            true => text.replace('\n', "\r\n"),
+            false => text.to_string(),
+        };
+        match self.encoding {
+            Encoding::Utf8 => text.into_bytes(),
+            Encoding::Utf8Bom => [&[0xEF, 0xBB, 0xBF], text.as_bytes()].concat(),
+            Encoding::Utf16Le => [0xFF, 0xFE]
+                .into_iter()
+                .chain(text.encode_utf16().flat_map(u16::to_le_bytes))
+                .collect(),
+            Encoding::Utf16Be => [0xFE, 0xFF]
+                .into_iter()
+                .chain(text.encode_utf16().flat_map(u16::to_be_bytes))
+                .collect(),
+        }
+    }
+}
@@ -0,0 +1,148 @@
+//! Interactive preview of a query's layout at an adjustable width.
+
+use std::io::{self, Write};
+
+use crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use crossterm::style::{Print, Stylize};
+use crossterm::terminal::{self, ClearType};
+use crossterm::{cursor, execute, queue};
+use groq_format::{FormatOptions, format_query_with_options};
+
+/// Widest width the preview can be adjusted to.
+const MAX_WIDTH: usize = 400;
+
+/// Show the formatted query full-screen and let the width be adjusted with
+/// the arrow keys. The preview is drawn on stderr so stdout stays free for
+/// the result: returns the query formatted at the final width if the
+/// preview was closed with Enter, `None` if it was closed with `q` or Esc.
+/// With `guides`, the preview starts out showing indentation guides.
+pub fn run(query: &str, options: FormatOptions, guides: bool) -> io::Result<Option<String>> {
+    let mut out = io::stderr();
+    terminal::enable_raw_mode()?;
+    execute!(out, terminal::EnterAlternateScreen, cursor::Hide)?;
+
+    let result = event_loop(&mut out, query, options, guides);
+
+    // Restore the terminal even if drawing failed.
+    let restored = execute!(out, cursor::Show, terminal::LeaveAlternateScreen)
+        .and_then(|()| terminal::disable_raw_mode());
+    let options = result?;
+    restored?;
+
+    Ok(options.map(|options| {
+        format_query_with_options(query, &options).unwrap_or_else(|_| query.to_string())
+    }))
+}
+
+/// Handle keys until the preview is closed, returning the options to format
+/// with if it was accepted.
+fn event_loop(
+    out: &mut impl Write,
+    query: &str,
+    mut options: FormatOptions,
+    mut guides: bool,
+) -> io::Result<Option<FormatOptions>> {
+    loop {
+        draw(out, query, &options, guides)?;
+        let Event::Key(key) = event::read()? else {
+            // Redraw on resize and other events.
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        options.width = match key.code {
+            KeyCode::Left => options.width.saturating_sub(1).max(1),
+            KeyCode::Right => (options.width + 1).min(MAX_WIDTH),
+            KeyCode::Down => options.width.saturating_sub(10).max(1),
+            KeyCode::Up => (options.width + 10).min(MAX_WIDTH),
+            KeyCode::Char('g') => {
+                guides = !guides;
+                options.width
+            }
+            KeyCode::Enter => return Ok(Some(options)),
+            KeyCode::Char('q') | KeyCode::Esc => return Ok(None),
+            _ => options.width,
+        };
+    }
+}
+
+fn draw(
+    out: &mut impl Write,
+    query: &str,
+    options: &FormatOptions,
+    guides: bool,
+) -> io::Result<()> {
+    let (columns, rows) = terminal::size()?;
+    let (columns, rows) = (columns as usize, rows as usize);
+    queue!(
+        out,
+        terminal::Clear(ClearType::All),
+        cursor::MoveTo(0, 0),
+        Print(
+            format!(
+                "width {}  ←/→ ±1  ↑/↓ ±10  g: guides  enter: print and quit  q: quit",
+                options.width
+            )
+            .reverse()
+        )
+    )?;
+
+    // Tabs would be drawn up to the terminal's tab stops, so the preview
+    // indents with spaces to keep columns lined up with the width.
+    let formatted = match format_query_with_options(query, &options.clone().with_use_tabs(false)) {
+        Ok(formatted) => formatted,
+        Err(e) => {
+            queue!(out, cursor::MoveTo(0, 2), Print(e.to_string().red()))?;
+            return out.flush();
+        }
+    };
+
+    for (row, line) in formatted.lines().take(rows.saturating_sub(2)).enumerate() {
+        let row = (row + 2) as u16;
+        let (fits, overflow) = split_at_column(line, options.width.min(columns));
+        queue!(out, cursor::MoveTo(0, row))?;
+        let fits = if guides {
+            let indent = fits.len() - fits.trim_start_matches(' ').len();
+            queue!(
+                out,
+                Print(indent_guides(indent, options.indent).dark_grey())
+            )?;
+            &fits[indent..]
+        } else {
+            fits
+        };
+        queue!(out, Print(fits))?;
+        if !overflow.is_empty() {
+            // Show the part past the width, as far as the terminal allows.
+            let (visible, _) = split_at_column(overflow, columns.saturating_sub(options.width));
+            queue!(out, Print(visible.red()))?;
+        } else if options.width < columns {
+            queue!(
+                out,
+                cursor::MoveTo(options.width as u16, row),
+                Print("│".dark_grey())
+            )?;
+        }
+    }
+    out.flush()
+}
+
+/// Indentation of `indent` columns with a guide at the start of each level
+/// of `level` columns, under the line that opened it.
+fn indent_guides(indent: usize, level: usize) -> String {
+    (0..indent)
+        .map(|column| match column % level.max(1) {
+            0 => '│',
+            _ => ' ',
+        })
+        .collect()
+}
+
+/// Split a line after `column` characters.
+fn split_at_column(line: &str, column: usize) -> (&str, &str) {
+    match line.char_indices().nth(column) {
+        Some((index, _)) => line.split_at(index),
+        None => (line, ""),
+    }
+}
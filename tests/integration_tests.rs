@@ -1,4 +1,16 @@
-use groq_format::{FormatError, FormatOptions, format_query, format_query_with_options};
+use groq_format::{
+    AstPath, BUILTIN_FUNCTIONS, BundledQuery, CallLayout, Category, Construct, DiskCache, Doc,
+    EmbeddedQuery, FormatCache, FormatError, FormatOptions, Formatter, Highlight, InputEncoding,
+    NodeId, Pipeline, Query, QueryMetadata, QuoteStyle, RequestQuery, Spacing, Step, StyleEdition,
+    TokenKind, Warning, builtin_function, detect_encoding, explain_query, extract_embedded_queries,
+    extract_request_queries, find_invisible_chars, fix_query, format_ast, format_ast_with_spans,
+    format_document, format_document_range, format_embedded_queries, format_many, format_query,
+    format_query_with_metadata, format_query_with_options, format_range, format_subexpression,
+    highlight_document, hoist_projections, is_formatted, lint_query, minify_query, minimum_width,
+    node_at, overflowing_lines, parse_to_json, query_fingerprint, query_metadata, query_name,
+    query_parameters, query_to_doc, render_ansi, render_html, select, tokenize, typescript_module,
+    verify_idempotent,
+};
 
 #[test]
 fn test_blog_post_query() {
@@ -158,10 +170,9 @@ fn test_empty_structures() {
 #[test]
 fn test_unicode_and_special_chars() {
     let input = r#"*[_type=="test"]{title:"Hello 🌍 World",description:"café résumé naïve"}"#;
-    let expected = r#"*[_type == "test"] {
-  title: "Hello 🌍 World",
-  description: "café résumé naïve"
-}"#;
+    // 79 characters, though more bytes, so it fits on one line.
+    let expected =
+        r#"*[_type == "test"] { title: "Hello 🌍 World", description: "café résumé naïve" }"#;
 
     let result = format_query(input, 80).unwrap();
     assert_eq!(result, expected);
@@ -335,3 +346,2630 @@ fn test_force_wrap_disabled_does_not_break_arithmetic() {
     let result = format_query(input, 30).unwrap();
     assert_eq!(result, input);
 }
+
+#[test]
+fn test_doc_serializes_to_json() {
+    let doc = Doc::group(Doc::concat([
+        Doc::text("["),
+        Doc::nest(2, Doc::concat([Doc::line_or_empty(), Doc::text("a")])),
+        Doc::hard_line(),
+    ]));
+    let json = serde_json::to_value(&doc).unwrap();
+    assert_eq!(
+        json,
+        serde_json::json!({
+            "group": {
+                "concat": [
+                    { "text": "[" },
+                    {
+                        "nest": {
+                            "indent": 2,
+                            "doc": {
+                                "concat": [
+                                    { "line": { "space": "" } },
+                                    { "text": "a" }
+                                ]
+                            }
+                        }
+                    },
+                    "hard_line"
+                ]
+            }
+        })
+    );
+}
+
+#[test]
+fn test_query_to_doc_renders_like_format_query() {
+    let input = r#"*[_type=="post"]{title}"#;
+    let doc = query_to_doc(input, &FormatOptions::default()).unwrap();
+    assert!(
+        serde_json::to_string(&doc)
+            .unwrap()
+            .contains(r#""text":"title""#)
+    );
+    assert_eq!(
+        format_query(input, 80).unwrap(),
+        r#"*[_type == "post"] { title }"#
+    );
+}
+
+#[test]
+fn test_doc_normalize_flattens_and_merges() {
+    let doc = Doc::concat([
+        Doc::text("a"),
+        Doc::concat([Doc::Nil, Doc::text("b")]),
+        Doc::group(Doc::group(Doc::concat([Doc::text("c"), Doc::line()]))),
+        Doc::nest(0, Doc::text("d")),
+        Doc::nest(2, Doc::Nil),
+    ]);
+    let expected = Doc::concat([
+        Doc::text("ab"),
+        Doc::group(Doc::concat([Doc::text("c"), Doc::line()])),
+        Doc::text("d"),
+    ]);
+    assert_eq!(doc.normalize(), expected);
+}
+
+#[test]
+fn test_highlight_document() {
+    let opts = FormatOptions::new(30);
+    let input =
+        "fn a::b($x)=$x{c};\n*[_type=='post'&&count(tags)>2] // recent\n| order(date desc)[0..10]";
+    let doc = highlight_document(input, &opts, Highlight::Ansi).unwrap();
+    assert!(doc.output.contains("\x1b[90m // recent\x1b[0m"));
+    assert!(doc.output.contains("\x1b[1;34mcount\x1b[0m("));
+    assert!(doc.output.contains("\x1b[32m\"post\"\x1b[0m"));
+
+    // Without the colors, the output is the formatted query.
+    let strip = |colored: &str| {
+        let mut plain = String::new();
+        let mut rest = colored;
+        while let Some(start) = rest.find('\x1b') {
+            plain.push_str(&rest[..start]);
+            rest = &rest[start + rest[start..].find('m').unwrap() + 1..];
+        }
+        plain + rest
+    };
+    assert_eq!(
+        strip(&doc.output),
+        format_query_with_options(input, &opts).unwrap()
+    );
+    for query in CORPUS {
+        let doc = highlight_document(query, &opts, Highlight::Ansi).unwrap();
+        assert_eq!(
+            strip(&doc.output),
+            format_query_with_options(query, &opts).unwrap()
+        );
+    }
+
+    // The color of an enclosing annotation comes back after a nested one.
+    let nested = Doc::annotated(
+        Category::Comment,
+        Doc::concat([
+            Doc::text("a"),
+            Doc::annotated(Category::Param, Doc::text("b")),
+            Doc::text("c"),
+        ]),
+    );
+    assert_eq!(
+        render_ansi(80, &nested),
+        "\x1b[90ma\x1b[31mb\x1b[0m\x1b[90mc\x1b[0m"
+    );
+}
+
+#[test]
+fn test_highlight_document_as_html() {
+    let input = "*[_type=='post'&&a<$max]{\"x\":x}\n\n*[b<";
+    let doc = highlight_document(input, &FormatOptions::default(), Highlight::Html).unwrap();
+    assert_eq!(
+        doc.output,
+        concat!(
+            r#"<span class="groq-keyword">*</span>[<span class="groq-field">_type</span>"#,
+            r#"<span class="groq-operator"> == </span><span class="groq-string">&quot;post&quot;</span>"#,
+            r#" <span class="groq-operator">&amp;&amp; </span><span class="groq-field">a</span>"#,
+            r#"<span class="groq-operator"> &lt; </span><span class="groq-param">$max</span>] { "#,
+            r#"<span class="groq-string">&quot;x&quot;</span>: <span class="groq-field">x</span> }"#,
+            "\n\n*[b&lt;",
+        )
+    );
+    assert_eq!(doc.errors.len(), 1);
+    assert_eq!(render_html(80, &Doc::text("a & b")), "a &amp; b");
+}
+
+#[test]
+fn test_doc_if_break() {
+    let doc = Doc::if_break(Doc::text(","), Doc::Nil);
+    assert_eq!(
+        serde_json::to_value(&doc).unwrap(),
+        serde_json::json!({ "if_break": { "broken": { "text": "," }, "flat": "nil" } })
+    );
+
+    // Alternatives that render the same are not kept apart.
+    assert_eq!(Doc::if_break(Doc::Nil, Doc::text("")).normalize(), Doc::Nil);
+    assert_eq!(
+        Doc::if_break(Doc::text("a"), Doc::concat([Doc::Nil, Doc::text("a")])).normalize(),
+        Doc::text("a")
+    );
+    assert_eq!(
+        Doc::if_break(Doc::concat([Doc::text("a"), Doc::text("b")]), Doc::Nil).normalize(),
+        Doc::if_break(Doc::text("ab"), Doc::Nil)
+    );
+}
+
+#[test]
+fn test_query_doc_is_normalized() {
+    let doc = query_to_doc("*[a]", &FormatOptions::default()).unwrap();
+    assert_eq!(
+        doc,
+        Doc::concat([Doc::text("*"), Doc::group(Doc::text("[a")), Doc::text("]")])
+    );
+}
+
+#[test]
+fn test_explain_records_group_decisions() {
+    let input = r#"*[_type=="test"&&condition1&&condition2&&condition3]"#;
+    let explanation = explain_query(input, &FormatOptions::new(30)).unwrap();
+    assert_eq!(explanation.output, format_query(input, 30).unwrap());
+
+    let first = &explanation.groups[0];
+    assert_eq!((first.line, first.column), (1, 2));
+    assert_eq!(first.available, 29);
+    assert!(first.flat_width.unwrap() > first.available);
+    assert!(first.broke);
+    // The group runs to the closing bracket on the last line.
+    let last = explanation.output.lines().last().unwrap();
+    assert_eq!(first.end_line, explanation.output.lines().count());
+    assert_eq!(first.end_column, last.len());
+
+    let annotated = explanation.annotated();
+    assert!(annotated.starts_with("1 | *[_type == \"test\""));
+    assert!(annotated.contains("\n  |  ^ group 1 broken: needs "));
+    assert!(annotated.contains(" ^ end of group 1\n"));
+}
+
+#[test]
+fn test_query_builder() {
+    let query = Query::all()
+        .filter(r#"_type == "post""#)
+        .filter("defined(slug.current)")
+        .project(["title", "author->{name}"])
+        .build(&FormatOptions::default())
+        .unwrap();
+    assert_eq!(
+        query,
+        r#"*[_type == "post"][defined(slug.current)] { title, author-> { name } }"#
+    );
+}
+
+#[test]
+fn test_query_builder_keeps_steps_apart() {
+    let options = FormatOptions::default();
+    assert_eq!(
+        Query::from("a || b").filter("x").build(&options).unwrap(),
+        "(a || b)[x]"
+    );
+    assert_eq!(Query::all().filter("0").build(&options).unwrap(), "*[(0)]");
+    assert_eq!(
+        Query::all().filter("1..2").build(&options).unwrap(),
+        "*[(1..2)]"
+    );
+    assert_eq!(
+        Query::from("author->")
+            .project(["name"])
+            .build(&options)
+            .unwrap(),
+        "author-> { name }"
+    );
+    assert!(matches!(
+        Query::all().project(["a, b"]).build(&options),
+        Err(FormatError::Parse(_))
+    ));
+}
+
+#[test]
+fn test_query_builder_rejects_invalid_fragment() {
+    let result = Query::all()
+        .filter("_type ==")
+        .build(&FormatOptions::default());
+    assert!(matches!(result, Err(FormatError::Parse(_))));
+}
+
+#[test]
+fn test_format_cache_keys_on_content_and_options() {
+    let cache = FormatCache::new();
+    let input = r#"*[_type=="test"]{field1,field2,field3,field4,field5}"#;
+
+    let wide = cache.format(input, &FormatOptions::new(80)).unwrap();
+    assert_eq!(cache.format(input, &FormatOptions::new(80)).unwrap(), wide);
+    assert_eq!(cache.len(), 1);
+
+    let narrow = cache.format(input, &FormatOptions::new(20)).unwrap();
+    assert_ne!(narrow, wide);
+    assert_eq!(cache.len(), 2);
+
+    assert!(cache.format("*[", &FormatOptions::new(80)).is_err());
+    assert_eq!(cache.len(), 3);
+}
+
+#[test]
+fn test_format_cache_drops_oldest_results() {
+    let cache = FormatCache::with_capacity(2);
+    let options = FormatOptions::default();
+    for query in ["*[a==1]", "*[a==2]", "*[a==3]"] {
+        cache.format(query, &options).unwrap();
+    }
+    assert_eq!(cache.len(), 2);
+    assert_eq!(cache.format("*[a==1]", &options).unwrap(), "*[a == 1]");
+    assert_eq!(cache.len(), 2);
+
+    let uncached = FormatCache::with_capacity(0);
+    assert_eq!(uncached.format("*[a==1]", &options).unwrap(), "*[a == 1]");
+    assert!(uncached.is_empty());
+}
+
+#[test]
+fn test_reusable_formatter_matches_format_query() {
+    let queries = [
+        r#"*[_type=="test"]{field1,field2,field3,field4,field5}"#,
+        r#"*[_type=="test"&&condition1&&condition2&&condition3]"#,
+        "*",
+    ];
+    let mut formatter = Formatter::new(FormatOptions::new(30));
+    for query in queries {
+        assert_eq!(
+            formatter.format(query).unwrap(),
+            format_query(query, 30).unwrap()
+        );
+    }
+    assert!(matches!(
+        formatter.format("  "),
+        Err(FormatError::EmptyQuery)
+    ));
+}
+
+#[test]
+fn test_lenient_removes_trailing_commas() {
+    let input = "*[_type in [\"a\", \"b\",]]{title, // keep me\n slug,}";
+    assert!(matches!(
+        format_query(input, 80),
+        Err(FormatError::Parse(_))
+    ));
+
+    let opts = FormatOptions::new(80).with_lenient(true);
+    let expected = "*[_type in [\"a\", \"b\"]] {\n  title, // keep me\n  slug\n}";
+    assert_eq!(format_query_with_options(input, &opts).unwrap(), expected);
+}
+
+#[test]
+fn test_lenient_leaves_commas_in_strings() {
+    let input = r#"*[title == "a,]"]"#;
+    let opts = FormatOptions::new(80).with_lenient(true);
+    assert_eq!(format_query_with_options(input, &opts).unwrap(), input);
+}
+
+#[test]
+fn test_document_formats_each_query() {
+    let input = "// first\n*[_type==\"a\"]\n\nfn x::y($d) = $d{a};\n\n*[_type==\"b\"]{x::y(@)}\n";
+    let doc = format_document(input, &FormatOptions::default()).unwrap();
+    assert!(doc.errors.is_empty());
+    assert_eq!(
+        doc.output,
+        "// first\n*[_type == \"a\"]\n\nfn x::y($d) = $d { a };\n\n*[_type == \"b\"] { x::y(@) }"
+    );
+}
+
+#[test]
+fn test_document_skips_broken_query() {
+    let input = "*[a==1]\n\n*[_type==\"post\"&&]\n\n*[c==3]";
+    let doc = format_document(input, &FormatOptions::default()).unwrap();
+    assert_eq!(doc.output, "*[a == 1]\n\n*[_type==\"post\"&&]\n\n*[c == 3]");
+    assert_eq!(doc.errors.len(), 1);
+    assert_eq!(doc.errors[0].line, 3);
+    assert!(matches!(doc.errors[0].error, FormatError::Parse(_)));
+}
+
+#[test]
+fn test_document_splits_at_semicolons() {
+    let input = "fn x::y($d) = $d{a}; *[a==1]{x::y(@)};*[b==2];\n*[c==\n";
+    let doc = format_document(input, &FormatOptions::default()).unwrap();
+    assert_eq!(
+        doc.output,
+        "fn x::y($d) = $d { a };\n\n*[a == 1] { x::y(@) };\n\n*[b == 2];\n\n*[c=="
+    );
+    assert_eq!(doc.errors.len(), 1);
+    assert_eq!(doc.errors[0].line, 2);
+
+    let doc = format_document("*[a==1];", &FormatOptions::default()).unwrap();
+    assert_eq!(doc.output, "*[a == 1];");
+}
+
+#[test]
+fn test_document_keeps_queries_apart() {
+    // Together these parse as `*[a==1] * [b==2]`.
+    let doc = format_document("*[a==1]\n\n*[b==2]\n", &FormatOptions::default()).unwrap();
+    assert!(doc.errors.is_empty());
+    assert_eq!(doc.output, "*[a == 1]\n\n*[b == 2]");
+}
+
+#[test]
+fn test_document_range_leaves_other_queries() {
+    let document = "*[b==\n\n*[a==1]{x,y}\n\n*[c==3]";
+    let start = document.find('{').unwrap();
+    let formatted = format_document_range(document, start..start + 1, &FormatOptions::default());
+    assert_eq!(formatted.unwrap(), "*[b==\n\n*[a==1]{ x, y }\n\n*[c==3]");
+
+    let broken = document.find("b==").unwrap();
+    assert!(
+        format_document_range(document, broken..broken + 1, &FormatOptions::default()).is_err()
+    );
+}
+
+#[test]
+fn test_document_single_query_with_blank_lines() {
+    let input = "*[_type==\"post\"]{\n\n  title\n}";
+    let doc = format_document(input, &FormatOptions::default()).unwrap();
+    assert_eq!(doc.output, r#"*[_type == "post"] { title }"#);
+}
+
+#[test]
+fn test_invisible_chars_reported_in_parse_error() {
+    let input = "*[_type\u{200B} == \"post\"]";
+    match format_query(input, 80) {
+        Err(FormatError::InvisibleChars { chars, .. }) => {
+            assert_eq!(chars.len(), 1);
+            assert_eq!((chars[0].line, chars[0].column), (1, 8));
+            assert_eq!(chars[0].ch, '\u{200B}');
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn test_strip_invisible_chars() {
+    let input = "*[_type\u{200B}\u{00A0}==\u{00A0}\"a\u{00A0}b\"]";
+    let opts = FormatOptions::new(80).with_strip_invisible(true);
+    assert_eq!(
+        format_query_with_options(input, &opts).unwrap(),
+        "*[_type == \"a\u{00A0}b\"]"
+    );
+    assert_eq!(find_invisible_chars(input).len(), 3);
+}
+
+#[test]
+fn test_javascript_input_gets_friendly_error() {
+    let input = "import {groq} from 'next-sanity'\n\nexport const q = groq`*[_type == \"post\"]`\n";
+    let err = format_query(input, 80).unwrap_err();
+    let FormatError::NotGroq(message) = &err else {
+        panic!("expected NotGroq, got {:?}", err);
+    };
+    let display = err.to_string();
+    assert!(display.contains("JavaScript/TypeScript"));
+    assert!(display.contains("--embedded js,ts"));
+    assert!(display.contains(message.as_str()));
+}
+
+#[test]
+fn test_encoded_input_suggests_decoding() {
+    let input = "*%5B_type%3D%3D%22post%22%5D%7Btitle%7D";
+    let err = format_query(input, 80).unwrap_err();
+    assert!(matches!(
+        err,
+        FormatError::Encoded {
+            encoding: InputEncoding::Url,
+            ..
+        }
+    ));
+
+    let opts = FormatOptions::new(80).with_decode(Some(InputEncoding::Url));
+    assert_eq!(
+        format_query_with_options(input, &opts).unwrap(),
+        r#"*[_type == "post"] { title }"#
+    );
+}
+
+#[test]
+fn test_decode_escaped_input() {
+    let input = r#"*[_type == \"post\" && title == \"a \\\"b\\\"\"]"#;
+    assert!(matches!(
+        format_query(input, 80),
+        Err(FormatError::Encoded {
+            encoding: InputEncoding::Escaped,
+            ..
+        })
+    ));
+
+    let opts = FormatOptions::new(80).with_decode(Some(InputEncoding::Escaped));
+    assert_eq!(
+        format_query_with_options(input, &opts).unwrap(),
+        r#"*[_type == "post" && title == "a \"b\""]"#
+    );
+}
+
+#[test]
+fn test_allow_empty_input() {
+    assert!(matches!(
+        format_query(" \n\t", 80),
+        Err(FormatError::EmptyQuery)
+    ));
+
+    let opts = FormatOptions::new(80).with_allow_empty(true);
+    assert_eq!(format_query_with_options(" \n\t", &opts).unwrap(), "");
+    assert_eq!(format_query_with_options("*", &opts).unwrap(), "*");
+}
+
+#[test]
+fn test_minimum_width() {
+    let input = r#"*[_type=="test"]{field1,field2,field3,field4,field5}"#;
+    let width = minimum_width(input, &FormatOptions::default()).unwrap();
+    assert_eq!(width, 20);
+    let output = format_query(input, width).unwrap();
+    assert!(output.lines().all(|line| line.len() <= width));
+    let narrower = format_query(input, width - 1).unwrap();
+    assert!(narrower.lines().any(|line| line.len() > width - 1));
+}
+
+#[test]
+fn test_width_counts_characters() {
+    let input = r#"*[_type=="test"]{"títle":"ñandú café","naïve":"日本語のテキスト"}"#;
+    let width = minimum_width(input, &FormatOptions::default()).unwrap();
+    let output = format_query(input, width).unwrap();
+    assert!(overflowing_lines(&output, width).is_empty());
+    let narrower = format_query(input, width - 1).unwrap();
+    assert!(!overflowing_lines(&narrower, width - 1).is_empty());
+
+    // A line of as many characters as the width fits, however many bytes.
+    let flat = format_query(input, usize::MAX).unwrap();
+    assert_eq!(format_query(input, flat.chars().count()).unwrap(), flat);
+    let strict = FormatOptions::new(flat.chars().count()).with_strict_width(true);
+    assert_eq!(format_query_with_options(input, &strict).unwrap(), flat);
+}
+
+#[test]
+fn test_clamp_width_raises_too_small_width() {
+    let input = r#"*[_type=="test"]{field1,field2,field3,field4,field5}"#;
+    let clamped = FormatOptions::new(0).with_clamp_width(true);
+    assert_eq!(
+        format_query_with_options(input, &clamped).unwrap(),
+        format_query(input, 20).unwrap()
+    );
+    // Widths that can be honored are left alone.
+    let wide = FormatOptions::new(80).with_clamp_width(true);
+    assert_eq!(
+        format_query_with_options(input, &wide).unwrap(),
+        format_query(input, 80).unwrap()
+    );
+}
+
+#[test]
+fn test_overflowing_lines() {
+    let input =
+        r#"*[_type=="test"]{title,"description":"a description that is far too long to fit"}"#;
+    let output = format_query(input, 40).unwrap();
+    let overflows = overflowing_lines(&output, 40);
+    assert_eq!(overflows.len(), 1);
+    let line = output.lines().nth(overflows[0].line - 1).unwrap();
+    assert!(line.contains("far too long"));
+    assert_eq!(overflows[0].width, line.len());
+
+    assert!(overflowing_lines(&output, 80).is_empty());
+}
+
+#[test]
+fn test_strict_width() {
+    let input =
+        r#"*[_type=="test"]{title,"description":"a description that is far too long to fit"}"#;
+    let options = FormatOptions::new(40).with_strict_width(true);
+    match format_query_with_options(input, &options) {
+        Err(FormatError::WidthExceeded { width, lines }) => {
+            assert_eq!(width, 40);
+            assert_eq!(lines.len(), 1);
+        }
+        other => panic!("expected WidthExceeded, got {:?}", other),
+    }
+
+    let options = FormatOptions::new(80).with_strict_width(true);
+    assert!(format_query_with_options(input, &options).is_ok());
+}
+
+#[test]
+fn test_exempt_long_strings() {
+    let input = r#"*[_type=="link" && url=="https://example.com/a/very/long/path/that/cannot/be/broken"]{title}"#;
+    let default = format_query(input, 40).unwrap();
+    assert!(default.contains('\n'));
+
+    let options = FormatOptions::new(40).with_exempt_long_strings(true);
+    let exempt = format_query_with_options(input, &options).unwrap();
+    assert_eq!(
+        exempt,
+        "*[_type == \"link\" && url == \"https://example.com/a/very/long/path/that/cannot/be/broken\"] {\n  title\n}"
+    );
+}
+
+#[test]
+fn test_disk_cache_persists_clean_entries() {
+    let dir = tempfile::tempdir().unwrap();
+    let options = FormatOptions::default();
+    let content = "*[_type == \"post\"]\n";
+
+    let mut cache = DiskCache::open(dir.path()).unwrap();
+    assert!(cache.is_empty());
+    cache.mark_clean(content, &options);
+    cache.save().unwrap();
+
+    let saved = std::fs::read_to_string(dir.path().join("clean")).unwrap();
+    let entry = saved.lines().nth(1).unwrap();
+    assert_eq!(entry.len(), 64);
+    assert!(entry.bytes().all(|b| b.is_ascii_hexdigit()));
+
+    let cache = DiskCache::open(dir.path()).unwrap();
+    assert!(cache.is_clean(content, &options));
+    assert!(!cache.is_clean(content, &options.clone().with_force_wrap(true)));
+    assert!(!cache.is_clean("*[_type == \"page\"]\n", &options));
+}
+
+#[test]
+fn test_string_escaping() {
+    assert_eq!(
+        format_query(r#"*[title=="plain"]"#, 80).unwrap(),
+        r#"*[title == "plain"]"#
+    );
+    assert_eq!(
+        format_query(r#"*[title=="say \"hi\"\n\\"]"#, 80).unwrap(),
+        r#"*[title == "say \"hi\"\n\\"]"#
+    );
+}
+
+#[test]
+fn test_large_generated_query() {
+    let fields: Vec<String> = (0..20000).map(|i| format!("field{}", i)).collect();
+    let input = format!("*[_type==\"generated\"]{{{}}}", fields.join(","));
+    let output = format_query(&input, 80).unwrap();
+    assert_eq!(output.lines().count(), 20002);
+    assert_eq!(output.lines().nth(20000), Some("  field19999"));
+}
+
+#[test]
+fn test_memory_limit() {
+    let fields: Vec<String> = (0..1000).map(|i| format!("field{}", i)).collect();
+    let input = format!("*[_type==\"generated\"]{{{}}}", fields.join(","));
+
+    let options = FormatOptions::default().with_approximate_memory_limit(Some(1 << 20));
+    assert!(format_query_with_options(&input, &options).is_ok());
+
+    let options = FormatOptions::default().with_approximate_memory_limit(Some(16 * 1024));
+    match format_query_with_options(&input, &options) {
+        Err(FormatError::MemoryLimit { limit, needed }) => {
+            assert_eq!(limit, 16 * 1024);
+            assert!(needed > limit);
+        }
+        other => panic!("expected MemoryLimit, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_memory_limit_stops_building_document() {
+    let input = format!("*{}", "[a==1]".repeat(2000));
+    let needed = |limit| {
+        let options = FormatOptions::default().with_approximate_memory_limit(Some(limit));
+        match format_query_with_options(&input, &options) {
+            Err(FormatError::MemoryLimit { needed, .. }) => needed,
+            other => panic!("expected MemoryLimit, got {:?}", other),
+        }
+    };
+
+    // Refused before parsing, with the estimate for the syntax tree.
+    let tree = needed(1);
+    // Refused while building the document, as soon as it is over the limit.
+    let limit = tree + 16 * 1024;
+    let needed = needed(limit);
+    assert!(needed > limit && needed < limit + 4 * 1024, "{}", needed);
+}
+
+#[test]
+fn test_long_chains_format_without_recursion() {
+    // Generated queries can chain more steps than the 2 MiB stack of a test
+    // thread would allow recursing through.
+    let n = 10_000;
+
+    let dots = format!("a{}", ".b".repeat(n));
+    assert_eq!(format_query(&dots, 80).unwrap(), dots);
+
+    let filters = format!("*{}", "[a==1]".repeat(n));
+    assert_eq!(
+        format_query(&filters, 80).unwrap(),
+        format!("*{}", "[a == 1]".repeat(n))
+    );
+
+    let or = vec!["a == 1"; n].join(" || ");
+    let formatted = format_query(&or, 40).unwrap();
+    let lines: Vec<&str> = formatted.lines().collect();
+    assert_eq!(lines[0], "a == 1 || a == 1 || a == 1 || a == 1");
+    assert_eq!(lines.len(), n - 3);
+    assert!(lines[1..].iter().all(|line| *line == "  || a == 1"));
+}
+
+#[test]
+fn test_is_formatted() {
+    let options = FormatOptions::new(30);
+    let input = r#"*[_type=="test"]{field1,field2,field3}"#;
+    assert!(!is_formatted(input, &options).unwrap());
+
+    let output = format_query_with_options(input, &options).unwrap();
+    assert!(is_formatted(&output, &options).unwrap());
+    // Any difference in whitespace means the query is not formatted.
+    assert!(!is_formatted(&output.replacen("  field1", "   field1", 1), &options).unwrap());
+    assert!(!is_formatted(&format!("{} ", output), &options).unwrap());
+    assert!(is_formatted("*[", &options).is_err());
+}
+
+#[test]
+fn test_format_many_matches_format_query() {
+    let queries = [
+        r#"*[_type=="post"]{title}"#,
+        "*[",
+        r#"*[_type=="test"]{field1,field2,field3}"#,
+    ];
+    let options = FormatOptions::new(30);
+    let results: Vec<_> = format_many(queries.iter().copied(), &options).collect();
+    assert_eq!(results.len(), 3);
+    for (query, result) in queries.iter().zip(results) {
+        match format_query_with_options(query, &options) {
+            Ok(expected) => assert_eq!(result.unwrap(), expected),
+            Err(_) => assert!(result.is_err()),
+        }
+    }
+}
+
+#[test]
+fn test_pipeline_stages_agree() {
+    let input = r#"*[_type=="post"]{title} // posts"#;
+    let options = FormatOptions::default();
+    let pipeline = Pipeline::run(input, &options).unwrap();
+    assert_eq!(pipeline.output, format_query(input, 80).unwrap());
+    assert_eq!(pipeline.doc, query_to_doc(input, &options).unwrap());
+    assert_eq!(
+        pipeline
+            .tokens
+            .iter()
+            .map(|t| t.text(&pipeline.source))
+            .collect::<String>(),
+        pipeline.source
+    );
+    assert!(pipeline.tokens.iter().any(|t| t.kind == TokenKind::Comment));
+    assert!(matches!(
+        Pipeline::run("", &options.with_allow_empty(true)),
+        Err(FormatError::EmptyQuery)
+    ));
+}
+
+#[test]
+fn test_core_types_are_send_and_sync() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Doc>();
+    assert_send_sync::<FormatOptions>();
+    assert_send_sync::<FormatError>();
+    assert_send_sync::<Formatter>();
+    assert_send_sync::<FormatCache>();
+    assert_send_sync::<DiskCache>();
+    assert_send_sync::<Query>();
+}
+
+#[test]
+fn test_format_cache_shared_between_threads() {
+    let cache = std::sync::Arc::new(FormatCache::new());
+    let handles: Vec<_> = (0..4)
+        .map(|i| {
+            let cache = cache.clone();
+            std::thread::spawn(move || {
+                let query = format!("*[a=={}]", i % 2);
+                cache.format(&query, &FormatOptions::default()).unwrap()
+            })
+        })
+        .collect();
+    for (i, handle) in handles.into_iter().enumerate() {
+        assert_eq!(handle.join().unwrap(), format!("*[a == {}]", i % 2));
+    }
+    assert_eq!(cache.len(), 2);
+}
+
+#[test]
+fn test_conditional_body_moves_to_next_line() {
+    let input = r#"*[_type=="movie"]{title,releaseYear>2000=>{"era":"modern","rating":rating,"studio":studio->name}}"#;
+    let expected = r#"*[_type == "movie"] {
+  title,
+  releaseYear > 2000 =>
+    { "era": "modern", "rating": rating, "studio": studio->name }
+}"#;
+    assert_eq!(format_query(input, 70).unwrap(), expected);
+
+    let expected = r#"*[_type == "movie"] {
+  title,
+  releaseYear > 2000 =>
+    {
+      "era": "modern",
+      "rating": rating,
+      "studio": studio->name
+    }
+}"#;
+    assert_eq!(format_query(input, 40).unwrap(), expected);
+}
+
+#[test]
+fn test_select_breaks_like_object() {
+    let input = r#"*[_type=="product"]{title,"badge":select(stock==0=>"sold out",stock<5=>"almost gone","in stock")}"#;
+    let expected = r#"*[_type == "product"] {
+  title,
+  "badge": select(
+    stock == 0 => "sold out",
+    stock < 5 => "almost gone",
+    "in stock"
+  )
+}"#;
+    assert_eq!(format_query(input, 60).unwrap(), expected);
+    assert_eq!(
+        format_query(r#"select(a=>"x","y")"#, 80).unwrap(),
+        r#"select(a => "x", "y")"#
+    );
+}
+
+#[test]
+fn test_score_puts_each_boost_on_its_own_line() {
+    let input =
+        r#"*[_type=="post"]|score(boost(title match $q,3),boost(body match $q,1),tags match $q)"#;
+    let expected = r#"*[_type == "post"]
+  | score(
+    boost(title match $q, 3),
+    boost(body match $q, 1),
+    tags match $q
+  )"#;
+    assert_eq!(format_query(input, 50).unwrap(), expected);
+}
+
+#[test]
+fn test_geo_constructors_stay_flat() {
+    let input = r#"*[_type=="store"&&geo::distance(location,geo::latLng(59.91,10.75))<10000]"#;
+    assert_eq!(
+        format_query(input, 81).unwrap(),
+        r#"*[_type == "store" && geo::distance(location, geo::latLng(59.91, 10.75)) < 10000]"#
+    );
+
+    let input = "geo::distance(location,geo::latLng(59.91,10.75))<10000";
+    let options = FormatOptions::new(50).with_force_wrap(true);
+    assert_eq!(
+        format_query_with_options(input, &options).unwrap(),
+        "geo::distance(location, geo::latLng(59.91, 10.75))\n  < 10000"
+    );
+
+    let output = format_query(input, 30).unwrap();
+    assert!(output.contains("geo::latLng(59.91, 10.75)"));
+}
+
+#[test]
+fn test_portable_text_calls_stay_attached() {
+    let input =
+        r#"*[_type=="post"]{title,"plaintext":pt::text(body[0..5]),"excerpt":pt::text(excerpt)}"#;
+    let expected = r#"*[_type == "post"] {
+  title,
+  "plaintext": pt::text(body[0..5]),
+  "excerpt": pt::text(excerpt)
+}"#;
+    assert_eq!(format_query(input, 40).unwrap(), expected);
+    let options = FormatOptions::new(40).with_force_wrap(true);
+    assert_eq!(
+        format_query_with_options(input, &options).unwrap(),
+        expected
+    );
+}
+
+#[test]
+fn test_force_wrap_fills_arithmetic_chains() {
+    let input =
+        "*[dateTime(publishedAt)>dateTime(now())-60*60*24*7*4*12+offsetSeconds-graceSeconds]";
+    let options = FormatOptions::new(40).with_force_wrap(true);
+    let expected = "*[
+  dateTime(publishedAt)
+    > dateTime(now())
+      - 60 * 60 * 24 * 7 * 4 * 12
+      + offsetSeconds - graceSeconds
+]";
+    assert_eq!(
+        format_query_with_options(input, &options).unwrap(),
+        expected
+    );
+}
+
+#[test]
+fn test_traversal_and_dereference_chains() {
+    assert_eq!(
+        format_query(r#"*[_type=="book"]{authors[]->posts[]->{title}}"#, 80).unwrap(),
+        r#"*[_type == "book"] { authors[]->posts[]-> { title } }"#
+    );
+    assert_eq!(
+        format_query(r#"*[_type=="book"]{"urls":images[].asset->url}"#, 80).unwrap(),
+        r#"*[_type == "book"] { "urls": images[].asset->url }"#
+    );
+    assert_eq!(
+        format_query(
+            r#"*[_type=="book"]{"x":authors[]->{name,"bio":bio[0].children[].text}}"#,
+            40
+        )
+        .unwrap(),
+        r#"*[_type == "book"] {
+  "x": authors[]-> {
+    name,
+    "bio": bio[0].children[].text
+  }
+}"#
+    );
+    // Traversal chains never break; only projection bodies do.
+    let output = format_query(
+        "*[_type==\"book\"]{authors[]->publisher->address.street.line1}",
+        20,
+    )
+    .unwrap();
+    assert!(output.contains("  authors[]->publisher->address.street.line1\n"));
+    assert_eq!(
+        format_query("*[_type==\"book\"]{authors[]{name}}", 80).unwrap(),
+        r#"*[_type == "book"] { authors[] { name } }"#
+    );
+}
+
+#[test]
+fn test_parent_reference_chains() {
+    let input = r#"*[_type=="x"]{"p":*[_id==^.^._id&&^.foo==^.bar]}"#;
+    assert_eq!(
+        format_query(input, 80).unwrap(),
+        r#"*[_type == "x"] { "p": *[_id == ^.^._id && ^.foo == ^.bar] }"#
+    );
+    // When the nested constraint breaks, parent chains stay whole.
+    let expected = r#"*[_type == "x"] {
+  "p": *[
+    _id == ^.^._id
+      && ^.foo == ^.bar
+  ]
+}"#;
+    let options = FormatOptions::new(30).with_force_wrap(true);
+    assert_eq!(
+        format_query_with_options(input, &options).unwrap(),
+        expected
+    );
+}
+
+#[test]
+fn test_long_tuple_breaks_like_array() {
+    let input = r#"*[(firstName,lastName,middleName,nickname)==("Jonathan","Appleseed","Quincy","Johnny")]"#;
+    let expected = r#"*[(firstName, lastName, middleName, nickname) == (
+  "Jonathan",
+  "Appleseed",
+  "Quincy",
+  "Johnny"
+)]"#;
+    assert_eq!(format_query(input, 80).unwrap(), expected);
+    assert_eq!(
+        format_query("*[(a,b)==(1,2)]", 80).unwrap(),
+        "*[(a, b) == (1, 2)]"
+    );
+}
+
+#[test]
+fn test_long_boolean_group_breaks_inside_parens() {
+    let input = r#"*[_type=="post"]{"visible":(defined(publishedAt)&&publishedAt<now()||preview==true&&defined(draftToken))}"#;
+    let expected = r#"*[_type == "post"] {
+  "visible": (
+    defined(publishedAt) && publishedAt < now()
+      || preview == true && defined(draftToken)
+  )
+}"#;
+    assert_eq!(format_query(input, 60).unwrap(), expected);
+    assert_eq!(
+        format_query("*[(a||b)&&c]", 80).unwrap(),
+        "*[(a || b) && c]"
+    );
+}
+
+#[test]
+fn test_filter_fitting_accounts_for_projection_brace() {
+    // The filter fits in 44 columns on its own, but not with the `] {`
+    // that follows it on the same line.
+    let input = r#"*[_type=="post"&&defined(slug.current)]{title,slug}"#;
+    assert_eq!(
+        format_query(input, 44).unwrap(),
+        r#"*[_type == "post"
+  && defined(slug.current)] { title, slug }"#
+    );
+    assert_eq!(
+        format_query(input, 45).unwrap(),
+        r#"*[_type == "post" && defined(slug.current)] {
+  title,
+  slug
+}"#
+    );
+}
+
+#[test]
+fn test_break_depth_forces_nested_objects_to_break() {
+    let input = r#"*[_type=="post"]{title,"image":image{alt,asset->{url}}}"#;
+    assert_eq!(
+        format_query(input, 80).unwrap(),
+        r#"*[_type == "post"] { title, "image": image { alt, asset-> { url } } }"#
+    );
+    let options = FormatOptions::new(80).with_break_depth(Some(2));
+    assert_eq!(
+        format_query_with_options(input, &options).unwrap(),
+        r#"*[_type == "post"] {
+  title,
+  "image": image {
+    alt,
+    asset-> {
+      url
+    }
+  }
+}"#
+    );
+    let options = FormatOptions::new(80).with_break_depth(Some(3));
+    assert_eq!(
+        format_query_with_options(input, &options).unwrap(),
+        format_query(input, 80).unwrap()
+    );
+}
+
+#[test]
+fn test_indentation() {
+    let input = r#"*[_type=="post" && defined(slug) && publishedAt < now()]{title, "author": author->{name, bio, image}}"#;
+    let options = FormatOptions::new(44).with_indent(4);
+    assert_eq!(
+        format_query_with_options(input, &options).unwrap(),
+        r#"*[_type == "post" && defined(slug)
+    && publishedAt < now()] {
+    title,
+    "author": author-> { name, bio, image }
+}"#
+    );
+
+    // Tabs stand for the indent width; alignment past them uses spaces.
+    let options = options.with_use_tabs(true).with_align_conditions(true);
+    assert_eq!(
+        format_query_with_options(input, &options).unwrap(),
+        "*[_type == \"post\" && defined(slug)\n  && publishedAt < now()] {\n\ttitle,\n\t\"author\": author-> { name, bio, image }\n}"
+    );
+}
+
+#[test]
+fn test_construct_widths() {
+    let input = r#"*[_type == "post" && defined(slug) && !(_id in path("drafts.**"))]{title, "slug": slug.current, "date": coalesce(publishedAt, _createdAt, _updatedAt)}"#;
+    let options = FormatOptions::new(60)
+        .with_filter_width(Some(100))
+        .with_projection_width(Some(40))
+        .with_argument_width(Some(40));
+    assert_eq!(
+        format_query_with_options(input, &options).unwrap(),
+        r#"*[_type == "post" && defined(slug) && !(_id in path("drafts.**"))] {
+  title,
+  "slug": slug.current,
+  "date": coalesce(publishedAt,
+    _createdAt,
+    _updatedAt)
+}"#
+    );
+    assert_eq!(options.max_width(), 100);
+
+    // Without them, everything breaks at the same width.
+    assert_eq!(
+        format_query(input, 60).unwrap(),
+        r#"*[_type == "post" && defined(slug)
+  && !(_id in path("drafts.**"))] {
+  title,
+  "slug": slug.current,
+  "date": coalesce(publishedAt, _createdAt, _updatedAt)
+}"#
+    );
+}
+
+#[test]
+fn test_inline_single_field_projections() {
+    let input =
+        r#"*[_type=="post"]{title,"category":category->{title},"authors":authors[]->{name,slug}}"#;
+    let options = FormatOptions::new(30).with_inline_single_field(true);
+    assert_eq!(
+        format_query_with_options(input, &options).unwrap(),
+        r#"*[_type == "post"] {
+  title,
+  "category": category-> { title },
+  "authors": authors[]-> {
+    name,
+    slug
+  }
+}"#
+    );
+    assert!(
+        format_query(input, 30)
+            .unwrap()
+            .contains("category-> {\n    title\n  }")
+    );
+    // Comments inside the object still lay it out normally.
+    let input = "*[_type==\"post\"]{category->{\n// shown in card\ntitle}}";
+    let output = format_query_with_options(input, &options).unwrap();
+    assert!(output.contains("// shown in card\n"));
+}
+
+#[test]
+fn test_field_order_is_preserved() {
+    let fields = [
+        "...",
+        r#""title":coalesce(title,name)"#,
+        "slug",
+        "author->{name,...}",
+        r#""tags":tags[]->title"#,
+        r#""image":image{...,asset->{url}}"#,
+        r#""count":count(*[_type=="comment"&&post._ref==^._id])"#,
+        "_id",
+    ];
+    let option_sets = [
+        FormatOptions::new(80),
+        FormatOptions::new(80).with_force_wrap(true),
+        FormatOptions::new(80).with_break_depth(Some(1)),
+        FormatOptions::new(80).with_inline_single_field(true),
+    ];
+
+    // Deterministic pseudo-random permutations of the fields.
+    let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+    for _ in 0..50 {
+        let mut order: Vec<&str> = fields.to_vec();
+        for i in (1..order.len()).rev() {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            order.swap(i, (seed % (i as u64 + 1)) as usize);
+        }
+        let input = format!(r#"*[_type=="post"]{{{}}}"#, order.join(","));
+        let stripped: String = input.split_whitespace().collect();
+
+        for options in &option_sets {
+            for width in [10, 30, 60, 120] {
+                let options = FormatOptions {
+                    width,
+                    ..options.clone()
+                };
+                let output = format_query_with_options(&input, &options).unwrap();
+                let output: String = output.split_whitespace().collect();
+                assert_eq!(output, stripped, "{input} at width {width}");
+            }
+        }
+    }
+}
+
+#[test]
+fn test_sort_fields() {
+    let options = FormatOptions::new(80).with_sort_fields(true);
+    let format = |input| format_query_with_options(input, &options).unwrap();
+    assert_eq!(
+        format(r#"*{title,_id,...,slug,"b":a,body,author->name,a,a}"#),
+        r#"* { _id, title, ..., slug, "b": a, body, author->name, a, a }"#
+    );
+    // Fields next to a comment stay with it.
+    assert_eq!(
+        format("*{d,c,b, // note\na}"),
+        "* {\n  c,\n  d,\n  b, // note\n  a\n}"
+    );
+    // Nested projections are sorted too, and the option is off by default.
+    assert_eq!(format("*{z{y,x}}"), "* { z { x, y } }");
+    assert_eq!(format_query("*{b,a}", 80).unwrap(), "* { b, a }");
+}
+
+#[test]
+fn test_quote_style() {
+    let input = r#"*[_type=='post' && title!="it's" && slug=='a\'b\"c'][0]{"x":'y'}"#;
+    let format = |quote_style| {
+        let options = FormatOptions::new(100).with_quote_style(quote_style);
+        format_query_with_options(input, &options).unwrap()
+    };
+    assert_eq!(
+        format(QuoteStyle::Double),
+        r#"*[_type == "post" && title != "it's" && slug == "a'b\"c"][0] { "x": "y" }"#
+    );
+    assert_eq!(
+        format(QuoteStyle::Single),
+        r#"*[_type == 'post' && title != 'it\'s' && slug == 'a\'b"c'][0] { 'x': 'y' }"#
+    );
+    assert_eq!(
+        format(QuoteStyle::Preserve),
+        r#"*[_type == 'post' && title != "it's" && slug == 'a\'b"c'][0] { "x": 'y' }"#
+    );
+    // Each style formats to itself.
+    for quote_style in [QuoteStyle::Double, QuoteStyle::Single, QuoteStyle::Preserve] {
+        let options = FormatOptions::new(100).with_quote_style(quote_style);
+        let output = format(quote_style);
+        assert_eq!(
+            format_query_with_options(&output, &options).unwrap(),
+            output
+        );
+    }
+}
+
+#[test]
+fn test_spacing_profiles() {
+    let input = r#"*[!defined(a)]|order(x)[0..10]{"b":select(a=>"x","y")}"#;
+    let format = |spacing| {
+        let options = FormatOptions::default().with_spacing(spacing);
+        format_query_with_options(input, &options).unwrap()
+    };
+    assert_eq!(
+        format(Spacing::Standard),
+        r#"*[!defined(a)] | order(x)[0..10] { "b": select(a => "x", "y") }"#
+    );
+    assert_eq!(
+        format(Spacing::Tight),
+        r#"*[!defined(a)] | order(x)[0..10] { "b": select(a=>"x", "y") }"#
+    );
+    assert_eq!(
+        format(Spacing::Spacious),
+        r#"*[! defined(a)] | order(x)[0 .. 10] { "b": select(a => "x", "y") }"#
+    );
+    assert_eq!(format(Spacing::Standard), format_query(input, 80).unwrap());
+}
+
+#[test]
+fn test_trailing_comments_stay_on_their_line() {
+    let input = r#"*[_type=="post" // only posts
+// and published
+&& defined(slug) && !(_id in path("drafts.**")) // no drafts
+]{title, // shown in card
+slug}"#;
+    let expected = r#"*[_type == "post" // only posts
+  // and published
+  && defined(slug)
+  && !(_id in path("drafts.**")) // no drafts
+] {
+  title, // shown in card
+  slug
+}"#;
+    let output = format_query(input, 80).unwrap();
+    assert_eq!(output, expected);
+    assert_eq!(format_query(&output, 80).unwrap(), expected);
+}
+
+#[test]
+fn test_reflow_comments() {
+    let input = "// Fetch all published posts for the landing page, including their authors and categories, sorted by date.
+//
+// - drafts are excluded
+*[_type==\"post\"]{
+  // The title is shown in the card header and also used as the alt text for the image when none is set.
+  title
+}";
+    let expected = "// Fetch all published posts for the landing page,
+// including their authors and categories, sorted
+// by date.
+//
+// - drafts are excluded
+*[_type == \"post\"] {
+  // The title is shown in the card header and
+  // also used as the alt text for the image when
+  // none is set.
+  title
+}";
+    let options = FormatOptions::new(50).with_reflow_comments(true);
+    let output = format_query_with_options(input, &options).unwrap();
+    assert_eq!(output, expected);
+    assert!(is_formatted(&output, &options).unwrap());
+    // Without the option, comments are kept as written.
+    assert!(format_query(input, 50).unwrap().contains("sorted by date."));
+}
+
+#[test]
+fn test_reflow_comments_with_tabs() {
+    let input = "*[_type==\"post\"]{
+\t// The title is shown in the card header and also used as the alt text for the image when none is set.
+\ttitle
+}";
+    // The tab counts as four columns, like in the rest of the layout.
+    let expected = "*[_type == \"post\"] {
+\t// The title is shown in the card header and
+\t// also used as the alt text for the image
+\t// when none is set.
+\ttitle
+}";
+    let options = FormatOptions::new(50)
+        .with_indent(4)
+        .with_use_tabs(true)
+        .with_reflow_comments(true);
+    let output = format_query_with_options(input, &options).unwrap();
+    assert_eq!(output, expected);
+    assert!(is_formatted(&output, &options).unwrap());
+}
+
+#[test]
+fn test_align_conditions_under_first_condition() {
+    let input =
+        r#"*[_type=="post"]{"c":*[_type=="comment"&&post._ref==^._id&&approved==true]{text}}"#;
+    let options = FormatOptions::new(40).with_align_conditions(true);
+    assert_eq!(
+        format_query_with_options(input, &options).unwrap(),
+        r#"*[_type == "post"] {
+  "c": *[_type == "comment"
+         && post._ref == ^._id
+         && approved == true] { text }
+}"#
+    );
+    let options = options.with_force_wrap(true);
+    assert_eq!(
+        format_query_with_options(input, &options).unwrap(),
+        r#"*[_type == "post"] {
+  "c": *[
+    _type == "comment"
+    && post._ref == ^._id
+    && approved == true
+  ] { text }
+}"#
+    );
+}
+
+#[test]
+fn test_long_pipelines_break_before_every_stage() {
+    let input = r#"*[_type=="post"]|order(a)|order(b)|order(c)"#;
+    assert_eq!(
+        format_query(input, 40).unwrap(),
+        r#"*[_type == "post"]
+  | order(a)
+  | order(b)
+  | order(c)"#
+    );
+    let input = r#"*[_type=="post"]|order(publishedAt desc)[0..10]|score(title match $q)|order(_score desc)[0]{title,slug}"#;
+    assert_eq!(
+        format_query(input, 60).unwrap(),
+        r#"*[_type == "post"]
+  | order(publishedAt desc)[0..10]
+  | score(title match $q)
+  | order(_score desc)[0] { title, slug }"#
+    );
+    // Two stages keep the generic layout.
+    assert_eq!(
+        format_query(r#"*[_type=="post"]|order(a)|order(b)"#, 30).unwrap(),
+        "*[_type == \"post\"] | order(a)\n  | order(b)"
+    );
+}
+
+#[test]
+fn test_closing_bracket_on_own_line() {
+    let input = r#"*[_type=="post"]{"c":*[_type=="comment"&&post._ref==^._id&&approved==true]{text,author}}"#;
+    let options = FormatOptions::new(40).with_closing_bracket_on_own_line(true);
+    assert_eq!(
+        format_query_with_options(input, &options).unwrap(),
+        r#"*[_type == "post"] {
+  "c": *[_type == "comment"
+    && post._ref == ^._id
+    && approved == true
+  ] { text, author }
+}"#
+    );
+    // Filters that fit keep the bracket in place.
+    let options = FormatOptions::new(100).with_closing_bracket_on_own_line(true);
+    assert_eq!(
+        format_query_with_options(input, &options).unwrap(),
+        format_query(input, 100).unwrap()
+    );
+}
+
+#[test]
+fn test_decode_quoted_string_input() {
+    let input = r#""*[_type == \"post\" && title == \"a \\\"b\\\"\"]{title}""#;
+    assert_eq!(detect_encoding(input), Some(InputEncoding::Quoted));
+    let opts = FormatOptions::new(80).with_decode(Some(InputEncoding::Quoted));
+    assert_eq!(
+        format_query_with_options(input, &opts).unwrap(),
+        r#"*[_type == "post" && title == "a \"b\""] { title }"#
+    );
+    assert_eq!(
+        detect_encoding(r#"'*[_type == "post"]'"#),
+        Some(InputEncoding::Quoted)
+    );
+    // Ordinary string literals are left alone.
+    assert_eq!(detect_encoding(r#""hello world""#), None);
+    assert_eq!(detect_encoding(r#""a" + "b""#), None);
+}
+
+#[test]
+fn test_query_parameters() {
+    let query = r#"*[_type == $type && slug.current == $slug] | order(_createdAt desc) [$start...$end] { "same": $type }"#;
+    assert_eq!(
+        query_parameters(query),
+        vec!["type", "slug", "start", "end"]
+    );
+    assert_eq!(
+        query_parameters(r#"*[title == "$notParam"]"#),
+        Vec::<&str>::new()
+    );
+    assert_eq!(query_parameters("*[a == $b"), vec!["b"]);
+}
+
+#[test]
+fn test_extract_request_queries() {
+    let har = r#"{"log": {"entries": [
+      {"request": {"method": "GET", "url": "https://abc.api.sanity.io/v2021-10-21/data/query/production?query=*%5B_type%20%3D%3D%20%24type%5D&%24type=%22post%22"}},
+      {"request": {"method": "GET", "url": "https://abc.api.sanity.io/v2021-10-21/assets/images"}},
+      {"request": {"method": "POST", "url": "https://abc.api.sanity.io/v2021-10-21/data/query/production",
+        "postData": {"mimeType": "application/json", "text": "{\"query\": \"*[_id == $id]\", \"params\": {\"id\": \"x\"}}"}}}
+    ]}}"#;
+    assert_eq!(
+        extract_request_queries(har),
+        vec![
+            RequestQuery {
+                url: "https://abc.api.sanity.io/v2021-10-21/data/query/production?query=*%5B_type%20%3D%3D%20%24type%5D&%24type=%22post%22".to_string(),
+                query: "*[_type == $type]".to_string(),
+            },
+            RequestQuery {
+                url: "https://abc.api.sanity.io/v2021-10-21/data/query/production".to_string(),
+                query: "*[_id == $id]".to_string(),
+            },
+        ]
+    );
+
+    let urls = "https://abc.apicdn.sanity.io/v1/data/query/production?query=count(*)\n\nhttps://example.com/\n";
+    let queries = extract_request_queries(urls);
+    assert_eq!(queries.len(), 1);
+    assert_eq!(queries[0].query, "count(*)");
+}
+
+#[test]
+fn test_bundle_typescript_module() {
+    assert_eq!(query_name("post_by_slug", "*"), "postBySlug");
+    assert_eq!(query_name("404-page", "*"), "_404Page");
+    assert_eq!(
+        query_name("ignored", "// Fetches a post.\n// @name postQuery\n*"),
+        "postQuery"
+    );
+    // Only the comments at the top of a query are headers.
+    assert_eq!(query_name("posts", "*[a]\n// @name other"), "posts");
+
+    let module = typescript_module(&[
+        BundledQuery {
+            name: "first".to_string(),
+            description: Some("All posts, */ newest first.".to_string()),
+            query: "*[_type == \"post\"] {\n  title\n}".to_string(),
+        },
+        BundledQuery {
+            name: "second".to_string(),
+            description: None,
+            query: r#"*[title match "a\\b${x}"]"#.to_string(),
+        },
+    ]);
+    let expected = r#"// This file is generated by groq-format from .groq files. Do not edit.
+
+import groq from "groq";
+
+/** All posts, *\/ newest first. */
+export const first = groq`*[_type == "post"] {
+  title
+}`;
+
+export const second = groq`*[title match "a\\\\b\${x}"]`;
+"#;
+    assert_eq!(module, expected);
+}
+
+#[test]
+fn test_query_metadata_header() {
+    let input = r#"// @name postBySlug
+// @description Fetch a published post by its slug,
+//   with its author expanded.
+// @internal
+
+*[_type=="post"&&slug.current==$slug][0]{...,author->}"#;
+    let metadata = query_metadata(input);
+    assert_eq!(metadata.name.as_deref(), Some("postBySlug"));
+    assert_eq!(
+        metadata.description.as_deref(),
+        Some("Fetch a published post by its slug, with its author expanded.")
+    );
+
+    // The header stays at the top, and so does the blank line after it.
+    let expected = r#"// @name postBySlug
+// @description Fetch a published post by its slug,
+//   with its author expanded.
+// @internal
+
+*[_type == "post" && slug.current == $slug][0] { ..., author-> }"#;
+    assert_eq!(format_query(input, 80).unwrap(), expected);
+    assert_eq!(format_query(expected, 80).unwrap(), expected);
+
+    // Tags after the first line of code are not metadata.
+    assert_eq!(query_metadata("*[a] // @name x"), QueryMetadata::default());
+}
+
+#[test]
+fn test_extract_embedded_queries() {
+    let source = r#"import groq from "groq";
+
+export const postQuery = groq`
+  // @name postBySlug
+  *[_type == "post" && slug.current == $slug][0]
+`;
+const authors: string = groq`*[_type == "author"] { "bio": pt::text(bio) }`;
+const dynamic = groq`*[_type == ${type}]`;
+export const pages = defineQuery("*[_type == \"page\"]");
+const notAQuery = mygroq`*`;
+"#;
+    assert_eq!(
+        extract_embedded_queries(source),
+        vec![
+            EmbeddedQuery {
+                line: 3,
+                name: Some("postBySlug".to_string()),
+                query:
+                    "\n  // @name postBySlug\n  *[_type == \"post\" && slug.current == $slug][0]\n"
+                        .to_string(),
+            },
+            EmbeddedQuery {
+                line: 7,
+                name: Some("authors".to_string()),
+                query: r#"*[_type == "author"] { "bio": pt::text(bio) }"#.to_string(),
+            },
+            EmbeddedQuery {
+                line: 9,
+                name: Some("pages".to_string()),
+                query: r#"*[_type == "page"]"#.to_string(),
+            },
+        ]
+    );
+
+    assert_eq!(
+        query_fingerprint("*[_type == \"post\"] // posts"),
+        query_fingerprint("*[_type==\"post\"]")
+    );
+    assert_ne!(query_fingerprint("*[a.b]"), query_fingerprint("*[a .b c]"));
+}
+
+#[test]
+fn test_format_embedded_queries() {
+    let source = r#"export const postQuery = groq`
+  // @name postBySlug
+  *[_type=="post"&&slug.current==$slug][0]
+`;
+function authors() {
+  return defineQuery('*[_type=="author" && name != \'\']{name, "bio": pt::text(bio), "posts": count(*[references(^._id)])}');
+}
+const dynamic = groq`*[_type==${type}]`;
+const broken = groq`*[_type ==`;
+"#;
+    let formatted = format_embedded_queries(source, &FormatOptions::new(60));
+    assert_eq!(
+        formatted.output,
+        r#"export const postQuery = groq`
+  // @name postBySlug
+  *[_type == "post" && slug.current == $slug][0]
+`;
+function authors() {
+  return defineQuery(`
+    *[_type == "author" && name != ""] {
+      name,
+      "bio": pt::text(bio),
+      "posts": count(*[references(^._id)])
+    }
+  `);
+}
+const dynamic = groq`*[_type==${type}]`;
+const broken = groq`*[_type ==`;
+"#
+    );
+    assert_eq!(formatted.errors.len(), 1);
+    assert_eq!(formatted.errors[0].line, 9);
+
+    // Queries that fit stay on their line, escaped for their quotes.
+    let source = "const q = defineQuery('*[name==\"O\\'Brien\"]');";
+    assert_eq!(
+        format_embedded_queries(source, &FormatOptions::default()).output,
+        "const q = defineQuery('*[name == \"O\\'Brien\"]');"
+    );
+}
+
+#[test]
+fn test_embedded_queries_decode_escapes() {
+    let source = r#"const a = defineQuery("*[name==\"caf\u00e9 \u{1F600}\x41\"]");
+const b = defineQuery("*[a==1 \
+&& b==2]");
+const c = defineQuery("*[name==\"\101\"]");
+"#;
+    let queries = extract_embedded_queries(source);
+    assert_eq!(queries.len(), 2);
+    assert_eq!(queries[0].query, "*[name==\"caf\u{e9} \u{1F600}A\"]");
+    assert_eq!(queries[1].query, "*[a==1 && b==2]");
+
+    // The decoded query is written back, and the literal with an octal escape
+    // is left alone.
+    assert_eq!(
+        format_embedded_queries(source, &FormatOptions::default()).output,
+        r#"const a = defineQuery("*[name == \"café 😀A\"]");
+const b = defineQuery("*[a == 1 && b == 2]");
+const c = defineQuery("*[name==\"\101\"]");
+"#
+    );
+}
+
+#[test]
+fn test_tokenize_spans_cover_input() {
+    let query = "*[_type == \"post\" && title match 'a\\'b'] // posts\n{ title, \"n\": count(tags) * 2.5, ~ }";
+    let tokens = tokenize(query);
+    let mut end = 0;
+    for token in &tokens {
+        assert_eq!(token.span().start, end);
+        end = token.span().end;
+    }
+    assert_eq!(end, query.len());
+    let text: String = tokens.iter().map(|token| token.text(query)).collect();
+    assert_eq!(text, query);
+    assert!(
+        tokens
+            .iter()
+            .any(|token| token.kind == TokenKind::Unknown && token.text(query) == "~")
+    );
+}
+
+#[test]
+fn test_format_ast_with_spans() {
+    use groq_parser::parser::{Parser, ParserConfig};
+
+    let query =
+        r#"*[_type=="post"&&defined(slug.current)&&publishedAt<now()]{title,"slug":slug.current}"#;
+    let parse = || {
+        Parser::new_with_config(query, ParserConfig::without_param_validation())
+            .parse()
+            .unwrap()
+    };
+    let result = parse();
+    let id = |steps: &[Step]| NodeId::of(&result.expr, &AstPath::new(steps.iter().copied()));
+
+    for width in [80, 40] {
+        let options = FormatOptions::new(width);
+        let formatted = format_ast_with_spans(&result.expr, &options);
+        assert_eq!(formatted.output, format_ast(&result.expr, &options));
+        let text = |steps: &[Step]| &formatted.output[formatted.span(id(steps).unwrap()).unwrap()];
+        assert_eq!(text(&[]), formatted.output);
+        assert_eq!(text(&[Step::Lhs, Step::Lhs]), "*");
+        let constraint = text(&[Step::Lhs, Step::Constraint]);
+        assert!(constraint.starts_with("_type == \"post\""));
+        assert!(constraint.ends_with("publishedAt < now()"));
+        assert_eq!(
+            text(&[Step::Object, Step::Item(1)]),
+            "\"slug\": slug.current"
+        );
+    }
+
+    // Ids are positions in the AST, the same in every parse of the query.
+    assert_eq!(id(&[]).map(NodeId::index), Some(0));
+    assert_eq!(id(&[Step::Lhs]).map(NodeId::index), Some(1));
+    let again = parse();
+    let formatted = format_ast_with_spans(&again.expr, &FormatOptions::default());
+    let field = NodeId::of(&again.expr, &AstPath::new([Step::Object, Step::Item(0)]));
+    assert_eq!(field, id(&[Step::Object, Step::Item(0)]));
+    assert_eq!(
+        &formatted.output[formatted.span(field.unwrap()).unwrap()],
+        "title"
+    );
+    // A projection's object is not an expression of its own.
+    assert_eq!(id(&[Step::Object]), None);
+}
+
+#[test]
+fn test_call_layout_overrides() {
+    const LAYOUTS: &[(&str, CallLayout)] = &[
+        ("coalesce", CallLayout::Fill),
+        ("global::select", CallLayout::Wrap),
+        ("pt::text", CallLayout::OnePerLine),
+    ];
+    let options = FormatOptions::new(40).with_call_layouts(LAYOUTS);
+
+    let input =
+        "*{\"name\":coalesce(displayName,fullName,firstName,lastName,nickname,email,username)}";
+    let expected = r#"* {
+  "name": coalesce(displayName,
+    fullName, firstName, lastName,
+    nickname, email, username)
+}"#;
+    assert_eq!(
+        format_query_with_options(input, &options).unwrap(),
+        expected
+    );
+
+    let input = r#"select(a=>"first choice",b=>"second choice","other")"#;
+    let expected = r#"select(
+  a => "first choice",
+  b => "second choice",
+  "other"
+)"#;
+    assert_eq!(format_query(input, 40).unwrap(), expected);
+    let expected = r#"select(a => "first choice",
+  b => "second choice",
+  "other")"#;
+    assert_eq!(
+        format_query_with_options(input, &options).unwrap(),
+        expected
+    );
+
+    // Namespaced functions are matched with their namespace.
+    let narrow = FormatOptions::new(10);
+    assert_eq!(
+        format_query_with_options("pt::text(body)", &narrow).unwrap(),
+        "pt::text(body)"
+    );
+    assert_eq!(
+        format_query_with_options("pt::text(body)", &narrow.with_call_layouts(LAYOUTS)).unwrap(),
+        "pt::text(\n  body\n)"
+    );
+}
+
+#[test]
+fn test_builtin_functions() {
+    // Lookups rely on the table being sorted.
+    assert!(
+        BUILTIN_FUNCTIONS
+            .windows(2)
+            .all(|pair| (pair[0].namespace, pair[0].name) < (pair[1].namespace, pair[1].name))
+    );
+    for function in BUILTIN_FUNCTIONS {
+        assert_eq!(
+            builtin_function(function.namespace, function.name),
+            Some(function)
+        );
+    }
+    assert_eq!(
+        builtin_function("", "select"),
+        builtin_function("global", "select")
+    );
+    assert_eq!(
+        builtin_function("pt", "text").unwrap().qualified_name(),
+        "pt::text"
+    );
+    assert_eq!(
+        builtin_function("", "order").unwrap().qualified_name(),
+        "order"
+    );
+    assert!(builtin_function("", "text").is_none());
+}
+
+#[test]
+fn test_style_edition() {
+    assert_eq!(
+        FormatOptions::default().style_edition,
+        StyleEdition::Edition2025
+    );
+    assert_eq!(
+        "2024".parse::<StyleEdition>(),
+        Ok(StyleEdition::Edition2024)
+    );
+    assert!("2023".parse::<StyleEdition>().is_err());
+    assert_eq!(StyleEdition::LATEST.to_string(), "2025");
+
+    // The latest edition formats like the default options.
+    let input = r#"*[_type=="post"&&defined(slug)]{title,"author":author->{name,image},"tags":tags[]->title}|order(publishedAt desc)[0...10]"#;
+    let options = FormatOptions::new(40).with_style_edition(StyleEdition::LATEST);
+    assert_eq!(
+        format_query_with_options(input, &options).unwrap(),
+        format_query(input, 40).unwrap()
+    );
+}
+
+#[test]
+fn test_style_editions_keep_their_layout() {
+    let format = |input: &str, width, force_wrap, edition| {
+        let options = FormatOptions::new(width)
+            .with_force_wrap(force_wrap)
+            .with_style_edition(edition);
+        format_query_with_options(input, &options).unwrap()
+    };
+
+    let input = r#"*[_type=="movie"]{title,releaseYear>2000=>{"era":"modern","rating":rating}}"#;
+    assert_eq!(
+        format(input, 44, false, StyleEdition::Edition2024),
+        r#"*[_type == "movie"] {
+  title,
+  releaseYear > 2000 => {
+    "era": "modern",
+    "rating": rating
+  }
+}"#
+    );
+    assert_eq!(
+        format(input, 44, false, StyleEdition::Edition2025),
+        r#"*[_type == "movie"] {
+  title,
+  releaseYear > 2000 =>
+    { "era": "modern", "rating": rating }
+}"#
+    );
+
+    let input =
+        "*[dateTime(publishedAt)>dateTime(now())-60*60*24*7*4*12+offsetSeconds-graceSeconds]";
+    assert_eq!(
+        format(input, 40, true, StyleEdition::Edition2024),
+        "*[
+  dateTime(publishedAt)
+    > dateTime(now())
+      - 60 * 60 * 24 * 7 * 4 * 12
+      + offsetSeconds
+      - graceSeconds
+]"
+    );
+    assert_eq!(
+        format(input, 40, true, StyleEdition::Edition2025),
+        "*[
+  dateTime(publishedAt)
+    > dateTime(now())
+      - 60 * 60 * 24 * 7 * 4 * 12
+      + offsetSeconds - graceSeconds
+]"
+    );
+
+    let input = r#"*[(firstName,lastName,middleName,nickname)==("Jonathan","Appleseed","Quincy","Johnny")]"#;
+    assert_eq!(
+        format(input, 80, false, StyleEdition::Edition2024),
+        r#"*[(firstName, lastName, middleName, nickname) == ("Jonathan",
+"Appleseed",
+"Quincy",
+"Johnny")]"#
+    );
+    assert_eq!(
+        format(input, 80, false, StyleEdition::Edition2025),
+        r#"*[(firstName, lastName, middleName, nickname) == (
+  "Jonathan",
+  "Appleseed",
+  "Quincy",
+  "Johnny"
+)]"#
+    );
+
+    let input = r#"*[_type=="post"]|order(a)|order(b)|order(c)"#;
+    assert_eq!(
+        format(input, 40, false, StyleEdition::Edition2024),
+        "*[_type == \"post\"] | order(a) | order(b)\n  | order(c)"
+    );
+    assert_eq!(
+        format(input, 40, false, StyleEdition::Edition2025),
+        "*[_type == \"post\"]\n  | order(a)\n  | order(b)\n  | order(c)"
+    );
+}
+
+#[test]
+fn test_simplify() {
+    let input = r#"*[(published) && ("x" in tags)]{"title": title, "n": (count(tags))}"#;
+    assert_eq!(
+        format_query_with_options(input, &FormatOptions::default()).unwrap(),
+        r#"*[(published) && ("x" in tags)] { "title": title, "n": (count(tags)) }"#
+    );
+    assert_eq!(
+        format_query_with_options(input, &FormatOptions::default().with_simplify(true)).unwrap(),
+        r#"*[published && ("x" in tags)] { title, "n": count(tags) }"#
+    );
+}
+
+#[test]
+fn test_format_query_with_metadata() {
+    let query = r#"*[_type=="post"&&defined(slug)&&!(_id in path("drafts.**"))]{title,"author":author->name}|order(publishedAt desc)|order(title)|order(_id)[0...10]"#;
+    let options = FormatOptions::new(50);
+    let formatted = format_query_with_metadata(query, &options).unwrap();
+    assert_eq!(
+        formatted.output,
+        format_query_with_options(query, &options).unwrap()
+    );
+    assert_eq!(
+        formatted.metadata.line_count,
+        formatted.output.lines().count()
+    );
+    assert_eq!(
+        formatted.metadata.broken,
+        vec![
+            Construct::Filter,
+            Construct::Projection,
+            Construct::Pipe,
+            Construct::Pipe,
+            Construct::Pipe
+        ]
+    );
+    assert!(!formatted.metadata.overflowed);
+
+    let flat = format_query_with_metadata("*[a == 1] { b } // done", &options).unwrap();
+    assert_eq!(flat.output, "*[a == 1] { b } // done");
+    assert_eq!(flat.metadata.line_count, 1);
+    assert_eq!(flat.metadata.max_line_width, 23);
+    assert!(flat.metadata.broken.is_empty());
+
+    let long = format_query_with_metadata(
+        r#"*[title == "a rather long title"]"#,
+        &FormatOptions::new(20),
+    )
+    .unwrap();
+    assert_eq!(long.metadata.max_line_width, 33);
+    assert!(long.metadata.overflowed);
+
+    let object = format_query_with_metadata(
+        r#"{"posts": *[_type == "post"], "pages": *[_type == "page"]}"#,
+        &FormatOptions::new(30),
+    )
+    .unwrap();
+    assert_eq!(object.metadata.broken, vec![Construct::Base]);
+}
+
+#[test]
+fn test_format_warnings() {
+    let options = FormatOptions::new(30)
+        .with_lenient(true)
+        .with_strip_invisible(true);
+    let query = "\n  *[_type\u{200B} == \"post\"]{\n  title,\n  \"summary\": \"a summary that is far too long\",\n}";
+    let formatted = format_query_with_metadata(query, &options).unwrap();
+    assert_eq!(
+        formatted.output,
+        format_query_with_options(query, &options).unwrap()
+    );
+    let warnings: Vec<String> = formatted.warnings.iter().map(Warning::to_string).collect();
+    assert_eq!(
+        warnings,
+        vec![
+            "removed U+200B ZERO WIDTH SPACE at 2:10",
+            "removed trailing comma on line 4",
+            "output line 3 (45 columns) exceeds the width",
+        ]
+    );
+
+    // Without the options, nothing is repaired and nothing is reported.
+    let clean = format_query_with_metadata("*[a == 1]", &FormatOptions::default()).unwrap();
+    assert!(clean.warnings.is_empty());
+}
+
+#[test]
+fn test_format_subexpression() {
+    let query = r#"*[_type == "post" && defined(slug)]{title, "author": author->{name, image}} | order(publishedAt desc, title asc)"#;
+    let options = FormatOptions::new(40);
+    let format = |steps: &[Step], column| {
+        format_subexpression(
+            query,
+            &AstPath::new(steps.iter().copied()),
+            column,
+            &options,
+        )
+    };
+
+    // The filter's condition fits on its own, but not after 20 columns.
+    let constraint = [Step::Lhs, Step::Lhs, Step::Constraint];
+    assert_eq!(
+        format(&constraint, 0).unwrap(),
+        r#"_type == "post" && defined(slug)"#
+    );
+    assert_eq!(
+        format(&constraint, 20).unwrap(),
+        "_type == \"post\"\n  && defined(slug)"
+    );
+
+    // A field of the projection, and the function of the pipe stage.
+    assert_eq!(
+        format(&[Step::Lhs, Step::Object, Step::Item(1)], 2).unwrap(),
+        r#""author": author-> { name, image }"#
+    );
+    assert_eq!(
+        format(&[Step::Function], 0).unwrap(),
+        "order(publishedAt desc, title asc)"
+    );
+
+    let missing = AstPath::new([Step::Lhs, Step::Object, Step::Item(5)]);
+    let err = format_subexpression(query, &missing, 0, &options).unwrap_err();
+    assert!(matches!(&err, FormatError::NodeNotFound(path) if *path == missing));
+    assert_eq!(
+        err.to_string(),
+        "the query has no node at `lhs.object.item[5]`"
+    );
+}
+
+#[test]
+fn test_format_range() {
+    let query = "// posts\n*[_type==\"post\"]{\n  title,\n  \"a\":author->{name,bio},\n  // the body\n  body[]{...,\"x\":1}\n}\n";
+    let options = FormatOptions::default();
+    let range = |query: &str, from: &str, to: &str| {
+        let start = query.find(from).unwrap();
+        start..query[start..].find(to).unwrap() + start + to.len()
+    };
+
+    // One field, and the fields on two lines around a comment.
+    assert_eq!(
+        format_range(query, range(query, "\"a\"", "bio}"), &options).unwrap(),
+        "// posts\n*[_type==\"post\"]{\n  title,\n  \"a\": author-> { name, bio },\n  // the body\n  body[]{...,\"x\":1}\n}\n"
+    );
+    assert_eq!(
+        format_range(query, range(query, "bio", "body[]"), &options).unwrap(),
+        "// posts\n*[_type==\"post\"]{\n  title,\n  \"a\": author-> { name, bio },\n  // the body\n  body[] { ..., \"x\": 1 }\n}\n"
+    );
+    // The whole query, whose object keeps its comment and layout.
+    assert_eq!(
+        format_range(query, 0..query.len(), &options).unwrap(),
+        "// posts\n*[_type == \"post\"]{\n  title,\n  \"a\": author-> { name, bio },\n  // the body\n  body[] { ..., \"x\": 1 }\n}\n"
+    );
+    // A range in the comment touches no expression.
+    assert_eq!(format_range(query, 0..5, &options).unwrap(), query);
+
+    // Later lines of a node are indented like the line it starts on.
+    let query = "*[_type == \"post\"] {\n    \"a\": author->{name, bio, image, slug, publishedAt, categories}\n}";
+    assert_eq!(
+        format_range(query, range(query, "author", "}"), &FormatOptions::new(40)).unwrap(),
+        "*[_type == \"post\"] {\n    \"a\": author-> {\n      name,\n      bio,\n      image,\n      slug,\n      publishedAt,\n      categories\n    }\n}"
+    );
+}
+
+#[test]
+fn test_partial_formatting_applies_width_options() {
+    let input = "// Fetch all published posts for the landing page, including their authors.\n*[_type==\"post\"&&defined(slug)]";
+    let options = FormatOptions::new(30).with_reflow_comments(true);
+    let explanation = explain_query(input, &options).unwrap();
+    assert_eq!(
+        explanation.output,
+        format_query_with_options(input, &options).unwrap()
+    );
+    // The filter's group starts on the line after the reflowed comment.
+    let lines: Vec<&str> = explanation.output.lines().collect();
+    let group = &explanation.groups[0];
+    assert_eq!(lines[group.line - 1], "*[_type == \"post\"");
+    assert_eq!(group.end_line, lines.len());
+
+    let clamped = FormatOptions::new(0).with_clamp_width(true);
+    let explanation = explain_query(input, &clamped).unwrap();
+    assert_eq!(
+        explanation.output,
+        format_query_with_options(input, &clamped).unwrap()
+    );
+
+    let query =
+        "*[_type == \"post\"] {\n  \"description\": \"a description that is far too long\"\n}";
+    let strict = FormatOptions::new(40).with_strict_width(true);
+    let field = AstPath::new([Step::Object, Step::Item(0)]);
+    assert!(matches!(
+        format_subexpression(query, &field, 2, &strict),
+        Err(FormatError::WidthExceeded { width: 40, .. })
+    ));
+    assert!(format_subexpression(query, &field, 2, &FormatOptions::new(60)).is_ok());
+
+    // Overflowing lines are reported by their line in the query.
+    let start = query.find("\"description").unwrap();
+    let end = query.find("long\"").unwrap() + 5;
+    match format_range(query, start..end, &strict) {
+        Err(FormatError::WidthExceeded { width, lines }) => {
+            assert_eq!(width, 40);
+            assert_eq!(lines.len(), 1);
+            assert_eq!((lines[0].line, lines[0].width), (2, 53));
+        }
+        other => panic!("expected WidthExceeded, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_node_addressing() {
+    let query = r#"
+*[_type == "post" && count(tags[]) > 2]{title, "author": author->{name}, "rank": [1, 2][0]}
+  | order(title asc)[0...10]"#;
+
+    // Every offset in the expression leads to a node whose range holds it.
+    for (offset, c) in query.char_indices().filter(|(_, c)| !c.is_whitespace()) {
+        let path = node_at(query, offset).unwrap().unwrap();
+        let range = select(query, &path).unwrap();
+        assert!(range.contains(&offset), "{} at {}: {:?}", c, offset, range);
+        // Paths round-trip through their text form.
+        assert_eq!(path.to_string().parse::<AstPath>(), Ok(path));
+    }
+    assert_eq!(node_at(query, 0).unwrap(), None);
+
+    let text = |path: &str| &query[select(query, &path.parse().unwrap()).unwrap()];
+    assert_eq!(
+        text("lhs.lhs.object.item[1]"),
+        r#""author": author->{name}"#
+    );
+    assert_eq!(text("lhs.lhs.object.item[2].rhs"), "[1, 2][0]");
+    assert_eq!(text("lhs.function"), "order(title asc)");
+    assert_eq!(text("lhs.lhs.lhs.constraint.rhs.lhs.arg[0]"), "tags[]");
+    assert_eq!(text("index"), "0...10");
+
+    // The same paths address nodes for formatting.
+    let path: AstPath = "lhs.lhs.object".parse().unwrap();
+    assert_eq!(
+        format_subexpression(query, &path, 0, &FormatOptions::default()).unwrap(),
+        r#"{ title, "author": author-> { name }, "rank": [1, 2][0] }"#
+    );
+
+    assert_eq!(
+        "lhs.items[0]".parse::<AstPath>(),
+        Err("invalid path step `items[0]`".to_string())
+    );
+    assert!(matches!(
+        select(query, &"rhs".parse().unwrap()),
+        Err(FormatError::NodeNotFound(_))
+    ));
+}
+
+#[test]
+fn test_parse_to_json() {
+    let query = "  *[_type == 'post'] | order(title) // newest\n";
+    let json: serde_json::Value = serde_json::from_str(&parse_to_json(query).unwrap()).unwrap();
+    let expr = &json["expr"];
+    assert_eq!(expr["kind"], "function_pipe");
+    assert_eq!(expr["span"], serde_json::json!({ "start": 2, "end": 35 }));
+
+    let filter = &expr["children"][0];
+    assert_eq!(
+        (&filter["kind"], &filter["role"]),
+        (&"filter".into(), &"lhs".into())
+    );
+    let constraint = &filter["children"][1];
+    assert_eq!(constraint["operator"], "==");
+    assert_eq!(constraint["children"][0]["name"], "_type");
+    assert_eq!(constraint["children"][1]["value"], "post");
+
+    // The arguments of a pipe stage appear once, under its function.
+    let order = &expr["children"][1];
+    assert_eq!(expr["children"].as_array().unwrap().len(), 2);
+    assert_eq!(
+        (&order["kind"], &order["name"]),
+        (&"function_call".into(), &"order".into())
+    );
+    let title = &order["children"][0];
+    assert_eq!(title["role"], "arg[0]");
+    let span = title["span"]["start"].as_u64().unwrap() as usize
+        ..title["span"]["end"].as_u64().unwrap() as usize;
+    assert_eq!(&query[span], "title");
+
+    assert_eq!(json["comments"][0]["text"], "// newest");
+    assert!(parse_to_json("*[").is_err());
+}
+
+#[test]
+fn test_lint_null_comparisons() {
+    let query = "*[_type == \"post\" && slug != null] {\n  \"hasImage\": select(null == image => false, true)\n}";
+    let lints = lint_query(query).unwrap();
+    assert_eq!(lints.len(), 2);
+    assert_eq!((lints[0].line, lints[0].column), (1, 22));
+    assert_eq!(&query[lints[0].range.clone()], "slug != null");
+    assert_eq!(
+        lints[0].to_string(),
+        "use `defined(slug)` instead of `slug != null`"
+    );
+    assert_eq!((lints[1].line, lints[1].column), (2, 22));
+    assert_eq!(lints[1].replacement, "!defined(image)");
+
+    assert_eq!(
+        fix_query(query).unwrap(),
+        "*[_type == \"post\" && defined(slug)] {\n  \"hasImage\": select(!defined(image) => false, true)\n}"
+    );
+    // Comparisons with anything but null are left alone.
+    assert!(
+        lint_query("*[a == b && c != 1 && null == null]")
+            .unwrap()
+            .is_empty()
+    );
+
+    // Each query of a document is linted on its own.
+    let lints = lint_query("*[a == null]\n\n*[b ==\n\n*[c != null]").unwrap();
+    let replacements: Vec<_> = lints.iter().map(|lint| lint.replacement.as_str()).collect();
+    assert_eq!(replacements, ["!defined(a)", "defined(c)"]);
+    assert_eq!((lints[1].line, lints[1].column), (5, 3));
+}
+
+#[test]
+fn test_hoist_projections() {
+    let query = r#"*[_type == "post"]{"cover": image{asset->{url}, alt}, "thumb": image{ asset->{url}, alt }, "author": author->{name, "n": ^.name}, "editor": editor->{name, "n": ^.name}}"#;
+    let hoisted = hoist_projections(query).unwrap();
+    assert_eq!(
+        format_query(&hoisted, 80).unwrap(),
+        r#"fn local::image($image) = $image { asset-> { url }, alt };
+
+*[_type == "post"] {
+  "cover": local::image(image),
+  "thumb": local::image(image),
+  "author": author-> { name, "n": ^.name },
+  "editor": editor-> { name, "n": ^.name }
+}"#
+    );
+
+    // Objects used once, or too short to be worth a function, are kept.
+    let query = "*[]{a{b, c}, d{b, c}, e{title, slug, body}}";
+    assert_eq!(hoist_projections(query).unwrap(), query);
+}
+
+/// Queries covering the constructs the formatter lays out differently, for
+/// checking properties that must hold for every query.
+const CORPUS: &[&str] = &[
+    r#"*[_type == "post"]"#,
+    r#"*[_type=="post"&&published==true]{_id,title,slug,author->{name,image{asset->{url}}},publishedAt,excerpt,categories[]->{title,slug}}"#,
+    r#"*[_type == "post" && (category == "news" || category == "blog") && !(_id in path("drafts.**"))] | order(publishedAt desc)[0...10]"#,
+    r#"*[_type == "movie"]{title, "cast": castMembers[]{characterName, person->{name, "image": image.asset->url}}}"#,
+    r#"count(*[_type == "post" && references(^._id)]) > 0"#,
+    r#"*[_type == "product"]{..., "price": coalesce(salePrice, price, defaultPrice, 0), defined(stock) => {stock}}"#,
+    r#"*[_type == "event" && dateTime(startsAt) > dateTime(now()) - 60*60*24*7]{name, startsAt} | order(startsAt asc)"#,
+    "// All posts\n*[_type == \"post\"] {\n  // The title\n  title, // trailing\n  body\n}",
+    r#"fn local::card($doc) = $doc{title, "slug": slug.current}; *[_type == "post"]{"card": local::card(@)}"#,
+    r#"{"posts": *[_type == "post"][0...3]{title}, "authors": *[_type == "author"]{name, "count": count(*[_type == "post" && author._ref == ^._id])}}"#,
+    r#"*[_type == "page" && slug.current == $slug][0]{title, sections[]{_type == "hero" => {heading, "image": image.asset->url}, _type == "text" => {body}}}"#,
+    r#"*[select(_type == "a" => 1, _type == "b" => 2, 3) > 1 && title match "hello*" && score(title match $q) > 0]"#,
+    r#"math::sum(*[_type == "order"].total) / count(*[_type == "order"])"#,
+    r#"[1, 2, 3, "four", true, null, {"five": 5}, [6, 7]]"#,
+];
+
+#[test]
+fn test_formatting_is_idempotent() {
+    let variants = [
+        FormatOptions::new(80),
+        FormatOptions::new(80).with_force_wrap(true),
+        FormatOptions::new(80)
+            .with_align_conditions(true)
+            .with_closing_bracket_on_own_line(true),
+        FormatOptions::new(80)
+            .with_simplify(true)
+            .with_break_depth(Some(1)),
+        FormatOptions::new(80).with_indent(4).with_use_tabs(true),
+    ];
+    for query in CORPUS {
+        for options in &variants {
+            for width in [10, 20, 40, 60, 80, 120] {
+                let options = FormatOptions {
+                    width,
+                    ..options.clone()
+                };
+                if let Err(err) = verify_idempotent(query, &options) {
+                    panic!("{} at width {}: {:?}", err, width, err);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_minify_query() {
+    assert_eq!(
+        minify_query("*[_type == \"movie\" && releaseYear >= 1979] | order(releaseYear desc) {\n  title,\n  \"director\": director->name\n}").unwrap(),
+        r#"*[_type=="movie"&&releaseYear>=1979]|order(releaseYear desc){title,"director":director->name}"#
+    );
+    assert!(matches!(minify_query("  "), Err(FormatError::EmptyQuery)));
+
+    // Minified queries mean the same and format the same.
+    for query in CORPUS {
+        let minified = minify_query(query).unwrap();
+        assert!(!minified.contains('\n'), "{}", minified);
+        assert_eq!(query_fingerprint(&minified), query_fingerprint(query));
+        if !query.contains("//") {
+            assert_eq!(
+                format_query(&minified, 80).unwrap(),
+                format_query(query, 80).unwrap()
+            );
+        }
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_daemon_formats_for_its_user_only() {
+    use std::io::Write;
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    use std::process::{Command, Stdio};
+
+    let dir = tempfile::tempdir().unwrap();
+    let socket = dir.path().join("daemon.sock");
+    let mut daemon = Command::new(env!("CARGO_BIN_EXE_groq-format"))
+        .arg("--daemon")
+        .arg("--socket")
+        .arg(&socket)
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    for _ in 0..500 {
+        if socket.exists() {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    let metadata = std::fs::symlink_metadata(&socket).unwrap();
+    assert!(metadata.file_type().is_socket());
+    assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+
+    let mut client = Command::new(env!("CARGO_BIN_EXE_groq-format"))
+        .arg("--use-daemon")
+        .arg("--socket")
+        .arg(&socket)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    client
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"*[a==1]\n\n*[b==2]")
+        .unwrap();
+    let output = client.wait_with_output().unwrap();
+    daemon.kill().unwrap();
+    daemon.wait().unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "*[a == 1]\n\n*[b == 2]\n"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_daemon_keeps_files_in_place_of_its_socket() {
+    use std::process::Command;
+
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("query.groq");
+    std::fs::write(&file, "*[a==1]").unwrap();
+
+    for mode in ["--daemon", "--use-daemon"] {
+        let output = Command::new(env!("CARGO_BIN_EXE_groq-format"))
+            .arg(mode)
+            .arg("--socket")
+            .arg(&file)
+            .output()
+            .unwrap();
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stderr).contains("is not a socket"));
+    }
+    assert_eq!(std::fs::read_to_string(&file).unwrap(), "*[a==1]");
+}
+
+/// Write `contents` to a file, format it in place with `args`, and return
+/// what was written.
+fn format_file_in_place(contents: &[u8], args: &[&str]) -> Vec<u8> {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("query.groq");
+    std::fs::write(&path, contents).unwrap();
+    let status = std::process::Command::new(env!("CARGO_BIN_EXE_groq-format"))
+        .arg("-w")
+        .args(args)
+        .arg(&path)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    std::fs::read(&path).unwrap()
+}
+
+#[test]
+fn test_write_keeps_file_encoding() {
+    let utf16 = |text: &str, bom: [u8; 2], to_bytes: fn(u16) -> [u8; 2]| -> Vec<u8> {
+        bom.into_iter()
+            .chain(text.encode_utf16().flat_map(to_bytes))
+            .collect()
+    };
+
+    assert_eq!(format_file_in_place(b"*[a==1]", &[]), b"*[a == 1]\n");
+    assert_eq!(
+        format_file_in_place(b"\xEF\xBB\xBF*[a==\"\xC3\xA9\"]\n", &[]),
+        b"\xEF\xBB\xBF*[a == \"\xC3\xA9\"]\n"
+    );
+    assert_eq!(
+        format_file_in_place(
+            &utf16("*[a==\"\u{e9}\"]\n", [0xFF, 0xFE], u16::to_le_bytes),
+            &[]
+        ),
+        utf16("*[a == \"\u{e9}\"]\n", [0xFF, 0xFE], u16::to_le_bytes)
+    );
+    assert_eq!(
+        format_file_in_place(
+            &utf16("*[a==\"\u{e9}\"]\n", [0xFE, 0xFF], u16::to_be_bytes),
+            &[]
+        ),
+        utf16("*[a == \"\u{e9}\"]\n", [0xFE, 0xFF], u16::to_be_bytes)
+    );
+    assert_eq!(
+        format_file_in_place(b"*[a==1]{\r\ntitle}\r\n", &["--width", "10"]),
+        b"*[a == 1] {\r\n  title\r\n}\r\n"
+    );
+    assert_eq!(
+        format_file_in_place(b"*[a==1]", &["--preserve-final-newline"]),
+        b"*[a == 1]"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_write_follows_symlinks() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let target = dir.path().join("target.groq");
+    let link = dir.path().join("link.groq");
+    std::fs::write(&target, "*[a==1]\n").unwrap();
+    std::fs::set_permissions(&target, std::fs::Permissions::from_mode(0o640)).unwrap();
+    std::os::unix::fs::symlink(&target, &link).unwrap();
+
+    let status = std::process::Command::new(env!("CARGO_BIN_EXE_groq-format"))
+        .arg("-w")
+        .arg(&link)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert!(
+        std::fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink()
+    );
+    assert_eq!(std::fs::read_to_string(&target).unwrap(), "*[a == 1]\n");
+    let mode = std::fs::metadata(&target).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o640);
+}
+
+/// Run the command-line tool in `dir` and return the lines it prints.
+fn run_in(dir: &std::path::Path, args: &[&str]) -> Vec<String> {
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_groq-format"))
+        .current_dir(dir)
+        .args(args)
+        .output()
+        .unwrap();
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(str::to_string)
+        .collect()
+}
+
+#[test]
+fn test_glob_and_ignore_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("work");
+    for file in [
+        "q/a.groq",
+        "q/sub/b.groq",
+        "q/sub/deep/c.groq",
+        "q/.hidden/d.groq",
+        "q/node_modules/e.groq",
+        "q/skip/f.groq",
+        "q/g.tmp.groq",
+        "q/keep.tmp.groq",
+        "q/[x].groq",
+        "q/notes.txt",
+    ] {
+        let path = root.join(file);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, "*[a==1]").unwrap();
+    }
+    std::fs::write(
+        root.join(".groqformatignore"),
+        "# generated\nskip/\n*.tmp.groq\n!keep.tmp.groq\n/q/sub/deep\n",
+    )
+    .unwrap();
+
+    let expected = ["q/[x].groq", "q/a.groq", "q/keep.tmp.groq", "q/sub/b.groq"];
+    assert_eq!(run_in(&root, &["-l", "q"]), expected);
+    assert_eq!(run_in(&root, &["-l", "q/**/*.groq"]), expected);
+    assert_eq!(
+        run_in(&root, &["-l", "./q/**/*.groq"]),
+        expected.map(|path| format!("./{}", path))
+    );
+    assert_eq!(run_in(&root, &["-l", "q/*/b.groq"]), ["q/sub/b.groq"]);
+    assert_eq!(run_in(&root, &["-l", "q/[!a-z]x?.groq"]), ["q/[x].groq"]);
+    assert_eq!(run_in(&root, &["-l", "q/\\[x].groq"]), ["q/[x].groq"]);
+    assert_eq!(
+        run_in(&root, &["-l", "--hidden", "q/.hidden"]),
+        ["q/.hidden/d.groq"]
+    );
+
+    // Paths through `..` are matched against the ignore file as well.
+    let sub = root.join("q/sub");
+    let ignore = root.join(".groqformatignore");
+    std::fs::rename(&ignore, sub.join(".groqformatignore")).unwrap();
+    std::fs::write(sub.join(".groqformatignore"), "/deep/\n").unwrap();
+    assert_eq!(run_in(&sub, &["-l", "../sub"]), ["../sub/b.groq"]);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_directory_links_and_hidden_files() {
+    use std::os::unix::fs::symlink;
+
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("q");
+    let outside = dir.path().join("outside");
+    for path in [
+        root.join("a.groq"),
+        root.join(".b.groq"),
+        root.join(".hidden/c.groq"),
+        outside.join("d.groq"),
+    ] {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, "*[a==1]").unwrap();
+    }
+    symlink(outside.join("d.groq"), root.join("file.groq")).unwrap();
+    symlink(&outside, root.join("dir")).unwrap();
+    // A link back to the directory being searched, and one to nothing.
+    symlink(&root, root.join("cycle")).unwrap();
+    symlink(dir.path().join("missing.groq"), root.join("dangling.groq")).unwrap();
+
+    // Links and hidden entries are skipped unless asked for.
+    assert_eq!(run_in(dir.path(), &["-l", "q"]), ["q/a.groq"]);
+    assert_eq!(
+        run_in(dir.path(), &["-l", "--hidden", "q"]),
+        ["q/.b.groq", "q/.hidden/c.groq", "q/a.groq"]
+    );
+    assert_eq!(
+        run_in(dir.path(), &["-l", "--follow-symlinks", "q"]),
+        ["q/a.groq", "q/dir/d.groq", "q/file.groq"]
+    );
+}
+
+#[test]
+fn test_glob_matching_does_not_backtrack() {
+    let dir = tempfile::tempdir().unwrap();
+    let name = format!("{}.groq", "a".repeat(200));
+    std::fs::write(dir.path().join(&name), "*[a==1]").unwrap();
+
+    let start = std::time::Instant::now();
+    let pattern = format!("{}b.groq", "*a".repeat(30));
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_groq-format"))
+        .current_dir(dir.path())
+        .args(["-l", &pattern])
+        .output()
+        .unwrap();
+    assert!(start.elapsed() < std::time::Duration::from_secs(10));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("no files match"));
+    assert_eq!(
+        run_in(dir.path(), &["-l", &format!("{}.groq", "*a".repeat(30))]),
+        [format!("./{}", name)]
+    );
+}
+
+/// Send `messages` to a language server started with `--lsp`, followed by
+/// `exit`, and return its responses.
+fn lsp_session(messages: &[String]) -> Vec<serde_json::Value> {
+    use std::io::{Read, Write};
+    use std::process::{Command, Stdio};
+
+    let mut server = Command::new(env!("CARGO_BIN_EXE_groq-format"))
+        .arg("--lsp")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = server.stdin.take().unwrap();
+    for body in messages
+        .iter()
+        .map(String::as_str)
+        .chain([r#"{"jsonrpc":"2.0","method":"exit"}"#])
+    {
+        write!(stdin, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+    }
+    drop(stdin);
+    let mut output = String::new();
+    server
+        .stdout
+        .take()
+        .unwrap()
+        .read_to_string(&mut output)
+        .unwrap();
+    assert!(server.wait().unwrap().success());
+    output
+        .split("Content-Length: ")
+        .filter(|message| !message.is_empty())
+        .map(|message| serde_json::from_str(message.split_once("\r\n\r\n").unwrap().1).unwrap())
+        .collect()
+}
+
+#[test]
+fn test_lsp_formats_ranges_of_documents() {
+    let text = "*[a==1]\n\n*[name==\"\u{1F600}\"]{x,y}";
+    let open = serde_json::json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/didOpen",
+        "params": { "textDocument": { "uri": "file:///q.groq", "text": text } },
+    });
+    // `{` is at character 13 of line 2, counting the emoji as two UTF-16
+    // code units.
+    let format_range = serde_json::json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": "textDocument/rangeFormatting",
+        "params": {
+            "textDocument": { "uri": "file:///q.groq" },
+            "options": { "tabSize": 2, "insertSpaces": true },
+            "range": {
+                "start": { "line": 2, "character": 13 },
+                "end": { "line": 2, "character": 14 },
+            },
+        },
+    });
+    let responses = lsp_session(&[open.to_string(), format_range.to_string()]);
+
+    assert_eq!(responses.len(), 1);
+    let edit = &responses[0]["result"][0];
+    assert_eq!(edit["newText"], "*[a==1]\n\n*[name==\"\u{1F600}\"]{ x, y }");
+    assert_eq!(
+        edit["range"]["end"],
+        serde_json::json!({ "line": 2, "character": 18 })
+    );
+}
+
+#[test]
+fn test_lsp_answers_malformed_messages() {
+    let shutdown = r#"{"jsonrpc":"2.0","id":2,"method":"shutdown"}"#;
+    let responses = lsp_session(&["{oops".to_string(), shutdown.to_string()]);
+
+    assert_eq!(responses.len(), 2);
+    assert_eq!(responses[0]["id"], serde_json::Value::Null);
+    assert_eq!(responses[0]["error"]["code"], -32700);
+    assert_eq!(responses[1]["id"], 2);
+    assert_eq!(responses[1]["result"], serde_json::Value::Null);
+}
+
+#[test]
+fn test_staged_uses_extensions() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path();
+    let git = |args: &[&str]| {
+        let status = std::process::Command::new("git")
+            .current_dir(root)
+            .args(args)
+            .status()
+            .unwrap();
+        assert!(status.success());
+    };
+    git(&["init", "-q"]);
+    std::fs::write(root.join("a.groq"), "*[a==1]\n").unwrap();
+    std::fs::write(root.join("b.query"), "*[b==2]\n").unwrap();
+    std::fs::write(
+        root.join("c.ts"),
+        "const q = groq`*[c==3]`;\nconst x = 1;\n",
+    )
+    .unwrap();
+    git(&["add", "."]);
+
+    assert_eq!(run_in(root, &["--staged", "-l"]), ["a.groq"]);
+    assert_eq!(
+        run_in(root, &["--staged", "-l", "--ext", "groq,query"]),
+        ["a.groq", "b.query"]
+    );
+    assert_eq!(
+        run_in(root, &["--staged", "-l", "--embedded", "ts"]),
+        ["c.ts"]
+    );
+
+    run_in(root, &["--staged", "--embedded", "ts"]);
+    assert_eq!(
+        std::fs::read_to_string(root.join("c.ts")).unwrap(),
+        "const q = groq`*[c == 3]`;\nconst x = 1;\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(root.join("a.groq")).unwrap(),
+        "*[a==1]\n"
+    );
+}
+
+#[test]
+fn test_staged_keeps_encoding() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path();
+    let git = |args: &[&str]| {
+        let output = std::process::Command::new("git")
+            .current_dir(root)
+            .args(["-c", "core.autocrlf=false"])
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        output.stdout
+    };
+    git(&["init", "-q"]);
+    std::fs::write(root.join("crlf.groq"), "*[a==1]{\r\n  a,b\r\n}\r\n").unwrap();
+    std::fs::write(root.join("bom.groq"), b"\xEF\xBB\xBF*[b==2]\n").unwrap();
+    git(&["add", "."]);
+
+    run_in(root, &["--staged"]);
+    for (path, expected) in [
+        ("crlf.groq", &b"*[a == 1] { a, b }\r\n"[..]),
+        ("bom.groq", &b"\xEF\xBB\xBF*[b == 2]\n"[..]),
+    ] {
+        assert_eq!(git(&["show", &format!(":{}", path)]), expected);
+        assert_eq!(std::fs::read(root.join(path)).unwrap(), expected);
+    }
+}
+
+#[test]
+fn test_color_formatted_file() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("q.groq"), "*[a == 1]\n").unwrap();
+
+    let output = run_in(dir.path(), &["--color", "q.groq"]);
+    assert_eq!(output.len(), 1);
+    assert!(output[0].contains("\x1b["), "{:?}", output);
+}